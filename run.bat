@@ -1,3 +1,4 @@
+python C:/Users/Killian/CLionProjects/Rust-Kernel/tools/embed_symbols.py C:/Users/Killian/CLionProjects/Rust-Kernel/target/x86_64-os/debug/rust-kernel
 
 cd C:/Users/Killian/.cargo/registry/src/github.com-1ecc6299db9ec823/bootloader-0.10.12
 cargo builder --kernel-manifest C:/Users/Killian/CLionProjects/Rust-Kernel/Cargo.toml --kernel-binary C:/Users/Killian/CLionProjects/Rust-Kernel/target/x86_64-os/debug/rust-kernel --target-dir C:/Users/Killian/CLionProjects/Rust-Kernel/target --out-dir C:/Users/Killian/CLionProjects/Rust-Kernel/target/x86_64-os/debug
@@ -19,7 +19,7 @@ impl AcpiDevice
         }
     }
 
-    fn decompress_eisa_id(eisa_id: u32) -> String
+    pub fn decompress_eisa_id(eisa_id: u32) -> String
     {
         let eisa_id_1 = eisa_id.to_be();
         format!("{}{}{}{:03X}{:X}",
@@ -10,6 +10,7 @@ use spin::Mutex;
 use alloc::boxed::Box;
 use crate::pci::PCI_HANDLER;
 use crate::acpi::ACPI;
+use crate::cmdline::Parameter;
 
 pub struct AmlHandler
 {
@@ -221,14 +222,17 @@ impl Handler for AmlHandler
     }
 }
 
+pub static AML_DEBUG_VERBOSITY: Parameter<DebugVerbosity> = Parameter::new("aml.debug", DebugVerbosity::None, "what the AML interpreter traces: none, scopes, all_scopes or all");
+
 lazy_static!
 {
     pub static ref AML_CONTEXT: Mutex<AmlContext> = {
         info!("[AML] Initializing AML context");
+        let verbosity = AML_DEBUG_VERBOSITY.get();
         let mut aml_context = match PCI_HANDLER.lock().as_ref()
         {
-            Some(pci_handler) => AmlContext::new(Box::new(AmlHandler::new_with_pci(pci_handler.clone())), DebugVerbosity::None),
-            None => AmlContext::new(Box::new(AmlHandler::new()), DebugVerbosity::None)
+            Some(pci_handler) => AmlContext::new(Box::new(AmlHandler::new_with_pci(pci_handler.clone())), verbosity),
+            None => AmlContext::new(Box::new(AmlHandler::new()), verbosity)
         };
 
         if let Some(dsdt) = &ACPI.lock().acpi_tables.dsdt
@@ -4,7 +4,7 @@ use acpi::AcpiTables;
 use lazy_static::lazy_static;
 use log::{error, info, warn};
 pub use crate::acpi::acpi_device::AcpiDevice;
-pub use crate::acpi::aml::AML_CONTEXT;
+pub use crate::acpi::aml::{AML_CONTEXT, AML_DEBUG_VERBOSITY};
 use crate::BOOT_INFO;
 use crate::device::Device;
 use crate::pci::PCI_HANDLER;
@@ -1,20 +1,120 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
 use linked_list_allocator::LockedHeap;
 use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::VirtAddr;
-use crate::VMM;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::cmdline::Parameter;
+use crate::sync::IrqMutex;
+use crate::pmm::PMM;
+use crate::{page_fault, VMM};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 1 << 20; // 1MiB 0b100000000000000000000
+/// Virtual memory reserved for the heap, the other mappings start after it
+pub const MAX_HEAP_SIZE: usize = 256 << 20;
+
+/// Smallest growth of the heap, so that a series of small allocations does not grow it each time
+const GROWTH_STEP: usize = 64 << 10;
+
+pub static HEAP_SIZE: Parameter<usize> = Parameter::new("heap_size", 1 << 20, "size of the kernel heap mapped at boot, it grows on demand, e.g. 4M, rounded up to pages");
+
+/// The heap, grown into the rest of its reserved memory when an allocation does not fit. Only `HEAP_SIZE` is
+/// mapped up front, the pages it grows into are mapped before they are added to it: the heap is never
+/// touched unmapped, e.g. by DMA or by code holding the page tables.
+struct GrowableHeap(LockedHeap);
+
+/// End of the mapped part of the memory reserved for the heap, 0 until the heap is initialized. Its lock
+/// serializes the growths.
+static MAPPED_END: IrqMutex<usize> = IrqMutex::new(0);
+
+unsafe impl GlobalAlloc for GrowableHeap
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        if let Ok(allocation) = self.0.lock().allocate_first_fit(layout)
+        {
+            return allocation.as_ptr();
+        }
+        // The heap is unlocked while it grows, the other processors keep allocating from it meanwhile
+        if !self.grow(layout)
+        {
+            return ptr::null_mut();
+        }
+        self.0.lock().allocate_first_fit(layout).map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout)
+    {
+        self.0.lock().deallocate(NonNull::new_unchecked(pointer), layout);
+    }
+}
+
+impl GrowableHeap
+{
+    /// Maps enough pages after the end of the heap for `layout` and adds them to it, returns false if the
+    /// reserved memory is exhausted or the pages cannot be mapped
+    fn grow(&self, layout: Layout) -> bool
+    {
+        // An exception or NMI handler interrupting a growth on this processor
+        if MAPPED_END.is_locked_by_current_cpu()
+        {
+            return false;
+        }
+        let mut mapped_end = MAPPED_END.lock();
+        if *mapped_end == 0
+        {
+            return false;
+        }
+
+        // The allocating code may hold the page tables or the frame allocator, e.g. an interrupt handler
+        let mut vmm = match VMM.try_lock()
+        {
+            Some(vmm) if !PMM.is_locked() => vmm,
+            _ => return false
+        };
+
+        // The alignment may need a hole in front of the allocation
+        let needed = (layout.size() + layout.align() + 0xFFF) & !0xFFF;
+        let remaining = HEAP_START + MAX_HEAP_SIZE - *mapped_end;
+        if needed > remaining
+        {
+            return false;
+        }
+        let growth = GROWTH_STEP.clamp(needed, remaining);
+
+        // Pages mapped before a failure are added to the heap all the same
+        let mut mapped = 0;
+        while mapped < growth
+        {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new((*mapped_end + mapped) as u64));
+            match unsafe { vmm.map(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE) }
+            {
+                Ok(flush) => flush.flush(),
+                Err(_) => break
+            }
+            mapped += 0x1000;
+        }
+        drop(vmm);
+
+        if mapped != 0
+        {
+            unsafe { self.0.lock().extend(mapped) };
+            *mapped_end += mapped;
+        }
+        mapped >= needed
+    }
+}
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: GrowableHeap = GrowableHeap(LockedHeap::empty());
 
 pub fn init() -> Result<(), MapToError<Size4KiB>>
 {
+    let heap_size = (HEAP_SIZE.get().clamp(0x1000, MAX_HEAP_SIZE) + 0xFFF) & !0xFFF;
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + heap_size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -27,12 +127,37 @@ pub fn init() -> Result<(), MapToError<Size4KiB>>
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(HEAP_START as *mut u8, heap_size);
     }
+    *MAPPED_END.lock() = HEAP_START + heap_size;
 
+    // Reserved for the growths of the heap, faults in it are reported as such
+    let growth_start = VirtAddr::new((HEAP_START + heap_size) as u64);
+    page_fault::register_region("kernel heap", growth_start, (MAX_HEAP_SIZE - heap_size) as u64, None);
     Ok(())
 }
 
+#[derive(Debug, Copy, Clone)]
+pub struct HeapStats
+{
+    pub size: usize,
+    pub used: usize,
+    pub free: usize
+}
+
+pub fn stats() -> HeapStats
+{
+    // An interrupt handler allocating while the heap is locked would deadlock
+    without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        HeapStats {
+            size: heap.size(),
+            used: heap.used(),
+            free: heap.free()
+        }
+    })
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> !
 {
@@ -0,0 +1,95 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use crate::vmm::{MappingError, VMM};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+const REGISTER_ID: u64 = 0x20;
+const REGISTER_TASK_PRIORITY: u64 = 0x80;
+const REGISTER_END_OF_INTERRUPT: u64 = 0xB0;
+const REGISTER_SPURIOUS_VECTOR: u64 = 0xF0;
+const REGISTER_INTERRUPT_COMMAND_LOW: u64 = 0x300;
+const REGISTER_INTERRUPT_COMMAND_HIGH: u64 = 0x310;
+
+/// Interrupt command: NMI delivery, asserted, to every processor but this one
+const IPI_DELIVERY_NMI: u32 = 0b100 << 8;
+const IPI_LEVEL_ASSERT: u32 = 1 << 14;
+const IPI_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// Vector delivered by the local APIC for spurious interrupts. It must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Base of the MSI address window, messages written there are delivered to a local APIC.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read(register: u64) -> u32
+{
+    ((LOCAL_APIC_BASE.load(Ordering::Relaxed) + register) as *const u32).read_volatile()
+}
+
+unsafe fn write(register: u64, value: u32)
+{
+    ((LOCAL_APIC_BASE.load(Ordering::Relaxed) + register) as *mut u32).write_volatile(value)
+}
+
+/// Maps the local APIC of the bootstrap processor and software-enables it so that it accepts MSIs.
+pub fn init() -> Result<(), MappingError>
+{
+    let mut apic_base_msr = Msr::new(IA32_APIC_BASE);
+    let apic_base = unsafe { apic_base_msr.read() };
+    unsafe { apic_base_msr.write(apic_base | (1 << 11)) };
+
+    let virt_addr = VMM.lock().map_region(
+        PhysAddr::new(apic_base & 0xF_FFFF_F000),
+        0x1000,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+    )?;
+    LOCAL_APIC_BASE.store(virt_addr.as_u64(), Ordering::Relaxed);
+
+    unsafe {
+        write(REGISTER_TASK_PRIORITY, 0);
+        write(REGISTER_SPURIOUS_VECTOR, (1 << 8) | SPURIOUS_VECTOR as u32);
+    }
+
+    Ok(())
+}
+
+pub fn is_initialized() -> bool
+{
+    LOCAL_APIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+pub fn id() -> u8
+{
+    unsafe { (read(REGISTER_ID) >> 24) as u8 }
+}
+
+/// ID of the running processor, 0 until the local APIC is initialized
+pub fn cpu_id() -> u8
+{
+    if is_initialized() { id() } else { 0 }
+}
+
+/// Address an MSI capable device must write to in order to interrupt this processor.
+pub fn msi_address() -> u64
+{
+    MSI_ADDRESS_BASE | ((id() as u64) << 12)
+}
+
+pub fn end_of_interrupt()
+{
+    unsafe { write(REGISTER_END_OF_INTERRUPT, 0) };
+}
+
+/// Sends an NMI to every other processor, e.g. to stop them after a fatal error
+pub fn send_nmi_to_others()
+{
+    unsafe {
+        write(REGISTER_INTERRUPT_COMMAND_HIGH, 0);
+        write(REGISTER_INTERRUPT_COMMAND_LOW, IPI_ALL_EXCLUDING_SELF | IPI_LEVEL_ASSERT | IPI_DELIVERY_NMI);
+    }
+}
@@ -0,0 +1,68 @@
+mod symbols;
+
+use core::arch::asm;
+use x86_64::VirtAddr;
+use crate::vmm::VMM;
+
+pub use crate::backtrace::symbols::{resolve, Symbol};
+
+/// Frames printed at most, in case the chain of frame pointers loops
+const MAX_FRAMES: usize = 64;
+/// The frames of a backtrace all lie on one stack, no larger than the kernel stack
+const MAX_STACK_SIZE: u64 = 1024 * 1024;
+
+/// The frame pointer of the function this is inlined in. The kernel is built with frame pointers, each frame
+/// starts with the frame pointer of its caller followed by the return address.
+#[inline(always)]
+pub fn frame_pointer() -> u64
+{
+    let frame_pointer: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack, preserves_flags)) };
+    frame_pointer
+}
+
+/// Whether the 16 bytes of the frame at `frame_pointer` can be read without faulting. The page tables are
+/// not checked when they are locked, e.g. when the fault happened while mapping memory.
+fn is_readable(frame_pointer: u64) -> bool
+{
+    let vmm = match VMM.try_lock()
+    {
+        Some(vmm) => vmm,
+        None => return true
+    };
+    [frame_pointer, frame_pointer + 15].iter().all(|address| {
+        VirtAddr::try_new(*address).map_or(false, |address| vmm.translate_addr(address).is_some())
+    })
+}
+
+/// Calls `f` with the return address of each frame from `frame_pointer` up. Returns false if frames were
+/// left out after `MAX_FRAMES`.
+pub fn walk(mut frame_pointer: u64, mut f: impl FnMut(usize, u64)) -> bool
+{
+    let bottom = frame_pointer;
+    for index in 0..MAX_FRAMES
+    {
+        if frame_pointer == 0 || frame_pointer % 8 != 0 || frame_pointer - bottom > MAX_STACK_SIZE || !is_readable(frame_pointer)
+        {
+            return true;
+        }
+
+        let (caller_frame, return_address) = unsafe {
+            let frame = frame_pointer as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0
+        {
+            return true;
+        }
+        f(index, return_address);
+
+        // The frames of the callers are higher on the stack
+        if caller_frame <= frame_pointer
+        {
+            return true;
+        }
+        frame_pointer = caller_frame;
+    }
+    false
+}
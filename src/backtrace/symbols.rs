@@ -0,0 +1,98 @@
+use core::str;
+
+/// Size reserved for the symbol table, filled after the build by `tools/embed_symbols.py`
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 16;
+
+/// Left empty by the compiler, the section is patched in the kernel ELF without moving anything else
+#[used]
+#[link_section = ".ksymtab"]
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+/// The function holding an address
+#[derive(Debug, Copy, Clone)]
+pub struct Symbol
+{
+    pub name: &'static str,
+    pub address: u64,
+    /// Offset of the address in the function
+    pub offset: u64
+}
+
+struct SymbolTable
+{
+    data: &'static [u8],
+    count: usize,
+    names: usize
+}
+
+impl SymbolTable
+{
+    /// The embedded table, if the build filled it and it is consistent
+    fn get() -> Option<Self>
+    {
+        // The compiler only knows the zeros it emitted, the contents must be read through an opaque reference
+        let data: &'static [u8] = core::hint::black_box(&SYMBOL_TABLE);
+        if &data[0..4] != MAGIC
+        {
+            return None;
+        }
+
+        let count = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let names = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+        if HEADER_SIZE + count * ENTRY_SIZE > names || names > data.len()
+        {
+            return None;
+        }
+        Some(SymbolTable { data, count, names })
+    }
+
+    /// Address, size and name offset of the entry `index`
+    fn entry(&self, index: usize) -> (u64, u64, usize)
+    {
+        let entry = &self.data[HEADER_SIZE + index * ENTRY_SIZE..HEADER_SIZE + (index + 1) * ENTRY_SIZE];
+        (
+            u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64,
+            u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize
+        )
+    }
+
+    fn name(&self, offset: usize) -> &'static str
+    {
+        let start = core::cmp::min(self.names + offset, self.data.len());
+        let length = self.data[start..].iter().position(|byte| *byte == 0).unwrap_or(0);
+        str::from_utf8(&self.data[start..start + length]).unwrap_or("<invalid name>")
+    }
+}
+
+/// The function holding `address`
+pub fn resolve(address: u64) -> Option<Symbol>
+{
+    let table = SymbolTable::get()?;
+
+    // Last function starting at or before the address
+    let index = {
+        let (mut low, mut high) = (0, table.count);
+        while low < high
+        {
+            let middle = (low + high) / 2;
+            if table.entry(middle).0 <= address { low = middle + 1 } else { high = middle }
+        }
+        low.checked_sub(1)?
+    };
+
+    let (start, size, name) = table.entry(index);
+    // Functions without a size are only trusted when nothing follows them
+    if address - start >= size && (size != 0 || index + 1 < table.count)
+    {
+        return None;
+    }
+    Some(Symbol {
+        name: table.name(name),
+        address: start,
+        offset: address - start
+    })
+}
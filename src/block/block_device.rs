@@ -0,0 +1,77 @@
+use alloc::string::String;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError
+{
+    /// The device went away
+    NoDevice,
+    /// The sector range does not fit in the device
+    OutOfRange,
+    /// The buffer is not a whole number of sectors, or cannot be used for DMA
+    InvalidBuffer,
+    ReadOnly,
+    /// The device does not implement the operation
+    Unsupported,
+    AlreadyRegistered,
+    /// The device is in use by code which cannot be waited for, e.g. the code that crashed
+    Busy,
+    /// The data could not be read back from the medium, e.g. a bad sector
+    Medium,
+    /// The device refused the command, as unsupported or with invalid parameters
+    Rejected,
+    /// The device failed the request, with a description of the failure
+    Io(String)
+}
+
+/// A device storing data in fixed size sectors: a disk, a partition of a disk or a RAM disk.
+///
+/// Buffers are a whole number of sectors long, and sector ranges are validated against the device size
+/// before the request reaches the hardware.
+pub trait BlockDevice: Send + Sync
+{
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes the data written so far persistent
+    fn flush(&self) -> Result<(), BlockError>;
+
+    /// Tells the device that `count` sectors at `lba` hold no data anymore
+    fn discard(&self, _lba: u64, _count: u64) -> Result<(), BlockError>
+    {
+        Err(BlockError::Unsupported)
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        false
+    }
+
+    /// Writes `buffer` at `lba` and makes it persistent by polling the hardware, without interrupts or the
+    /// kernel main loop, e.g. to save a crash dump. Fails with `Busy` rather than waiting for a lock. The
+    /// sector size is the one the caller learned beforehand, asking the device for it may take a lock.
+    fn write_polled(&self, _lba: u64, _buffer: &[u8], _sector_size: usize) -> Result<(), BlockError>
+    {
+        Err(BlockError::Unsupported)
+    }
+}
+
+/// Checks that `length` bytes starting at sector `lba` are whole sectors within `device`
+pub fn check_range<D: BlockDevice + ?Sized>(device: &D, lba: u64, length: usize) -> Result<(), BlockError>
+{
+    let sector_size = device.sector_size();
+    if sector_size == 0 || length % sector_size != 0
+    {
+        return Err(BlockError::InvalidBuffer);
+    }
+
+    match lba.checked_add((length / sector_size) as u64)
+    {
+        Some(end) if end <= device.sector_count() => Ok(()),
+        _ => Err(BlockError::OutOfRange)
+    }
+}
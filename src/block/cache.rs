@@ -0,0 +1,738 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::block::{check_range, BlockDevice, BlockError};
+use crate::cmdline::Parameter;
+use crate::pmm::PMM;
+use crate::{deferred, time, VMM};
+
+/// Size of a cached block, one page
+pub const CACHE_BLOCK_SIZE: usize = 0x1000;
+/// Share of the physical memory the cache may use unless configured otherwise
+pub const DEFAULT_MEMORY_PERCENT: usize = 10;
+/// Longest run of blocks read with a single request, the size of the bounce buffer
+const MAX_BATCH_BLOCKS: usize = 32;
+/// Twice the longest run, so that loading a run never evicts the blocks it has just loaded
+const MIN_CAPACITY_BLOCKS: usize = 2 * MAX_BATCH_BLOCKS;
+const INITIAL_READAHEAD_BLOCKS: usize = 4;
+const MAX_READAHEAD_BLOCKS: usize = 32;
+const WRITEBACK_INTERVAL_MS: u64 = 1_000;
+/// Dirty blocks are written back by the periodic write-back once they have been dirty this long
+const DIRTY_EXPIRE_MS: u64 = 5_000;
+
+pub static CACHE_PERCENT: Parameter<usize> = Parameter::new("cache.percent", DEFAULT_MEMORY_PERCENT, "share of the physical memory the buffer cache may use, from 1 to 100");
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats
+{
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks read ahead of a sequential reader
+    pub readahead: u64,
+    pub writebacks: u64,
+    pub evictions: u64,
+    pub cached_blocks: usize,
+    pub dirty_blocks: usize,
+    pub capacity_blocks: usize
+}
+
+/// A cached block is identified by the cache id of its device and its index on the device
+type BlockKey = (usize, u64);
+
+struct CachedBlock
+{
+    page: VirtAddr,
+    /// Uptime at which the block was first written since it was last clean
+    dirty_since: Option<u64>,
+    last_use: u64,
+    /// The block is being written to its device, it is not evicted and its page is not reused until then
+    writing: bool
+}
+
+struct DeviceState
+{
+    device: Arc<dyn BlockDevice>,
+    /// Sector following the last read, a read starting there is sequential
+    next_lba: u64,
+    readahead: usize
+}
+
+/// A block taken for writing back, written to the device once the cache is unlocked
+struct WriteBack
+{
+    key: BlockKey,
+    device: Arc<dyn BlockDevice>,
+    lba: u64,
+    page: VirtAddr,
+    length: usize,
+    /// Put back if the write fails, so that the block is written again later
+    dirty_since: u64
+}
+
+impl WriteBack
+{
+    fn write(&self) -> Result<(), BlockError>
+    {
+        let data = unsafe { core::slice::from_raw_parts(self.page.as_ptr::<u8>(), self.length) };
+        self.device.write_sectors(self.lba, data)
+    }
+}
+
+/// Caches the sectors of block devices in page sized blocks, evicting the least recently used ones.
+///
+/// Writes only dirty the cached blocks, which reach the device when evicted, when they expire or when the
+/// device is synced. Pages come from the PMM, which cannot free them: evicted pages are kept for reuse.
+///
+/// The cache is not locked while its devices are read or written, except to write back a block evicted when
+/// every evictable block is dirty, so that a slow device does not hold up the others.
+pub struct BufferCache
+{
+    devices: BTreeMap<usize, DeviceState>,
+    blocks: BTreeMap<BlockKey, CachedBlock>,
+    /// Blocks ordered from the least to the most recently used
+    lru: BTreeMap<u64, BlockKey>,
+    free_pages: Vec<VirtAddr>,
+    allocated_pages: usize,
+    capacity: usize,
+    clock: u64,
+    next_id: usize,
+    stats: CacheStats
+}
+
+impl BufferCache
+{
+    fn new(memory_percent: usize) -> Self
+    {
+        BufferCache {
+            devices: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            free_pages: Vec::new(),
+            allocated_pages: 0,
+            capacity: Self::capacity_for(memory_percent),
+            clock: 0,
+            next_id: 0,
+            stats: CacheStats::default()
+        }
+    }
+
+    fn capacity_for(memory_percent: usize) -> usize
+    {
+        let memory = PMM.lock().total_memory() as usize;
+        core::cmp::max(memory / 100 * memory_percent / CACHE_BLOCK_SIZE, MIN_CAPACITY_BLOCKS)
+    }
+
+    /// Changes the share of physical memory the cache may use. Shrinking evicts blocks, but the pages
+    /// already allocated stay in the pool.
+    pub fn set_memory_percent(&mut self, memory_percent: usize) -> Result<(), BlockError>
+    {
+        self.capacity = Self::capacity_for(memory_percent);
+        while self.blocks.len() > self.capacity
+        {
+            let page = self.evict()?;
+            self.free_pages.push(page);
+        }
+        Ok(())
+    }
+
+    fn add_device(&mut self, device: Arc<dyn BlockDevice>) -> usize
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.devices.insert(id, DeviceState { device, next_lba: 0, readahead: 0 });
+        id
+    }
+
+    /// Drops the blocks of the device, which must have been synced. The device is returned so that it is
+    /// dropped once the cache is unlocked, in case it is a cached device itself.
+    fn remove_device(&mut self, id: usize) -> Option<Arc<dyn BlockDevice>>
+    {
+        let keys: Vec<BlockKey> = self.blocks.range((id, 0)..=(id, u64::MAX)).map(|(key, _)| *key).collect();
+        for key in keys
+        {
+            self.remove(key);
+        }
+        self.devices.remove(&id).map(|state| state.device)
+    }
+
+    fn device(&self, id: usize) -> Arc<dyn BlockDevice>
+    {
+        self.devices[&id].device.clone()
+    }
+
+    /// Sectors per block and number of sectors of `block`, the last block of a device may be partial
+    fn block_sectors(device: &dyn BlockDevice, block: u64) -> (u64, usize)
+    {
+        let sectors_per_block = (CACHE_BLOCK_SIZE / device.sector_size()) as u64;
+        let first = block * sectors_per_block;
+        let sectors = core::cmp::min(sectors_per_block, device.sector_count().saturating_sub(first));
+        (sectors_per_block, sectors as usize)
+    }
+
+    fn touch(&mut self, key: BlockKey)
+    {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(block) = self.blocks.get_mut(&key)
+        {
+            self.lru.remove(&block.last_use);
+            block.last_use = clock;
+            self.lru.insert(clock, key);
+        }
+    }
+
+    fn insert(&mut self, key: BlockKey, page: VirtAddr)
+    {
+        self.blocks.insert(key, CachedBlock { page, dirty_since: None, last_use: 0, writing: false });
+        self.touch(key);
+    }
+
+    fn remove(&mut self, key: BlockKey)
+    {
+        if let Some(block) = self.blocks.remove(&key)
+        {
+            self.lru.remove(&block.last_use);
+            // The write-back in progress frees the page once it is done with it
+            if !block.writing
+            {
+                self.free_pages.push(block.page);
+            }
+        }
+    }
+
+    /// Takes a page for a new block: a free one, a newly allocated one while under capacity, or the page of
+    /// an evicted block
+    fn allocate_page(&mut self) -> Result<VirtAddr, BlockError>
+    {
+        if let Some(page) = self.free_pages.pop()
+        {
+            return Ok(page);
+        }
+
+        if self.allocated_pages < self.capacity
+        {
+            if let Ok(page) = VMM.lock().allocate_pages(1)
+            {
+                self.allocated_pages += 1;
+                return Ok(page);
+            }
+        }
+
+        self.evict()
+    }
+
+    /// Evicts the least recently used clean block, or the least recently used dirty one once it is written
+    /// back if every block is dirty. Blocks being written back are skipped.
+    fn evict(&mut self) -> Result<VirtAddr, BlockError>
+    {
+        let evictable = |clean: bool| self.lru.values()
+            .find(|key| self.blocks.get(key).map_or(false, |block| !block.writing && (!clean || block.dirty_since.is_none())))
+            .copied();
+        let key = evictable(true).or_else(|| evictable(false)).ok_or(BlockError::Io(String::from("buffer cache has no evictable block")))?;
+
+        if let Some(write_back) = self.begin_write_back(key)
+        {
+            let result = write_back.write();
+            self.end_write_back(&write_back, &result);
+            if let Err(e) = result
+            {
+                // Keep the data, another block may be evictable next time
+                self.touch(key);
+                return Err(e);
+            }
+        }
+
+        let block = self.blocks.remove(&key).unwrap();
+        self.lru.remove(&block.last_use);
+        self.stats.evictions += 1;
+        Ok(block.page)
+    }
+
+    /// Marks the block clean and keeps it cached until `end_write_back`, `None` if it is clean or already
+    /// being written back
+    fn begin_write_back(&mut self, key: BlockKey) -> Option<WriteBack>
+    {
+        let device = self.devices.get(&key.0)?.device.clone();
+        let (sectors_per_block, sectors) = Self::block_sectors(device.as_ref(), key.1);
+        let block = self.blocks.get_mut(&key)?;
+        if block.writing
+        {
+            return None;
+        }
+        let dirty_since = block.dirty_since.take()?;
+        block.writing = true;
+
+        Some(WriteBack {
+            key,
+            lba: key.1 * sectors_per_block,
+            page: block.page,
+            length: sectors * device.sector_size(),
+            dirty_since,
+            device
+        })
+    }
+
+    fn end_write_back(&mut self, write_back: &WriteBack, result: &Result<(), BlockError>)
+    {
+        match self.blocks.get_mut(&write_back.key)
+        {
+            Some(block) if block.page == write_back.page => {
+                block.writing = false;
+                if result.is_err()
+                {
+                    block.dirty_since = Some(block.dirty_since.map_or(write_back.dirty_since, |since| since.min(write_back.dirty_since)));
+                }
+            }
+            // The block was removed during the write and left its page to it
+            _ => self.free_pages.push(write_back.page)
+        }
+        if result.is_ok()
+        {
+            self.stats.writebacks += 1;
+        }
+    }
+
+    /// The missing blocks from `first` up to `end`, and `readahead` more blocks past it, to read with a single
+    /// request: the run stops at the first block already cached. Returns the end of the run and its number of
+    /// sectors, `None` if `first` is cached.
+    fn plan_load(&self, id: usize, device: &dyn BlockDevice, first: u64, end: u64, readahead: usize) -> Option<(u64, usize)>
+    {
+        if self.blocks.contains_key(&(id, first))
+        {
+            return None;
+        }
+
+        let (sectors_per_block, _) = Self::block_sectors(device, first);
+        let block_count = (device.sector_count() + sectors_per_block - 1) / sectors_per_block;
+        let limit = (end + readahead as u64).min(block_count).min(first + MAX_BATCH_BLOCKS as u64);
+        let mut last = first + 1;
+        while last < limit && !self.blocks.contains_key(&(id, last))
+        {
+            last += 1;
+        }
+
+        let sectors = ((last - 1) * sectors_per_block + Self::block_sectors(device, last - 1).1 as u64 - first * sectors_per_block) as usize;
+        Some((last, sectors))
+    }
+
+    /// Caches the blocks `first..last` read into `bounce`, but those loaded or written meanwhile
+    fn insert_loaded(&mut self, id: usize, device: &dyn BlockDevice, first: u64, last: u64, end: u64, bounce: VirtAddr) -> Result<(), BlockError>
+    {
+        for block in first..last
+        {
+            if self.blocks.contains_key(&(id, block))
+            {
+                continue;
+            }
+
+            let page = self.allocate_page()?;
+            let offset = (block - first) as usize * CACHE_BLOCK_SIZE;
+            let length = Self::block_sectors(device, block).1 * device.sector_size();
+            unsafe { core::ptr::copy_nonoverlapping(bounce.as_ptr::<u8>().add(offset), page.as_mut_ptr::<u8>(), length) };
+            self.insert((id, block), page);
+
+            if block < end
+            {
+                self.stats.misses += 1;
+            }
+            else
+            {
+                self.stats.readahead += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with each block overlapping the sectors `lba..lba + sectors`, its index, and the byte range
+    /// of the block and of the buffer that overlap
+    fn for_each_block(device: &dyn BlockDevice, lba: u64, length: usize, mut f: impl FnMut(u64, usize, usize, usize) -> Result<(), BlockError>) -> Result<(), BlockError>
+    {
+        let sector_size = device.sector_size();
+        let sectors_per_block = (CACHE_BLOCK_SIZE / sector_size) as u64;
+        let mut done = 0;
+        while done < length
+        {
+            let sector = lba + (done / sector_size) as u64;
+            let block = sector / sectors_per_block;
+            let offset = (sector % sectors_per_block) as usize * sector_size;
+            let chunk = core::cmp::min(CACHE_BLOCK_SIZE - offset, length - done);
+            f(block, offset, done, chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Copies from the block at `offset` to `buffer`, returns false if it is not cached
+    fn copy_from_block(&mut self, key: BlockKey, offset: usize, buffer: &mut [u8], hit: bool) -> bool
+    {
+        let page = match self.blocks.get(&key)
+        {
+            Some(block) => block.page,
+            None => return false
+        };
+        unsafe { core::ptr::copy_nonoverlapping(page.as_ptr::<u8>().add(offset), buffer.as_mut_ptr(), buffer.len()) };
+        self.touch(key);
+        if hit
+        {
+            self.stats.hits += 1;
+        }
+        true
+    }
+
+    /// Copies `buffer` to the block at `offset` and dirties it. A block which is not cached is only created
+    /// when `whole` tells the whole block is overwritten, false is returned otherwise.
+    fn copy_to_block(&mut self, key: BlockKey, offset: usize, buffer: &[u8], now: u64, whole: bool, hit: bool) -> Result<bool, BlockError>
+    {
+        if self.blocks.contains_key(&key)
+        {
+            if hit
+            {
+                self.stats.hits += 1;
+            }
+        }
+        else if whole
+        {
+            // The whole block is overwritten, there is no need to read it
+            let page = self.allocate_page()?;
+            self.insert(key, page);
+        }
+        else
+        {
+            return Ok(false);
+        }
+
+        let cached = self.blocks.get_mut(&key).unwrap();
+        unsafe { core::ptr::copy_nonoverlapping(buffer.as_ptr(), cached.page.as_mut_ptr::<u8>().add(offset), buffer.len()) };
+        cached.dirty_since.get_or_insert(now);
+        self.touch(key);
+        Ok(true)
+    }
+
+    fn dirty_blocks(&self, id: usize) -> Vec<BlockKey>
+    {
+        self.blocks.range((id, 0)..=(id, u64::MAX)).filter(|(_, block)| block.dirty_since.is_some()).map(|(key, _)| *key).collect()
+    }
+
+    /// The blocks dirty for longer than `DIRTY_EXPIRE_MS`
+    fn expired_blocks(&self) -> Vec<BlockKey>
+    {
+        let now = time::uptime_ms();
+        self.blocks.iter()
+            .filter(|(_, block)| block.dirty_since.map_or(false, |since| now - since >= DIRTY_EXPIRE_MS))
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    pub fn stats(&self) -> CacheStats
+    {
+        CacheStats {
+            cached_blocks: self.blocks.len(),
+            dirty_blocks: self.blocks.values().filter(|block| block.dirty_since.is_some()).count(),
+            capacity_blocks: self.capacity,
+            ..self.stats
+        }
+    }
+}
+
+lazy_static!
+{
+    pub static ref BUFFER_CACHE: Mutex<BufferCache> = Mutex::new(BufferCache::new(DEFAULT_MEMORY_PERCENT));
+}
+
+/// Bounce buffers of `MAX_BATCH_BLOCKS` blocks for the loads in progress, returned once the load is done as
+/// the PMM cannot free them
+static BOUNCE_BUFFERS: Mutex<Vec<VirtAddr>> = Mutex::new(Vec::new());
+
+fn take_bounce_buffer() -> Result<VirtAddr, BlockError>
+{
+    if let Some(bounce) = BOUNCE_BUFFERS.lock().pop()
+    {
+        return Ok(bounce);
+    }
+    VMM.lock().allocate_pages(MAX_BATCH_BLOCKS).map_err(|e| BlockError::Io(format!("{:?}", e)))
+}
+
+/// Writes back the block if it is dirty, with the cache unlocked during the write
+fn write_back(key: BlockKey) -> Result<(), BlockError>
+{
+    let write_back = match BUFFER_CACHE.lock().begin_write_back(key)
+    {
+        Some(write_back) => write_back,
+        None => return Ok(())
+    };
+    let result = write_back.write();
+    BUFFER_CACHE.lock().end_write_back(&write_back, &result);
+    result
+}
+
+/// Reads the missing blocks from `first` up to `end`, and `readahead` more blocks past it, with a single
+/// request made with the cache unlocked
+fn load(id: usize, first: u64, end: u64, readahead: usize) -> Result<(), BlockError>
+{
+    let (device, plan) = {
+        let cache = BUFFER_CACHE.lock();
+        let device = cache.device(id);
+        let plan = cache.plan_load(id, device.as_ref(), first, end, readahead);
+        (device, plan)
+    };
+    let (last, sectors) = match plan
+    {
+        Some(plan) => plan,
+        None => return Ok(())
+    };
+
+    let (sectors_per_block, _) = BufferCache::block_sectors(device.as_ref(), first);
+    let bounce = take_bounce_buffer()?;
+    let data = unsafe { core::slice::from_raw_parts_mut(bounce.as_mut_ptr::<u8>(), sectors * device.sector_size()) };
+    let result = device.read_sectors(first * sectors_per_block, data)
+        .and_then(|()| BUFFER_CACHE.lock().insert_loaded(id, device.as_ref(), first, last, end, bounce));
+    BOUNCE_BUFFERS.lock().push(bounce);
+    result
+}
+
+fn read(id: usize, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+{
+    let (device, end, readahead) = {
+        let mut cache = BUFFER_CACHE.lock();
+        let device = cache.device(id);
+        check_range(device.as_ref(), lba, buffer.len())?;
+
+        // Readahead grows while the device is read sequentially and stops at the first random read
+        let state = cache.devices.get_mut(&id).unwrap();
+        state.readahead = if lba == state.next_lba { (state.readahead * 2).clamp(INITIAL_READAHEAD_BLOCKS, MAX_READAHEAD_BLOCKS) } else { 0 };
+        state.next_lba = lba + (buffer.len() / device.sector_size()) as u64;
+
+        let sectors_per_block = (CACHE_BLOCK_SIZE / device.sector_size()) as u64;
+        (device, (state.next_lba + sectors_per_block - 1) / sectors_per_block, state.readahead)
+    };
+
+    BufferCache::for_each_block(device.as_ref(), lba, buffer.len(), |block, offset, done, chunk| {
+        // The block may be evicted again between its load and the copy, it is then loaded once more
+        let mut hit = true;
+        while !BUFFER_CACHE.lock().copy_from_block((id, block), offset, &mut buffer[done..done + chunk], hit)
+        {
+            hit = false;
+            load(id, block, end, readahead)?;
+        }
+        Ok(())
+    })
+}
+
+fn write(id: usize, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+{
+    let device = BUFFER_CACHE.lock().device(id);
+    check_range(device.as_ref(), lba, buffer.len())?;
+    if device.is_read_only()
+    {
+        return Err(BlockError::ReadOnly);
+    }
+
+    let now = time::uptime_ms();
+    BufferCache::for_each_block(device.as_ref(), lba, buffer.len(), |block, offset, done, chunk| {
+        let whole = offset == 0 && chunk == BufferCache::block_sectors(device.as_ref(), block).1 * device.sector_size();
+        let mut hit = true;
+        while !BUFFER_CACHE.lock().copy_to_block((id, block), offset, &buffer[done..done + chunk], now, whole, hit)?
+        {
+            hit = false;
+            load(id, block, block + 1, 0)?;
+        }
+        Ok(())
+    })
+}
+
+/// Drops the blocks overlapping the discarded sectors, writing back the dirty ones which hold other
+/// sectors too, then discards the sectors on the device
+fn discard(id: usize, lba: u64, count: u64) -> Result<(), BlockError>
+{
+    let device = BUFFER_CACHE.lock().device(id);
+    let sectors_per_block = (CACHE_BLOCK_SIZE / device.sector_size()) as u64;
+    let end = lba.checked_add(count).filter(|&end| end <= device.sector_count()).ok_or(BlockError::OutOfRange)?;
+    let partial = |key: &BlockKey| key.1 * sectors_per_block < lba || (key.1 + 1) * sectors_per_block > end;
+
+    let keys: Vec<BlockKey> = BUFFER_CACHE.lock().blocks.range((id, lba / sectors_per_block)..(id, (end + sectors_per_block - 1) / sectors_per_block))
+        .map(|(key, _)| *key)
+        .collect();
+    for key in keys.iter().filter(|key| partial(key))
+    {
+        write_back(*key)?;
+    }
+
+    {
+        let mut cache = BUFFER_CACHE.lock();
+        for key in keys
+        {
+            // A partial block written meanwhile keeps its other sectors
+            if !partial(&key) || cache.blocks.get(&key).map_or(false, |block| block.dirty_since.is_none())
+            {
+                cache.remove(key);
+            }
+        }
+    }
+
+    device.discard(lba, count)
+}
+
+/// Writes back the dirty blocks of the device and flushes it
+fn sync_device(id: usize) -> Result<(), BlockError>
+{
+    let (keys, device) = {
+        let cache = BUFFER_CACHE.lock();
+        (cache.dirty_blocks(id), cache.device(id))
+    };
+    for key in keys
+    {
+        write_back(key)?;
+    }
+    device.flush()
+}
+
+/// Writes back the blocks dirty for longer than `DIRTY_EXPIRE_MS`
+fn write_back_expired()
+{
+    let expired = BUFFER_CACHE.lock().expired_blocks();
+    for key in expired
+    {
+        if let Err(e) = write_back(key)
+        {
+            warn!("[CACHE] Failed to write back block {} of device {}: {:?}", key.1, key.0, e);
+        }
+    }
+}
+
+/// Starts the periodic write-back of expired dirty blocks
+pub fn init()
+{
+    configure();
+    let capacity = BUFFER_CACHE.lock().capacity;
+    info!("[CACHE] Up to {} blocks of {} bytes", capacity, CACHE_BLOCK_SIZE);
+
+    let work = deferred::register(write_back_expired);
+    time::schedule_periodic(WRITEBACK_INTERVAL_MS, work);
+}
+
+/// Applies `cache.percent`, again once the command line file is loaded
+pub fn configure()
+{
+    let percent = CACHE_PERCENT.get();
+    if percent == 0 || percent > 100
+    {
+        warn!("[CACHE] Invalid cache.percent {}, expected 1 to 100", percent);
+        return;
+    }
+    if let Err(e) = BUFFER_CACHE.lock().set_memory_percent(percent)
+    {
+        warn!("[CACHE] Failed to shrink the cache to {}% of the memory: {:?}", percent, e);
+    }
+}
+
+/// Writes back every dirty block and flushes the devices
+pub fn sync() -> Result<(), BlockError>
+{
+    let ids: Vec<usize> = BUFFER_CACHE.lock().devices.keys().copied().collect();
+    let mut result = Ok(());
+    for id in ids
+    {
+        if let Err(e) = sync_device(id)
+        {
+            result = Err(e);
+        }
+    }
+    result
+}
+
+pub fn stats() -> CacheStats
+{
+    BUFFER_CACHE.lock().stats()
+}
+
+/// A block device whose sectors go through the buffer cache. Devices with sectors larger than a cache
+/// block, or not dividing it, are not cached.
+pub struct CachedDevice
+{
+    id: usize,
+    device: Arc<dyn BlockDevice>,
+    cacheable: bool
+}
+
+impl CachedDevice
+{
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self
+    {
+        let sector_size = device.sector_size();
+        CachedDevice {
+            id: BUFFER_CACHE.lock().add_device(device.clone()),
+            cacheable: sector_size != 0 && sector_size <= CACHE_BLOCK_SIZE && CACHE_BLOCK_SIZE % sector_size == 0,
+            device
+        }
+    }
+}
+
+impl Drop for CachedDevice
+{
+    fn drop(&mut self)
+    {
+        if let Err(e) = sync_device(self.id)
+        {
+            warn!("[CACHE] Failed to write back a removed device: {:?}", e);
+        }
+        let device = BUFFER_CACHE.lock().remove_device(self.id);
+        drop(device);
+    }
+}
+
+impl BlockDevice for CachedDevice
+{
+    fn sector_size(&self) -> usize
+    {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64
+    {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        if !self.cacheable
+        {
+            return self.device.read_sectors(lba, buffer);
+        }
+        read(self.id, lba, buffer)
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        if !self.cacheable
+        {
+            return self.device.write_sectors(lba, buffer);
+        }
+        write(self.id, lba, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        sync_device(self.id)
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> Result<(), BlockError>
+    {
+        if !self.cacheable
+        {
+            return self.device.discard(lba, count);
+        }
+        discard(self.id, lba, count)
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.device.is_read_only()
+    }
+}
@@ -0,0 +1,129 @@
+mod block_device;
+pub mod cache;
+pub mod partition;
+pub mod queue;
+pub mod ramdisk;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use spin::Mutex;
+
+pub use crate::block::block_device::{check_range, BlockDevice, BlockError};
+use crate::block::partition::Partition;
+use crate::crash;
+
+/// Block devices by name: `sata0` for the first AHCI disk, `sata0p1` for its first partition
+pub struct BlockDeviceRegistry
+{
+    devices: BTreeMap<String, Arc<dyn BlockDevice>>
+}
+
+impl BlockDeviceRegistry
+{
+    pub const fn new() -> Self
+    {
+        BlockDeviceRegistry {
+            devices: BTreeMap::new()
+        }
+    }
+
+    /// Registers `device` under `prefix` followed by the lowest free index, and returns its name
+    pub fn register(&mut self, prefix: &str, device: Arc<dyn BlockDevice>) -> String
+    {
+        let name = (0..).map(|index| format!("{}{}", prefix, index)).find(|name| !self.devices.contains_key(name)).unwrap();
+        self.register_as(name.clone(), device).unwrap();
+        name
+    }
+
+    /// Registers `device` under `name`, which must not be taken
+    pub fn register_as(&mut self, name: String, device: Arc<dyn BlockDevice>) -> Result<(), BlockError>
+    {
+        if self.devices.contains_key(&name)
+        {
+            return Err(BlockError::AlreadyRegistered);
+        }
+
+        info!("[BLOCK] Registered {}: {} sectors of {} bytes", name, device.sector_count(), device.sector_size());
+        self.devices.insert(name, device);
+        Ok(())
+    }
+
+    /// Removes the device called `name` along with its partitions
+    pub fn unregister(&mut self, name: &str) -> Option<Arc<dyn BlockDevice>>
+    {
+        let partitions: Vec<String> = self.devices.keys().filter(|key| is_partition_of(key, name)).cloned().collect();
+        for partition in partitions
+        {
+            self.devices.remove(&partition);
+            info!("[BLOCK] Unregistered {}", partition);
+        }
+
+        let device = self.devices.remove(name);
+        if device.is_some()
+        {
+            info!("[BLOCK] Unregistered {}", name);
+        }
+        device
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn BlockDevice>>
+    {
+        self.devices.get(name).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Arc<dyn BlockDevice>)>
+    {
+        self.devices.iter()
+    }
+}
+
+/// Registers a whole disk under `prefix` followed by the lowest free index, then scans its partition table
+/// and registers each partition after the disk, e.g. `sata0p1`. Returns the name of the disk.
+pub fn register_disk(prefix: &str, disk: Arc<dyn BlockDevice>) -> String
+{
+    let name = BLOCK_DEVICES.lock().register(prefix, disk.clone());
+
+    match partition::scan(disk.as_ref())
+    {
+        Ok(partitions) => {
+            for info in partitions
+            {
+                let partition_name = partition_name(&name, info.number);
+                info!("[BLOCK] {}: {:?}", partition_name, info.kind);
+                let partition: Arc<dyn BlockDevice> = Arc::new(Partition::new(disk.clone(), info.clone()));
+                if let Err(e) = BLOCK_DEVICES.lock().register_as(partition_name.clone(), partition.clone())
+                {
+                    warn!("[BLOCK] Failed to register a partition of {}: {:?}", name, e);
+                    continue;
+                }
+                crash::probe_partition(&partition_name, &info, partition);
+            }
+        }
+        Err(e) => warn!("[BLOCK] Failed to read the partition table of {}: {:?}", name, e)
+    }
+
+    name
+}
+
+/// Name of the partition `index` of `disk`, counting from 1
+pub fn partition_name(disk: &str, index: usize) -> String
+{
+    format!("{}p{}", disk, index)
+}
+
+fn is_partition_of(name: &str, disk: &str) -> bool
+{
+    name.strip_prefix(disk)
+        .and_then(|suffix| suffix.strip_prefix('p'))
+        .map_or(false, |index| !index.is_empty() && index.bytes().all(|c| c.is_ascii_digit()))
+}
+
+lazy_static!
+{
+    pub static ref BLOCK_DEVICES: Mutex<BlockDeviceRegistry> = Mutex::new(BlockDeviceRegistry::new());
+}
@@ -0,0 +1,166 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::warn;
+
+use crate::block::partition::{le_u32, le_u64, read_sector, Guid, PartitionInfo, PartitionKind};
+use crate::block::{BlockDevice, BlockError};
+use crate::crc32;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const PRIMARY_HEADER_LBA: u64 = 1;
+const MIN_HEADER_SIZE: usize = 92;
+const HEADER_CRC_OFFSET: usize = 16;
+const MIN_ENTRY_SIZE: usize = 128;
+/// Bounds the partition entry array read from a corrupted header, the usual array is 16KiB
+const MAX_ENTRY_ARRAY_SIZE: usize = 1 << 20;
+const NAME_OFFSET: usize = 56;
+const NAME_LENGTH: usize = 36;
+
+#[derive(Debug)]
+struct Header
+{
+    my_lba: u64,
+    alternate_lba: u64,
+    entries_lba: u64,
+    entry_count: usize,
+    entry_size: usize,
+    entries_crc: u32
+}
+
+impl Header
+{
+    /// Parses the header in `sector` and checks its signature, CRC and location
+    fn parse(sector: &[u8], lba: u64) -> Option<Header>
+    {
+        if &sector[0..8] != SIGNATURE
+        {
+            return None;
+        }
+
+        let size = le_u32(sector, 12) as usize;
+        if size < MIN_HEADER_SIZE || size > sector.len()
+        {
+            return None;
+        }
+
+        let mut header = sector[..size].to_vec();
+        header[HEADER_CRC_OFFSET..HEADER_CRC_OFFSET + 4].fill(0);
+        if crc32::crc32(&header) != le_u32(sector, HEADER_CRC_OFFSET)
+        {
+            return None;
+        }
+
+        let header = Header {
+            my_lba: le_u64(sector, 24),
+            alternate_lba: le_u64(sector, 32),
+            entries_lba: le_u64(sector, 72),
+            entry_count: le_u32(sector, 80) as usize,
+            entry_size: le_u32(sector, 84) as usize,
+            entries_crc: le_u32(sector, 88)
+        };
+
+        let array_size = header.entry_count.checked_mul(header.entry_size)?;
+        if header.my_lba != lba || header.entry_size < MIN_ENTRY_SIZE || header.entry_size % 8 != 0 || array_size > MAX_ENTRY_ARRAY_SIZE
+        {
+            return None;
+        }
+
+        Some(header)
+    }
+}
+
+/// Reads the header at `lba` and its partition entry array, `None` if either is invalid
+fn read_table(disk: &dyn BlockDevice, lba: u64) -> Result<Option<(Header, Vec<u8>)>, BlockError>
+{
+    let header = match Header::parse(&read_sector(disk, lba)?, lba)
+    {
+        Some(header) => header,
+        None => return Ok(None)
+    };
+
+    let sector_size = disk.sector_size();
+    let array_size = header.entry_count * header.entry_size;
+    let sectors = (array_size + sector_size - 1) / sector_size;
+    if header.entries_lba.checked_add(sectors as u64).map_or(true, |end| end > disk.sector_count())
+    {
+        return Ok(None);
+    }
+
+    let mut entries = vec![0u8; sectors * sector_size];
+    disk.read_sectors(header.entries_lba, &mut entries)?;
+    entries.truncate(array_size);
+
+    if crc32::crc32(&entries) != header.entries_crc
+    {
+        return Ok(None);
+    }
+
+    Ok(Some((header, entries)))
+}
+
+/// Lists the partitions of the GPT, from the primary table or from the backup one at the end of the disk
+/// if the primary header or its entries are corrupted
+pub fn scan(disk: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, BlockError>
+{
+    let (header, entries) = match read_table(disk, PRIMARY_HEADER_LBA)?
+    {
+        Some(table) => table,
+        None => {
+            let backup_lba = disk.sector_count() - 1;
+            warn!("[BLOCK] Invalid primary GPT, trying the backup at sector {}", backup_lba);
+            match read_table(disk, backup_lba)?
+            {
+                Some(table) => table,
+                None => {
+                    warn!("[BLOCK] Invalid backup GPT, ignoring the partition table");
+                    return Ok(Vec::new());
+                }
+            }
+        }
+    };
+
+    if header.my_lba == PRIMARY_HEADER_LBA && header.alternate_lba < disk.sector_count()
+        && read_table(disk, header.alternate_lba)?.is_none()
+    {
+        warn!("[BLOCK] Invalid backup GPT at sector {}", header.alternate_lba);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(header.entry_size).enumerate()
+    {
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid.is_zero()
+        {
+            continue;
+        }
+
+        let first_lba = le_u64(entry, 32);
+        let last_lba = le_u64(entry, 40);
+        if last_lba < first_lba
+        {
+            warn!("[BLOCK] GPT entry {} ends before it starts", i + 1);
+            continue;
+        }
+
+        let name: Vec<u16> = entry[NAME_OFFSET..NAME_OFFSET + NAME_LENGTH * 2]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+
+        partitions.push(PartitionInfo {
+            number: i + 1,
+            start_lba: first_lba,
+            sector_count: last_lba - first_lba + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: Guid(entry[16..32].try_into().unwrap()),
+                name: String::from_utf16_lossy(&name),
+                attributes: le_u64(entry, 48)
+            }
+        });
+    }
+
+    Ok(partitions)
+}
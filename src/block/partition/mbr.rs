@@ -0,0 +1,131 @@
+use alloc::vec::Vec;
+use log::warn;
+
+use crate::block::partition::{le_u32, read_sector, PartitionInfo, PartitionKind};
+use crate::block::{BlockDevice, BlockError};
+
+pub const MBR_SIZE: usize = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const PARTITION_TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+
+pub const SYSTEM_ID_EMPTY: u8 = 0x00;
+pub const SYSTEM_ID_EXTENDED_CHS: u8 = 0x05;
+pub const SYSTEM_ID_EXTENDED_LBA: u8 = 0x0F;
+pub const SYSTEM_ID_EXTENDED_LINUX: u8 = 0x85;
+pub const SYSTEM_ID_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// Logical partitions are numbered after the four primary ones
+const FIRST_LOGICAL_NUMBER: usize = 5;
+/// Bounds the walk of the extended boot record chain, which could loop on a corrupted disk
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+#[derive(Debug, Copy, Clone)]
+pub struct MbrEntry
+{
+    pub bootable: bool,
+    pub system_id: u8,
+    /// Relative to the sector the table is in for primary partitions, to the EBR for logical ones
+    pub start_lba: u32,
+    pub sector_count: u32
+}
+
+impl MbrEntry
+{
+    fn is_extended(&self) -> bool
+    {
+        matches!(self.system_id, SYSTEM_ID_EXTENDED_CHS | SYSTEM_ID_EXTENDED_LBA | SYSTEM_ID_EXTENDED_LINUX)
+    }
+}
+
+/// Parses the four entries of the MBR or EBR in `sector`, `None` if the boot signature is missing
+pub fn parse(sector: &[u8]) -> Option<[MbrEntry; 4]>
+{
+    if sector.len() < MBR_SIZE || sector[MBR_SIZE - 2..MBR_SIZE] != MBR_SIGNATURE
+    {
+        return None;
+    }
+
+    let mut entries = [MbrEntry { bootable: false, system_id: SYSTEM_ID_EMPTY, start_lba: 0, sector_count: 0 }; 4];
+    for (i, entry) in entries.iter_mut().enumerate()
+    {
+        let raw = &sector[PARTITION_TABLE_OFFSET + i * ENTRY_SIZE..PARTITION_TABLE_OFFSET + (i + 1) * ENTRY_SIZE];
+        *entry = MbrEntry {
+            bootable: raw[0] & 0x80 != 0,
+            system_id: raw[4],
+            start_lba: le_u32(raw, 8),
+            sector_count: le_u32(raw, 12)
+        };
+    }
+
+    Some(entries)
+}
+
+/// Lists the primary partitions of the MBR and the logical partitions of its extended partition
+pub fn scan(disk: &dyn BlockDevice, entries: &[MbrEntry; 4]) -> Result<Vec<PartitionInfo>, BlockError>
+{
+    let mut partitions = Vec::new();
+
+    for (i, entry) in entries.iter().enumerate()
+    {
+        if entry.system_id == SYSTEM_ID_EMPTY || entry.sector_count == 0
+        {
+            continue;
+        }
+
+        if entry.is_extended()
+        {
+            scan_extended(disk, entry.start_lba as u64, &mut partitions)?;
+            continue;
+        }
+
+        partitions.push(PartitionInfo {
+            number: i + 1,
+            start_lba: entry.start_lba as u64,
+            sector_count: entry.sector_count as u64,
+            kind: PartitionKind::Mbr { system_id: entry.system_id, bootable: entry.bootable }
+        });
+    }
+
+    Ok(partitions)
+}
+
+/// Walks the chain of extended boot records. Each EBR describes one logical partition relative to itself,
+/// and links to the next EBR relative to the start of the extended partition.
+fn scan_extended(disk: &dyn BlockDevice, extended_start: u64, partitions: &mut Vec<PartitionInfo>) -> Result<(), BlockError>
+{
+    let mut ebr_lba = extended_start;
+    for i in 0..MAX_LOGICAL_PARTITIONS
+    {
+        let sector = read_sector(disk, ebr_lba)?;
+        let entries = match parse(&sector)
+        {
+            Some(entries) => entries,
+            None => {
+                warn!("[BLOCK] Invalid extended boot record at sector {}", ebr_lba);
+                return Ok(());
+            }
+        };
+
+        let logical = &entries[0];
+        if logical.system_id != SYSTEM_ID_EMPTY && logical.sector_count != 0
+        {
+            partitions.push(PartitionInfo {
+                number: FIRST_LOGICAL_NUMBER + i,
+                start_lba: ebr_lba + logical.start_lba as u64,
+                sector_count: logical.sector_count as u64,
+                kind: PartitionKind::Mbr { system_id: logical.system_id, bootable: logical.bootable }
+            });
+        }
+
+        let next = &entries[1];
+        if !next.is_extended() || next.start_lba == 0
+        {
+            return Ok(());
+        }
+        ebr_lba = extended_start + next.start_lba as u64;
+    }
+
+    warn!("[BLOCK] Too many logical partitions, the extended boot record chain may loop");
+    Ok(())
+}
@@ -0,0 +1,191 @@
+mod gpt;
+mod mbr;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use log::warn;
+
+use crate::block::{check_range, BlockDevice, BlockError};
+
+/// A GUID as stored on disk: the first three fields are little endian, the last two are big endian
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid
+{
+    pub fn is_zero(&self) -> bool
+    {
+        self.0.iter().all(|&byte| byte == 0)
+    }
+}
+
+impl Display for Guid
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result
+    {
+        let g = &self.0;
+        write!(f, "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]), u16::from_le_bytes([g[4], g[5]]), u16::from_le_bytes([g[6], g[7]]),
+            g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15])
+    }
+}
+
+impl core::fmt::Debug for Guid
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result
+    {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PartitionKind
+{
+    Mbr { system_id: u8, bootable: bool },
+    Gpt { type_guid: Guid, unique_guid: Guid, name: String, attributes: u64 }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionInfo
+{
+    /// Number of the partition in its table, counting from 1. MBR logical partitions start at 5.
+    pub number: usize,
+    pub start_lba: u64,
+    pub sector_count: u64,
+    pub kind: PartitionKind
+}
+
+/// A partition exposed as a block device, its sectors are translated to the sectors of the disk
+pub struct Partition
+{
+    disk: Arc<dyn BlockDevice>,
+    info: PartitionInfo
+}
+
+impl Partition
+{
+    pub fn new(disk: Arc<dyn BlockDevice>, info: PartitionInfo) -> Self
+    {
+        Partition {
+            disk,
+            info
+        }
+    }
+}
+
+impl BlockDevice for Partition
+{
+    fn sector_size(&self) -> usize
+    {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64
+    {
+        self.info.sector_count
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        check_range(self, lba, buffer.len())?;
+        self.disk.read_sectors(self.info.start_lba + lba, buffer)
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        check_range(self, lba, buffer.len())?;
+        self.disk.write_sectors(self.info.start_lba + lba, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        self.disk.flush()
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> Result<(), BlockError>
+    {
+        match lba.checked_add(count)
+        {
+            Some(end) if end <= self.info.sector_count => self.disk.discard(self.info.start_lba + lba, count),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.disk.is_read_only()
+    }
+
+    fn write_polled(&self, lba: u64, buffer: &[u8], sector_size: usize) -> Result<(), BlockError>
+    {
+        if sector_size == 0 || buffer.len() % sector_size != 0
+        {
+            return Err(BlockError::InvalidBuffer);
+        }
+        match lba.checked_add((buffer.len() / sector_size) as u64)
+        {
+            Some(end) if end <= self.info.sector_count => self.disk.write_polled(self.info.start_lba + lba, buffer, sector_size),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+}
+
+fn read_sector(disk: &dyn BlockDevice, lba: u64) -> Result<Vec<u8>, BlockError>
+{
+    let mut sector = vec![0u8; disk.sector_size()];
+    disk.read_sectors(lba, &mut sector)?;
+    Ok(sector)
+}
+
+#[inline]
+fn le_u32(data: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+fn le_u64(data: &[u8], offset: usize) -> u64
+{
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads the partition table of `disk`: a GPT if the MBR is protective, the MBR and its extended partitions
+/// otherwise. A disk without a valid MBR signature has no partitions.
+pub fn scan(disk: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, BlockError>
+{
+    if disk.sector_count() == 0 || disk.sector_size() < mbr::MBR_SIZE
+    {
+        return Ok(Vec::new());
+    }
+
+    let sector = read_sector(disk, 0)?;
+    let entries = match mbr::parse(&sector)
+    {
+        Some(entries) => entries,
+        None => return Ok(Vec::new())
+    };
+
+    let mut partitions = if entries.iter().any(|entry| entry.system_id == mbr::SYSTEM_ID_GPT_PROTECTIVE)
+    {
+        gpt::scan(disk)?
+    }
+    else
+    {
+        mbr::scan(disk, &entries)?
+    };
+
+    // A partition lying partly outside of the disk would fail every access
+    partitions.retain(|partition| {
+        let within_disk = partition.start_lba.checked_add(partition.sector_count).map_or(false, |end| end <= disk.sector_count());
+        if !within_disk
+        {
+            warn!("[BLOCK] Partition {} extends past the end of the disk, ignoring it", partition.number);
+        }
+        within_disk
+    });
+
+    Ok(partitions)
+}
@@ -0,0 +1,587 @@
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::block::{check_range, BlockDevice, BlockError};
+use crate::deferred::{self, Work};
+use crate::time;
+
+/// A pending request waiting this long is dispatched before the ones the elevator would pick
+const DEADLINE_MS: u64 = 500;
+/// Interval at which the requests in flight are polled, which notices timeouts and drives devices without
+/// completion interrupts
+const POLL_INTERVAL_MS: u64 = 10;
+const DEFAULT_MAX_TRANSFER_SIZE: usize = 128 * 1024;
+const DEFAULT_MAX_SEGMENTS: usize = 16;
+
+/// Called once with the result of a request, from the kernel main loop or from a thread waiting on the queue
+pub type Callback = Box<dyn FnOnce(Result<(), BlockError>) + Send>;
+
+/// Completes a request split in parts once every part has completed, with the first error of the parts
+struct Split
+{
+    remaining: usize,
+    result: Result<(), BlockError>,
+    callback: Option<Callback>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operation
+{
+    Read,
+    Write,
+    /// Makes the writes completed before it persistent. Requests submitted before a flush are dispatched
+    /// before it, requests submitted after it wait for it to complete.
+    Flush,
+    Discard
+}
+
+impl Operation
+{
+    /// Whether the operation changes the data of the sectors it covers
+    fn modifies(&self) -> bool
+    {
+        matches!(self, Operation::Write | Operation::Discard)
+    }
+}
+
+/// A buffer a request transfers data to or from
+#[derive(Debug, Copy, Clone)]
+pub struct Segment
+{
+    pub buffer: *mut u8,
+    pub length: usize
+}
+
+unsafe impl Send for Segment {}
+
+/// A request to a block device, completed asynchronously through its callback.
+///
+/// Adjacent requests are merged by the queue: the backend then sees a single request covering all of them,
+/// with one segment per merged buffer, and every callback is called with its result.
+pub struct BlockRequest
+{
+    pub operation: Operation,
+    pub lba: u64,
+    /// Sectors covered by the request, set by the queue for reads and writes
+    pub count: u64,
+    /// Buffers in LBA order, empty for flushes and discards
+    pub segments: Vec<Segment>,
+    callbacks: Vec<Callback>,
+    submitted_at: u64
+}
+
+impl BlockRequest
+{
+    fn new(operation: Operation, lba: u64, count: u64, segments: Vec<Segment>, callback: Callback) -> Self
+    {
+        BlockRequest {
+            operation,
+            lba,
+            count,
+            segments,
+            callbacks: alloc::vec![callback],
+            submitted_at: time::uptime_ms()
+        }
+    }
+
+    /// Reads `length` bytes at sector `lba` into `buffer`
+    ///
+    /// # Safety
+    /// `buffer` must stay valid, and must not be accessed, until the callback is called
+    pub unsafe fn read(lba: u64, buffer: *mut u8, length: usize, callback: Callback) -> Self
+    {
+        Self::new(Operation::Read, lba, 0, alloc::vec![Segment { buffer, length }], callback)
+    }
+
+    /// Writes `length` bytes from `buffer` at sector `lba`
+    ///
+    /// # Safety
+    /// `buffer` must stay valid, and must not be modified, until the callback is called
+    pub unsafe fn write(lba: u64, buffer: *const u8, length: usize, callback: Callback) -> Self
+    {
+        Self::new(Operation::Write, lba, 0, alloc::vec![Segment { buffer: buffer as *mut u8, length }], callback)
+    }
+
+    pub fn flush(callback: Callback) -> Self
+    {
+        Self::new(Operation::Flush, 0, 0, Vec::new(), callback)
+    }
+
+    pub fn discard(lba: u64, count: u64, callback: Callback) -> Self
+    {
+        Self::new(Operation::Discard, lba, count, Vec::new(), callback)
+    }
+
+    /// Bytes transferred by the request
+    pub fn length(&self) -> usize
+    {
+        self.segments.iter().map(|segment| segment.length).sum()
+    }
+
+    #[inline]
+    fn end(&self) -> u64
+    {
+        self.lba + self.count
+    }
+
+    /// Whether the two requests must complete in the order they were submitted: they cover common sectors
+    /// and at least one of them modifies them
+    fn conflicts(&self, other: &BlockRequest) -> bool
+    {
+        (self.operation.modifies() || other.operation.modifies())
+            && self.operation != Operation::Flush && other.operation != Operation::Flush
+            && self.lba < other.end() && other.lba < self.end()
+    }
+
+    fn complete(self, result: Result<(), BlockError>)
+    {
+        for callback in self.callbacks
+        {
+            callback(result.clone());
+        }
+    }
+}
+
+/// How a backend took a dispatched request
+pub enum Submission
+{
+    /// The request is in flight, `poll` reports its completion given this tag
+    Issued(usize),
+    /// The request completed, or could not be started
+    Completed(Result<(), BlockError>),
+    /// The device cannot take more requests until some complete
+    Busy
+}
+
+/// A device a `RequestQueue` dispatches to.
+///
+/// By default each request is executed synchronously with the `BlockDevice` methods, devices able to keep
+/// several requests in flight override `submit` and `poll`.
+pub trait QueueBackend: BlockDevice
+{
+    /// Starts `request`, which was validated against the device size and is at most `max_transfer_size`
+    /// bytes in at most `max_segments` segments
+    fn submit(&self, request: &BlockRequest) -> Submission
+    {
+        Submission::Completed(execute(self, request))
+    }
+
+    /// The result of the request issued with `tag`, `None` while it is in flight
+    fn poll(&self, _tag: usize) -> Option<Result<(), BlockError>>
+    {
+        None
+    }
+
+    /// Gives the backend work to schedule when requests complete, so that they are collected before the next poll
+    fn set_completion_work(&self, _work: Arc<Work>) {}
+
+    /// Longest request in bytes, merging stops there
+    fn max_transfer_size(&self) -> usize
+    {
+        DEFAULT_MAX_TRANSFER_SIZE
+    }
+
+    fn max_segments(&self) -> usize
+    {
+        DEFAULT_MAX_SEGMENTS
+    }
+
+    /// Longest discard in sectors
+    fn max_discard_sectors(&self) -> u64
+    {
+        u64::MAX
+    }
+}
+
+/// Executes `request` with the synchronous methods of `device`, one segment after the other
+fn execute<D: BlockDevice + ?Sized>(device: &D, request: &BlockRequest) -> Result<(), BlockError>
+{
+    let mut lba = request.lba;
+    for segment in request.segments.iter()
+    {
+        match request.operation
+        {
+            Operation::Read => device.read_sectors(lba, unsafe { core::slice::from_raw_parts_mut(segment.buffer, segment.length) })?,
+            _ => device.write_sectors(lba, unsafe { core::slice::from_raw_parts(segment.buffer, segment.length) })?
+        }
+        lba += (segment.length / device.sector_size()) as u64;
+    }
+
+    match request.operation
+    {
+        Operation::Flush => device.flush(),
+        Operation::Discard => device.discard(request.lba, request.count),
+        _ => Ok(())
+    }
+}
+
+struct QueueState
+{
+    /// Requests not dispatched yet, in submission order
+    pending: Vec<BlockRequest>,
+    in_flight: Vec<(usize, BlockRequest)>,
+    /// Sector following the last dispatched request, where the elevator goes on
+    head: u64
+}
+
+/// Queues the requests to a block device and dispatches them as the device can take them.
+///
+/// Adjacent requests are merged, and the next request is picked by a C-LOOK elevator: the lowest sector at
+/// or after the last dispatched one, wrapping around to the lowest sector. A request waiting past its
+/// deadline goes first instead. Requests covering common sectors are never reordered if one of them writes.
+///
+/// Completions are collected by deferred work, so callbacks run in the kernel main loop. The queue must not
+/// be used from interrupt handlers.
+pub struct RequestQueue
+{
+    backend: Arc<dyn QueueBackend>,
+    state: Mutex<QueueState>,
+    work: Arc<Work>
+}
+
+impl RequestQueue
+{
+    pub fn new(backend: Arc<dyn QueueBackend>) -> Arc<RequestQueue>
+    {
+        let queue = Arc::new_cyclic(|queue: &Weak<RequestQueue>| {
+            let queue = queue.clone();
+            RequestQueue {
+                backend,
+                state: Mutex::new(QueueState {
+                    pending: Vec::new(),
+                    in_flight: Vec::new(),
+                    head: 0
+                }),
+                work: deferred::register(move || {
+                    if let Some(queue) = queue.upgrade()
+                    {
+                        queue.process();
+                    }
+                })
+            }
+        });
+
+        queue.backend.set_completion_work(queue.work.clone());
+        time::schedule_periodic(POLL_INTERVAL_MS, queue.work.clone());
+        queue
+    }
+
+    /// Queues `request` and dispatches what the device can take. An invalid request is completed right away.
+    pub fn submit(&self, mut request: BlockRequest)
+    {
+        match self.validate(&mut request)
+        {
+            Err(e) => return request.complete(Err(e)),
+            Ok(()) if request.count == 0 && request.operation != Operation::Flush => return request.complete(Ok(())),
+            Ok(()) => {}
+        }
+
+        let mut finished = Vec::new();
+        {
+            let mut state = self.state.lock();
+            for request in self.split(request)
+            {
+                if let Some(request) = self.merge(&mut state, request)
+                {
+                    state.pending.push(request);
+                }
+            }
+            self.dispatch(&mut state, &mut finished);
+        }
+
+        Self::complete(finished);
+    }
+
+    /// Collects the completed requests, dispatches pending ones in their place and calls the callbacks
+    pub fn process(&self)
+    {
+        let mut finished = Vec::new();
+        {
+            let mut state = self.state.lock();
+            let mut i = 0;
+            while i < state.in_flight.len()
+            {
+                match self.backend.poll(state.in_flight[i].0)
+                {
+                    Some(result) => {
+                        let (_, request) = state.in_flight.swap_remove(i);
+                        finished.push((request, result));
+                    }
+                    None => i += 1
+                }
+            }
+
+            self.dispatch(&mut state, &mut finished);
+        }
+
+        Self::complete(finished);
+    }
+
+    fn validate(&self, request: &mut BlockRequest) -> Result<(), BlockError>
+    {
+        if request.operation.modifies() && self.backend.is_read_only()
+        {
+            return Err(BlockError::ReadOnly);
+        }
+
+        match request.operation
+        {
+            Operation::Read | Operation::Write => {
+                let length = request.length();
+                check_range(self.backend.as_ref(), request.lba, length)?;
+                request.count = (length / self.backend.sector_size()) as u64;
+                Ok(())
+            }
+            Operation::Discard => match request.lba.checked_add(request.count)
+            {
+                Some(end) if end <= self.backend.sector_count() => Ok(()),
+                _ => Err(BlockError::OutOfRange)
+            },
+            Operation::Flush => Ok(())
+        }
+    }
+
+    /// Splits a request longer than the backend takes into parts, the callback of the request being called
+    /// once all of them have completed
+    fn split(&self, request: BlockRequest) -> Vec<BlockRequest>
+    {
+        let max_sectors = match request.operation
+        {
+            Operation::Read | Operation::Write => (self.backend.max_transfer_size() / self.backend.sector_size()) as u64,
+            Operation::Discard => self.backend.max_discard_sectors(),
+            Operation::Flush => u64::MAX
+        };
+        if request.count <= max_sectors
+        {
+            return alloc::vec![request];
+        }
+
+        let parts = ((request.count + max_sectors - 1) / max_sectors) as usize;
+        let split = Arc::new(Mutex::new(Split {
+            remaining: parts,
+            result: Ok(()),
+            callback: request.callbacks.into_iter().next()
+        }));
+
+        let sector_size = self.backend.sector_size();
+        (0..parts).map(|part| {
+            let offset = part as u64 * max_sectors;
+            let count = core::cmp::min(request.count - offset, max_sectors);
+            // A request is split before being merged, so it has at most one segment
+            let segments = request.segments.first().map(|segment| Segment {
+                buffer: unsafe { segment.buffer.add(offset as usize * sector_size) },
+                length: count as usize * sector_size
+            });
+
+            let split = split.clone();
+            let callback: Callback = Box::new(move |result| {
+                let mut split = split.lock();
+                if split.result.is_ok()
+                {
+                    split.result = result;
+                }
+                split.remaining -= 1;
+                if split.remaining == 0
+                {
+                    let callback = split.callback.take();
+                    let result = split.result.clone();
+                    drop(split);
+                    if let Some(callback) = callback
+                    {
+                        callback(result);
+                    }
+                }
+            });
+
+            BlockRequest {
+                operation: request.operation,
+                lba: request.lba + offset,
+                count,
+                segments: segments.into_iter().collect(),
+                callbacks: alloc::vec![callback],
+                submitted_at: request.submitted_at
+            }
+        }).collect()
+    }
+
+    /// Merges `request` into an adjacent pending request submitted after the last flush, unless a request
+    /// submitted in between conflicts with it. Returns the request if it could not be merged.
+    fn merge(&self, state: &mut QueueState, request: BlockRequest) -> Option<BlockRequest>
+    {
+        if request.operation == Operation::Flush
+        {
+            return Some(request);
+        }
+
+        let max_transfer_size = self.backend.max_transfer_size();
+        let max_segments = self.backend.max_segments();
+        let max_discard_sectors = self.backend.max_discard_sectors();
+        let first = state.pending.iter().rposition(|pending| pending.operation == Operation::Flush).map_or(0, |i| i + 1);
+
+        let target = (first..state.pending.len()).rev().find(|&i| {
+            let pending = &state.pending[i];
+            pending.operation == request.operation
+                && (pending.end() == request.lba || request.end() == pending.lba)
+                && match request.operation
+                {
+                    // Discards carry no data, only their sector count is limited
+                    Operation::Discard => pending.count + request.count <= max_discard_sectors,
+                    _ => pending.length() + request.length() <= max_transfer_size
+                        && pending.segments.len() + request.segments.len() <= max_segments
+                }
+                && !state.pending[i + 1..].iter().any(|later| later.conflicts(&request))
+        })?;
+
+        let pending = &mut state.pending[target];
+        if pending.end() == request.lba
+        {
+            pending.segments.extend(request.segments);
+        }
+        else
+        {
+            pending.segments.splice(0..0, request.segments);
+            pending.lba = request.lba;
+        }
+        pending.count += request.count;
+        pending.callbacks.extend(request.callbacks);
+
+        None
+    }
+
+    /// Index of the pending request to dispatch next, if any can be
+    fn next_request(state: &QueueState) -> Option<usize>
+    {
+        let pending = &state.pending;
+
+        // A flush is a barrier: it waits for the requests before it, and the requests after it wait for it
+        if state.in_flight.iter().any(|(_, request)| request.operation == Operation::Flush)
+        {
+            return None;
+        }
+        let barrier = pending.iter().position(|request| request.operation == Operation::Flush).unwrap_or(pending.len());
+        if barrier == 0 && !pending.is_empty()
+        {
+            return if state.in_flight.is_empty() { Some(0) } else { None };
+        }
+
+        let ready = |&i: &usize| {
+            !pending[..i].iter().any(|earlier| earlier.conflicts(&pending[i]))
+                && !state.in_flight.iter().any(|(_, request)| request.conflicts(&pending[i]))
+        };
+
+        let now = time::uptime_ms();
+        let expired = (0..barrier).filter(ready)
+            .filter(|&i| now.saturating_sub(pending[i].submitted_at) >= DEADLINE_MS)
+            .min_by_key(|&i| pending[i].submitted_at);
+
+        expired.or_else(|| (0..barrier).filter(ready).min_by_key(|&i| (pending[i].lba < state.head, pending[i].lba)))
+    }
+
+    fn dispatch(&self, state: &mut QueueState, finished: &mut Vec<(BlockRequest, Result<(), BlockError>)>)
+    {
+        while let Some(index) = Self::next_request(state)
+        {
+            let (tag, result) = match self.backend.submit(&state.pending[index])
+            {
+                Submission::Busy => break,
+                Submission::Issued(tag) => (Some(tag), Ok(())),
+                Submission::Completed(result) => (None, result)
+            };
+
+            let request = state.pending.remove(index);
+            state.head = request.end();
+
+            match tag
+            {
+                Some(tag) => state.in_flight.push((tag, request)),
+                None => finished.push((request, result))
+            }
+        }
+    }
+
+    /// Calls the callbacks of the finished requests, with the queue unlocked since they may submit more
+    fn complete(finished: Vec<(BlockRequest, Result<(), BlockError>)>)
+    {
+        for (request, result) in finished
+        {
+            request.complete(result);
+        }
+    }
+
+    /// Submits the request built by `build` and waits for its completion, collecting the completions of the
+    /// queue meanwhile since the main loop does not run while waiting
+    fn submit_and_wait(&self, build: impl FnOnce(Callback) -> BlockRequest) -> Result<(), BlockError>
+    {
+        let result = Arc::new(Mutex::new(None));
+        let completion = result.clone();
+        self.submit(build(Box::new(move |r| *completion.lock() = Some(r))));
+
+        loop
+        {
+            if let Some(result) = result.lock().take()
+            {
+                return result;
+            }
+
+            // The completion interrupt schedules the queue work, which also wakes the CPU up
+            if interrupts::are_enabled()
+            {
+                x86_64::instructions::hlt();
+            }
+            else
+            {
+                core::hint::spin_loop();
+            }
+            self.process();
+        }
+    }
+}
+
+/// Synchronous access through the queue, each call waits for its request
+impl BlockDevice for RequestQueue
+{
+    fn sector_size(&self) -> usize
+    {
+        self.backend.sector_size()
+    }
+
+    fn sector_count(&self) -> u64
+    {
+        self.backend.sector_count()
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        self.submit_and_wait(|callback| unsafe { BlockRequest::read(lba, buffer.as_mut_ptr(), buffer.len(), callback) })
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        self.submit_and_wait(|callback| unsafe { BlockRequest::write(lba, buffer.as_ptr(), buffer.len(), callback) })
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        self.submit_and_wait(BlockRequest::flush)
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> Result<(), BlockError>
+    {
+        self.submit_and_wait(|callback| BlockRequest::discard(lba, count, callback))
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.backend.is_read_only()
+    }
+
+    /// Bypasses the queue, which is kept from dispatching meanwhile
+    fn write_polled(&self, lba: u64, buffer: &[u8], sector_size: usize) -> Result<(), BlockError>
+    {
+        let _state = self.state.try_lock().ok_or(BlockError::Busy)?;
+        self.backend.write_polled(lba, buffer, sector_size)
+    }
+}
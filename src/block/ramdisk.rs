@@ -0,0 +1,160 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+#[cfg(feature = "initrd")]
+use log::{info, warn};
+use spin::RwLock;
+use x86_64::VirtAddr;
+
+use crate::block::{self, check_range, BlockDevice, BlockError};
+use crate::vmm::MappingError;
+use crate::VMM;
+
+pub const RAMDISK_SECTOR_SIZE: usize = 512;
+
+/// Prefix of the block device names of RAM disks
+const BLOCK_DEVICE_PREFIX: &str = "ram";
+
+/// Image embedded by building with the `initrd` feature, from the file named by the `INITRD` environment variable
+#[cfg(feature = "initrd")]
+static INITRD: &[u8] = include_bytes!(env!("INITRD"));
+
+/// A block device backed by memory, its content is lost on reboot.
+///
+/// The memory comes from the PMM and is mapped contiguously. It is never given back, since the PMM cannot
+/// free frames.
+pub struct RamDisk
+{
+    memory: VirtAddr,
+    sector_count: u64,
+    read_only: bool,
+    /// Keeps readers from seeing a write half done
+    lock: RwLock<()>
+}
+
+impl RamDisk
+{
+    /// Allocates a zeroed RAM disk of `size` bytes, rounded up to whole pages
+    pub fn new(size: usize) -> Result<Self, MappingError>
+    {
+        let pages = (size + 0xFFF) / 0x1000;
+        let memory = VMM.lock().allocate_pages(pages)?;
+        unsafe { core::ptr::write_bytes(memory.as_mut_ptr::<u8>(), 0, pages * 0x1000) };
+
+        Ok(RamDisk {
+            memory,
+            sector_count: (pages * 0x1000 / RAMDISK_SECTOR_SIZE) as u64,
+            read_only: false,
+            lock: RwLock::new(())
+        })
+    }
+
+    /// Creates a RAM disk holding a copy of `image`, padded with zeroes to whole pages
+    #[cfg(feature = "initrd")]
+    pub fn from_image(image: &[u8], read_only: bool) -> Result<Self, MappingError>
+    {
+        let mut disk = Self::new(image.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(image.as_ptr(), disk.memory.as_mut_ptr::<u8>(), image.len()) };
+        disk.read_only = read_only;
+        Ok(disk)
+    }
+
+    fn sector(&self, lba: u64) -> *mut u8
+    {
+        (self.memory + lba * RAMDISK_SECTOR_SIZE as u64).as_mut_ptr()
+    }
+}
+
+impl BlockDevice for RamDisk
+{
+    fn sector_size(&self) -> usize
+    {
+        RAMDISK_SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64
+    {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        check_range(self, lba, buffer.len())?;
+
+        let _guard = self.lock.read();
+        unsafe { core::ptr::copy_nonoverlapping(self.sector(lba), buffer.as_mut_ptr(), buffer.len()) };
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        check_range(self, lba, buffer.len())?;
+        if self.read_only
+        {
+            return Err(BlockError::ReadOnly);
+        }
+
+        let _guard = self.lock.write();
+        unsafe { core::ptr::copy_nonoverlapping(buffer.as_ptr(), self.sector(lba), buffer.len()) };
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        Ok(())
+    }
+
+    /// Discarded sectors read back as zeroes
+    fn discard(&self, lba: u64, count: u64) -> Result<(), BlockError>
+    {
+        match lba.checked_add(count)
+        {
+            Some(end) if end <= self.sector_count => {}
+            _ => return Err(BlockError::OutOfRange)
+        }
+        if self.read_only
+        {
+            return Err(BlockError::ReadOnly);
+        }
+
+        let _guard = self.lock.write();
+        unsafe { core::ptr::write_bytes(self.sector(lba), 0, count as usize * RAMDISK_SECTOR_SIZE) };
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.read_only
+    }
+}
+
+/// Creates a zeroed RAM disk of `size` bytes and registers it, e.g. as `ram0`. Returns its name.
+pub fn create(size: usize) -> Result<String, MappingError>
+{
+    let disk = RamDisk::new(size)?;
+    Ok(block::register_disk(BLOCK_DEVICE_PREFIX, Arc::new(disk)))
+}
+
+/// Registers a writable copy of the initrd embedded in the kernel, along with its partitions. Bootloader 0.10
+/// cannot load a ramdisk next to the kernel, so the image is embedded at build time with the `initrd` feature.
+#[cfg(feature = "initrd")]
+pub fn load_initrd() -> Option<String>
+{
+    match RamDisk::from_image(INITRD, false)
+    {
+        Ok(disk) => {
+            let name = block::register_disk(BLOCK_DEVICE_PREFIX, Arc::new(disk));
+            info!("[BLOCK] Loaded the initrd as {} ({} bytes)", name, INITRD.len());
+            Some(name)
+        }
+        Err(e) => {
+            warn!("[BLOCK] Failed to allocate memory for the initrd: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "initrd"))]
+pub fn load_initrd() -> Option<String>
+{
+    None
+}
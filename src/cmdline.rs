@@ -0,0 +1,302 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use ::aml::DebugVerbosity;
+use log::{info, warn, LevelFilter};
+use spin::RwLock;
+
+use crate::block::BLOCK_DEVICES;
+use crate::fs::{self, OpenFlags};
+
+/// Command line embedded by building with the `KERNEL_CMDLINE` environment variable, as the bootloader does
+/// not pass one to the kernel
+const BUILTIN_COMMAND_LINE: &str = match option_env!("KERNEL_CMDLINE")
+{
+    Some(command_line) => command_line,
+    None => ""
+};
+
+/// Mount point of the boot partition, and the file on it holding the rest of the command line
+const BOOT_MOUNT_POINT: &str = "/boot";
+const COMMAND_LINE_FILE: &str = "/boot/cmdline";
+
+/// The built-in command line, followed by the command line file once it is loaded. A parameter given twice
+/// takes its last value.
+static COMMAND_LINE: RwLock<&'static str> = RwLock::new(BUILTIN_COMMAND_LINE);
+
+/// The arguments of `command_line`, separated by whitespace: `key=value` or a flag alone. A value can be
+/// quoted to hold whitespace.
+fn arguments(command_line: &str) -> impl Iterator<Item = (&str, Option<&str>)>
+{
+    let mut rest = command_line;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty()
+        {
+            return None;
+        }
+
+        let key_end = rest.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = &rest[key_end..];
+        let value = match rest.strip_prefix('=')
+        {
+            None => None,
+            Some(quoted) if quoted.starts_with('"') => {
+                let end = quoted[1..].find('"').map_or(quoted.len(), |end| end + 1);
+                rest = quoted.get(end + 1..).unwrap_or("");
+                Some(&quoted[1..end])
+            }
+            Some(value) => {
+                let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                rest = &value[end..];
+                Some(&value[..end])
+            }
+        };
+        Some((key, value))
+    })
+}
+
+/// The value of the last argument called `name`: `None` if there is none, `Some(None)` for a flag
+fn lookup(name: &str) -> Option<Option<&'static str>>
+{
+    arguments(command_line()).filter(|(key, _)| *key == name).last().map(|(_, value)| value)
+}
+
+/// A type a parameter can have, parsed from the value of its argument, `None` for a flag
+pub trait ParameterType: Copy + Debug + Sync + 'static
+{
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+impl ParameterType for bool
+{
+    fn parse(value: Option<&'static str>) -> Option<Self>
+    {
+        match value
+        {
+            None | Some("1") | Some("on") | Some("yes") | Some("true") => Some(true),
+            Some("0") | Some("off") | Some("no") | Some("false") => Some(false),
+            _ => None
+        }
+    }
+}
+
+/// A number, in hexadecimal with a `0x` prefix, and optionally followed by `K`, `M` or `G` to count in KiB,
+/// MiB or GiB
+impl ParameterType for usize
+{
+    fn parse(value: Option<&'static str>) -> Option<Self>
+    {
+        let value = value?;
+        let (number, unit) = match value.as_bytes().last()?
+        {
+            b'K' | b'k' => (&value[..value.len() - 1], 1 << 10),
+            b'M' | b'm' => (&value[..value.len() - 1], 1 << 20),
+            b'G' | b'g' => (&value[..value.len() - 1], 1 << 30),
+            _ => (value, 1)
+        };
+        let number = match number.strip_prefix("0x")
+        {
+            Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?
+        };
+        number.checked_mul(unit)
+    }
+}
+
+impl ParameterType for &'static str
+{
+    fn parse(value: Option<&'static str>) -> Option<Self>
+    {
+        value
+    }
+}
+
+impl ParameterType for LevelFilter
+{
+    fn parse(value: Option<&'static str>) -> Option<Self>
+    {
+        value?.parse().ok()
+    }
+}
+
+impl ParameterType for DebugVerbosity
+{
+    fn parse(value: Option<&'static str>) -> Option<Self>
+    {
+        match value?
+        {
+            "none" => Some(DebugVerbosity::None),
+            "scopes" => Some(DebugVerbosity::Scopes),
+            "all_scopes" => Some(DebugVerbosity::AllScopes),
+            "all" => Some(DebugVerbosity::All),
+            _ => None
+        }
+    }
+}
+
+/// A parameter of the command line, declared as a static by the module reading it and listed in
+/// `PARAMETERS`
+pub struct Parameter<T: ParameterType>
+{
+    name: &'static str,
+    default: T,
+    description: &'static str
+}
+
+impl<T: ParameterType> Parameter<T>
+{
+    pub const fn new(name: &'static str, default: T, description: &'static str) -> Self
+    {
+        Parameter {
+            name,
+            default,
+            description
+        }
+    }
+
+    /// The value given on the command line, or the default if there is none or it is invalid
+    pub fn get(&self) -> T
+    {
+        match lookup(self.name)
+        {
+            None => self.default,
+            Some(value) => T::parse(value).unwrap_or_else(|| {
+                warn!("[CMDLINE] Invalid value {:?} for {}, using {:?}", value, self.name, self.default);
+                self.default
+            })
+        }
+    }
+}
+
+/// A parameter of any type, for the registry
+pub trait AnyParameter: Sync
+{
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// The value in use, formatted
+    fn value(&self) -> String;
+
+    /// Whether `value` is valid for the parameter, `None` for a flag
+    fn is_valid(&self, value: Option<&'static str>) -> bool;
+}
+
+impl<T: ParameterType> AnyParameter for Parameter<T>
+{
+    fn name(&self) -> &'static str
+    {
+        self.name
+    }
+
+    fn description(&self) -> &'static str
+    {
+        self.description
+    }
+
+    fn value(&self) -> String
+    {
+        format!("{:?}", self.get())
+    }
+
+    fn is_valid(&self, value: Option<&'static str>) -> bool
+    {
+        T::parse(value).is_some()
+    }
+}
+
+/// The parameters the kernel reads
+static PARAMETERS: &[&dyn AnyParameter] = &[
+    &crate::acpi::AML_DEBUG_VERBOSITY,
+    &crate::allocator::HEAP_SIZE,
+    &crate::block::cache::CACHE_PERCENT,
+    &crate::drivers::SKIPPED_DRIVERS,
+    &crate::gdb::GDB_ENABLED,
+    &crate::gdb::GDB_WAIT,
+    &crate::logger::LOG_LEVELS,
+    &crate::pci::PCI_ENABLED,
+    &crate::shell::SHELL_ENABLED,
+    &crate::ROOT_DEVICE,
+    &crate::TEST_MODE
+];
+
+pub fn parameters() -> &'static [&'static dyn AnyParameter]
+{
+    PARAMETERS
+}
+
+/// The command line in use
+pub fn command_line() -> &'static str
+{
+    let command_line = COMMAND_LINE.read();
+    &command_line
+}
+
+/// Warns about the arguments of `command_line` which are not parameters of the kernel or have an invalid
+/// value
+fn check(command_line: &'static str)
+{
+    for (key, value) in arguments(command_line)
+    {
+        match PARAMETERS.iter().find(|parameter| parameter.name() == key)
+        {
+            Some(parameter) if !parameter.is_valid(value) => warn!("[CMDLINE] Invalid value for {}, expected {}", key, parameter.description()),
+            Some(_) => {}
+            None => warn!("[CMDLINE] Unknown parameter {}", key)
+        }
+    }
+}
+
+/// Logs the built-in command line and checks it, once the logger is set up
+pub fn init()
+{
+    info!("[CMDLINE] Command line: {}", command_line());
+    check(command_line());
+}
+
+/// Looks for the command line file on the FAT block devices, and appends it to the command line.
+///
+/// The partition holding it stays mounted on `/boot`. The parameters read before, e.g. the heap size, only
+/// see the built-in command line. Returns whether the file was loaded, now or by an earlier call.
+pub fn load_boot_file() -> bool
+{
+    if fs::metadata(COMMAND_LINE_FILE).is_ok()
+    {
+        return true;
+    }
+
+    let devices: Vec<String> = BLOCK_DEVICES.lock().iter().map(|(name, _)| name.clone()).collect();
+    for device in devices
+    {
+        if fs::mount_device(BOOT_MOUNT_POINT, &device, Some("fat")).is_err()
+        {
+            continue;
+        }
+        match fs::open(COMMAND_LINE_FILE, OpenFlags::READ).and_then(|file| file.read_to_end())
+        {
+            Ok(content) => {
+                // Lines starting with '#' are comments
+                let file: String = String::from_utf8_lossy(&content).lines()
+                    .filter(|line| !line.trim_start().starts_with('#'))
+                    .flat_map(|line| [line, " "])
+                    .collect();
+                let file: &'static str = Box::leak(String::from(file.trim()).into_boxed_str());
+                let mut command_line = COMMAND_LINE.write();
+                *command_line = Box::leak(format!("{} {}", *command_line, file).into_boxed_str());
+                drop(command_line);
+
+                info!("[CMDLINE] Loaded {} from {}: {}", COMMAND_LINE_FILE, device, file);
+                check(file);
+                return true;
+            }
+            Err(_) => {
+                let _ = fs::unmount(BOOT_MOUNT_POINT);
+            }
+        }
+    }
+    false
+}
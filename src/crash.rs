@@ -0,0 +1,487 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::block::partition::{PartitionInfo, PartitionKind};
+use crate::block::{BlockDevice, BlockError};
+use crate::interrupts::TrapFrame;
+use crate::logger::LOG_RING;
+use crate::vmm::VMM;
+use crate::{apic, backtrace, crc32, exit_qemu, interrupts, serial, serial_print, serial_println, time, QemuExitCode, BOOT_INFO, TEST_MODE};
+
+/// Version of the dump format, increased whenever a field changes meaning or is removed
+pub const FORMAT_VERSION: u32 = 1;
+
+pub const BEGIN_MARKER: &str = "-----BEGIN KERNEL CRASH DUMP-----";
+pub const END_MARKER: &str = "-----END KERNEL CRASH DUMP-----";
+
+/// Size of the dump, the partition keeping it must hold as many bytes
+const DUMP_SIZE: usize = 64 * 1024;
+/// Room kept for the checksum and end marker once the fields fill the dump
+const TRAILER_SIZE: usize = 64;
+/// Records of the kernel log included in the dump
+const LOG_RECORDS: usize = 64;
+
+/// Name of a GPT partition, or system ID of a MBR partition, reserved for crash dumps
+const GPT_PARTITION_NAME: &str = "crashdump";
+const MBR_SYSTEM_ID: u8 = 0xDA;
+
+/// What brought the kernel down
+pub enum Cause<'a>
+{
+    Exception { vector: u8, name: &'static str, error_code: Option<u64> },
+    Panic(&'a PanicInfo<'a>)
+}
+
+/// The registers of the code which crashed
+struct Registers
+{
+    rip: u64,
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cs: u64,
+    ss: u64
+}
+
+/// The dump being written. It lives outside of the heap, which may be what broke.
+///
+/// The dump is made of ASCII `key=value` lines between `BEGIN_MARKER` and `END_MARKER`. Backslashes, line
+/// feeds and the bytes of the values which are not printable ASCII are escaped as `\\`, `\n` and `\xNN`.
+/// The last field is the CRC32 of the lines before it, from the begin marker on.
+#[repr(C, align(4096))]
+struct Dump
+{
+    data: [u8; DUMP_SIZE],
+    length: usize,
+    truncated: bool
+}
+
+impl Dump
+{
+    const fn new() -> Self
+    {
+        Dump {
+            data: [0; DUMP_SIZE],
+            length: 0,
+            truncated: false
+        }
+    }
+
+    fn push(&mut self, byte: u8, limit: usize)
+    {
+        if self.length < limit
+        {
+            self.data[self.length] = byte;
+            self.length += 1;
+        }
+        else
+        {
+            self.truncated = true;
+        }
+    }
+
+    fn push_escaped(&mut self, byte: u8)
+    {
+        let limit = DUMP_SIZE - TRAILER_SIZE;
+        match byte
+        {
+            b'\\' => {
+                self.push(b'\\', limit);
+                self.push(b'\\', limit);
+            }
+            b'\n' => {
+                self.push(b'\\', limit);
+                self.push(b'n', limit);
+            }
+            byte if !(0x20..0x7F).contains(&byte) => {
+                let _ = write!(self, "\\x{:02x}", byte);
+            }
+            byte => self.push(byte, limit)
+        }
+    }
+
+    /// Writes the line `key=value`
+    fn field(&mut self, key: fmt::Arguments, value: fmt::Arguments)
+    {
+        let _ = self.write_fmt(key);
+        let _ = self.write_str("=");
+        let _ = Escaped(self).write_fmt(value);
+        let _ = self.write_str("\n");
+    }
+
+    /// Appends the checksum and the end marker, which always fit
+    fn finish(&mut self)
+    {
+        if self.truncated
+        {
+            let _ = self.write_str("truncated=1\n");
+        }
+        let checksum = crc32::crc32(&self.data[..self.length]);
+        let mut trailer = [0u8; TRAILER_SIZE];
+        let mut writer = TrailerWriter { buffer: &mut trailer, length: 0 };
+        let _ = write!(writer, "checksum={:08x}\n{}\n", checksum, END_MARKER);
+        let length = writer.length;
+        for byte in &trailer[..length]
+        {
+            self.push(*byte, DUMP_SIZE);
+        }
+    }
+
+    fn as_str(&self) -> &str
+    {
+        // Everything but ASCII is escaped
+        core::str::from_utf8(&self.data[..self.length]).unwrap_or("")
+    }
+}
+
+impl Write for Dump
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes()
+        {
+            self.push(byte, DUMP_SIZE - TRAILER_SIZE);
+        }
+        Ok(())
+    }
+}
+
+/// Writes a value into the dump, escaping it
+struct Escaped<'a>(&'a mut Dump);
+
+impl Write for Escaped<'_>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes()
+        {
+            self.0.push_escaped(byte);
+        }
+        Ok(())
+    }
+}
+
+struct TrailerWriter<'a>
+{
+    buffer: &'a mut [u8],
+    length: usize
+}
+
+impl Write for TrailerWriter<'_>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        let end = self.length + s.len();
+        if end > self.buffer.len()
+        {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.length..end].copy_from_slice(s.as_bytes());
+        self.length = end;
+        Ok(())
+    }
+}
+
+static DUMP: Mutex<Dump> = Mutex::new(Dump::new());
+
+/// A partition crash dumps are written to: its name, the device and its sector size
+type DumpDevice = (String, Arc<dyn BlockDevice>, usize);
+
+/// The partition crash dumps are written to
+static DUMP_DEVICE: Mutex<Option<DumpDevice>> = Mutex::new(None);
+
+/// The dump found on the crash dump partition at boot, left by the previous crash
+static PREVIOUS_DUMP: Mutex<Option<String>> = Mutex::new(None);
+
+/// Writes a crash dump for the exception of `stack_frame` to the serial port and the crash dump partition,
+/// then halts every processor. Must be inlined in the exception handler, whose frame holds the frame pointer
+/// of the interrupted function.
+#[inline(always)]
+pub fn exception(vector: u8, name: &'static str, error_code: Option<u64>, stack_frame: &InterruptStackFrame) -> !
+{
+    let registers = Registers {
+        rip: stack_frame.instruction_pointer.as_u64(),
+        rsp: stack_frame.stack_pointer.as_u64(),
+        rbp: unsafe { *(backtrace::frame_pointer() as *const u64) },
+        rflags: stack_frame.cpu_flags,
+        cs: stack_frame.code_segment,
+        ss: stack_frame.stack_segment
+    };
+    crash(Cause::Exception { vector, name, error_code }, &registers, true)
+}
+
+/// Writes a crash dump for the exception whose registers were saved in `frame` by its entry, then halts every
+/// processor
+pub fn trap(vector: u8, name: &'static str, frame: &TrapFrame) -> !
+{
+    let registers = Registers {
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rbp: frame.rbp,
+        rflags: frame.rflags,
+        cs: frame.cs,
+        ss: frame.ss
+    };
+    crash(Cause::Exception { vector, name, error_code: None }, &registers, true)
+}
+
+/// Writes a crash dump for the panic described by `info`, then halts every processor
+#[inline(never)]
+pub fn panic(info: &PanicInfo) -> !
+{
+    let (rip, rsp): (u64, u64);
+    unsafe { asm!("lea {}, [rip]", "mov {}, rsp", out(reg) rip, out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let registers = Registers {
+        rip,
+        rsp,
+        rbp: backtrace::frame_pointer(),
+        rflags: rflags::read_raw(),
+        cs: CS::get_reg().0 as u64,
+        ss: SS::get_reg().0 as u64
+    };
+    crash(Cause::Panic(info), &registers, false)
+}
+
+fn crash(cause: Cause, registers: &Registers, faulting_frame: bool) -> !
+{
+    // A crash while another processor, or this one, is already dumping is not dumped
+    if !interrupts::stop_other_cpus()
+    {
+        interrupts::halt_all_cpus();
+    }
+    // The stopped processors may hold the serial port or the log
+    serial::enter_panic_mode();
+
+    let mut dump = match DUMP.try_lock()
+    {
+        Some(dump) => dump,
+        None => interrupts::halt_all_cpus()
+    };
+    write_dump(&mut dump, &cause, registers, faulting_frame);
+
+    serial_print!("{}", dump.as_str());
+    save(&dump);
+    if TEST_MODE.get()
+    {
+        exit_qemu(QemuExitCode::Failure);
+    }
+    interrupts::halt_all_cpus();
+}
+
+fn write_dump(dump: &mut Dump, cause: &Cause, registers: &Registers, faulting_frame: bool)
+{
+    let _ = writeln!(dump, "{}", BEGIN_MARKER);
+    dump.field(format_args!("version"), format_args!("{}", FORMAT_VERSION));
+    dump.field(format_args!("uptime_ms"), format_args!("{}", time::uptime_ms()));
+    if apic::is_initialized()
+    {
+        dump.field(format_args!("cpu"), format_args!("{}", apic::id()));
+    }
+
+    match cause
+    {
+        Cause::Exception { vector, name, error_code } => {
+            dump.field(format_args!("cause"), format_args!("exception"));
+            dump.field(format_args!("vector"), format_args!("{}", vector));
+            dump.field(format_args!("exception"), format_args!("{}", name));
+            if let Some(error_code) = error_code
+            {
+                dump.field(format_args!("error_code"), format_args!("{:#x}", error_code));
+            }
+        }
+        Cause::Panic(info) => {
+            dump.field(format_args!("cause"), format_args!("panic"));
+            dump.field(format_args!("message"), format_args!("{}", info));
+            if let Some(location) = info.location()
+            {
+                dump.field(format_args!("location"), format_args!("{}:{}:{}", location.file(), location.line(), location.column()));
+            }
+        }
+    }
+
+    write_registers(dump, registers);
+    write_backtrace(dump, registers, faulting_frame);
+    write_log(dump);
+    write_memory_map(dump);
+    dump.finish();
+}
+
+fn write_registers(dump: &mut Dump, registers: &Registers)
+{
+    let (cr3_frame, cr3_flags) = Cr3::read_raw();
+    let values = [
+        ("rip", registers.rip),
+        ("rsp", registers.rsp),
+        ("rbp", registers.rbp),
+        ("rflags", registers.rflags),
+        ("cs", registers.cs),
+        ("ss", registers.ss),
+        ("cr0", Cr0::read_raw()),
+        ("cr2", Cr2::read_raw()),
+        ("cr3", cr3_frame.start_address().as_u64() | cr3_flags as u64),
+        ("cr4", Cr4::read_raw())
+    ];
+    for (name, value) in values
+    {
+        dump.field(format_args!("reg.{}", name), format_args!("{:#018x}", value));
+    }
+}
+
+/// One `frame.N` field per frame, with the symbol of the address when the kernel has a symbol table.
+/// The faulting instruction of an exception is frame 0.
+fn write_backtrace(dump: &mut Dump, registers: &Registers, faulting_frame: bool)
+{
+    let mut write_frame = |index: usize, address: u64, lookup_address: u64| {
+        match backtrace::resolve(lookup_address)
+        {
+            Some(symbol) => dump.field(format_args!("frame.{}", index),
+                format_args!("{:#018x} {}+{:#x}", address, symbol.name, symbol.offset + (address - lookup_address))),
+            None => dump.field(format_args!("frame.{}", index), format_args!("{:#018x}", address))
+        }
+    };
+
+    let first = if faulting_frame
+    {
+        write_frame(0, registers.rip, registers.rip);
+        1
+    }
+    else
+    {
+        0
+    };
+    // Return addresses follow the call, the call itself is in the function being resolved
+    let complete = backtrace::walk(registers.rbp, |index, address| write_frame(first + index, address, address - 1));
+    if !complete
+    {
+        dump.field(format_args!("frames_truncated"), format_args!("1"));
+    }
+}
+
+/// The last `LOG_RECORDS` records of the kernel log, as `log.N` fields from the oldest
+fn write_log(dump: &mut Dump)
+{
+    // The crash may have happened while logging
+    let ring = match LOG_RING.try_lock()
+    {
+        Some(ring) => ring,
+        None => return
+    };
+    let count = ring.records().count();
+    for (index, record) in ring.records().skip(count.saturating_sub(LOG_RECORDS)).enumerate()
+    {
+        dump.field(format_args!("log.{}", index), format_args!("{}", record));
+    }
+}
+
+/// The memory map given by the bootloader, as `memory.N` fields
+fn write_memory_map(dump: &mut Dump)
+{
+    unsafe {
+        if BOOT_INFO.boot_info_ptr.is_null()
+        {
+            return;
+        }
+        for (index, region) in BOOT_INFO.memory_regions.iter().enumerate()
+        {
+            dump.field(format_args!("memory.{}", index), format_args!("{:#x}-{:#x} {:?}", region.start, region.end, region.kind));
+        }
+    }
+}
+
+/// Writes the dump to the start of the crash dump partition, if there is one. The disk is polled, and the dump
+/// is not saved if the crashed code was using the block queue or the disk driver.
+fn save(dump: &Dump)
+{
+    let device = match DUMP_DEVICE.try_lock()
+    {
+        Some(device) => device,
+        None => return
+    };
+    let (name, device, sector_size) = match device.as_ref()
+    {
+        Some(device) => device,
+        None => return
+    };
+    // The disk drivers map their buffers through the page tables, which the crashed code may hold
+    if VMM.try_lock().is_none()
+    {
+        serial_println!("[CRASH] Page tables locked, not saving the dump to {}", name);
+        return;
+    }
+
+    let length = core::cmp::min((dump.length + sector_size - 1) / sector_size * sector_size, DUMP_SIZE);
+    match device.write_polled(0, &dump.data[..length], *sector_size)
+    {
+        Ok(()) => {
+            serial_println!("[CRASH] Dump saved to {}", name);
+        }
+        Err(BlockError::Busy) => {
+            serial_println!("[CRASH] {} is in use by the crashed code, not saving the dump", name);
+        }
+        Err(e) => {
+            serial_println!("[CRASH] Failed to save the dump to {}: {:?}", name, e);
+        }
+    }
+}
+
+fn is_dump_partition(info: &PartitionInfo) -> bool
+{
+    match &info.kind
+    {
+        PartitionKind::Mbr { system_id, .. } => *system_id == MBR_SYSTEM_ID,
+        PartitionKind::Gpt { name, .. } => name.eq_ignore_ascii_case(GPT_PARTITION_NAME)
+    }
+}
+
+/// Takes the partition `name` to save crash dumps if it is reserved for them: a GPT partition named
+/// `crashdump`, or a MBR partition of type 0xDA. A dump left on it by the previous crash is kept in memory,
+/// readable from `previous_dump`, and erased from the partition.
+pub fn probe_partition(name: &str, info: &PartitionInfo, device: Arc<dyn BlockDevice>)
+{
+    if !is_dump_partition(info)
+    {
+        return;
+    }
+    let sector_size = device.sector_size();
+    // A dump is written and read in whole sectors, which must not go past the dump buffer
+    if sector_size == 0 || DUMP_SIZE % sector_size != 0 || device.is_read_only()
+        || device.sector_count() * (sector_size as u64) < DUMP_SIZE as u64
+    {
+        warn!("[CRASH] {} is reserved for crash dumps but cannot hold them", name);
+        return;
+    }
+
+    let mut data = vec![0u8; DUMP_SIZE];
+    match device.read_sectors(0, &mut data)
+    {
+        Ok(()) if data.starts_with(BEGIN_MARKER.as_bytes()) => {
+            let length = data.iter().position(|&byte| byte == 0).unwrap_or(DUMP_SIZE);
+            warn!("[CRASH] Found the dump of a previous crash on {}, see /proc/crash", name);
+            *PREVIOUS_DUMP.lock() = Some(String::from_utf8_lossy(&data[..length]).into_owned());
+            if let Err(e) = device.write_sectors(0, &vec![0u8; sector_size])
+            {
+                warn!("[CRASH] Failed to erase the dump on {}: {:?}", name, e);
+            }
+        }
+        Ok(()) => {}
+        Err(e) => warn!("[CRASH] Failed to read {}: {:?}", name, e)
+    }
+
+    info!("[CRASH] Saving crash dumps to {}", name);
+    *DUMP_DEVICE.lock() = Some((String::from(name), device, sector_size));
+}
+
+/// The dump left by the crash before this boot, if any
+pub fn previous_dump() -> Option<String>
+{
+    PREVIOUS_DUMP.lock().clone()
+}
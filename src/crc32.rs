@@ -0,0 +1,38 @@
+/// CRC-32 as used by GPT, zlib and ethernet: reflected polynomial 0xEDB88320, initial value and final
+/// XOR of 0xFFFFFFFF
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256]
+{
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256
+    {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8
+        {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues a CRC computed over previous data, starting from `crc32(&[])` which is 0
+pub fn update(crc: u32, data: &[u8]) -> u32
+{
+    let mut crc = !crc;
+    for &byte in data
+    {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32
+{
+    update(0, data)
+}
@@ -0,0 +1,60 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// Work requested by an interrupt handler and run later by the kernel main loop.
+///
+/// Registering allocates, scheduling does not: an interrupt handler only sets a flag, so it never touches
+/// the heap or a lock held by the code it interrupted.
+pub struct Work
+{
+    pending: AtomicBool,
+    function: Box<dyn Fn() + Send + Sync>
+}
+
+impl Work
+{
+    /// Requests the work to run, several requests before it runs are merged into one
+    #[inline]
+    pub fn schedule(&self)
+    {
+        self.pending.store(true, Ordering::Release);
+    }
+}
+
+impl Debug for Work
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result
+    {
+        f.debug_struct("Work").field("pending", &self.pending.load(Ordering::Relaxed)).finish()
+    }
+}
+
+static WORK: Mutex<Vec<Arc<Work>>> = Mutex::new(Vec::new());
+
+pub fn register(function: impl Fn() + Send + Sync + 'static) -> Arc<Work>
+{
+    let work = Arc::new(Work {
+        pending: AtomicBool::new(false),
+        function: Box::new(function)
+    });
+    WORK.lock().push(work.clone());
+    work
+}
+
+/// Runs the scheduled work, must not be called from an interrupt handler
+pub fn run_pending()
+{
+    // The work may register more work, the list is not locked while it runs
+    let work = WORK.lock().clone();
+    for work in work.iter()
+    {
+        if work.pending.swap(false, Ordering::AcqRel)
+        {
+            (work.function)();
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use alloc::boxed::Box;
+use log::info;
+use crate::cmdline::Parameter;
 use crate::pci;
 use crate::acpi;
 
@@ -6,6 +8,19 @@ mod sata_controller_ahci;
 
 pub use sata_controller_ahci::SataControllerAhci;
 
+pub static SKIPPED_DRIVERS: Parameter<&str> = Parameter::new("skip_drivers", "", "comma-separated names of the drivers not to start, e.g. ahci");
+
+/// Whether the driver called `name` must not be started, logging it if so
+pub fn is_skipped(name: &str) -> bool
+{
+    let skipped = SKIPPED_DRIVERS.get().split(',').any(|skipped| skipped.trim() == name);
+    if skipped
+    {
+        info!("Driver {} skipped on the command line", name);
+    }
+    skipped
+}
+
 #[derive(Debug)]
 pub enum Driver
 {
@@ -0,0 +1,113 @@
+use alloc::string::String;
+
+pub const ATA_CMD_PACKET: u8 = 0xA0;
+pub const ATA_CMD_IDENTIFY_PACKET_DEVICE: u8 = 0xA1;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_READ_12: u8 = 0xA8;
+
+pub const SENSE_KEY_NOT_READY: u8 = 0x02;
+pub const SENSE_KEY_UNIT_ATTENTION: u8 = 0x06;
+
+/// Additional sense code reported by a drive without a disc
+pub const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3A;
+
+pub const PERIPHERAL_DEVICE_TYPE_CD_DVD: u8 = 0x05;
+
+/// Logical block size of CD and DVD media
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+
+pub const INQUIRY_LENGTH: usize = 36;
+pub const SENSE_LENGTH: usize = 18;
+pub const CAPACITY_LENGTH: usize = 8;
+
+/// A SCSI command descriptor block, ATAPI devices use the first 12 bytes
+pub type Packet = [u8; 16];
+
+pub fn test_unit_ready() -> Packet
+{
+    [SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+pub fn request_sense() -> Packet
+{
+    [SCSI_REQUEST_SENSE, 0, 0, 0, SENSE_LENGTH as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+pub fn inquiry() -> Packet
+{
+    [SCSI_INQUIRY, 0, 0, 0, INQUIRY_LENGTH as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+pub fn read_capacity() -> Packet
+{
+    [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+/// Reads `count` blocks at `lba`, with READ(10) when the count fits in 16 bits and READ(12) otherwise
+pub fn read(lba: u32, count: u32) -> Packet
+{
+    let lba = lba.to_be_bytes();
+    let mut packet = [0u8; 16];
+    packet[2..6].copy_from_slice(&lba);
+
+    if count <= u16::MAX as u32
+    {
+        packet[0] = SCSI_READ_10;
+        packet[7..9].copy_from_slice(&(count as u16).to_be_bytes());
+    }
+    else
+    {
+        packet[0] = SCSI_READ_12;
+        packet[6..10].copy_from_slice(&count.to_be_bytes());
+    }
+
+    packet
+}
+
+#[derive(Debug, Clone)]
+pub struct Inquiry
+{
+    pub device_type: u8,
+    pub removable: bool,
+    pub vendor: String,
+    pub product: String,
+    pub revision: String
+}
+
+impl Inquiry
+{
+    fn scsi_string(bytes: &[u8]) -> String
+    {
+        String::from(String::from_utf8_lossy(bytes).trim())
+    }
+
+    pub fn parse(data: &[u8; INQUIRY_LENGTH]) -> Self
+    {
+        Inquiry {
+            device_type: data[0] & 0x1F,
+            removable: data[1] & (1 << 7) != 0,
+            vendor: Self::scsi_string(&data[8..16]),
+            product: Self::scsi_string(&data[16..32]),
+            revision: Self::scsi_string(&data[32..36])
+        }
+    }
+}
+
+/// Parses READ CAPACITY(10) data into a block count and a block size
+pub fn parse_capacity(data: &[u8; CAPACITY_LENGTH]) -> (u64, usize)
+{
+    let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    let block_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    (last_lba as u64 + 1, block_size as usize)
+}
+
+/// Parses fixed format sense data into its sense key, additional sense code and qualifier
+pub fn parse_sense(data: &[u8; SENSE_LENGTH]) -> (u8, u8, u8)
+{
+    (data[2] & 0xF, data[12], data[13])
+}
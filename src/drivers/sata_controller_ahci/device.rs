@@ -0,0 +1,236 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::block::{check_range, BlockDevice, BlockError};
+use crate::block::queue::{BlockRequest, Operation, QueueBackend, Submission};
+use crate::deferred::Work;
+use crate::drivers::sata_controller_ahci::atapi;
+use crate::drivers::sata_controller_ahci::port::*;
+
+/// A disk or an optical drive on an AHCI port, attached directly or behind a port multiplier
+#[derive(Debug, Clone)]
+pub struct AhciDevice
+{
+    port: Arc<AhciPort>,
+    pmp: u8,
+    /// Ranges of the TRIM commands in flight, by slot
+    trim_ranges: Arc<Mutex<BTreeMap<usize, Box<TrimRanges>>>>
+}
+
+impl AhciDevice
+{
+    pub fn new(port: Arc<AhciPort>, pmp: u8) -> Self
+    {
+        AhciDevice {
+            port,
+            pmp,
+            trim_ranges: Arc::new(Mutex::new(BTreeMap::new()))
+        }
+    }
+
+    #[inline]
+    pub fn port(&self) -> &Arc<AhciPort>
+    {
+        &self.port
+    }
+
+    #[inline]
+    pub fn pmp(&self) -> u8
+    {
+        self.pmp
+    }
+
+    /// Port multiplier port of the device, `None` if it is attached directly
+    #[inline]
+    pub fn multiplier_port(&self) -> Option<u8>
+    {
+        if self.port.has_multiplier() { Some(self.pmp) } else { None }
+    }
+
+    /// The identity of the device, `None` once it has been detached
+    pub fn identity(&self) -> Option<AtaIdentity>
+    {
+        self.port.identity(self.pmp)
+    }
+}
+
+impl core::fmt::Display for AhciDevice
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result
+    {
+        match self.multiplier_port()
+        {
+            Some(pmp) => write!(f, "port {}.{}", self.port.number(), pmp),
+            None => write!(f, "port {}", self.port.number())
+        }
+    }
+}
+
+impl From<AhciError> for BlockError
+{
+    fn from(error: AhciError) -> Self
+    {
+        match error
+        {
+            AhciError::NoDevice => BlockError::NoDevice,
+            AhciError::InvalidBuffer => BlockError::InvalidBuffer,
+            AhciError::WriteProtected => BlockError::ReadOnly,
+            AhciError::Unsupported => BlockError::Unsupported,
+            AhciError::Busy => BlockError::Busy,
+            AhciError::TaskFile { error, .. } if error & ATA_ERROR_UNCORRECTABLE != 0 => BlockError::Medium,
+            AhciError::TaskFile { error, .. } if error & ATA_ERROR_ID_NOT_FOUND != 0 => BlockError::OutOfRange,
+            AhciError::TaskFile { error, .. } if error & ATA_ERROR_ABORTED != 0 => BlockError::Rejected,
+            error => BlockError::Io(format!("{:?}", error))
+        }
+    }
+}
+
+impl BlockDevice for AhciDevice
+{
+    fn sector_size(&self) -> usize
+    {
+        self.identity().map_or(0, |identity| identity.sector_size)
+    }
+
+    fn sector_count(&self) -> u64
+    {
+        self.identity().map_or(0, |identity| identity.sector_count)
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        check_range(self, lba, buffer.len())?;
+        Ok(self.port.read_sectors(self.pmp, lba, buffer)?)
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        check_range(self, lba, buffer.len())?;
+        Ok(self.port.write_sectors(self.pmp, lba, buffer)?)
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        Ok(self.port.flush(self.pmp)?)
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> Result<(), BlockError>
+    {
+        match lba.checked_add(count)
+        {
+            Some(end) if end <= self.sector_count() => Ok(self.port.discard(self.pmp, lba, count)?),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.identity().map_or(true, |identity| identity.atapi)
+    }
+
+    fn write_polled(&self, lba: u64, buffer: &[u8], sector_size: usize) -> Result<(), BlockError>
+    {
+        Ok(self.port.write_polled(self.pmp, lba, buffer, sector_size)?)
+    }
+}
+
+/// Requests are issued into the free command slots of the port and collected with `AhciPort::poll`.
+/// Unlike synchronous transfers, failed requests are not retried.
+impl QueueBackend for AhciDevice
+{
+    fn submit(&self, request: &BlockRequest) -> Submission
+    {
+        let identity = match self.identity()
+        {
+            Some(identity) => identity,
+            None => return Submission::Completed(Err(BlockError::NoDevice))
+        };
+
+        let mut trim_ranges = None;
+        let command = match request.operation
+        {
+            Operation::Read if identity.atapi => {
+                Command::packet(self.pmp, atapi::read(request.lba as u32, request.count as u32), core::ptr::null_mut(), 0)
+            }
+            Operation::Write | Operation::Discard if identity.atapi => return Submission::Completed(Err(BlockError::ReadOnly)),
+            Operation::Read | Operation::Write => {
+                let write = request.operation == Operation::Write;
+                Command {
+                    lba: request.lba,
+                    count: request.count as u16,
+                    write,
+                    ..Command::non_data(self.pmp, if write { ATA_CMD_WRITE_DMA_EXT } else { ATA_CMD_READ_DMA_EXT })
+                }
+            }
+            // Optical drives are only read, there is nothing to flush
+            Operation::Flush if identity.atapi => return Submission::Completed(Ok(())),
+            Operation::Flush => Command::non_data(self.pmp, ATA_CMD_FLUSH_CACHE_EXT),
+            Operation::Discard if !identity.trim => return Submission::Completed(Err(BlockError::Unsupported)),
+            Operation::Discard => {
+                let mut ranges = Box::new([0u64; TRIM_RANGES_PER_BLOCK]);
+                let (command, covered) = Command::trim(self.pmp, &mut ranges, request.lba, request.count);
+                // The queue splits discards at max_discard_sectors, a longer one would be trimmed in part
+                if covered != request.count
+                {
+                    return Submission::Completed(Err(BlockError::Io(format!("discard of {} sectors exceeds the {} a TRIM covers",
+                        request.count, covered))));
+                }
+                trim_ranges = Some(ranges);
+                command
+            }
+        };
+
+        let segments: Vec<(*mut u8, usize)> = if trim_ranges.is_some()
+        {
+            alloc::vec![(command.buffer, command.length)]
+        }
+        else
+        {
+            request.segments.iter().map(|segment| (segment.buffer, segment.length)).collect()
+        };
+
+        match self.port.issue_vectored(&command, &segments)
+        {
+            Ok(slot) => {
+                if let Some(ranges) = trim_ranges
+                {
+                    self.trim_ranges.lock().insert(slot, ranges);
+                }
+                Submission::Issued(slot)
+            }
+            Err(AhciError::NoFreeSlot | AhciError::Busy) => Submission::Busy,
+            Err(e) => Submission::Completed(Err(e.into()))
+        }
+    }
+
+    fn poll(&self, tag: usize) -> Option<Result<(), BlockError>>
+    {
+        let result = self.port.poll(tag)?;
+        self.trim_ranges.lock().remove(&tag);
+        Some(result.map_err(BlockError::from))
+    }
+
+    fn set_completion_work(&self, work: Arc<Work>)
+    {
+        self.port.set_completion_work(self.pmp, Some(work));
+    }
+
+    fn max_transfer_size(&self) -> usize
+    {
+        MAX_TRANSFER_SIZE
+    }
+
+    fn max_segments(&self) -> usize
+    {
+        MAX_SEGMENTS
+    }
+
+    fn max_discard_sectors(&self) -> u64
+    {
+        MAX_TRIM_SECTORS
+    }
+}
@@ -0,0 +1,240 @@
+pub enum FrameInformationStructure
+{
+    HostToDevice(HostToDeviceFIS),
+    DeviceToHost(DeviceToHostFIS),
+    DmaSetup(DmaSetupFIS),
+    Data(DataFIS),
+    PioSetup(PioSetupFIS),
+}
+
+impl FrameInformationStructure
+{
+    pub fn new(fis_type: u8) -> FrameInformationStructure
+    {
+        match fis_type
+        {
+            0x27 => FrameInformationStructure::HostToDevice(HostToDeviceFIS::new()),
+            0x34 => FrameInformationStructure::DeviceToHost(DeviceToHostFIS::new()),
+            0x41 => FrameInformationStructure::DmaSetup(DmaSetupFIS::new()),
+            0x46 => FrameInformationStructure::Data(DataFIS::new()),
+            0x5F => FrameInformationStructure::PioSetup(PioSetupFIS::new()),
+            _ => panic!("[SATA] Unsupported FIS type: {}", fis_type)
+        }
+    }
+}
+
+#[repr(C)]
+pub struct HostToDeviceFIS
+{
+    pub fis_type: u8,
+    pub pm_port_c: u8,
+    pub command: u8,
+    pub feature_low: u8,
+
+    pub lba_low: u8,
+    pub lba_mid: u8,
+    pub lba_high: u8,
+    pub device: u8,
+
+    pub lba_low_exp: u8,
+    pub lba_mid_exp: u8,
+    pub lba_high_exp: u8,
+    pub feature_high: u8,
+
+    pub count_low: u8,
+    pub count_high: u8,
+    pub icc: u8,
+    pub control: u8,
+
+    pub reserved: [u8; 4],
+}
+
+impl HostToDeviceFIS
+{
+    pub fn new() -> HostToDeviceFIS
+    {
+        HostToDeviceFIS {
+            fis_type: 0x27,
+            pm_port_c: 0x00,
+            command: 0x00,
+            feature_low: 0x00,
+            lba_low: 0x00,
+            lba_mid: 0x00,
+            lba_high: 0x00,
+            device: 0x00,
+            lba_low_exp: 0x00,
+            lba_mid_exp: 0x00,
+            lba_high_exp: 0x00,
+            feature_high: 0x00,
+            count_low: 0x00,
+            count_high: 0x00,
+            icc: 0x00,
+            control: 0x00,
+            reserved: [0x00; 4],
+        }
+    }
+}
+
+#[repr(C)]
+pub struct DeviceToHostFIS
+{
+    pub fis_type: u8,
+    pub pm_port_i: u8,
+    pub status: u8,
+    pub error: u8,
+
+    pub lba0: u8,
+    pub lba1: u8,
+    pub lba2: u8,
+    pub device: u8,
+
+    pub lba5: u8,
+    pub lba3: u8,
+    pub lba4: u8,
+    pub reserved2: u8,
+
+    pub count_low: u8,
+    pub count_high: u8,
+    pub reserved3: [u8; 2],
+
+    pub reserved4: [u8; 4]
+}
+
+impl DeviceToHostFIS
+{
+    pub fn new() -> DeviceToHostFIS
+    {
+        DeviceToHostFIS {
+            fis_type: 0x34,
+            pm_port_i: 0x00,
+            status: 0x00,
+            error: 0x00,
+            lba0: 0x00,
+            lba1: 0x00,
+            lba2: 0x00,
+            device: 0x00,
+            lba5: 0x00,
+            lba3: 0x00,
+            lba4: 0x00,
+            reserved2: 0x00,
+            count_low: 0x00,
+            count_high: 0x00,
+            reserved3: [0x00; 2],
+            reserved4: [0x00; 4],
+        }
+    }
+}
+
+#[repr(C)]
+pub struct DataFIS
+{
+    pub fis_type: u8,
+    pub pm_port: u8,
+    pub reserved1: u8,
+    pub reserved2: u8,
+
+    pub data: [u8; 0],
+}
+
+impl DataFIS
+{
+    pub fn new() -> DataFIS
+    {
+        DataFIS {
+            fis_type: 0x46,
+            pm_port: 0x00,
+            reserved1: 0x00,
+            reserved2: 0x00,
+            data: [0x00; 0],
+        }
+    }
+}
+
+#[repr(C)]
+pub struct PioSetupFIS
+{
+    pub fis_type: u8,
+    pub pm_port_d_i: u8,
+    pub status: u8,
+    pub error: u8,
+
+    pub lba0: u8,
+    pub lba1: u8,
+    pub lba2: u8,
+    pub device: u8,
+
+    pub lba3: u8,
+    pub lba4: u8,
+    pub lba5: u8,
+    pub reserved2: u8,
+
+    pub count_low: u8,
+    pub count_high: u8,
+    pub reserved3: u8,
+    pub e_status: u8,
+
+    pub tc: u16,
+    pub reserved4: [u8; 2]
+}
+
+impl PioSetupFIS
+{
+    pub fn new() -> PioSetupFIS
+    {
+        PioSetupFIS {
+            fis_type: 0x5F,
+            pm_port_d_i: 0x00,
+            status: 0x00,
+            error: 0x00,
+            lba0: 0x00,
+            lba1: 0x00,
+            lba2: 0x00,
+            device: 0x00,
+            lba3: 0x00,
+            lba4: 0x00,
+            lba5: 0x00,
+            reserved2: 0x00,
+            count_low: 0x00,
+            count_high: 0x00,
+            reserved3: 0x00,
+            e_status: 0x00,
+            tc: 0x00,
+            reserved4: [0x00; 2],
+        }
+    }
+}
+
+#[repr(C)]
+pub struct DmaSetupFIS
+{
+    pub fis_type: u8,
+    pub pm_port_d_i_a: u8,
+    pub reserved1: [u8; 2],
+
+    pub dma_buffer_id: u64,
+
+    pub reserved3: [u32; 2],
+
+    pub dma_buffer_offset: u32,
+
+    pub transfer_count: u32,
+
+    pub reserved4: u32,
+}
+
+impl DmaSetupFIS
+{
+    pub fn new() -> DmaSetupFIS
+    {
+        DmaSetupFIS {
+            fis_type: 0x41,
+            pm_port_d_i_a: 0x00,
+            reserved1: [0x00; 2],
+            dma_buffer_id: 0x00,
+            reserved3: [0x00; 2],
+            dma_buffer_offset: 0x00,
+            transfer_count: 0x00,
+            reserved4: 0x00,
+        }
+    }
+}
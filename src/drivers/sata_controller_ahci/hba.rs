@@ -0,0 +1,209 @@
+use core::cell::UnsafeCell;
+use core::fmt::{Debug, Formatter};
+
+/// A 32 bit memory mapped register of the HBA. Every access is volatile, and writes only need a shared
+/// reference so that the interrupt handler and the command path can both reach the same registers.
+#[repr(transparent)]
+pub struct Register(UnsafeCell<u32>);
+
+unsafe impl Sync for Register {}
+
+impl Register
+{
+    #[inline]
+    pub fn read(&self) -> u32
+    {
+        unsafe { self.0.get().read_volatile() }
+    }
+
+    #[inline]
+    pub fn write(&self, value: u32)
+    {
+        unsafe { self.0.get().write_volatile(value) }
+    }
+
+    #[inline]
+    pub fn set_bits(&self, mask: u32)
+    {
+        self.write(self.read() | mask);
+    }
+
+    #[inline]
+    pub fn clear_bits(&self, mask: u32)
+    {
+        self.write(self.read() & !mask);
+    }
+}
+
+impl Debug for Register
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result
+    {
+        write!(f, "{:#010x}", self.read())
+    }
+}
+
+/*
+ * Host capabilities (CAP) bits
+ */
+pub const CAP_FIS_BASED_SWITCHING: u32 = 1 << 16;
+pub const CAP_PORT_MULTIPLIER: u32 = 1 << 17;
+pub const CAP_COMMAND_LIST_OVERRIDE: u32 = 1 << 24;
+
+/*
+ * Global HBA control (GHC) bits
+ */
+pub const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+pub const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+/*
+ * Port command and status (PxCMD) bits
+ */
+pub const PORT_CMD_START: u32 = 1 << 0;
+pub const PORT_CMD_SPIN_UP_DEVICE: u32 = 1 << 1;
+pub const PORT_CMD_COMMAND_LIST_OVERRIDE: u32 = 1 << 3;
+pub const PORT_CMD_FIS_RECEIVE_ENABLE: u32 = 1 << 4;
+pub const PORT_CMD_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+pub const PORT_CMD_COMMAND_LIST_RUNNING: u32 = 1 << 15;
+pub const PORT_CMD_PORT_MULTIPLIER_ATTACHED: u32 = 1 << 17;
+pub const PORT_CMD_FIS_BASED_SWITCHING_CAPABLE: u32 = 1 << 22;
+
+/*
+ * Port interrupt status / enable (PxIS / PxIE) bits
+ */
+pub const PORT_IS_DEVICE_TO_HOST: u32 = 1 << 0;
+pub const PORT_IS_PIO_SETUP: u32 = 1 << 1;
+pub const PORT_IS_DMA_SETUP: u32 = 1 << 2;
+pub const PORT_IS_SET_DEVICE_BITS: u32 = 1 << 3;
+pub const PORT_IS_UNKNOWN_FIS: u32 = 1 << 4;
+pub const PORT_IS_DESCRIPTOR_PROCESSED: u32 = 1 << 5;
+pub const PORT_IS_PORT_CONNECT_CHANGE: u32 = 1 << 6;
+pub const PORT_IS_DEVICE_INTERLOCK: u32 = 1 << 7;
+pub const PORT_IS_PHY_READY_CHANGE: u32 = 1 << 22;
+pub const PORT_IS_OVERFLOW: u32 = 1 << 24;
+pub const PORT_IS_INTERFACE_NON_FATAL: u32 = 1 << 26;
+pub const PORT_IS_INTERFACE_FATAL: u32 = 1 << 27;
+pub const PORT_IS_HOST_BUS_DATA_ERROR: u32 = 1 << 28;
+pub const PORT_IS_HOST_BUS_FATAL: u32 = 1 << 29;
+pub const PORT_IS_TASK_FILE_ERROR: u32 = 1 << 30;
+
+/// Interrupts signalling a command completion
+pub const PORT_IS_COMPLETION: u32 = PORT_IS_DEVICE_TO_HOST | PORT_IS_PIO_SETUP | PORT_IS_DMA_SETUP
+    | PORT_IS_SET_DEVICE_BITS | PORT_IS_DESCRIPTOR_PROCESSED;
+
+/// Interrupts after which the command engine stops and outstanding commands must be failed
+pub const PORT_IS_FATAL: u32 = PORT_IS_TASK_FILE_ERROR | PORT_IS_HOST_BUS_FATAL | PORT_IS_HOST_BUS_DATA_ERROR
+    | PORT_IS_INTERFACE_FATAL | PORT_IS_OVERFLOW | PORT_IS_UNKNOWN_FIS;
+
+/// Interrupts signalling that a device may have been connected or removed
+pub const PORT_IS_HOTPLUG: u32 = PORT_IS_PORT_CONNECT_CHANGE | PORT_IS_PHY_READY_CHANGE | PORT_IS_DEVICE_INTERLOCK;
+
+/*
+ * SATA error (PxSERR) diagnostics bits, PxIS.PRCS and PxIS.PCS stay set until they are cleared
+ */
+pub const SERR_DIAG_PHY_READY_CHANGE: u32 = 1 << 16;
+pub const SERR_DIAG_EXCHANGED: u32 = 1 << 26;
+
+/*
+ * FIS-based switching control (PxFBS) bits, the device with an error is in bits 16..20
+ */
+pub const FBS_ENABLE: u32 = 1 << 0;
+pub const FBS_SINGLE_DEVICE_ERROR: u32 = 1 << 2;
+
+/*
+ * Task file data (PxTFD) bits
+ */
+pub const TFD_STATUS_DRQ: u32 = 1 << 3;
+pub const TFD_STATUS_BUSY: u32 = 1 << 7;
+
+/// Whether a SStatus value, from PxSSTS or from a port multiplier port, reports an established link
+pub fn link_up(sata_status: u32) -> bool
+{
+    sata_status & 0xF == 3 && (sata_status >> 8) & 0xF == 1
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct HbaMemory
+{
+    pub host_capabilities: Register,
+    pub global_host_control: Register,
+    pub interrupt_status: Register,
+    pub port_implemented: Register,
+    pub version: Register,
+    pub command_completion_coalescing_control: Register,
+    pub command_completion_coalescing_ports: Register,
+    pub enclosure_management_location: Register,
+    pub enclosure_management_control: Register,
+    pub host_capabilities_extended: Register,
+    pub handoff_control_status: Register,
+    reserved: [u8; 0xA0 - 0x2C],
+    vendor_specific: [u8; 0x100 - 0xA0],
+    pub hba_ports: [HbaPort; 32]
+}
+
+#[repr(u32)]
+#[derive(Copy, Debug, Clone, PartialEq)]
+pub enum PortSignature
+{
+    Ata = 0x00000101,
+    Atapi = 0xEB140101,
+    Semb = 0xC33C0101,
+    PortMultiplier = 0x96690101,
+}
+
+impl TryFrom<u32> for PortSignature
+{
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error>
+    {
+        match value
+        {
+            0x00000101 => Ok(PortSignature::Ata),
+            0xEB140101 => Ok(PortSignature::Atapi),
+            0xC33C0101 => Ok(PortSignature::Semb),
+            0x96690101 => Ok(PortSignature::PortMultiplier),
+            _ => Err(())
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct HbaPort
+{
+    pub command_list_base_address_low: Register,
+    pub command_list_base_address_high: Register,
+    pub fis_base_address_low: Register,
+    pub fis_base_address_high: Register,
+    pub interrupt_status: Register,
+    pub interrupt_enable: Register,
+    pub command_status: Register,
+    _reserved0: Register,
+    pub task_file_data: Register,
+    pub signature: Register,
+    pub sata_status: Register,
+    pub sata_control: Register,
+    pub sata_error: Register,
+    pub sata_active: Register,
+    pub command_issue: Register,
+    pub sata_notification: Register,
+    pub fis_based_control_switch: Register,
+    _reserved1: [Register; 11],
+    vendor_specific: [Register; 4]
+}
+
+impl HbaPort
+{
+    /// A device is present and the phy communication is established
+    pub fn device_present(&self) -> bool
+    {
+        link_up(self.sata_status.read())
+    }
+
+    pub fn signature(&self) -> Option<PortSignature>
+    {
+        PortSignature::try_from(self.signature.read()).ok()
+    }
+}
@@ -0,0 +1,326 @@
+mod atapi;
+mod device;
+mod fis;
+mod hba;
+mod port;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::{apic, deferred};
+use crate::block::{self, BLOCK_DEVICES};
+use crate::block::queue::RequestQueue;
+use crate::interrupts::{register_dynamic_handler, register_legacy_handler, InterruptHandler};
+use crate::pci::{Bar, BistError, PciDevice, PciDriver, PciHandler, StandardHeader};
+use crate::{PCI_HANDLER, VMM};
+use crate::drivers::sata_controller_ahci::hba::*;
+
+pub use device::AhciDevice;
+pub use hba::PortSignature;
+pub use port::{AhciError, AhciPort, AtaIdentity};
+
+#[derive(Debug, Copy, Clone)]
+pub enum ControllerInterrupt
+{
+    Msi(u8),
+    Legacy(u8)
+}
+
+/// Block device names of the attached devices, shared with the hot-plug work
+type Disks = Arc<Mutex<Vec<(AhciDevice, String)>>>;
+
+/// Prefix of the block device names of AHCI devices
+const BLOCK_DEVICE_PREFIX: &str = "sata";
+
+#[derive(Debug)]
+pub struct SataControllerAhci
+{
+    pci_device: PciDevice,
+    header: StandardHeader,
+    abar: &'static HbaMemory,
+    ports: Vec<Arc<AhciPort>>,
+    interrupt: Option<ControllerInterrupt>,
+    disks: Disks
+}
+
+impl PciDriver for SataControllerAhci
+{
+    fn init(device: PciDevice) -> Result<Self, String>
+    {
+        // Make self test (BIST)
+        if let Err(e) = device.get_header().do_bist(PCI_HANDLER.lock().as_ref().unwrap())
+        {
+            if e != BistError::NotSupported
+            {
+                return Err(format!("SATA controller self test failed: {:?}", e));
+            }
+        }
+
+        let standard_header = StandardHeader::new(device.get_address());
+
+        let bar = PCI_HANDLER.lock().as_ref().unwrap().bars(device.get_address()).iter()
+            .find(|(slot, _)| *slot == 5)
+            .map(|(_, bar)| *bar);
+        match bar
+        {
+            Some(Bar::Memory { base, size: _, prefetchable}) => {
+                device.get_header().enable_bus_mastering(PCI_HANDLER.lock().as_ref().unwrap());
+
+                let mut controller = SataControllerAhci {
+                    pci_device: device,
+                    header: standard_header,
+                    abar: unsafe {
+                        &*(VMM.lock().map_region(
+                            base,
+                            core::mem::size_of::<HbaMemory>() as u64,
+                            PageTableFlags::PRESENT | PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE | if prefetchable {PageTableFlags::WRITE_THROUGH} else {PageTableFlags::WRITABLE}
+                        ).map_err(|e| format!("Failed to map HBA memory: {:?}", e))?.as_ptr() as *const HbaMemory)
+                    },
+                    ports: Vec::new(),
+                    interrupt: None,
+                    disks: Arc::new(Mutex::new(Vec::new()))
+                };
+
+                controller.enable_ahci();
+                controller.enumerate_ports();
+                controller.setup_interrupts();
+                controller.attach_ports();
+
+                Ok(controller)
+            }
+            _ => {
+                Err(String::from("Failed to get ABAR"))
+            }
+        }
+    }
+}
+
+impl SataControllerAhci
+{
+    /// Takes the controller from the firmware if needed and switches it to AHCI mode
+    fn enable_ahci(&mut self)
+    {
+        // BIOS/OS handoff is supported: request ownership and wait for the BIOS to release it
+        if self.abar.host_capabilities_extended.read().get_bit(0)
+        {
+            self.abar.handoff_control_status.set_bits(1 << 1);
+            for _ in 0..1_000_000
+            {
+                if !self.abar.handoff_control_status.read().get_bit(0)
+                {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        self.abar.global_host_control.set_bits(GHC_AHCI_ENABLE);
+        self.abar.global_host_control.clear_bits(GHC_INTERRUPT_ENABLE);
+    }
+
+    /// Sets up every implemented port, including empty ones so that devices can be plugged in later
+    fn enumerate_ports(&mut self)
+    {
+        let port_implemented = self.abar.port_implemented.read();
+        let capabilities = self.abar.host_capabilities.read();
+        let slot_count = capabilities.get_bits(8..13) as usize + 1;
+
+        for i in 0..32u8
+        {
+            if !port_implemented.get_bit(i as usize)
+            {
+                continue;
+            }
+
+            let registers = &self.abar.hba_ports[i as usize];
+            match AhciPort::new(i, registers, capabilities, slot_count)
+            {
+                Ok(port) => self.ports.push(Arc::new(port)),
+                Err(e) => warn!("[SATA] Failed to initialize port {}: {}", i, e)
+            }
+        }
+    }
+
+    /// Routes the controller interrupt to `handle_interrupt`, through MSI if the function supports it or
+    /// through its legacy PIC line otherwise. Ports fall back to polling if neither is available, and
+    /// hot-plug is then not detected.
+    fn setup_interrupts(&mut self)
+    {
+        let abar = self.abar;
+        let ports = self.ports.clone();
+        let handler: InterruptHandler = Arc::new(move || Self::handle_interrupt(abar, &ports));
+
+        let pci_handler = PCI_HANDLER.lock();
+        let pci_handler = pci_handler.as_ref().unwrap();
+
+        self.interrupt = Self::setup_msi(&self.pci_device, pci_handler, handler.clone())
+            .or_else(|| Self::setup_legacy_interrupt(&self.pci_device, pci_handler, handler));
+
+        match self.interrupt
+        {
+            Some(interrupt) => {
+                info!("[SATA] Using interrupt {:?}", interrupt);
+
+                let ports = self.ports.clone();
+                let disks = self.disks.clone();
+                let hotplug_work = deferred::register(move || Self::service_hotplug(&ports, &disks));
+
+                self.abar.interrupt_status.write(u32::MAX);
+                for port in self.ports.iter()
+                {
+                    port.enable_interrupts(hotplug_work.clone());
+                }
+                self.abar.global_host_control.set_bits(GHC_INTERRUPT_ENABLE);
+            }
+            None => warn!("[SATA] No usable interrupt, falling back to polling")
+        }
+    }
+
+    fn setup_msi(device: &PciDevice, pci_handler: &PciHandler, handler: InterruptHandler) -> Option<ControllerInterrupt>
+    {
+        if !apic::is_initialized()
+        {
+            return None;
+        }
+
+        let header = device.get_header();
+        let msi = header.capabilities(pci_handler).iter().find_map(|capability| capability.as_msi())?;
+        let vector = register_dynamic_handler(handler)?;
+
+        msi.enable(pci_handler, apic::msi_address(), vector as u16);
+        header.set_interrupt_disable(pci_handler, true);
+
+        Some(ControllerInterrupt::Msi(vector))
+    }
+
+    fn setup_legacy_interrupt(device: &PciDevice, pci_handler: &PciHandler, handler: InterruptHandler) -> Option<ControllerInterrupt>
+    {
+        let header = device.get_header();
+        let line = header.interrupt_line(pci_handler);
+        if header.interrupt_pin(pci_handler) == 0 || !register_legacy_handler(line, handler)
+        {
+            return None;
+        }
+
+        header.set_interrupt_disable(pci_handler, false);
+
+        Some(ControllerInterrupt::Legacy(line))
+    }
+
+    fn attach_ports(&mut self)
+    {
+        for port in self.ports.iter()
+        {
+            if port.registers().device_present()
+            {
+                Self::attach_port(port, &self.disks);
+            }
+        }
+    }
+
+    fn attach_port(port: &Arc<AhciPort>, disks: &Disks)
+    {
+        match port.attach()
+        {
+            Ok(devices) => Self::register_devices(port, &devices, disks),
+            Err(e) => {
+                warn!("[SATA] {}", e);
+                port.detach();
+            }
+        }
+    }
+
+    /// Registers the devices at `devices` behind `port`, each through a request queue dispatching into the
+    /// command slots of the port
+    fn register_devices(port: &Arc<AhciPort>, devices: &[u8], disks: &Disks)
+    {
+        for &pmp in devices
+        {
+            let device = AhciDevice::new(port.clone(), pmp);
+            if let Some(identity) = device.identity()
+            {
+                let name = block::register_disk(BLOCK_DEVICE_PREFIX, RequestQueue::new(Arc::new(device.clone())));
+                info!("[SATA] Attached {} as {}: {} ({} sectors of {} bytes)", device, name, identity.model, identity.sector_count, identity.sector_size);
+                disks.lock().push((device, name));
+            }
+        }
+    }
+
+    /// Unregisters the devices of `port`, or only the one at `pmp` behind its multiplier
+    fn unregister_devices(port: &Arc<AhciPort>, pmp: Option<u8>, disks: &Disks)
+    {
+        disks.lock().retain(|(device, name)| {
+            if !Arc::ptr_eq(device.port(), port) || pmp.map_or(false, |pmp| pmp != device.pmp())
+            {
+                return true;
+            }
+
+            info!("[SATA] Detached {} ({})", device, name);
+            BLOCK_DEVICES.lock().unregister(name);
+            false
+        });
+    }
+
+    /// Attaches and detaches devices after the hot-plug events recorded by the ports. Runs as deferred work,
+    /// since bringing up a device waits for it.
+    fn service_hotplug(ports: &[Arc<AhciPort>], disks: &Disks)
+    {
+        for port in ports
+        {
+            let events = port.take_hotplug_events();
+            if events == 0
+            {
+                continue;
+            }
+
+            if events & PORT_IS_HOTPLUG != 0
+            {
+                // The link went down, or came up with a possibly different device: start over
+                if port.is_attached()
+                {
+                    Self::unregister_devices(port, None, disks);
+                    port.detach();
+                }
+                if port.registers().device_present()
+                {
+                    Self::attach_port(port, disks);
+                }
+            }
+            else if port.has_multiplier()
+            {
+                let (attached, removed) = port.rescan_multiplier();
+                for pmp in removed
+                {
+                    Self::unregister_devices(port, Some(pmp), disks);
+                }
+                Self::register_devices(port, &attached, disks);
+            }
+        }
+    }
+
+    fn handle_interrupt(abar: &HbaMemory, ports: &[Arc<AhciPort>])
+    {
+        let pending = abar.interrupt_status.read();
+        if pending == 0
+        {
+            // The legacy line is shared with another device
+            return;
+        }
+
+        for port in ports
+        {
+            if pending.get_bit(port.number() as usize)
+            {
+                port.handle_interrupt();
+            }
+        }
+
+        abar.interrupt_status.write(pending);
+    }
+}
@@ -0,0 +1,1613 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use bit_field::BitField;
+use log::{info, warn};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::deferred::Work;
+use crate::drivers::sata_controller_ahci::atapi;
+use crate::drivers::sata_controller_ahci::fis::{DeviceToHostFIS, HostToDeviceFIS};
+use crate::drivers::sata_controller_ahci::hba::*;
+use crate::sync::Completion;
+use crate::time;
+use crate::VMM;
+
+pub const ATA_CMD_DATA_SET_MANAGEMENT: u8 = 0x06;
+pub const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+pub const ATA_CMD_IDENTIFY_DEVICE: u8 = 0xEC;
+pub const ATA_CMD_READ_PORT_MULTIPLIER: u8 = 0xE4;
+pub const ATA_CMD_WRITE_PORT_MULTIPLIER: u8 = 0xE8;
+
+pub const ATA_DEVICE_LBA: u8 = 1 << 6;
+pub const ATA_FEATURE_TRIM: u16 = 1 << 0;
+pub const ATA_CONTROL_SOFT_RESET: u8 = 1 << 2;
+
+pub const ATA_STATUS_DEVICE_FAULT: u8 = 1 << 5;
+pub const ATA_ERROR_ABORTED: u8 = 1 << 2;
+pub const ATA_ERROR_ID_NOT_FOUND: u8 = 1 << 4;
+pub const ATA_ERROR_UNCORRECTABLE: u8 = 1 << 6;
+pub const ATA_ERROR_INTERFACE_CRC: u8 = 1 << 7;
+
+/// Number of PRDT entries of a command table, so that a table takes exactly 1KiB
+const PRDT_ENTRIES: usize = 56;
+const PRDT_MAX_BYTE_COUNT: usize = 4 << 20;
+const COMMAND_TABLES_PER_PAGE: usize = 0x1000 / core::mem::size_of::<CommandTable>();
+
+/// Largest transfer issued as a single command, it always fits in the PRDT even with a fully fragmented buffer.
+pub const MAX_TRANSFER_SIZE: usize = 128 * 1024;
+/// Most buffers of a vectored command, each may add two partial pages to the PRDT of a `MAX_TRANSFER_SIZE` transfer
+pub const MAX_SEGMENTS: usize = 8;
+
+/// A TRIM range is a 48 bit LBA and a 16 bit sector count, a 512 bytes block holds 64 of them
+const TRIM_RANGE_MAX_SECTORS: u64 = 0xFFFF;
+pub const TRIM_RANGES_PER_BLOCK: usize = 64;
+/// Most sectors trimmed by a single command
+pub const MAX_TRIM_SECTORS: u64 = TRIM_RANGE_MAX_SECTORS * TRIM_RANGES_PER_BLOCK as u64;
+
+/// The block of ranges sent with DATA SET MANAGEMENT
+pub type TrimRanges = [u64; TRIM_RANGES_PER_BLOCK];
+
+const RECEIVED_FIS_OFFSET: u64 = 0x400;
+/// With FIS-based switching, each device behind the multiplier has its own 256 bytes received FIS area
+const RECEIVED_FIS_SIZE: u64 = 0x100;
+const D2H_FIS_OFFSET: u64 = 0x40;
+
+/// Port multiplier port of a device attached directly to the HBA port
+pub const PMP_DIRECT: u8 = 0;
+/// Port multiplier port addressing the multiplier itself
+const PMP_CONTROL: u8 = 15;
+/// Devices behind a port multiplier, which addresses them with ports 0 to 14
+pub const MAX_DEVICES: usize = 15;
+
+/*
+ * Port multiplier registers: general registers (GSCR) of the control port, and SStatus, SError and
+ * SControl of each device port (PSCR)
+ */
+const GSCR_PRODUCT_ID: u16 = 0;
+const GSCR_PORT_INFO: u16 = 2;
+const GSCR_FEATURES_ENABLE: u16 = 96;
+const GSCR_FEATURE_NOTIFICATION: u32 = 1 << 3;
+const PSCR_SATA_STATUS: u16 = 0;
+const PSCR_SATA_ERROR: u16 = 1;
+const PSCR_SATA_CONTROL: u16 = 2;
+
+const COMMAND_TIMEOUT_MS: u64 = 30_000;
+const ENGINE_TIMEOUT_MS: u64 = 500;
+const LINK_TIMEOUT_MS: u64 = 1_000;
+const DEVICE_READY_TIMEOUT_MS: u64 = 10_000;
+/// Bounds a polled command when the tick cannot advance because interrupts are disabled
+const POLL_TIMEOUT_ITERATIONS: u64 = 100_000_000;
+/// Retries of a command failing with a transient error, after the port has been recovered
+const MAX_RETRIES: usize = 3;
+
+const SLOT_SUCCEEDED: u8 = 0;
+const SLOT_FAILED: u8 = 1;
+const SLOT_ABORTED: u8 = 2;
+const SLOT_DETACHED: u8 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AhciError
+{
+    /// The device is absent or was not identified as a disk
+    NoDevice,
+    /// Every command slot of the port is in use
+    NoFreeSlot,
+    /// Another device behind the port multiplier has commands in flight, and the HBA only supports
+    /// command-based switching. Or a polled write found the port in use by the interrupted code.
+    Busy,
+    /// The buffer is not mapped, not sector aligned or too fragmented for a single command
+    InvalidBuffer,
+    /// The command did not complete in time and was aborted by a port reset
+    Timeout,
+    /// The command was aborted while the port recovered from the failure of another command
+    Aborted,
+    /// The device is an optical drive, which cannot be written to
+    WriteProtected,
+    /// The device does not implement the command
+    Unsupported,
+    /// An ATAPI device rejected a packet, with the sense key, additional sense code and qualifier it reported
+    CheckCondition { sense_key: u8, asc: u8, ascq: u8 },
+    /// The device reported an error, with the ATA status and error registers from PxTFD
+    TaskFile { status: u8, error: u8 },
+    /// The link reported an error, with the raw PxIS and PxSERR registers
+    Interface { interrupt_status: u32, sata_error: u32 },
+    /// The HBA could not transfer data to or from system memory
+    HostBus { interrupt_status: u32 },
+}
+
+impl AhciError
+{
+    /// Whether the command may succeed if issued again once the port has been recovered.
+    /// Errors reported by the device itself are final, except for interface CRC errors.
+    pub fn is_retryable(&self) -> bool
+    {
+        match self
+        {
+            AhciError::Timeout | AhciError::Aborted | AhciError::Interface { .. } | AhciError::HostBus { .. } => true,
+            AhciError::TaskFile { status, error } => status & ATA_STATUS_DEVICE_FAULT == 0 && error & ATA_ERROR_INTERFACE_CRC != 0,
+            AhciError::CheckCondition { sense_key, .. } => *sense_key == atapi::SENSE_KEY_UNIT_ATTENTION,
+            _ => false
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct CommandHeader
+{
+    /// Command FIS length, ATAPI, write, prefetchable, reset, BIST, clear busy and port multiplier port
+    flags: u16,
+    prdt_length: u16,
+    prd_byte_count: u32,
+    command_table_base_address_low: u32,
+    command_table_base_address_high: u32,
+    reserved: [u32; 4]
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct PrdtEntry
+{
+    data_base_address_low: u32,
+    data_base_address_high: u32,
+    reserved: u32,
+    /// Byte count minus one in bits 0..22, interrupt on completion in bit 31
+    byte_count: u32
+}
+
+#[repr(C)]
+struct CommandTable
+{
+    command_fis: [u8; 64],
+    atapi_command: [u8; 16],
+    reserved: [u8; 48],
+    prdt: [PrdtEntry; PRDT_ENTRIES]
+}
+
+/// An ATA command as built by the upper layers, before it is bound to a slot
+pub struct Command
+{
+    /// Port multiplier port of the device, `PMP_DIRECT` without a multiplier
+    pub pmp: u8,
+    pub command: u8,
+    pub features: u16,
+    pub lba: u64,
+    pub count: u16,
+    pub device: u8,
+    pub write: bool,
+    pub buffer: *mut u8,
+    pub length: usize,
+    /// SCSI command sent with the PACKET command to ATAPI devices
+    pub packet: Option<atapi::Packet>,
+    /// Device control register sent in a control FIS instead of a command, for software resets
+    pub control: Option<u8>
+}
+
+impl Command
+{
+    pub fn non_data(pmp: u8, command: u8) -> Self
+    {
+        Command {
+            pmp,
+            command,
+            features: 0,
+            lba: 0,
+            count: 0,
+            device: ATA_DEVICE_LBA,
+            write: false,
+            buffer: core::ptr::null_mut(),
+            length: 0,
+            packet: None,
+            control: None
+        }
+    }
+
+    /// A PACKET command carrying `packet`, with its data transferred by DMA
+    pub fn packet(pmp: u8, packet: atapi::Packet, buffer: *mut u8, length: usize) -> Self
+    {
+        Command {
+            features: 1,
+            device: 0,
+            buffer,
+            length,
+            packet: Some(packet),
+            ..Command::non_data(pmp, atapi::ATA_CMD_PACKET)
+        }
+    }
+
+    /// A TRIM of up to `MAX_TRIM_SECTORS` sectors at `lba`, described in `ranges` which must outlive the command.
+    /// Returns the command and the number of sectors it covers.
+    pub fn trim(pmp: u8, ranges: &mut TrimRanges, lba: u64, count: u64) -> (Self, u64)
+    {
+        ranges.fill(0);
+        let mut covered = 0;
+        for range in ranges.iter_mut()
+        {
+            if covered >= count
+            {
+                break;
+            }
+            let sectors = core::cmp::min(count - covered, TRIM_RANGE_MAX_SECTORS);
+            *range = (lba + covered) | sectors << 48;
+            covered += sectors;
+        }
+
+        let command = Command {
+            features: ATA_FEATURE_TRIM,
+            count: 1,
+            write: true,
+            buffer: ranges.as_mut_ptr() as *mut u8,
+            length: core::mem::size_of::<TrimRanges>(),
+            ..Command::non_data(pmp, ATA_CMD_DATA_SET_MANAGEMENT)
+        };
+        (command, covered)
+    }
+
+    /// A control FIS setting or clearing SRST, the two halves of a software reset
+    pub fn soft_reset(pmp: u8, assert: bool) -> Self
+    {
+        Command {
+            device: 0,
+            control: Some(if assert { ATA_CONTROL_SOFT_RESET } else { 0 }),
+            ..Command::non_data(pmp, 0)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot
+{
+    completion: Completion,
+    status: AtomicU8,
+    /// PxIS, PxTFD and PxSERR captured when the command failed
+    interrupt_status: AtomicU32,
+    task_file: AtomicU32,
+    sata_error: AtomicU32,
+    /// Port multiplier port the command was sent to
+    pmp: AtomicU8,
+    /// The command raises no interrupt on completion, e.g. the first half of a software reset
+    polled: AtomicBool,
+    /// Uptime at which the command was issued, `poll` aborts it once it is past its timeout
+    issued_at: AtomicU64,
+    command_table: VirtAddr,
+    command_table_phys: PhysAddr
+}
+
+/// Whether a sector size reported by a device can be used: a multiple of 512 bytes which fits in a command
+fn valid_sector_size(sector_size: usize) -> bool
+{
+    sector_size != 0 && sector_size % 512 == 0 && sector_size <= MAX_TRANSFER_SIZE
+}
+
+#[derive(Debug, Clone)]
+pub struct AtaIdentity
+{
+    pub model: String,
+    pub serial: String,
+    pub sector_count: u64,
+    pub sector_size: usize,
+    pub lba48: bool,
+    /// The device supports the TRIM function of DATA SET MANAGEMENT
+    pub trim: bool,
+    /// The device is an ATAPI optical drive, read through SCSI packets and never written to
+    pub atapi: bool
+}
+
+impl AtaIdentity
+{
+    fn ata_string(words: &[u16]) -> String
+    {
+        let mut string = String::with_capacity(words.len() * 2);
+        for word in words
+        {
+            string.push((word >> 8) as u8 as char);
+            string.push((word & 0xFF) as u8 as char);
+        }
+        String::from(string.trim())
+    }
+
+    fn parse(data: &[u16; 256]) -> Self
+    {
+        let lba48 = data[83] & (1 << 10) != 0;
+        let sector_count = if lba48
+        {
+            data[100] as u64 | (data[101] as u64) << 16 | (data[102] as u64) << 32 | (data[103] as u64) << 48
+        }
+        else
+        {
+            data[60] as u64 | (data[61] as u64) << 16
+        };
+
+        // Word 106 is valid if bit 14 is set and bit 15 clear, bit 12 then tells if words 117-118 hold the sector size
+        let sector_size = if data[106] & 0xC000 == 0x4000 && data[106] & (1 << 12) != 0
+        {
+            (data[117] as usize | (data[118] as usize) << 16) * 2
+        }
+        else
+        {
+            512
+        };
+        let sector_size = if valid_sector_size(sector_size)
+        {
+            sector_size
+        }
+        else
+        {
+            warn!("[SATA] Ignoring invalid sector size {}, using 512", sector_size);
+            512
+        };
+
+        AtaIdentity {
+            model: Self::ata_string(&data[27..47]),
+            serial: Self::ata_string(&data[10..20]),
+            sector_count,
+            sector_size,
+            lba48,
+            trim: data[169] & 1 != 0,
+            atapi: false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AhciPort
+{
+    number: u8,
+    registers: &'static HbaPort,
+    /// HBA capabilities (CAP)
+    capabilities: u32,
+    command_list: VirtAddr,
+    received_fis: VirtAddr,
+    slots: Vec<Slot>,
+    free_slots: Mutex<u32>,
+    /// Slots issued to the HBA and not completed yet
+    in_flight: AtomicU32,
+    interrupt_driven: AtomicBool,
+    /// Set when the command engine halted on an error, the port is recovered before the next command
+    needs_recovery: AtomicBool,
+    /// PxIS error bits accumulated since the last recovery
+    error_status: AtomicU32,
+    recovery: Mutex<()>,
+    /// The port could not be recovered or has no device, every command fails until the device is attached again
+    failed: AtomicBool,
+    attached: AtomicBool,
+    /// Set while the port resets its own link, so that the PhyRdy change it causes is not taken for a hot-plug
+    resetting: AtomicBool,
+    /// PxIS hot-plug bits accumulated by the interrupt handler, `PORT_IS_SET_DEVICE_BITS` standing for a
+    /// notification from the port multiplier
+    hotplug_events: AtomicU32,
+    hotplug_work: Once<Arc<Work>>,
+    multiplier: AtomicBool,
+    multiplier_ports: AtomicU8,
+    fis_based_switching: AtomicBool,
+    /// Device owning the commands in flight when a port multiplier is used with command-based switching
+    active_pmp: AtomicU8,
+    /// Identified devices, indexed by port multiplier port
+    devices: Mutex<[Option<AtaIdentity>; MAX_DEVICES]>,
+    /// Work scheduled when a command completes, indexed by the port multiplier port of the command
+    completion_work: Mutex<[Option<Arc<Work>>; MAX_DEVICES + 1]>
+}
+
+impl AhciPort
+{
+    /// Stops the port, allocates its command list, received FIS area and command tables, then enables FIS
+    /// reception. The device is brought up by `attach`.
+    pub fn new(number: u8, registers: &'static HbaPort, capabilities: u32, slot_count: usize) -> Result<AhciPort, String>
+    {
+        const NO_DEVICE: Option<AtaIdentity> = None;
+        const NO_WORK: Option<Arc<Work>> = None;
+
+        let mut port = AhciPort {
+            number,
+            registers,
+            capabilities,
+            command_list: VirtAddr::zero(),
+            received_fis: VirtAddr::zero(),
+            slots: Vec::with_capacity(slot_count),
+            free_slots: Mutex::new(if slot_count == 32 { u32::MAX } else { (1 << slot_count) - 1 }),
+            in_flight: AtomicU32::new(0),
+            interrupt_driven: AtomicBool::new(false),
+            needs_recovery: AtomicBool::new(false),
+            error_status: AtomicU32::new(0),
+            recovery: Mutex::new(()),
+            failed: AtomicBool::new(true),
+            attached: AtomicBool::new(false),
+            resetting: AtomicBool::new(false),
+            hotplug_events: AtomicU32::new(0),
+            hotplug_work: Once::new(),
+            multiplier: AtomicBool::new(false),
+            multiplier_ports: AtomicU8::new(0),
+            fis_based_switching: AtomicBool::new(false),
+            active_pmp: AtomicU8::new(PMP_DIRECT),
+            devices: Mutex::new([NO_DEVICE; MAX_DEVICES]),
+            completion_work: Mutex::new([NO_WORK; MAX_DEVICES + 1])
+        };
+
+        port.stop()?;
+        registers.fis_based_control_switch.write(0);
+        registers.command_status.clear_bits(PORT_CMD_PORT_MULTIPLIER_ATTACHED);
+
+        // The command list (1KiB) and the received FIS area (256B) share one page, unless FIS-based switching
+        // needs a received FIS area for each device
+        let (phys, virt) = VMM.lock().allocate_dma_page().map_err(|e| format!("Failed to allocate command list: {:?}", e))?;
+        port.command_list = virt;
+        let (fis_phys, fis_virt) = if port.fis_based_switching_supported()
+        {
+            VMM.lock().allocate_dma_page().map_err(|e| format!("Failed to allocate received FIS area: {:?}", e))?
+        }
+        else
+        {
+            (phys + RECEIVED_FIS_OFFSET, virt + RECEIVED_FIS_OFFSET)
+        };
+        port.received_fis = fis_virt;
+
+        registers.command_list_base_address_low.write(phys.as_u64() as u32);
+        registers.command_list_base_address_high.write((phys.as_u64() >> 32) as u32);
+        registers.fis_base_address_low.write(fis_phys.as_u64() as u32);
+        registers.fis_base_address_high.write((fis_phys.as_u64() >> 32) as u32);
+
+        let mut table_page = None;
+        for i in 0..slot_count
+        {
+            if i % COMMAND_TABLES_PER_PAGE == 0
+            {
+                table_page = Some(VMM.lock().allocate_dma_page().map_err(|e| format!("Failed to allocate command tables: {:?}", e))?);
+            }
+
+            let (page_phys, page_virt) = table_page.unwrap();
+            let offset = ((i % COMMAND_TABLES_PER_PAGE) * core::mem::size_of::<CommandTable>()) as u64;
+            port.slots.push(Slot {
+                completion: Completion::new(),
+                status: AtomicU8::new(SLOT_SUCCEEDED),
+                interrupt_status: AtomicU32::new(0),
+                task_file: AtomicU32::new(0),
+                sata_error: AtomicU32::new(0),
+                pmp: AtomicU8::new(PMP_DIRECT),
+                polled: AtomicBool::new(false),
+                issued_at: AtomicU64::new(0),
+                command_table: page_virt + offset,
+                command_table_phys: page_phys + offset
+            });
+        }
+
+        registers.sata_error.write(u32::MAX);
+        registers.interrupt_status.write(u32::MAX);
+        registers.command_status.set_bits(PORT_CMD_SPIN_UP_DEVICE | PORT_CMD_FIS_RECEIVE_ENABLE);
+
+        Ok(port)
+    }
+
+    #[inline]
+    pub fn number(&self) -> u8
+    {
+        self.number
+    }
+
+    #[inline]
+    pub fn registers(&self) -> &'static HbaPort
+    {
+        self.registers
+    }
+
+    #[inline]
+    pub fn is_attached(&self) -> bool
+    {
+        self.attached.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn has_multiplier(&self) -> bool
+    {
+        self.multiplier.load(Ordering::Acquire)
+    }
+
+    pub fn identity(&self, pmp: u8) -> Option<AtaIdentity>
+    {
+        self.devices.lock().get(pmp as usize).cloned().flatten()
+    }
+
+    fn fis_based_switching_supported(&self) -> bool
+    {
+        self.capabilities & CAP_FIS_BASED_SWITCHING != 0
+            && self.registers.command_status.read() & PORT_CMD_FIS_BASED_SWITCHING_CAPABLE != 0
+    }
+
+    /// Clears ST and waits for the command list engine to stop, which also clears PxCI
+    fn stop_command_engine(&self) -> Result<(), String>
+    {
+        self.registers.command_status.clear_bits(PORT_CMD_START);
+        if !Self::wait_clear(&self.registers.command_status, PORT_CMD_COMMAND_LIST_RUNNING, ENGINE_TIMEOUT_MS)
+        {
+            return Err(format!("Port {}: command list did not stop", self.number));
+        }
+
+        Ok(())
+    }
+
+    /// Clears ST and FRE and waits for the command list and FIS receive engines to stop
+    pub fn stop(&self) -> Result<(), String>
+    {
+        self.stop_command_engine()?;
+
+        self.registers.command_status.clear_bits(PORT_CMD_FIS_RECEIVE_ENABLE);
+        if !Self::wait_clear(&self.registers.command_status, PORT_CMD_FIS_RECEIVE_RUNNING, ENGINE_TIMEOUT_MS)
+        {
+            return Err(format!("Port {}: FIS receive did not stop", self.number));
+        }
+
+        Ok(())
+    }
+
+    /// Starts the command engine once the device is no longer busy
+    pub fn start(&self) -> Result<(), String>
+    {
+        if !Self::wait_clear(&self.registers.task_file_data, TFD_STATUS_BUSY | TFD_STATUS_DRQ, DEVICE_READY_TIMEOUT_MS)
+        {
+            return Err(format!("Port {}: device stays busy", self.number));
+        }
+
+        self.registers.command_status.set_bits(PORT_CMD_FIS_RECEIVE_ENABLE | PORT_CMD_START);
+        Ok(())
+    }
+
+    /// Starts the command engine even if the device is still busy, which a port multiplier is after a
+    /// COMRESET until its control port receives a software reset. Needs command list override support.
+    fn start_overriding_busy(&self) -> Result<(), String>
+    {
+        if self.registers.task_file_data.read() & (TFD_STATUS_BUSY | TFD_STATUS_DRQ) == 0
+            || self.capabilities & CAP_COMMAND_LIST_OVERRIDE == 0
+        {
+            return self.start();
+        }
+
+        self.registers.command_status.set_bits(PORT_CMD_COMMAND_LIST_OVERRIDE);
+        if !Self::wait_clear(&self.registers.command_status, PORT_CMD_COMMAND_LIST_OVERRIDE, ENGINE_TIMEOUT_MS)
+        {
+            return Err(format!("Port {}: command list override did not complete", self.number));
+        }
+
+        self.registers.command_status.set_bits(PORT_CMD_FIS_RECEIVE_ENABLE | PORT_CMD_START);
+        Ok(())
+    }
+
+    /// Resets the link with a COMRESET and waits for the device to come back. The command engine must be stopped.
+    pub fn reset(&self) -> Result<(), String>
+    {
+        self.resetting.store(true, Ordering::Release);
+        let result = self.reset_link();
+        self.resetting.store(false, Ordering::Release);
+        result
+    }
+
+    fn reset_link(&self) -> Result<(), String>
+    {
+        // DET = 1 makes the phy send COMRESET, it must be held for at least 1ms
+        let control = self.registers.sata_control.read() & !0xF;
+        self.registers.sata_control.write(control | 1);
+        time::sleep_ms(2);
+        self.registers.sata_control.write(control);
+
+        let deadline = time::uptime_ms() + LINK_TIMEOUT_MS;
+        while !self.registers.device_present()
+        {
+            if time::uptime_ms() >= deadline
+            {
+                return Err(format!("Port {}: no device after COMRESET", self.number));
+            }
+            time::sleep_ms(1);
+        }
+
+        self.registers.sata_error.write(u32::MAX);
+        Ok(())
+    }
+
+    fn wait_clear(register: &Register, mask: u32, timeout_ms: u64) -> bool
+    {
+        let deadline = time::uptime_ms() + timeout_ms;
+        while register.read() & mask != 0
+        {
+            if time::uptime_ms() >= deadline
+            {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        true
+    }
+
+    /// Brings the port back to a usable state after a fatal error or a timeout. The commands still in flight
+    /// are aborted, the command engine is stopped, the link is reset if the device is stuck or the interface
+    /// failed, and the engine is restarted.
+    fn recover(&self)
+    {
+        let _guard = self.recovery.lock();
+        if !self.needs_recovery.swap(false, Ordering::AcqRel)
+        {
+            return;
+        }
+
+        // Stopping the engine clears PxCI, the in flight commands must be taken first or the interrupt
+        // handler would see them as completed
+        let stuck = interrupts::without_interrupts(|| self.in_flight.swap(0, Ordering::AcqRel));
+        self.complete_slots(stuck, SLOT_ABORTED);
+
+        let error_status = self.error_status.swap(0, Ordering::AcqRel);
+        let task_file = self.registers.task_file_data.read();
+        let sata_error = self.registers.sata_error.read();
+        warn!("[SATA] Port {}: recovering from error (PxIS={:#010x} PxTFD={:#010x} PxSERR={:#010x})", self.number, error_status, task_file, sata_error);
+
+        let stopped = self.stop_command_engine().is_ok();
+        self.registers.sata_error.write(u32::MAX);
+
+        let link_failed = error_status & (PORT_IS_INTERFACE_FATAL | PORT_IS_HOST_BUS_FATAL | PORT_IS_HOST_BUS_DATA_ERROR) != 0;
+        if !stopped || link_failed || task_file & (TFD_STATUS_BUSY | TFD_STATUS_DRQ) != 0
+        {
+            let reset = self.stop_command_engine().and_then(|_| self.reset());
+            if let Err(e) = reset
+            {
+                warn!("[SATA] {}, giving up on the device", e);
+                self.failed.store(true, Ordering::Release);
+                return;
+            }
+
+            // The COMRESET also reset the devices behind the multiplier, they have to be probed again
+            if self.has_multiplier()
+            {
+                self.notify_hotplug(PORT_IS_PHY_READY_CHANGE);
+            }
+        }
+
+        self.registers.interrupt_status.write(u32::MAX);
+        match self.start()
+        {
+            Ok(()) => self.failed.store(false, Ordering::Release),
+            Err(e) => {
+                warn!("[SATA] {}, giving up on the device", e);
+                self.failed.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Enables the port interrupts, commands then wait for their completion interrupt instead of polling.
+    /// Hot-plug events are reported to `hotplug_work`, which is expected to call `attach` and `detach`.
+    pub fn enable_interrupts(&self, hotplug_work: Arc<Work>)
+    {
+        self.hotplug_work.call_once(|| hotplug_work);
+        self.registers.sata_error.write(u32::MAX);
+        self.registers.interrupt_status.write(u32::MAX);
+        self.registers.interrupt_enable.write(PORT_IS_COMPLETION | PORT_IS_FATAL | PORT_IS_INTERFACE_NON_FATAL | PORT_IS_HOTPLUG);
+        self.interrupt_driven.store(true, Ordering::Release);
+    }
+
+    fn notify_hotplug(&self, events: u32)
+    {
+        self.hotplug_events.fetch_or(events, Ordering::AcqRel);
+        if let Some(work) = self.hotplug_work.get()
+        {
+            work.schedule();
+        }
+    }
+
+    /// Schedules `work` whenever a command for the device at `pmp` completes, so that commands collected with
+    /// `poll` need not be waited for
+    pub fn set_completion_work(&self, pmp: u8, work: Option<Arc<Work>>)
+    {
+        interrupts::without_interrupts(|| self.completion_work.lock()[pmp as usize & 0xF] = work);
+    }
+
+    /// Takes the hot-plug events recorded since the last call
+    pub fn take_hotplug_events(&self) -> u32
+    {
+        self.hotplug_events.swap(0, Ordering::AcqRel)
+    }
+
+    /// Acknowledges the port interrupts and completes the commands the HBA is done with.
+    /// Called from the controller interrupt handler, or in a loop when the port is polled.
+    pub fn handle_interrupt(&self)
+    {
+        let status = self.registers.interrupt_status.read();
+        self.registers.interrupt_status.write(status);
+
+        // Commands cleared from PxCI completed successfully, even if a later one failed
+        let in_flight = self.in_flight.load(Ordering::Acquire);
+        let done = in_flight & !self.registers.command_issue.read();
+        if done != 0
+        {
+            self.in_flight.fetch_and(!done, Ordering::AcqRel);
+            self.complete_slots(done, SLOT_SUCCEEDED);
+        }
+
+        if status & PORT_IS_FATAL != 0
+        {
+            // The command engine is halted: the command pointed by PxCMD.CCS failed, the others are aborted.
+            // With FIS-based switching, PxFBS tells which device failed instead.
+            self.error_status.fetch_or(status, Ordering::AcqRel);
+            self.needs_recovery.store(true, Ordering::Release);
+
+            let remaining = self.in_flight.swap(0, Ordering::AcqRel);
+            let current = self.registers.command_status.read().get_bits(8..13) as usize;
+            let fis_based_switching = self.registers.fis_based_control_switch.read();
+            let failed = if self.fis_based_switching.load(Ordering::Acquire) && fis_based_switching & FBS_SINGLE_DEVICE_ERROR != 0
+            {
+                self.slots_of(remaining, fis_based_switching.get_bits(16..20) as u8)
+            }
+            else if remaining.get_bit(current)
+            {
+                1 << current
+            }
+            else
+            {
+                remaining
+            };
+
+            self.record_failure(failed, status);
+            self.complete_slots(failed, SLOT_FAILED);
+            self.complete_slots(remaining & !failed, SLOT_ABORTED);
+        }
+
+        if status & PORT_IS_SET_DEVICE_BITS != 0 && self.has_multiplier()
+        {
+            let notification = self.registers.sata_notification.read();
+            if notification != 0
+            {
+                self.registers.sata_notification.write(notification);
+                self.notify_hotplug(PORT_IS_SET_DEVICE_BITS);
+            }
+        }
+
+        if status & PORT_IS_HOTPLUG != 0
+        {
+            self.registers.sata_error.write(SERR_DIAG_PHY_READY_CHANGE | SERR_DIAG_EXCHANGED);
+            if !self.resetting.load(Ordering::Acquire)
+            {
+                // Commands to a removed device would only complete by timing out
+                if !self.registers.device_present()
+                {
+                    self.failed.store(true, Ordering::Release);
+                    let remaining = self.in_flight.swap(0, Ordering::AcqRel);
+                    self.complete_slots(remaining, SLOT_DETACHED);
+                }
+                self.notify_hotplug(status & PORT_IS_HOTPLUG);
+            }
+        }
+    }
+
+    /// The slots among `slots` holding a command for the device at `pmp`
+    fn slots_of(&self, slots: u32, pmp: u8) -> u32
+    {
+        (0..self.slots.len())
+            .filter(|&slot| slots.get_bit(slot) && self.slots[slot].pmp.load(Ordering::Relaxed) == pmp)
+            .fold(0, |mask, slot| mask | 1 << slot)
+    }
+
+    fn record_failure(&self, mut slots: u32, interrupt_status: u32)
+    {
+        let task_file = self.registers.task_file_data.read();
+        let sata_error = self.registers.sata_error.read();
+
+        while slots != 0
+        {
+            let slot = slots.trailing_zeros() as usize;
+            slots &= !(1 << slot);
+
+            self.slots[slot].interrupt_status.store(interrupt_status, Ordering::Relaxed);
+            self.slots[slot].task_file.store(task_file, Ordering::Relaxed);
+            self.slots[slot].sata_error.store(sata_error, Ordering::Relaxed);
+        }
+    }
+
+    fn complete_slots(&self, mut slots: u32, status: u8)
+    {
+        interrupts::without_interrupts(|| {
+            let completion_work = self.completion_work.lock();
+            while slots != 0
+            {
+                let slot = slots.trailing_zeros() as usize;
+                slots &= !(1 << slot);
+
+                self.slots[slot].status.store(status, Ordering::Release);
+                self.slots[slot].completion.complete();
+                if let Some(work) = &completion_work[self.slots[slot].pmp.load(Ordering::Relaxed) as usize]
+                {
+                    work.schedule();
+                }
+            }
+        });
+    }
+
+    fn allocate_slot(&self) -> Result<usize, AhciError>
+    {
+        interrupts::without_interrupts(|| {
+            let mut free_slots = self.free_slots.lock();
+            if *free_slots == 0
+            {
+                return Err(AhciError::NoFreeSlot);
+            }
+
+            let slot = free_slots.trailing_zeros() as usize;
+            *free_slots &= !(1 << slot);
+            Ok(slot)
+        })
+    }
+
+    fn release_slot(&self, slot: usize)
+    {
+        interrupts::without_interrupts(|| *self.free_slots.lock() |= 1 << slot);
+    }
+
+    /// With command-based switching the HBA talks to one device behind the multiplier at a time, a command for
+    /// another device can only be issued once the commands in flight have completed
+    fn claim_device(&self, pmp: u8) -> bool
+    {
+        if !self.has_multiplier() || self.fis_based_switching.load(Ordering::Acquire)
+        {
+            return true;
+        }
+
+        interrupts::without_interrupts(|| {
+            if self.in_flight.load(Ordering::Acquire) == 0
+            {
+                self.active_pmp.store(pmp, Ordering::Release);
+                true
+            }
+            else
+            {
+                self.active_pmp.load(Ordering::Acquire) == pmp
+            }
+        })
+    }
+
+    /// Fills the PRDT of `table` with the physical pages backing `segments`, one buffer after the other,
+    /// merging contiguous pages
+    fn build_prdt(table: &mut CommandTable, segments: &[(*mut u8, usize)]) -> Result<u16, AhciError>
+    {
+        if segments.iter().any(|&(buffer, length)| length % 2 != 0 || buffer as usize % 2 != 0)
+        {
+            return Err(AhciError::InvalidBuffer);
+        }
+
+        let vmm = VMM.lock();
+        let mut entries = 0usize;
+        let mut last_end = PhysAddr::zero();
+
+        for &(buffer, length) in segments.iter().filter(|&&(_, length)| length != 0)
+        {
+            let mut addr = VirtAddr::from_ptr(buffer);
+            let end = addr + length;
+            while addr < end
+            {
+                let phys = vmm.translate_addr(addr).ok_or(AhciError::InvalidBuffer)?;
+                let chunk = core::cmp::min(end - addr, 0x1000 - (addr.as_u64() % 0x1000)) as usize;
+
+                if entries > 0 && last_end == phys && table.prdt[entries - 1].byte_count as usize + 1 + chunk <= PRDT_MAX_BYTE_COUNT
+                {
+                    table.prdt[entries - 1].byte_count += chunk as u32;
+                }
+                else
+                {
+                    if entries == PRDT_ENTRIES
+                    {
+                        return Err(AhciError::InvalidBuffer);
+                    }
+
+                    table.prdt[entries] = PrdtEntry {
+                        data_base_address_low: phys.as_u64() as u32,
+                        data_base_address_high: (phys.as_u64() >> 32) as u32,
+                        reserved: 0,
+                        byte_count: chunk as u32 - 1
+                    };
+                    entries += 1;
+                }
+
+                last_end = phys + chunk;
+                addr += chunk;
+            }
+        }
+
+        Ok(entries as u16)
+    }
+
+    /// Binds `command` to a free slot and issues it. The result is collected with `finish` or `poll`.
+    pub fn issue(&self, command: &Command) -> Result<usize, AhciError>
+    {
+        self.issue_vectored(command, &[(command.buffer, command.length)])
+    }
+
+    /// Like `issue`, but transfers the data of `command` to or from `segments` one after the other, instead
+    /// of its own buffer. At most `MAX_SEGMENTS` segments fit in a command of `MAX_TRANSFER_SIZE` bytes.
+    pub fn issue_vectored(&self, command: &Command, segments: &[(*mut u8, usize)]) -> Result<usize, AhciError>
+    {
+        if self.needs_recovery.load(Ordering::Acquire)
+        {
+            self.recover();
+        }
+        if self.failed.load(Ordering::Acquire)
+        {
+            return Err(AhciError::NoDevice);
+        }
+        if !self.claim_device(command.pmp)
+        {
+            return Err(AhciError::Busy);
+        }
+
+        let slot = self.allocate_slot()?;
+        let table = unsafe { &mut *(self.slots[slot].command_table.as_mut_ptr::<CommandTable>()) };
+
+        let prdt_length = match Self::build_prdt(table, segments)
+        {
+            Ok(length) => length,
+            Err(e) => {
+                self.release_slot(slot);
+                return Err(e);
+            }
+        };
+
+        let mut fis = HostToDeviceFIS::new();
+        fis.pm_port_c = command.pmp & 0xF;
+        match command.control
+        {
+            Some(control) => fis.control = control,
+            None => {
+                fis.pm_port_c |= 1 << 7;
+                fis.command = command.command;
+                fis.feature_low = command.features as u8;
+                fis.feature_high = (command.features >> 8) as u8;
+                fis.lba_low = command.lba as u8;
+                fis.lba_mid = (command.lba >> 8) as u8;
+                fis.lba_high = (command.lba >> 16) as u8;
+                fis.lba_low_exp = (command.lba >> 24) as u8;
+                fis.lba_mid_exp = (command.lba >> 32) as u8;
+                fis.lba_high_exp = (command.lba >> 40) as u8;
+                fis.device = command.device;
+                fis.count_low = command.count as u8;
+                fis.count_high = (command.count >> 8) as u8;
+            }
+        }
+        unsafe { (table.command_fis.as_mut_ptr() as *mut HostToDeviceFIS).write_volatile(fis) };
+
+        if let Some(packet) = command.packet
+        {
+            unsafe { (table.atapi_command.as_mut_ptr() as *mut atapi::Packet).write_volatile(packet) };
+        }
+
+        // Asserting SRST gets no response from the device: the reset bit makes the HBA send the FIS as is and
+        // the clear busy bit completes the slot as soon as it is sent
+        let reset = command.control.map_or(false, |control| control & ATA_CONTROL_SOFT_RESET != 0);
+        let fis_length = (core::mem::size_of::<HostToDeviceFIS>() / 4) as u16;
+        let mut flags = fis_length | (command.pmp as u16 & 0xF) << 12;
+        flags |= if command.packet.is_some() { 1 << 5 } else { 0 } | if command.write { 1 << 6 } else { 0 };
+        flags |= if reset { 1 << 8 | 1 << 10 } else { 0 };
+
+        let header = CommandHeader {
+            flags,
+            prdt_length,
+            prd_byte_count: 0,
+            command_table_base_address_low: self.slots[slot].command_table_phys.as_u64() as u32,
+            command_table_base_address_high: (self.slots[slot].command_table_phys.as_u64() >> 32) as u32,
+            reserved: [0; 4]
+        };
+        unsafe { (self.command_list.as_mut_ptr::<CommandHeader>()).add(slot).write_volatile(header) };
+
+        self.slots[slot].pmp.store(command.pmp, Ordering::Relaxed);
+        self.slots[slot].polled.store(reset, Ordering::Relaxed);
+        self.slots[slot].issued_at.store(time::uptime_ms(), Ordering::Relaxed);
+        self.slots[slot].completion.reset();
+        self.in_flight.fetch_or(1 << slot, Ordering::AcqRel);
+        self.registers.command_issue.write(1 << slot);
+
+        Ok(slot)
+    }
+
+    /// Waits for the command issued in `slot` to complete, frees the slot and returns the command status.
+    /// A command that does not complete in time is aborted by recovering the port.
+    pub fn finish(&self, slot: usize) -> Result<(), AhciError>
+    {
+        let completed = self.wait_slot(slot);
+        self.collect(slot, completed)
+    }
+
+    /// Returns the status of the command issued in `slot` and frees the slot if the command completed,
+    /// without waiting for it. A command past its timeout is aborted by recovering the port.
+    pub fn poll(&self, slot: usize) -> Option<Result<(), AhciError>>
+    {
+        let completion = &self.slots[slot].completion;
+        let interrupt_pending = self.interrupt_driven.load(Ordering::Acquire) && interrupts::are_enabled();
+        if !completion.is_completed() && !interrupt_pending
+        {
+            self.handle_interrupt();
+        }
+
+        let completed = completion.is_completed();
+        if !completed && time::uptime_ms() < self.slots[slot].issued_at.load(Ordering::Relaxed) + COMMAND_TIMEOUT_MS
+        {
+            return None;
+        }
+
+        Some(self.collect(slot, completed))
+    }
+
+    fn collect(&self, slot: usize, completed: bool) -> Result<(), AhciError>
+    {
+        if !completed
+        {
+            self.needs_recovery.store(true, Ordering::Release);
+        }
+        if self.needs_recovery.load(Ordering::Acquire)
+        {
+            self.recover();
+        }
+
+        let result = match self.slots[slot].status.load(Ordering::Acquire)
+        {
+            SLOT_SUCCEEDED => Ok(()),
+            SLOT_DETACHED => Err(AhciError::NoDevice),
+            SLOT_ABORTED if !completed => Err(AhciError::Timeout),
+            SLOT_ABORTED => Err(AhciError::Aborted),
+            _ => Err(Self::decode_error(&self.slots[slot]))
+        };
+        self.release_slot(slot);
+
+        result
+    }
+
+    fn wait_slot(&self, slot: usize) -> bool
+    {
+        let completion = &self.slots[slot].completion;
+        if self.interrupt_driven.load(Ordering::Acquire) && interrupts::are_enabled() && !self.slots[slot].polled.load(Ordering::Relaxed)
+        {
+            return completion.wait_timeout(COMMAND_TIMEOUT_MS);
+        }
+
+        let deadline = time::uptime_ms() + COMMAND_TIMEOUT_MS;
+        let mut iterations = 0;
+        while !completion.is_completed()
+        {
+            self.handle_interrupt();
+
+            iterations += 1;
+            if time::uptime_ms() >= deadline || iterations >= POLL_TIMEOUT_ITERATIONS
+            {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        true
+    }
+
+    fn decode_error(slot: &Slot) -> AhciError
+    {
+        let interrupt_status = slot.interrupt_status.load(Ordering::Relaxed);
+        if interrupt_status & PORT_IS_TASK_FILE_ERROR != 0
+        {
+            let task_file = slot.task_file.load(Ordering::Relaxed);
+            AhciError::TaskFile {
+                status: task_file as u8,
+                error: (task_file >> 8) as u8
+            }
+        }
+        else if interrupt_status & (PORT_IS_HOST_BUS_FATAL | PORT_IS_HOST_BUS_DATA_ERROR) != 0
+        {
+            AhciError::HostBus { interrupt_status }
+        }
+        else
+        {
+            AhciError::Interface { interrupt_status, sata_error: slot.sata_error.load(Ordering::Relaxed) }
+        }
+    }
+
+    /// Issues `command` and waits for it, retrying transient failures after the port has been recovered.
+    /// If the port cannot take the command yet, waits for the commands in flight to make room.
+    pub fn execute(&self, command: &Command) -> Result<(), AhciError>
+    {
+        let deadline = time::uptime_ms() + COMMAND_TIMEOUT_MS;
+        let mut attempt = 0;
+        loop
+        {
+            match self.issue(command).and_then(|slot| self.finish(slot))
+            {
+                Err(AhciError::NoFreeSlot | AhciError::Busy) if interrupts::are_enabled() && time::uptime_ms() < deadline => {
+                    x86_64::instructions::hlt();
+                }
+                Err(e) if e.is_retryable() && attempt < MAX_RETRIES => {
+                    attempt += 1;
+                    warn!("[SATA] Port {}: command {:#04x} failed with {:?}, retrying ({}/{})", self.number, command.command, e, attempt, MAX_RETRIES);
+                }
+                result => return result
+            }
+        }
+    }
+
+    /// Sends `packet` to an ATAPI device. A rejected packet is reported with the sense data fetched by
+    /// REQUEST SENSE, and packets failing with UNIT ATTENTION (e.g. after a disc change) are retried.
+    pub fn execute_packet(&self, pmp: u8, packet: atapi::Packet, buffer: *mut u8, length: usize) -> Result<(), AhciError>
+    {
+        let mut attempt = 0;
+        loop
+        {
+            let error = match self.execute(&Command::packet(pmp, packet, buffer, length))
+            {
+                Err(AhciError::TaskFile { .. }) => self.request_sense(pmp)?,
+                result => return result
+            };
+
+            if !error.is_retryable() || attempt >= MAX_RETRIES
+            {
+                return Err(error);
+            }
+            attempt += 1;
+        }
+    }
+
+    fn request_sense(&self, pmp: u8) -> Result<AhciError, AhciError>
+    {
+        let mut data = [0u8; atapi::SENSE_LENGTH];
+        self.execute(&Command::packet(pmp, atapi::request_sense(), data.as_mut_ptr(), data.len()))?;
+
+        let (sense_key, asc, ascq) = atapi::parse_sense(&data);
+        Ok(AhciError::CheckCondition { sense_key, asc, ascq })
+    }
+
+    fn received_d2h_fis(&self, pmp: u8) -> DeviceToHostFIS
+    {
+        let area = if self.fis_based_switching.load(Ordering::Acquire)
+        {
+            self.received_fis + pmp as u64 * RECEIVED_FIS_SIZE
+        }
+        else
+        {
+            self.received_fis
+        };
+
+        unsafe { (area + D2H_FIS_OFFSET).as_ptr::<DeviceToHostFIS>().read_volatile() }
+    }
+
+    /// The 32 bit value carried by the count and LBA fields of a D2H FIS, the layout of both a device
+    /// signature and a port multiplier register
+    fn received_value(&self, pmp: u8) -> u32
+    {
+        let fis = self.received_d2h_fis(pmp);
+        fis.count_low as u32 | (fis.lba0 as u32) << 8 | (fis.lba1 as u32) << 16 | (fis.lba2 as u32) << 24
+    }
+
+    /// Resets the device at `pmp` with SRST and returns the signature it answers with
+    fn soft_reset(&self, pmp: u8) -> Result<Option<PortSignature>, AhciError>
+    {
+        self.issue(&Command::soft_reset(pmp, true)).and_then(|slot| self.finish(slot))?;
+        // SRST must be held for at least 5us
+        time::sleep_ms(1);
+        self.issue(&Command::soft_reset(pmp, false)).and_then(|slot| self.finish(slot))?;
+
+        Ok(PortSignature::try_from(self.received_value(pmp)).ok())
+    }
+
+    /// Reads `register` of the multiplier port `port`, or a general register with `PMP_CONTROL`
+    fn read_multiplier_register(&self, port: u8, register: u16) -> Result<u32, AhciError>
+    {
+        let mut command = Command::non_data(PMP_CONTROL, ATA_CMD_READ_PORT_MULTIPLIER);
+        command.features = register;
+        command.device = port & 0xF;
+        self.execute(&command)?;
+
+        Ok(self.received_value(PMP_CONTROL))
+    }
+
+    fn write_multiplier_register(&self, port: u8, register: u16, value: u32) -> Result<(), AhciError>
+    {
+        let mut command = Command::non_data(PMP_CONTROL, ATA_CMD_WRITE_PORT_MULTIPLIER);
+        command.features = register;
+        command.device = port & 0xF;
+        command.count = value as u8 as u16;
+        command.lba = (value >> 8) as u64;
+        self.execute(&command)
+    }
+
+    /// Brings up the device behind the port: a disk or an optical drive, or every device behind a port
+    /// multiplier. The command engine must be stopped. Returns the port multiplier ports of the identified
+    /// devices, `PMP_DIRECT` without a multiplier.
+    pub fn attach(&self) -> Result<Vec<u8>, String>
+    {
+        self.failed.store(false, Ordering::Release);
+        self.needs_recovery.store(false, Ordering::Release);
+        self.registers.sata_error.write(u32::MAX);
+
+        let signature = if self.capabilities & CAP_PORT_MULTIPLIER != 0
+        {
+            self.detect_multiplier()?
+        }
+        else
+        {
+            self.start()?;
+            self.registers.signature()
+        };
+
+        let devices = match signature
+        {
+            Some(PortSignature::PortMultiplier) => self.attach_multiplier()?,
+            Some(signature @ (PortSignature::Ata | PortSignature::Atapi)) => {
+                self.identify(PMP_DIRECT, signature).map_err(|e| format!("Port {}: IDENTIFY failed: {:?}", self.number, e))?;
+                alloc::vec![PMP_DIRECT]
+            }
+            signature => return Err(format!("Port {}: unsupported device signature {:?}", self.number, signature))
+        };
+
+        self.attached.store(true, Ordering::Release);
+        Ok(devices)
+    }
+
+    /// With PxCMD.PMA set, a port multiplier answers a software reset of its control port with its own
+    /// signature. Other devices ignore the port field and answer with theirs.
+    fn detect_multiplier(&self) -> Result<Option<PortSignature>, String>
+    {
+        self.registers.command_status.set_bits(PORT_CMD_PORT_MULTIPLIER_ATTACHED);
+        self.start_overriding_busy()?;
+
+        let signature = self.soft_reset(PMP_CONTROL).map_err(|e| format!("Port {}: software reset failed: {:?}", self.number, e))?;
+        if signature != Some(PortSignature::PortMultiplier)
+        {
+            self.stop_command_engine()?;
+            self.registers.command_status.clear_bits(PORT_CMD_PORT_MULTIPLIER_ATTACHED);
+            self.start()?;
+        }
+
+        Ok(signature)
+    }
+
+    fn attach_multiplier(&self) -> Result<Vec<u8>, String>
+    {
+        self.multiplier.store(true, Ordering::Release);
+
+        let error = |e| format!("Port {}: port multiplier did not answer: {:?}", self.number, e);
+        let product = self.read_multiplier_register(PMP_CONTROL, GSCR_PRODUCT_ID).map_err(error)?;
+        let port_info = self.read_multiplier_register(PMP_CONTROL, GSCR_PORT_INFO).map_err(error)?;
+        let ports = core::cmp::min(port_info.get_bits(0..4) as usize, MAX_DEVICES) as u8;
+        self.multiplier_ports.store(ports, Ordering::Release);
+
+        if self.fis_based_switching_supported()
+        {
+            self.stop_command_engine()?;
+            self.registers.fis_based_control_switch.write(FBS_ENABLE);
+            self.fis_based_switching.store(true, Ordering::Release);
+            self.start()?;
+        }
+
+        info!("[SATA] Port {}: port multiplier {:04x}:{:04x} with {} ports, {} switching", self.number, product & 0xFFFF, product >> 16,
+            ports, if self.fis_based_switching.load(Ordering::Acquire) { "FIS-based" } else { "command-based" });
+
+        // Devices plugged behind the multiplier are then reported by an asynchronous notification
+        match self.read_multiplier_register(PMP_CONTROL, GSCR_FEATURES_ENABLE)
+        {
+            Ok(features) => {
+                if let Err(e) = self.write_multiplier_register(PMP_CONTROL, GSCR_FEATURES_ENABLE, features | GSCR_FEATURE_NOTIFICATION)
+                {
+                    warn!("[SATA] Port {}: failed to enable port multiplier notifications: {:?}", self.number, e);
+                }
+            }
+            Err(e) => warn!("[SATA] Port {}: failed to enable port multiplier notifications: {:?}", self.number, e)
+        }
+
+        Ok(self.rescan_multiplier().0)
+    }
+
+    /// Attaches the devices that appeared behind the port multiplier and forgets the ones that went away.
+    /// Returns the ports of the attached devices and the ports of the removed ones.
+    pub fn rescan_multiplier(&self) -> (Vec<u8>, Vec<u8>)
+    {
+        let mut attached = Vec::new();
+        let mut removed = Vec::new();
+
+        for pmp in 0..self.multiplier_ports.load(Ordering::Acquire)
+        {
+            let known = self.devices.lock()[pmp as usize].is_some();
+            match self.attach_multiplier_device(pmp)
+            {
+                Ok(true) if !known => attached.push(pmp),
+                Ok(true) => {}
+                Ok(false) | Err(_) if known => {
+                    self.devices.lock()[pmp as usize] = None;
+                    removed.push(pmp);
+                }
+                Ok(false) => {}
+                Err(e) => warn!("[SATA] Port {}.{}: {}", self.number, pmp, e)
+            }
+        }
+
+        (attached, removed)
+    }
+
+    /// Brings up the link of the multiplier port `pmp` and identifies its device, unless it is already known.
+    /// Returns whether a device is attached to the port.
+    fn attach_multiplier_device(&self, pmp: u8) -> Result<bool, String>
+    {
+        let error = |e| format!("port multiplier register access failed: {:?}", e);
+        let mut status = self.read_multiplier_register(pmp, PSCR_SATA_STATUS).map_err(error)?;
+
+        // DET = 1: a device is detected but the link is not established, it needs a COMRESET
+        if status & 0xF == 1
+        {
+            let control = self.read_multiplier_register(pmp, PSCR_SATA_CONTROL).map_err(error)? & !0xF;
+            self.write_multiplier_register(pmp, PSCR_SATA_CONTROL, control | 1).map_err(error)?;
+            time::sleep_ms(2);
+            self.write_multiplier_register(pmp, PSCR_SATA_CONTROL, control).map_err(error)?;
+
+            let deadline = time::uptime_ms() + LINK_TIMEOUT_MS;
+            while !link_up(status) && time::uptime_ms() < deadline
+            {
+                time::sleep_ms(10);
+                status = self.read_multiplier_register(pmp, PSCR_SATA_STATUS).map_err(error)?;
+            }
+        }
+
+        self.write_multiplier_register(pmp, PSCR_SATA_ERROR, u32::MAX).map_err(error)?;
+        if !link_up(status)
+        {
+            return Ok(false);
+        }
+        if self.devices.lock()[pmp as usize].is_some()
+        {
+            return Ok(true);
+        }
+
+        match self.soft_reset(pmp).map_err(|e| format!("software reset failed: {:?}", e))?
+        {
+            Some(signature @ (PortSignature::Ata | PortSignature::Atapi)) => {
+                self.identify(pmp, signature).map_err(|e| format!("IDENTIFY failed: {:?}", e))?;
+                Ok(true)
+            }
+            signature => Err(format!("unsupported device signature {:?}", signature))
+        }
+    }
+
+    /// Forgets the devices of the port, fails the commands in flight and stops the command engine. FIS
+    /// reception stays enabled so that the next device can be attached.
+    pub fn detach(&self)
+    {
+        let _guard = self.recovery.lock();
+
+        self.failed.store(true, Ordering::Release);
+        self.attached.store(false, Ordering::Release);
+        let remaining = interrupts::without_interrupts(|| self.in_flight.swap(0, Ordering::AcqRel));
+        self.complete_slots(remaining, SLOT_DETACHED);
+
+        if let Err(e) = self.stop_command_engine()
+        {
+            warn!("[SATA] {}", e);
+        }
+        self.registers.fis_based_control_switch.write(0);
+        self.registers.command_status.clear_bits(PORT_CMD_PORT_MULTIPLIER_ATTACHED);
+
+        self.multiplier.store(false, Ordering::Release);
+        self.multiplier_ports.store(0, Ordering::Release);
+        self.fis_based_switching.store(false, Ordering::Release);
+        self.needs_recovery.store(false, Ordering::Release);
+        self.error_status.store(0, Ordering::Release);
+        for device in self.devices.lock().iter_mut()
+        {
+            *device = None;
+        }
+
+        self.registers.sata_error.write(u32::MAX);
+        self.registers.interrupt_status.write(u32::MAX);
+    }
+
+    fn identify(&self, pmp: u8, signature: PortSignature) -> Result<AtaIdentity, AhciError>
+    {
+        let identity = match signature
+        {
+            PortSignature::Atapi => self.identify_packet_device(pmp)?,
+            _ => {
+                let mut data = [0u16; 256];
+                let mut command = Command::non_data(pmp, ATA_CMD_IDENTIFY_DEVICE);
+                command.buffer = data.as_mut_ptr() as *mut u8;
+                command.length = 512;
+
+                self.execute(&command)?;
+                AtaIdentity::parse(&data)
+            }
+        };
+
+        self.devices.lock()[pmp as usize] = Some(identity.clone());
+        Ok(identity)
+    }
+
+    fn identify_packet_device(&self, pmp: u8) -> Result<AtaIdentity, AhciError>
+    {
+        let mut data = [0u16; 256];
+        let mut command = Command::non_data(pmp, atapi::ATA_CMD_IDENTIFY_PACKET_DEVICE);
+        command.buffer = data.as_mut_ptr() as *mut u8;
+        command.length = 512;
+        self.execute(&command)?;
+
+        let mut identity = AtaIdentity::parse(&data);
+        identity.atapi = true;
+        identity.lba48 = false;
+        identity.trim = false;
+        identity.sector_size = atapi::ATAPI_SECTOR_SIZE;
+        identity.sector_count = 0;
+
+        let mut inquiry = [0u8; atapi::INQUIRY_LENGTH];
+        self.execute_packet(pmp, atapi::inquiry(), inquiry.as_mut_ptr(), inquiry.len())?;
+        let inquiry = atapi::Inquiry::parse(&inquiry);
+        if inquiry.device_type != atapi::PERIPHERAL_DEVICE_TYPE_CD_DVD
+        {
+            warn!("[SATA] Port {}: ATAPI device type {:#04x} is not an optical drive", self.number, inquiry.device_type);
+        }
+
+        // The first packets after a reset or a disc change report UNIT ATTENTION, which execute_packet retries
+        match self.execute_packet(pmp, atapi::test_unit_ready(), core::ptr::null_mut(), 0)
+        {
+            Ok(()) => {
+                let mut capacity = [0u8; atapi::CAPACITY_LENGTH];
+                self.execute_packet(pmp, atapi::read_capacity(), capacity.as_mut_ptr(), capacity.len())?;
+                let (sector_count, sector_size) = atapi::parse_capacity(&capacity);
+                identity.sector_count = sector_count;
+                if valid_sector_size(sector_size)
+                {
+                    identity.sector_size = sector_size;
+                }
+                else
+                {
+                    warn!("[SATA] Port {}: ignoring invalid sector size {}", self.number, sector_size);
+                }
+            }
+            Err(AhciError::CheckCondition { sense_key: atapi::SENSE_KEY_NOT_READY, asc: atapi::ASC_MEDIUM_NOT_PRESENT, .. }) => {
+                warn!("[SATA] Port {}: no disc in {} {}", self.number, inquiry.vendor, inquiry.product);
+            }
+            Err(e) => return Err(e)
+        }
+
+        Ok(identity)
+    }
+
+    fn transfer(&self, pmp: u8, lba: u64, buffer: *mut u8, length: usize, write: bool) -> Result<(), AhciError>
+    {
+        let identity = self.identity(pmp).ok_or(AhciError::NoDevice)?;
+        let sector_size = identity.sector_size;
+        if length % sector_size != 0
+        {
+            return Err(AhciError::InvalidBuffer);
+        }
+        if write && identity.atapi
+        {
+            return Err(AhciError::WriteProtected);
+        }
+
+        let sectors = length / sector_size;
+        let sectors_per_command = MAX_TRANSFER_SIZE / sector_size;
+        let mut done = 0;
+        while done < sectors
+        {
+            let count = core::cmp::min(sectors - done, sectors_per_command);
+            let chunk = unsafe { buffer.add(done * sector_size) };
+
+            if identity.atapi
+            {
+                self.execute_packet(pmp, atapi::read((lba + done as u64) as u32, count as u32), chunk, count * sector_size)?;
+            }
+            else
+            {
+                let command = Command {
+                    lba: lba + done as u64,
+                    count: count as u16,
+                    write,
+                    buffer: chunk,
+                    length: count * sector_size,
+                    ..Command::non_data(pmp, if write { ATA_CMD_WRITE_DMA_EXT } else { ATA_CMD_READ_DMA_EXT })
+                };
+                self.execute(&command)?;
+            }
+            done += count;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_sectors(&self, pmp: u8, lba: u64, buffer: &mut [u8]) -> Result<(), AhciError>
+    {
+        self.transfer(pmp, lba, buffer.as_mut_ptr(), buffer.len(), false)
+    }
+
+    pub fn write_sectors(&self, pmp: u8, lba: u64, buffer: &[u8]) -> Result<(), AhciError>
+    {
+        self.transfer(pmp, lba, buffer.as_ptr() as *mut u8, buffer.len(), true)
+    }
+
+    /// Trims `count` sectors at `lba`, with as many ranges per command as a 512 bytes block holds
+    pub fn discard(&self, pmp: u8, lba: u64, count: u64) -> Result<(), AhciError>
+    {
+        match self.identity(pmp)
+        {
+            None => return Err(AhciError::NoDevice),
+            Some(identity) if !identity.trim => return Err(AhciError::Unsupported),
+            Some(_) => {}
+        }
+
+        let mut ranges = [0u64; TRIM_RANGES_PER_BLOCK];
+        let mut done = 0;
+        while done < count
+        {
+            let (command, covered) = Command::trim(pmp, &mut ranges, lba + done, count - done);
+            self.execute(&command)?;
+            done += covered;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buffer` at `lba` and flushes the device cache with interrupts disabled, e.g. to save a crash
+    /// dump. Nothing is waited for: if the interrupted code holds a lock of the port, or the port needs a
+    /// recovery, which logs, the write fails with `Busy`.
+    pub fn write_polled(&self, pmp: u8, lba: u64, buffer: &[u8], sector_size: usize) -> Result<(), AhciError>
+    {
+        interrupts::without_interrupts(|| {
+            // Nothing else runs until interrupts are enabled again, the locks found free stay free
+            if self.recovery.is_locked() || self.free_slots.is_locked() || self.completion_work.is_locked()
+                || self.needs_recovery.load(Ordering::Acquire)
+            {
+                return Err(AhciError::Busy);
+            }
+            let identity = self.devices.try_lock().ok_or(AhciError::Busy)?.get(pmp as usize).cloned().flatten()
+                .ok_or(AhciError::NoDevice)?;
+            if identity.atapi
+            {
+                return Err(AhciError::WriteProtected);
+            }
+            if identity.sector_size != sector_size || buffer.len() % sector_size != 0
+            {
+                return Err(AhciError::InvalidBuffer);
+            }
+
+            let sectors = buffer.len() / sector_size;
+            let sectors_per_command = MAX_TRANSFER_SIZE / sector_size;
+            let mut done = 0;
+            while done < sectors
+            {
+                let count = core::cmp::min(sectors - done, sectors_per_command);
+                let command = Command {
+                    lba: lba + done as u64,
+                    count: count as u16,
+                    write: true,
+                    buffer: buffer[done * sector_size..].as_ptr() as *mut u8,
+                    length: count * sector_size,
+                    ..Command::non_data(pmp, ATA_CMD_WRITE_DMA_EXT)
+                };
+                self.execute_polled(&command)?;
+                done += count;
+            }
+            self.execute_polled(&Command::non_data(pmp, ATA_CMD_FLUSH_CACHE_EXT))
+        })
+    }
+
+    /// Issues `command` and polls for its completion. A failed command leaves the port for the next boot to
+    /// recover.
+    fn execute_polled(&self, command: &Command) -> Result<(), AhciError>
+    {
+        let slot = self.issue(command)?;
+        if !self.wait_slot(slot)
+        {
+            return Err(AhciError::Timeout);
+        }
+        if self.needs_recovery.load(Ordering::Acquire)
+        {
+            return Err(Self::decode_error(&self.slots[slot]));
+        }
+        self.collect(slot, true)
+    }
+
+    pub fn flush(&self, pmp: u8) -> Result<(), AhciError>
+    {
+        match self.identity(pmp)
+        {
+            None => Err(AhciError::NoDevice),
+            // Optical drives are only read, there is nothing to flush
+            Some(identity) if identity.atapi => Ok(()),
+            Some(_) => self.execute(&Command::non_data(pmp, ATA_CMD_FLUSH_CACHE_EXT))
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+/// An entry of the exception table: an instruction allowed to fault and the code to resume at when it does.
+/// Offsets are relative to the fields themselves, so that the table needs no relocation.
+#[repr(C)]
+struct ExtableEntry
+{
+    instruction: i32,
+    fixup: i32
+}
+
+impl ExtableEntry
+{
+    fn instruction(&self) -> u64
+    {
+        (&self.instruction as *const i32 as u64).wrapping_add(self.instruction as i64 as u64)
+    }
+
+    fn fixup(&self) -> u64
+    {
+        (&self.fixup as *const i32 as u64).wrapping_add(self.fixup as i64 as u64)
+    }
+}
+
+/// Emits an exception table entry for the labels `$instruction` and `$fixup`, inside an `asm!` template
+macro_rules! extable_entry {
+    ($instruction:literal, $fixup:literal) => {
+        concat!(".pushsection extable, \"aR\"\n.balign 4\n.long ", $instruction, " - .\n.long ", $fixup, " - .\n.popsection")
+    };
+}
+
+// The linker only defines the bounds of the table if it holds an entry, this one matches no instruction. The
+// section is retained, the linker would otherwise discard it as nothing refers to it.
+global_asm!(".pushsection extable, \"aR\"", ".balign 4", ".long 0, 0", ".popsection");
+
+extern "C"
+{
+    static __start_extable: ExtableEntry;
+    static __stop_extable: ExtableEntry;
+}
+
+fn entries() -> &'static [ExtableEntry]
+{
+    unsafe {
+        let start = &__start_extable as *const ExtableEntry;
+        let end = &__stop_extable as *const ExtableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// An exception caught by a fixup
+#[derive(Debug, Copy, Clone)]
+pub enum Fault
+{
+    GeneralProtection { error_code: u64 },
+    PageFault { address: VirtAddr, error_code: PageFaultErrorCode }
+}
+
+const FAULT_GENERAL_PROTECTION: u8 = 13;
+const FAULT_PAGE: u8 = 14;
+
+/// The last fault caught, for the fixup to return it. Kernel code does not fault in a fixup region
+/// concurrently, so one record is enough.
+static LAST_FAULT_KIND: AtomicU8 = AtomicU8::new(0);
+static LAST_FAULT_CODE: AtomicU64 = AtomicU64::new(0);
+static LAST_FAULT_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+fn record(fault: Fault)
+{
+    let (kind, code, address) = match fault
+    {
+        Fault::GeneralProtection { error_code } => (FAULT_GENERAL_PROTECTION, error_code, 0),
+        Fault::PageFault { address, error_code } => (FAULT_PAGE, error_code.bits(), address.as_u64())
+    };
+    LAST_FAULT_CODE.store(code, Ordering::Relaxed);
+    LAST_FAULT_ADDRESS.store(address, Ordering::Relaxed);
+    LAST_FAULT_KIND.store(kind, Ordering::Release);
+}
+
+fn last_fault() -> Fault
+{
+    let kind = LAST_FAULT_KIND.load(Ordering::Acquire);
+    let code = LAST_FAULT_CODE.load(Ordering::Relaxed);
+    if kind == FAULT_PAGE
+    {
+        Fault::PageFault {
+            address: VirtAddr::new_truncate(LAST_FAULT_ADDRESS.load(Ordering::Relaxed)),
+            error_code: PageFaultErrorCode::from_bits_truncate(code)
+        }
+    }
+    else
+    {
+        Fault::GeneralProtection { error_code: code }
+    }
+}
+
+/// Resumes the faulting code at its fixup if the faulting instruction has an exception table entry, returns
+/// whether it has one
+pub fn fixup(stack_frame: &mut InterruptStackFrame, fault: Fault) -> bool
+{
+    let instruction = stack_frame.instruction_pointer.as_u64();
+    let fixup = match entries().iter().find(|entry| entry.instruction() == instruction)
+    {
+        Some(entry) => entry.fixup(),
+        None => return false
+    };
+
+    record(fault);
+    unsafe {
+        stack_frame.as_mut().update(|frame| frame.instruction_pointer = VirtAddr::new(fixup));
+    }
+    true
+}
+
+/// Reads the model specific register `msr`, which may not exist on this processor
+pub unsafe fn read_msr(msr: u32) -> Result<u64, Fault>
+{
+    let (low, high): (u32, u32);
+    let failed: u32;
+    asm!(
+        "xor {failed:e}, {failed:e}",
+        "2: rdmsr",
+        "jmp 4f",
+        "3: mov {failed:e}, 1",
+        "xor eax, eax",
+        "xor edx, edx",
+        "4:",
+        extable_entry!("2b", "3b"),
+        failed = out(reg) failed,
+        in("ecx") msr,
+        out("eax") low,
+        out("edx") high,
+        options(nostack, nomem)
+    );
+    if failed == 0 { Ok((high as u64) << 32 | low as u64) } else { Err(last_fault()) }
+}
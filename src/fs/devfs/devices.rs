@@ -0,0 +1,187 @@
+use bootloader::boot_info::FrameBuffer;
+use spin::Mutex;
+use uart_16550::SerialPort;
+
+use crate::fs::devfs::{CharDevice, FB_GET_BYTES_PER_PIXEL, FB_GET_HEIGHT, FB_GET_STRIDE, FB_GET_WIDTH};
+use crate::fs::FsError;
+use crate::serial::{self, COM2, SERIAL1};
+
+/// `/dev/null`: reads nothing and discards the writes
+pub struct NullDevice;
+
+impl CharDevice for NullDevice
+{
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        Ok(buffer.len())
+    }
+}
+
+/// `/dev/zero`: reads zeros and discards the writes
+pub struct ZeroDevice;
+
+impl CharDevice for ZeroDevice
+{
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        Ok(buffer.len())
+    }
+}
+
+/// The first serial port. Reads return the bytes already received.
+pub struct SerialDevice;
+
+impl CharDevice for SerialDevice
+{
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let mut read = 0;
+        while read < buffer.len()
+        {
+            match serial::try_receive()
+            {
+                Some(byte) => buffer[read] = byte,
+                None => break
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        let mut serial = SERIAL1.lock();
+        for byte in buffer
+        {
+            serial.send(*byte);
+        }
+        Ok(buffer.len())
+    }
+}
+
+/// The second serial port, until the GDB stub takes it. Reads return the bytes already received.
+pub struct SecondSerialDevice
+{
+    port: Mutex<SerialPort>
+}
+
+impl SecondSerialDevice
+{
+    pub fn new() -> Self
+    {
+        let mut port = unsafe { SerialPort::new(COM2) };
+        port.init();
+        SecondSerialDevice {
+            port: Mutex::new(port)
+        }
+    }
+}
+
+impl CharDevice for SecondSerialDevice
+{
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let mut port = self.port.lock();
+        let mut read = 0;
+        while read < buffer.len()
+        {
+            match serial::try_receive_on(&mut port, COM2)
+            {
+                Some(byte) => buffer[read] = byte,
+                None => break
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        let mut port = self.port.lock();
+        for byte in buffer
+        {
+            port.send(*byte);
+        }
+        Ok(buffer.len())
+    }
+}
+
+/// The framebuffer set up by the bootloader, its pixels addressed in bytes
+pub struct FramebufferDevice
+{
+    framebuffer: Mutex<&'static mut FrameBuffer>
+}
+
+impl FramebufferDevice
+{
+    pub fn new(framebuffer: &'static mut FrameBuffer) -> Self
+    {
+        FramebufferDevice {
+            framebuffer: Mutex::new(framebuffer)
+        }
+    }
+
+    /// Runs `f` on the framebuffer, for the kernel to draw on it. Returns `None` without waiting if the
+    /// framebuffer is in use, which could be by the code an interrupt handler drawing on it interrupted.
+    pub fn try_with_framebuffer<R>(&self, f: impl FnOnce(&mut FrameBuffer) -> R) -> Option<R>
+    {
+        let mut framebuffer = self.framebuffer.try_lock()?;
+        Some(f(&mut framebuffer))
+    }
+}
+
+impl CharDevice for FramebufferDevice
+{
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let framebuffer = self.framebuffer.lock();
+        let pixels = framebuffer.buffer();
+        let start = core::cmp::min(offset, pixels.len() as u64) as usize;
+        let length = core::cmp::min(buffer.len(), pixels.len() - start);
+        buffer[..length].copy_from_slice(&pixels[start..start + length]);
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        let mut framebuffer = self.framebuffer.lock();
+        let pixels = framebuffer.buffer_mut();
+        let start = core::cmp::min(offset, pixels.len() as u64) as usize;
+        let length = core::cmp::min(buffer.len(), pixels.len() - start);
+        if length == 0 && !buffer.is_empty()
+        {
+            return Err(FsError::NoSpace);
+        }
+        pixels[start..start + length].copy_from_slice(&buffer[..length]);
+        Ok(length)
+    }
+
+    fn size(&self) -> u64
+    {
+        self.framebuffer.lock().info().byte_len as u64
+    }
+
+    fn ioctl(&self, request: u32, _argument: u64) -> Result<u64, FsError>
+    {
+        let info = self.framebuffer.lock().info();
+        match request
+        {
+            FB_GET_WIDTH => Ok(info.horizontal_resolution as u64),
+            FB_GET_HEIGHT => Ok(info.vertical_resolution as u64),
+            FB_GET_STRIDE => Ok(info.stride as u64),
+            FB_GET_BYTES_PER_PIXEL => Ok(info.bytes_per_pixel as u64),
+            _ => Err(FsError::InvalidArgument)
+        }
+    }
+}
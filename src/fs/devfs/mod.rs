@@ -0,0 +1,330 @@
+mod devices;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use log::info;
+use spin::Mutex;
+
+use crate::block::{BlockDevice, BLOCK_DEVICES};
+use crate::fs::volume::Volume;
+use crate::fs::{path, DirEntry, Directory, File, FileSystem, FileType, FsError, Inode, Metadata};
+
+pub use crate::fs::devfs::devices::{FramebufferDevice, NullDevice, SecondSerialDevice, SerialDevice, ZeroDevice};
+
+/// Requests of `File::ioctl` on block devices
+pub const BLOCK_GET_SECTOR_SIZE: u32 = 0x100;
+pub const BLOCK_GET_SECTOR_COUNT: u32 = 0x101;
+/// Writes the cached sectors of the device back to it
+pub const BLOCK_FLUSH: u32 = 0x102;
+
+/// Requests of `File::ioctl` on framebuffers
+pub const FB_GET_WIDTH: u32 = 0x200;
+pub const FB_GET_HEIGHT: u32 = 0x201;
+/// Pixels between the start of two lines
+pub const FB_GET_STRIDE: u32 = 0x202;
+pub const FB_GET_BYTES_PER_PIXEL: u32 = 0x203;
+
+/// A device transferring bytes without sectors, e.g. a serial port or a framebuffer
+pub trait CharDevice: Send + Sync
+{
+    /// Reads at `offset`, which stream devices ignore. Returns 0 when no byte is available, stream devices
+    /// never wait for one.
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError>;
+
+    /// Size of the memory of the device, 0 for stream devices
+    fn size(&self) -> u64
+    {
+        0
+    }
+
+    fn ioctl(&self, _request: u32, _argument: u64) -> Result<u64, FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+}
+
+static CHAR_DEVICES: Mutex<BTreeMap<String, Arc<dyn CharDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Registers the character devices always present, the others are registered by their drivers
+pub fn init()
+{
+    let devices: [(&str, Arc<dyn CharDevice>); 4] = [
+        ("null", Arc::new(NullDevice)),
+        ("zero", Arc::new(ZeroDevice)),
+        ("ttyS0", Arc::new(SerialDevice)),
+        ("ttyS1", Arc::new(SecondSerialDevice::new()))
+    ];
+    for (name, device) in devices
+    {
+        register_char_device(name, device).expect("Failed to register a built-in character device");
+    }
+}
+
+/// Registers `device`, which appears as `/dev/<name>` until it is unregistered
+pub fn register_char_device(name: &str, device: Arc<dyn CharDevice>) -> Result<(), FsError>
+{
+    if name.is_empty() || name.contains(path::SEPARATOR) || name.contains('\0')
+    {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut devices = CHAR_DEVICES.lock();
+    if devices.contains_key(name) || BLOCK_DEVICES.lock().get(name).is_some()
+    {
+        return Err(FsError::AlreadyExists);
+    }
+    devices.insert(name.to_string(), device);
+    info!("[DEVFS] Registered character device {}", name);
+    Ok(())
+}
+
+pub fn unregister_char_device(name: &str) -> Option<Arc<dyn CharDevice>>
+{
+    let device = CHAR_DEVICES.lock().remove(name);
+    if device.is_some()
+    {
+        info!("[DEVFS] Unregistered character device {}", name);
+    }
+    device
+}
+
+/// The filesystem mounted on `/dev`, with a node for each block device and character device.
+///
+/// Nothing is stored: the registries are read on each lookup, so that devices appear and disappear as
+/// their drivers register and unregister them. Open nodes keep their device until they are closed.
+pub struct DevFs
+{
+    root: Arc<DevDirectory>
+}
+
+impl DevFs
+{
+    pub fn new() -> Self
+    {
+        DevFs {
+            root: Arc::new(DevDirectory {
+                inodes: Mutex::new(BTreeMap::new())
+            })
+        }
+    }
+}
+
+impl FileSystem for DevFs
+{
+    fn name(&self) -> &str
+    {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode>
+    {
+        self.root.clone()
+    }
+}
+
+const ROOT_INODE: u64 = 1;
+
+struct DevDirectory
+{
+    /// Inode numbers given to the names seen so far, so that a device keeps its number
+    inodes: Mutex<BTreeMap<String, u64>>
+}
+
+impl DevDirectory
+{
+    fn inode_of(&self, name: &str) -> u64
+    {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(name)
+        {
+            return *inode;
+        }
+        let inode = ROOT_INODE + 1 + inodes.len() as u64;
+        inodes.insert(name.to_string(), inode);
+        inode
+    }
+}
+
+impl Inode for DevDirectory
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        Ok(Metadata::new(ROOT_INODE, FileType::Directory, 0))
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory>
+    {
+        Some(self)
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl Directory for DevDirectory
+{
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        if let Some(device) = BLOCK_DEVICES.lock().get(name)
+        {
+            return Ok(Arc::new(BlockNode::new(self.inode_of(name), device)));
+        }
+        let device = CHAR_DEVICES.lock().get(name).cloned().ok_or(FsError::NotFound)?;
+        Ok(Arc::new(CharNode {
+            inode: self.inode_of(name),
+            device
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>
+    {
+        let block_devices: Vec<String> = BLOCK_DEVICES.lock().iter().map(|(name, _)| name.clone()).collect();
+        let char_devices: Vec<String> = CHAR_DEVICES.lock().keys().cloned().collect();
+
+        let entries = block_devices.into_iter().map(|name| (name, FileType::BlockDevice))
+            .chain(char_devices.into_iter().map(|name| (name, FileType::CharDevice)))
+            .map(|(name, file_type)| DirEntry {
+                inode: self.inode_of(&name),
+                name,
+                file_type
+            })
+            .collect();
+        Ok(entries)
+    }
+}
+
+/// A block device, addressed in bytes through the buffer cache
+struct BlockNode
+{
+    inode: u64,
+    device: Arc<dyn BlockDevice>,
+    volume: Volume
+}
+
+impl BlockNode
+{
+    fn new(inode: u64, device: Arc<dyn BlockDevice>) -> Self
+    {
+        BlockNode {
+            inode,
+            volume: Volume::new(device.clone()),
+            device
+        }
+    }
+
+    /// Length of a transfer of `length` bytes at `offset` clipped to the end of the device
+    fn clip(&self, offset: u64, length: usize) -> usize
+    {
+        core::cmp::min(length as u64, self.volume.size().saturating_sub(offset)) as usize
+    }
+}
+
+impl Inode for BlockNode
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        Ok(Metadata {
+            mode: 0o660,
+            ..Metadata::new(self.inode, FileType::BlockDevice, self.volume.size())
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File>
+    {
+        Some(self)
+    }
+
+    fn sync(&self) -> Result<(), FsError>
+    {
+        self.volume.sync()
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl File for BlockNode
+{
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let length = self.clip(offset, buffer.len());
+        self.volume.read(offset, &mut buffer[..length])?;
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        let length = self.clip(offset, buffer.len());
+        if length == 0 && !buffer.is_empty()
+        {
+            return Err(FsError::NoSpace);
+        }
+        self.volume.write(offset, &buffer[..length])?;
+        Ok(length)
+    }
+
+    fn ioctl(&self, request: u32, _argument: u64) -> Result<u64, FsError>
+    {
+        match request
+        {
+            BLOCK_GET_SECTOR_SIZE => Ok(self.device.sector_size() as u64),
+            BLOCK_GET_SECTOR_COUNT => Ok(self.device.sector_count()),
+            BLOCK_FLUSH => self.volume.sync().map(|()| 0),
+            _ => Err(FsError::InvalidArgument)
+        }
+    }
+}
+
+struct CharNode
+{
+    inode: u64,
+    device: Arc<dyn CharDevice>
+}
+
+impl Inode for CharNode
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        Ok(Metadata {
+            mode: 0o666,
+            ..Metadata::new(self.inode, FileType::CharDevice, self.device.size())
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File>
+    {
+        Some(self)
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl File for CharNode
+{
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        self.device.read_at(offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        self.device.write_at(offset, buffer)
+    }
+
+    fn ioctl(&self, request: u32, argument: u64) -> Result<u64, FsError>
+    {
+        self.device.ioctl(request, argument)
+    }
+}
@@ -0,0 +1,109 @@
+use alloc::format;
+use alloc::vec::Vec;
+
+use crate::fs::ext2::inode::{MODE_BLOCK_DEVICE, MODE_CHAR_DEVICE, MODE_DIRECTORY, MODE_FIFO, MODE_REGULAR, MODE_SOCKET, MODE_SYMLINK, MODE_TYPE_MASK};
+use crate::fs::FsError;
+
+/// Size of a record without its name
+pub const HEADER_SIZE: usize = 8;
+
+/// A directory record, located by its offset in its block
+#[derive(Debug, Clone)]
+pub struct Record
+{
+    pub offset: usize,
+    /// Inode of the entry, 0 for an unused record
+    pub inode: u32,
+    /// Length of the record up to the next one, including unused space
+    pub length: usize,
+    pub name: Vec<u8>,
+    pub file_type: u8
+}
+
+impl Record
+{
+    /// The space needed by the record, the rest of its length can hold other records
+    pub fn used_length(&self) -> usize
+    {
+        if self.inode == 0 { 0 } else { record_size(self.name.len()) }
+    }
+
+    pub fn is_dot(&self) -> bool
+    {
+        self.name == b"." || self.name == b".."
+    }
+}
+
+/// Size of a record holding a name of `name_length` bytes, records are aligned on 4 bytes
+#[inline]
+pub fn record_size(name_length: usize) -> usize
+{
+    (HEADER_SIZE + name_length + 3) & !3
+}
+
+/// Parses the records of a directory block. Without the file type feature, the name length has 16 bits.
+pub fn parse_block(data: &[u8], file_types: bool) -> Result<Vec<Record>, FsError>
+{
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < data.len()
+    {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        if offset + HEADER_SIZE > data.len()
+        {
+            return Err(FsError::Corrupted(format!("ext2: truncated directory record at {}", offset)));
+        }
+
+        let inode = u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let length = u16_at(offset + 4) as usize;
+        let (name_length, file_type) = if file_types { (data[offset + 6] as usize, data[offset + 7]) } else { (u16_at(offset + 6) as usize, 0) };
+
+        if length < HEADER_SIZE || length % 4 != 0 || offset + length > data.len() || (inode != 0 && HEADER_SIZE + name_length > length)
+        {
+            return Err(FsError::Corrupted(format!("ext2: invalid directory record at {}", offset)));
+        }
+
+        records.push(Record {
+            offset,
+            inode,
+            length,
+            name: data[offset + HEADER_SIZE..offset + HEADER_SIZE + if inode != 0 { name_length } else { 0 }].to_vec(),
+            file_type
+        });
+        offset += length;
+    }
+    Ok(records)
+}
+
+/// Writes a record at the start of `data`
+pub fn encode(data: &mut [u8], inode: u32, length: usize, name: &[u8], file_type: u8, file_types: bool)
+{
+    data[0..4].copy_from_slice(&inode.to_le_bytes());
+    data[4..6].copy_from_slice(&(length as u16).to_le_bytes());
+    if file_types
+    {
+        data[6] = name.len() as u8;
+        data[7] = file_type;
+    }
+    else
+    {
+        data[6..8].copy_from_slice(&(name.len() as u16).to_le_bytes());
+    }
+    data[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+/// The file type stored in the records of an inode with the mode `mode`
+pub fn file_type_of(mode: u16) -> u8
+{
+    match mode & MODE_TYPE_MASK
+    {
+        MODE_REGULAR => 1,
+        MODE_DIRECTORY => 2,
+        MODE_CHAR_DEVICE => 3,
+        MODE_BLOCK_DEVICE => 4,
+        MODE_FIFO => 5,
+        MODE_SOCKET => 6,
+        MODE_SYMLINK => 7,
+        _ => 0
+    }
+}
@@ -0,0 +1,167 @@
+use crate::fs::FileType;
+
+/// Size of the fields of an inode known to the driver, larger inodes hold extra fields after them
+pub const INODE_FIELDS_SIZE: usize = 128;
+
+pub const DIRECT_BLOCKS: usize = 12;
+pub const BLOCK_POINTERS: usize = 15;
+
+/// Symbolic links with a shorter target store it in the block pointers
+pub const FAST_SYMLINK_MAX_LENGTH: usize = 60;
+
+pub const MODE_TYPE_MASK: u16 = 0xF000;
+pub const MODE_FIFO: u16 = 0x1000;
+pub const MODE_CHAR_DEVICE: u16 = 0x2000;
+pub const MODE_DIRECTORY: u16 = 0x4000;
+pub const MODE_BLOCK_DEVICE: u16 = 0x6000;
+pub const MODE_REGULAR: u16 = 0x8000;
+pub const MODE_SYMLINK: u16 = 0xA000;
+pub const MODE_SOCKET: u16 = 0xC000;
+
+#[derive(Debug, Clone)]
+pub struct DiskInode
+{
+    pub mode: u16,
+    pub uid: u16,
+    pub size: u64,
+    pub accessed: u32,
+    pub changed: u32,
+    pub modified: u32,
+    pub deleted: u32,
+    pub gid: u16,
+    pub links: u16,
+    /// Number of 512 byte sectors used by the data and indirect blocks
+    pub sectors: u32,
+    pub flags: u32,
+    pub blocks: [u32; BLOCK_POINTERS],
+    pub generation: u32,
+    pub file_acl: u32
+}
+
+impl DiskInode
+{
+    pub fn new(mode: u16) -> Self
+    {
+        DiskInode {
+            mode,
+            uid: 0,
+            size: 0,
+            accessed: 0,
+            changed: 0,
+            modified: 0,
+            deleted: 0,
+            gid: 0,
+            links: 1,
+            sectors: 0,
+            flags: 0,
+            blocks: [0; BLOCK_POINTERS],
+            generation: 0,
+            file_acl: 0
+        }
+    }
+
+    /// Parses an inode, `large_files` telling whether the size of regular files has 64 bits
+    pub fn parse(data: &[u8], large_files: bool) -> Self
+    {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+        let mode = u16_at(0);
+        // The high half of the size is the directory ACL on directories
+        let size_high = if large_files && mode & MODE_TYPE_MASK == MODE_REGULAR { u32_at(108) } else { 0 };
+        let mut blocks = [0; BLOCK_POINTERS];
+        for (i, block) in blocks.iter_mut().enumerate()
+        {
+            *block = u32_at(40 + i * 4);
+        }
+
+        DiskInode {
+            mode,
+            uid: u16_at(2),
+            size: (size_high as u64) << 32 | u32_at(4) as u64,
+            accessed: u32_at(8),
+            changed: u32_at(12),
+            modified: u32_at(16),
+            deleted: u32_at(20),
+            gid: u16_at(24),
+            links: u16_at(26),
+            sectors: u32_at(28),
+            flags: u32_at(32),
+            blocks,
+            generation: u32_at(100),
+            file_acl: u32_at(104)
+        }
+    }
+
+    /// Writes the fields to the first `INODE_FIELDS_SIZE` bytes of `data`, keeping the OS specific ones
+    pub fn encode(&self, data: &mut [u8])
+    {
+        data[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        data[2..4].copy_from_slice(&self.uid.to_le_bytes());
+        data[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        data[8..12].copy_from_slice(&self.accessed.to_le_bytes());
+        data[12..16].copy_from_slice(&self.changed.to_le_bytes());
+        data[16..20].copy_from_slice(&self.modified.to_le_bytes());
+        data[20..24].copy_from_slice(&self.deleted.to_le_bytes());
+        data[24..26].copy_from_slice(&self.gid.to_le_bytes());
+        data[26..28].copy_from_slice(&self.links.to_le_bytes());
+        data[28..32].copy_from_slice(&self.sectors.to_le_bytes());
+        data[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        for (i, block) in self.blocks.iter().enumerate()
+        {
+            data[40 + i * 4..44 + i * 4].copy_from_slice(&block.to_le_bytes());
+        }
+        data[100..104].copy_from_slice(&self.generation.to_le_bytes());
+        data[104..108].copy_from_slice(&self.file_acl.to_le_bytes());
+        if self.mode & MODE_TYPE_MASK == MODE_REGULAR
+        {
+            data[108..112].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+        }
+    }
+
+    pub fn file_type(&self) -> FileType
+    {
+        match self.mode & MODE_TYPE_MASK
+        {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            // FIFOs and sockets have no type of their own in the VFS, and no data either
+            _ => FileType::Regular
+        }
+    }
+
+    #[inline]
+    pub fn is_directory(&self) -> bool
+    {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    /// Whether the inode is a symbolic link with its target in the block pointers
+    pub fn is_fast_symlink(&self) -> bool
+    {
+        self.mode & MODE_TYPE_MASK == MODE_SYMLINK && self.sectors == 0 && (self.size as usize) < FAST_SYMLINK_MAX_LENGTH
+    }
+
+    /// The block pointers as bytes, holding the target of fast symbolic links
+    pub fn inline_data(&self) -> [u8; FAST_SYMLINK_MAX_LENGTH]
+    {
+        let mut data = [0u8; FAST_SYMLINK_MAX_LENGTH];
+        for (i, block) in self.blocks.iter().enumerate()
+        {
+            data[i * 4..i * 4 + 4].copy_from_slice(&block.to_le_bytes());
+        }
+        data
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8])
+    {
+        let mut bytes = [0u8; FAST_SYMLINK_MAX_LENGTH];
+        bytes[..data.len()].copy_from_slice(data);
+        for (i, block) in self.blocks.iter_mut().enumerate()
+        {
+            *block = u32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+        }
+    }
+}
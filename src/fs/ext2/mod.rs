@@ -0,0 +1,1165 @@
+mod directory;
+mod inode;
+pub mod superblock;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use log::{info, warn};
+use spin::{Mutex, MutexGuard};
+
+use crate::block::BlockDevice;
+use crate::fs::ext2::directory::Record;
+use crate::fs::ext2::inode::{DiskInode, BLOCK_POINTERS, DIRECT_BLOCKS, FAST_SYMLINK_MAX_LENGTH, INODE_FIELDS_SIZE, MODE_DIRECTORY, MODE_REGULAR, MODE_SYMLINK};
+use crate::fs::ext2::superblock::{GroupDescriptor, Superblock, GROUP_DESCRIPTOR_SIZE, INCOMPAT_FILETYPE, RO_COMPAT_LARGE_FILE, STATE_CLEAN, STATE_ERRORS, SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE};
+use crate::fs::path::MAX_NAME_LENGTH;
+use crate::fs::volume::Volume;
+use crate::fs::{register_filesystem_type, DirEntry, Directory, File, FileSystem, FileSystemType, FileType, FsError, FsStats, Inode, Metadata};
+
+pub const ROOT_INODE: u32 = 2;
+
+pub fn init()
+{
+    register_filesystem_type(FileSystemType {
+        name: "ext2",
+        mount
+    });
+}
+
+/// Mounts the ext2 filesystem stored on `device`, read-only if it uses unsupported features or has errors
+pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError>
+{
+    let volume = Volume::new(device);
+    let mut data = [0u8; SUPERBLOCK_SIZE];
+    volume.read(SUPERBLOCK_OFFSET, &mut data)?;
+    let mut superblock = Superblock::parse(&data)?;
+    if superblock.block_offset(superblock.blocks_count) > volume.size()
+    {
+        return Err(FsError::Corrupted(format!("ext2: {} blocks, larger than the device", superblock.blocks_count)));
+    }
+
+    let group_count = superblock.group_count();
+    let mut table = vec![0u8; group_count as usize * GROUP_DESCRIPTOR_SIZE];
+    volume.read(superblock.group_table_offset(), &mut table)?;
+    let mut groups = Vec::new();
+    for (group, data) in table.chunks_exact(GROUP_DESCRIPTOR_SIZE).enumerate()
+    {
+        let descriptor = GroupDescriptor::parse(data);
+        descriptor.check(&superblock, group as u32)?;
+        groups.push(descriptor);
+    }
+
+    // The counts of the superblock are only updated lazily, the group counts are authoritative
+    let free_blocks = groups.iter().map(|group| group.free_blocks_count as u32).sum();
+    let free_inodes = groups.iter().map(|group| group.free_inodes_count as u32).sum();
+    if free_blocks != superblock.free_blocks_count || free_inodes != superblock.free_inodes_count
+    {
+        warn!("[EXT2] Superblock free counts ({} blocks, {} inodes) differ from the groups ({} blocks, {} inodes)",
+            superblock.free_blocks_count, superblock.free_inodes_count, free_blocks, free_inodes);
+        superblock.free_blocks_count = free_blocks;
+        superblock.free_inodes_count = free_inodes;
+    }
+
+    let mut read_only = volume.is_read_only();
+    if superblock.unsupported_read_only_features() != 0
+    {
+        warn!("[EXT2] Unsupported features {:#x}, mounting read-only", superblock.unsupported_read_only_features());
+        read_only = true;
+    }
+    if superblock.state & STATE_ERRORS != 0
+    {
+        warn!("[EXT2] The filesystem has errors, mounting read-only");
+        read_only = true;
+    }
+    else if superblock.state & STATE_CLEAN == 0
+    {
+        warn!("[EXT2] The filesystem was not cleanly unmounted");
+    }
+
+    info!("[EXT2] Mounting \"{}\", {} blocks of {} bytes in {} groups, {} inodes{}", superblock.volume_name, superblock.blocks_count,
+        superblock.block_size, group_count, superblock.inodes_count, if read_only { ", read-only" } else { "" });
+
+    let filesystem = Arc::new_cyclic(|this| Ext2FileSystem {
+        file_types: superblock.feature_incompat & INCOMPAT_FILETYPE != 0,
+        large_files: superblock.feature_ro_compat & RO_COMPAT_LARGE_FILE != 0,
+        read_only,
+        geometry: superblock.clone(),
+        inner: Mutex::new(Ext2Inner {
+            volume,
+            superblock,
+            groups,
+            nodes: BTreeMap::new(),
+            root: DiskInode::new(0),
+            dirty: false
+        }),
+        this: this.clone()
+    });
+
+    {
+        let mut inner = filesystem.inner.lock();
+        let root = filesystem.load(&mut inner, ROOT_INODE)?;
+        if !root.is_directory()
+        {
+            return Err(FsError::Corrupted(String::from("ext2: the root inode is not a directory")));
+        }
+        inner.root = root;
+    }
+    Ok(filesystem)
+}
+
+/// A mounted ext2 filesystem. Every operation holds the filesystem lock, node states are only locked with it
+/// held and never for longer than a load or a store.
+pub struct Ext2FileSystem
+{
+    /// The superblock as mounted, for the geometry of the filesystem
+    geometry: Superblock,
+    file_types: bool,
+    large_files: bool,
+    read_only: bool,
+    inner: Mutex<Ext2Inner>,
+    this: Weak<Ext2FileSystem>
+}
+
+struct Ext2Inner
+{
+    volume: Volume,
+    /// The superblock with the current free counts
+    superblock: Superblock,
+    groups: Vec<GroupDescriptor>,
+    /// Nodes in use by inode number, whose state is the current content of their inode
+    nodes: BTreeMap<u32, Weak<Ext2Node>>,
+    /// The root inode as last stored, so that its node can be made again without reading the device
+    root: DiskInode,
+    /// Whether the free counts of the superblock need to be written
+    dirty: bool
+}
+
+/// Index of the first clear bit of `bitmap` among its first `count` bits
+fn find_clear_bit(bitmap: &[u8], count: usize) -> Option<usize>
+{
+    bitmap.iter().enumerate()
+        .filter(|(_, &byte)| byte != 0xFF)
+        .map(|(index, byte)| index * 8 + byte.trailing_ones() as usize)
+        .find(|&bit| bit < count)
+}
+
+impl Ext2FileSystem
+{
+    fn this(&self) -> Arc<Ext2FileSystem>
+    {
+        self.this.upgrade().unwrap()
+    }
+
+    #[inline]
+    fn block_size(&self) -> u64
+    {
+        self.geometry.block_size as u64
+    }
+
+    #[inline]
+    fn pointers_per_block(&self) -> u64
+    {
+        self.block_size() / 4
+    }
+
+    /// Number of 512 byte sectors of a block, the unit of the block count of inodes
+    #[inline]
+    fn sectors_per_block(&self) -> u32
+    {
+        self.geometry.block_size / 512
+    }
+
+    fn group_of_inode(&self, number: u32) -> u32
+    {
+        (number - 1) / self.geometry.inodes_per_group
+    }
+
+    /// The largest file size, limited by the block map and without the large file feature by a 32 bit size
+    fn max_file_size(&self) -> u64
+    {
+        let pointers = self.pointers_per_block();
+        let blocks = DIRECT_BLOCKS as u64 + pointers + pointers * pointers + pointers * pointers * pointers;
+        core::cmp::min(blocks * self.block_size(), if self.large_files { u64::MAX } else { i32::MAX as u64 })
+    }
+
+    fn check_writable(&self) -> Result<(), FsError>
+    {
+        if self.read_only { Err(FsError::ReadOnly) } else { Ok(()) }
+    }
+
+    fn node(&self, inner: &mut Ext2Inner, number: u32) -> Result<Arc<Ext2Node>, FsError>
+    {
+        if let Some(node) = inner.nodes.get(&number).and_then(Weak::upgrade)
+        {
+            return Ok(node);
+        }
+
+        let inode = self.load(inner, number)?;
+        Ok(self.insert_node(inner, number, inode))
+    }
+
+    /// Makes the node of the inode `number`, whose content is `inode`
+    fn insert_node(&self, inner: &mut Ext2Inner, number: u32, inode: DiskInode) -> Arc<Ext2Node>
+    {
+        let node = Arc::new(Ext2Node {
+            fs: self.this(),
+            number,
+            state: Mutex::new(NodeState {
+                inode,
+                deleted: false
+            })
+        });
+        inner.nodes.retain(|_, node| node.strong_count() != 0);
+        inner.nodes.insert(number, Arc::downgrade(&node));
+        node
+    }
+
+    fn inode_offset(&self, inner: &Ext2Inner, number: u32) -> Result<u64, FsError>
+    {
+        if number == 0 || number > self.geometry.inodes_count
+        {
+            return Err(FsError::Corrupted(format!("ext2: invalid inode {}", number)));
+        }
+
+        let index = (number - 1) % self.geometry.inodes_per_group;
+        let table = inner.groups[self.group_of_inode(number) as usize].inode_table;
+        Ok(self.geometry.block_offset(table) + index as u64 * self.geometry.inode_size as u64)
+    }
+
+    /// The current content of the inode `number`
+    fn load(&self, inner: &mut Ext2Inner, number: u32) -> Result<DiskInode, FsError>
+    {
+        if let Some(node) = inner.nodes.get(&number).and_then(Weak::upgrade)
+        {
+            return Ok(node.state.lock().inode.clone());
+        }
+
+        let mut data = [0u8; INODE_FIELDS_SIZE];
+        inner.volume.read(self.inode_offset(inner, number)?, &mut data)?;
+        Ok(DiskInode::parse(&data, self.large_files))
+    }
+
+    /// Writes the inode `number`, and updates its node
+    fn store(&self, inner: &mut Ext2Inner, number: u32, inode: &DiskInode) -> Result<(), FsError>
+    {
+        let offset = self.inode_offset(inner, number)?;
+        let mut data = [0u8; INODE_FIELDS_SIZE];
+        inner.volume.read(offset, &mut data)?;
+        inode.encode(&mut data);
+        inner.volume.write(offset, &data)?;
+
+        if number == ROOT_INODE
+        {
+            inner.root = inode.clone();
+        }
+        if let Some(node) = inner.nodes.get(&number).and_then(Weak::upgrade)
+        {
+            node.state.lock().inode = inode.clone();
+        }
+        Ok(())
+    }
+
+    fn write_group(&self, inner: &mut Ext2Inner, group: u32) -> Result<(), FsError>
+    {
+        let offset = self.geometry.group_table_offset() + group as u64 * GROUP_DESCRIPTOR_SIZE as u64 + GroupDescriptor::COUNTS_OFFSET;
+        let counts = inner.groups[group as usize].encode_counts();
+        inner.volume.write(offset, &counts)?;
+        inner.dirty = true;
+        Ok(())
+    }
+
+    /// Allocates a block filled with zeroes, preferably in the group `goal`
+    fn allocate_block(&self, inner: &mut Ext2Inner, goal: u32) -> Result<u32, FsError>
+    {
+        if inner.superblock.free_blocks_count == 0
+        {
+            return Err(FsError::NoSpace);
+        }
+
+        let group_count = self.geometry.group_count();
+        for i in 0..group_count
+        {
+            let group = (goal + i) % group_count;
+            if inner.groups[group as usize].free_blocks_count == 0
+            {
+                continue;
+            }
+
+            let bitmap_offset = self.geometry.block_offset(inner.groups[group as usize].block_bitmap);
+            let mut bitmap = vec![0u8; self.block_size() as usize];
+            inner.volume.read(bitmap_offset, &mut bitmap)?;
+            let bit = find_clear_bit(&bitmap, self.geometry.blocks_in_group(group) as usize)
+                .ok_or_else(|| FsError::Corrupted(format!("ext2 group {}: no free block in the bitmap", group)))?;
+
+            inner.volume.write(bitmap_offset + bit as u64 / 8, &[bitmap[bit / 8] | 1 << (bit % 8)])?;
+            inner.groups[group as usize].free_blocks_count -= 1;
+            inner.superblock.free_blocks_count -= 1;
+            self.write_group(inner, group)?;
+
+            let block = self.geometry.first_data_block + group * self.geometry.blocks_per_group + bit as u32;
+            inner.volume.zero(self.geometry.block_offset(block), self.block_size())?;
+            return Ok(block);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, inner: &mut Ext2Inner, block: u32) -> Result<(), FsError>
+    {
+        if block < self.geometry.first_data_block || block >= self.geometry.blocks_count
+        {
+            return Err(FsError::Corrupted(format!("ext2: freeing invalid block {}", block)));
+        }
+
+        let group = (block - self.geometry.first_data_block) / self.geometry.blocks_per_group;
+        let bit = (block - self.geometry.first_data_block) % self.geometry.blocks_per_group;
+        let offset = self.geometry.block_offset(inner.groups[group as usize].block_bitmap) + bit as u64 / 8;
+        let mut byte = [0u8];
+        inner.volume.read(offset, &mut byte)?;
+        if byte[0] & 1 << (bit % 8) == 0
+        {
+            return Err(FsError::Corrupted(format!("ext2: block {} freed twice", block)));
+        }
+
+        inner.volume.write(offset, &[byte[0] & !(1 << (bit % 8))])?;
+        inner.groups[group as usize].free_blocks_count += 1;
+        inner.superblock.free_blocks_count += 1;
+        self.write_group(inner, group)
+    }
+
+    /// Allocates an inode. Directories go to the group with the most free inodes and blocks among those
+    /// with an average number of free inodes, files to the group of their directory if possible.
+    fn allocate_inode(&self, inner: &mut Ext2Inner, parent_group: u32, directory: bool) -> Result<u32, FsError>
+    {
+        if inner.superblock.free_inodes_count == 0
+        {
+            return Err(FsError::NoSpace);
+        }
+
+        let group_count = self.geometry.group_count();
+        let mut candidates: Vec<u32> = (0..group_count).map(|i| (parent_group + i) % group_count).collect();
+        if directory
+        {
+            let average = inner.superblock.free_inodes_count / group_count;
+            let groups = &inner.groups;
+            candidates.sort_by_key(|&group| {
+                let descriptor = &groups[group as usize];
+                ((descriptor.free_inodes_count as u32) < average, core::cmp::Reverse(descriptor.free_blocks_count))
+            });
+        }
+
+        for group in candidates
+        {
+            if inner.groups[group as usize].free_inodes_count == 0
+            {
+                continue;
+            }
+
+            let bitmap_offset = self.geometry.block_offset(inner.groups[group as usize].inode_bitmap);
+            let mut bitmap = vec![0u8; self.block_size() as usize];
+            inner.volume.read(bitmap_offset, &mut bitmap)?;
+
+            // The reserved inodes are marked used by mke2fs, they are skipped anyway
+            let first = group * self.geometry.inodes_per_group;
+            let reserved = self.geometry.first_inode.saturating_sub(first + 1) as usize;
+            let bit = (reserved..self.geometry.inodes_per_group as usize)
+                .find(|&bit| bitmap[bit / 8] & 1 << (bit % 8) == 0)
+                .ok_or_else(|| FsError::Corrupted(format!("ext2 group {}: no free inode in the bitmap", group)))?;
+
+            inner.volume.write(bitmap_offset + bit as u64 / 8, &[bitmap[bit / 8] | 1 << (bit % 8)])?;
+            let descriptor = &mut inner.groups[group as usize];
+            descriptor.free_inodes_count -= 1;
+            if directory
+            {
+                descriptor.used_dirs_count += 1;
+            }
+            inner.superblock.free_inodes_count -= 1;
+            self.write_group(inner, group)?;
+
+            let number = first + bit as u32 + 1;
+            let offset = self.inode_offset(inner, number)?;
+            inner.volume.zero(offset, self.geometry.inode_size as u64)?;
+            return Ok(number);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, inner: &mut Ext2Inner, number: u32, directory: bool) -> Result<(), FsError>
+    {
+        let group = self.group_of_inode(number);
+        let bit = (number - 1) % self.geometry.inodes_per_group;
+        let offset = self.geometry.block_offset(inner.groups[group as usize].inode_bitmap) + bit as u64 / 8;
+        let mut byte = [0u8];
+        inner.volume.read(offset, &mut byte)?;
+        if byte[0] & 1 << (bit % 8) == 0
+        {
+            return Err(FsError::Corrupted(format!("ext2: inode {} freed twice", number)));
+        }
+
+        inner.volume.write(offset, &[byte[0] & !(1 << (bit % 8))])?;
+        let descriptor = &mut inner.groups[group as usize];
+        descriptor.free_inodes_count += 1;
+        if directory
+        {
+            descriptor.used_dirs_count = descriptor.used_dirs_count.saturating_sub(1);
+        }
+        inner.superblock.free_inodes_count += 1;
+        self.write_group(inner, group)
+    }
+
+    /// The block holding the block `index` of `inode`, `None` for a hole. With `allocate`, holes are filled
+    /// with blocks allocated near the inode `number`, and the block count of the inode is updated.
+    fn map_block(&self, inner: &mut Ext2Inner, number: u32, inode: &mut DiskInode, index: u64, allocate: bool) -> Result<Option<u32>, FsError>
+    {
+        let pointers = self.pointers_per_block();
+        let mut path = [0u64; 3];
+        let (root, depth) = if index < DIRECT_BLOCKS as u64
+        {
+            (index as usize, 0)
+        }
+        else
+        {
+            // The indirection level of each root pointer maps the following span of blocks
+            let mut index = index - DIRECT_BLOCKS as u64;
+            let mut span = pointers;
+            let mut level = 1;
+            while index >= span
+            {
+                index -= span;
+                span *= pointers;
+                level += 1;
+                if level > 3
+                {
+                    return Err(FsError::NoSpace);
+                }
+            }
+            for i in (0..level).rev()
+            {
+                path[level - 1 - i] = index / pointers.pow(i as u32) % pointers;
+            }
+            (DIRECT_BLOCKS + level - 1, level)
+        };
+
+        let goal = self.group_of_inode(number);
+        let mut block = inode.blocks[root];
+        if block == 0
+        {
+            if !allocate
+            {
+                return Ok(None);
+            }
+            block = self.allocate_block(inner, goal)?;
+            inode.blocks[root] = block;
+            inode.sectors += self.sectors_per_block();
+        }
+
+        for &entry in path[..depth].iter()
+        {
+            if block >= self.geometry.blocks_count
+            {
+                return Err(FsError::Corrupted(format!("ext2 inode {}: invalid block {}", number, block)));
+            }
+
+            let offset = self.geometry.block_offset(block) + entry * 4;
+            let mut next = inner.volume.read_u32(offset)?;
+            if next == 0
+            {
+                if !allocate
+                {
+                    return Ok(None);
+                }
+                next = self.allocate_block(inner, goal)?;
+                inner.volume.write(offset, &next.to_le_bytes())?;
+                inode.sectors += self.sectors_per_block();
+            }
+            block = next;
+        }
+
+        if block >= self.geometry.blocks_count
+        {
+            return Err(FsError::Corrupted(format!("ext2 inode {}: invalid block {}", number, block)));
+        }
+        Ok(Some(block))
+    }
+
+    /// Frees the blocks mapped by the indirect block `block` of indirection `level` from its block `from` on,
+    /// and `block` itself when `from` is 0. Returns the number of freed blocks.
+    fn truncate_tree(&self, inner: &mut Ext2Inner, block: u32, level: u32, from: u64) -> Result<u32, FsError>
+    {
+        let span = self.pointers_per_block().pow(level - 1);
+        let mut data = vec![0u8; self.block_size() as usize];
+        inner.volume.read(self.geometry.block_offset(block), &mut data)?;
+
+        let mut freed = 0;
+        let mut changed = false;
+        for (index, pointer) in data.chunks_exact_mut(4).enumerate()
+        {
+            let child = u32::from_le_bytes([pointer[0], pointer[1], pointer[2], pointer[3]]);
+            let base = index as u64 * span;
+            if child == 0 || base + span <= from
+            {
+                continue;
+            }
+
+            let child_from = from.saturating_sub(base);
+            if level == 1
+            {
+                self.free_block(inner, child)?;
+                freed += 1;
+            }
+            else
+            {
+                freed += self.truncate_tree(inner, child, level - 1, child_from)?;
+            }
+            if child_from == 0
+            {
+                pointer.fill(0);
+                changed = true;
+            }
+        }
+
+        if from == 0
+        {
+            self.free_block(inner, block)?;
+            freed += 1;
+        }
+        else if changed
+        {
+            inner.volume.write(self.geometry.block_offset(block), &data)?;
+        }
+        Ok(freed)
+    }
+
+    /// Frees the blocks of `inode` from its block `from` on
+    fn truncate_blocks(&self, inner: &mut Ext2Inner, inode: &mut DiskInode, from: u64) -> Result<(), FsError>
+    {
+        let mut freed = 0;
+        for index in (from as usize)..DIRECT_BLOCKS
+        {
+            if inode.blocks[index] != 0
+            {
+                self.free_block(inner, inode.blocks[index])?;
+                inode.blocks[index] = 0;
+                freed += 1;
+            }
+        }
+
+        let mut base = DIRECT_BLOCKS as u64;
+        let mut span = self.pointers_per_block();
+        for level in 1..=3
+        {
+            let root = DIRECT_BLOCKS + level as usize - 1;
+            if inode.blocks[root] != 0 && from < base + span
+            {
+                let relative = from.saturating_sub(base);
+                freed += self.truncate_tree(inner, inode.blocks[root], level, relative)?;
+                if relative == 0
+                {
+                    inode.blocks[root] = 0;
+                }
+            }
+            base += span;
+            span *= self.pointers_per_block();
+        }
+
+        inode.sectors = inode.sectors.saturating_sub(freed * self.sectors_per_block());
+        Ok(())
+    }
+
+    fn read_data(&self, inner: &mut Ext2Inner, number: u32, inode: &DiskInode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        if offset >= inode.size
+        {
+            return Ok(0);
+        }
+
+        let mut inode = inode.clone();
+        let length = core::cmp::min(buffer.len() as u64, inode.size - offset) as usize;
+        let block_size = self.block_size();
+        let mut done = 0;
+        while done < length
+        {
+            let position = offset + done as u64;
+            let start = position % block_size;
+            let chunk = core::cmp::min((block_size - start) as usize, length - done);
+
+            // Holes read as zeroes
+            match self.map_block(inner, number, &mut inode, position / block_size, false)?
+            {
+                Some(block) => inner.volume.read(self.geometry.block_offset(block) + start, &mut buffer[done..done + chunk])?,
+                None => buffer[done..done + chunk].fill(0)
+            }
+            done += chunk;
+        }
+        Ok(length)
+    }
+
+    /// Writes `buffer` at `offset` in the inode `number`, allocating its blocks, and stores the inode
+    fn write_data(&self, inner: &mut Ext2Inner, number: u32, inode: &mut DiskInode, offset: u64, buffer: &[u8]) -> Result<(), FsError>
+    {
+        let block_size = self.block_size();
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buffer.len()
+        {
+            let position = offset + done as u64;
+            let start = position % block_size;
+            let chunk = core::cmp::min((block_size - start) as usize, buffer.len() - done);
+
+            result = self.map_block(inner, number, inode, position / block_size, true)
+                .and_then(|block| inner.volume.write(self.geometry.block_offset(block.unwrap()) + start, &buffer[done..done + chunk]));
+            if result.is_err()
+            {
+                break;
+            }
+            done += chunk;
+        }
+
+        // The blocks allocated before a failure belong to the inode, and have to be stored with it
+        inode.size = core::cmp::max(inode.size, offset + done as u64);
+        self.store(inner, number, inode)?;
+        result
+    }
+
+    /// Sets the size of the inode `number`, freeing the blocks past the end and zeroing the end of the last one
+    fn resize(&self, inner: &mut Ext2Inner, number: u32, inode: &mut DiskInode, size: u64) -> Result<(), FsError>
+    {
+        if size < inode.size
+        {
+            let block_size = self.block_size();
+            self.truncate_blocks(inner, inode, (size + block_size - 1) / block_size)?;
+            if size % block_size != 0
+            {
+                if let Some(block) = self.map_block(inner, number, inode, size / block_size, false)?
+                {
+                    inner.volume.zero(self.geometry.block_offset(block) + size % block_size, block_size - size % block_size)?;
+                }
+            }
+        }
+
+        inode.size = size;
+        self.store(inner, number, inode)
+    }
+
+    /// The records of the directory `number`, with the block holding each of them
+    fn read_records(&self, inner: &mut Ext2Inner, number: u32) -> Result<Vec<(u32, Record)>, FsError>
+    {
+        let mut inode = self.load(inner, number)?;
+        let block_size = self.block_size();
+        let mut data = vec![0u8; block_size as usize];
+        let mut records = Vec::new();
+        for index in 0..(inode.size + block_size - 1) / block_size
+        {
+            if let Some(block) = self.map_block(inner, number, &mut inode, index, false)?
+            {
+                inner.volume.read(self.geometry.block_offset(block), &mut data)?;
+                records.extend(directory::parse_block(&data, self.file_types)?.into_iter().map(|record| (block, record)));
+            }
+        }
+        Ok(records)
+    }
+
+    fn find_record(&self, inner: &mut Ext2Inner, directory: u32, name: &str) -> Result<Option<(u32, Record)>, FsError>
+    {
+        Ok(self.read_records(inner, directory)?.into_iter().find(|(_, record)| record.inode != 0 && record.name == name.as_bytes()))
+    }
+
+    /// Adds the entry `name` for the inode `inode` to the directory `directory`, in the first record with
+    /// enough unused space or in a new block
+    fn add_record(&self, inner: &mut Ext2Inner, directory: u32, name: &str, inode: u32, file_type: u8) -> Result<(), FsError>
+    {
+        let needed = directory::record_size(name.len());
+        let block_size = self.block_size();
+        let mut data = vec![0u8; block_size as usize];
+        let mut directory_inode = self.load(inner, directory)?;
+        let blocks = (directory_inode.size + block_size - 1) / block_size;
+
+        for index in 0..blocks
+        {
+            let block = match self.map_block(inner, directory, &mut directory_inode, index, false)?
+            {
+                Some(block) => block,
+                None => continue
+            };
+            inner.volume.read(self.geometry.block_offset(block), &mut data)?;
+
+            for record in directory::parse_block(&data, self.file_types)?
+            {
+                let used = record.used_length();
+                if record.length - used < needed
+                {
+                    continue;
+                }
+
+                // A used record gives the end of its space to the new one
+                if used != 0
+                {
+                    data[record.offset + 4..record.offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                }
+                directory::encode(&mut data[record.offset + used..], inode, record.length - used, name.as_bytes(), file_type, self.file_types);
+                return inner.volume.write(self.geometry.block_offset(block), &data);
+            }
+        }
+
+        let block = self.map_block(inner, directory, &mut directory_inode, blocks, true)?.unwrap();
+        data.fill(0);
+        directory::encode(&mut data, inode, block_size as usize, name.as_bytes(), file_type, self.file_types);
+        inner.volume.write(self.geometry.block_offset(block), &data)?;
+        directory_inode.size = (blocks + 1) * block_size;
+        self.store(inner, directory, &directory_inode)
+    }
+
+    /// Removes the record at `offset` in the directory block `block`, merging it with the previous record
+    fn remove_record(&self, inner: &mut Ext2Inner, block: u32, offset: usize) -> Result<(), FsError>
+    {
+        let mut data = vec![0u8; self.block_size() as usize];
+        inner.volume.read(self.geometry.block_offset(block), &mut data)?;
+        let records = directory::parse_block(&data, self.file_types)?;
+
+        match records.iter().find(|record| record.offset + record.length == offset)
+        {
+            Some(previous) => {
+                let removed = records.iter().find(|record| record.offset == offset).unwrap();
+                let length = (previous.length + removed.length) as u16;
+                data[previous.offset + 4..previous.offset + 6].copy_from_slice(&length.to_le_bytes());
+            }
+            // The first record of a block is only marked unused
+            None => data[offset..offset + 4].fill(0)
+        }
+        inner.volume.write(self.geometry.block_offset(block), &data)
+    }
+
+    fn is_empty_directory(&self, inner: &mut Ext2Inner, number: u32) -> Result<bool, FsError>
+    {
+        Ok(self.read_records(inner, number)?.iter().all(|(_, record)| record.inode == 0 || record.is_dot()))
+    }
+
+    fn adjust_links(&self, inner: &mut Ext2Inner, number: u32, delta: i16) -> Result<(), FsError>
+    {
+        let mut inode = self.load(inner, number)?;
+        inode.links = (inode.links as i16 + delta) as u16;
+        self.store(inner, number, &inode)
+    }
+
+    /// Removes a link to the inode `number`, releasing it with its blocks once it has none. A directory loses
+    /// all its links at once, its entry and `.`.
+    fn unlink(&self, inner: &mut Ext2Inner, number: u32) -> Result<(), FsError>
+    {
+        let mut inode = self.load(inner, number)?;
+        inode.links = if inode.is_directory() { 0 } else { inode.links.saturating_sub(1) };
+        if inode.links != 0
+        {
+            return self.store(inner, number, &inode);
+        }
+
+        if inode.is_fast_symlink()
+        {
+            inode.blocks = [0; BLOCK_POINTERS];
+        }
+        else
+        {
+            self.truncate_blocks(inner, &mut inode, 0)?;
+        }
+        inode.size = 0;
+        // There is no wall clock, but e2fsck expects a deletion time on released inodes
+        inode.deleted = core::cmp::max(inode.changed, 1);
+        self.store(inner, number, &inode)?;
+        self.free_inode(inner, number, inode.is_directory())?;
+
+        if let Some(node) = inner.nodes.remove(&number).and_then(|node| node.upgrade())
+        {
+            node.state.lock().deleted = true;
+        }
+        Ok(())
+    }
+
+    /// Creates an inode of mode `mode` linked as `name` in the directory `parent`
+    fn create_inode(&self, inner: &mut Ext2Inner, parent: u32, name: &str, mode: u16, content: &[u8]) -> Result<u32, FsError>
+    {
+        if self.find_record(inner, parent, name)?.is_some()
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let is_directory = mode & inode::MODE_TYPE_MASK == MODE_DIRECTORY;
+        let number = self.allocate_inode(inner, self.group_of_inode(parent), is_directory)?;
+        let mut inode = DiskInode::new(mode);
+
+        let result = if is_directory
+        {
+            // A directory starts with `.` and `..`
+            let block_size = self.block_size() as usize;
+            let mut data = vec![0u8; block_size];
+            let file_type = directory::file_type_of(MODE_DIRECTORY);
+            let dot_length = directory::record_size(1);
+            directory::encode(&mut data, number, dot_length, b".", file_type, self.file_types);
+            directory::encode(&mut data[dot_length..], parent, block_size - dot_length, b"..", file_type, self.file_types);
+            inode.links = 2;
+            self.write_data(inner, number, &mut inode, 0, &data)
+        }
+        else if mode & inode::MODE_TYPE_MASK == MODE_SYMLINK && content.len() < FAST_SYMLINK_MAX_LENGTH
+        {
+            inode.set_inline_data(content);
+            inode.size = content.len() as u64;
+            self.store(inner, number, &inode)
+        }
+        else
+        {
+            self.write_data(inner, number, &mut inode, 0, content)
+        };
+
+        if let Err(e) = result.and_then(|_| self.add_record(inner, parent, name, number, directory::file_type_of(mode)))
+        {
+            self.unlink(inner, number)?;
+            return Err(e);
+        }
+
+        // The `..` of a directory is a link to its parent
+        if is_directory
+        {
+            self.adjust_links(inner, parent, 1)?;
+        }
+        Ok(number)
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), FsError>
+{
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0')
+    {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > MAX_NAME_LENGTH
+    {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+impl FileSystem for Ext2FileSystem
+{
+    fn name(&self) -> &str
+    {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode>
+    {
+        let mut inner = self.inner.lock();
+        if let Some(node) = inner.nodes.get(&ROOT_INODE).and_then(Weak::upgrade)
+        {
+            return node;
+        }
+        let inode = inner.root.clone();
+        self.insert_node(&mut inner, ROOT_INODE, inode)
+    }
+
+    fn sync(&self) -> Result<(), FsError>
+    {
+        let mut inner = self.inner.lock();
+        if inner.dirty
+        {
+            let counts = inner.superblock.encode_counts();
+            inner.volume.write(SUPERBLOCK_OFFSET + Superblock::COUNTS_OFFSET, &counts)?;
+            inner.dirty = false;
+        }
+        inner.volume.sync()
+    }
+
+    fn stats(&self) -> Result<FsStats, FsError>
+    {
+        let inner = self.inner.lock();
+        Ok(FsStats {
+            block_size: self.geometry.block_size as usize,
+            total_blocks: self.geometry.blocks_count as u64,
+            free_blocks: inner.superblock.free_blocks_count as u64,
+            total_inodes: self.geometry.inodes_count as u64,
+            free_inodes: inner.superblock.free_inodes_count as u64
+        })
+    }
+}
+
+struct NodeState
+{
+    inode: DiskInode,
+    deleted: bool
+}
+
+/// An inode of an ext2 filesystem
+pub struct Ext2Node
+{
+    fs: Arc<Ext2FileSystem>,
+    number: u32,
+    state: Mutex<NodeState>
+}
+
+impl Ext2Node
+{
+    /// Locks the filesystem, and loads the inode unless it was deleted
+    fn lock(&self) -> Result<(MutexGuard<'_, Ext2Inner>, DiskInode), FsError>
+    {
+        let inner = self.fs.inner.lock();
+        let state = self.state.lock();
+        if state.deleted
+        {
+            return Err(FsError::NotFound);
+        }
+        let inode = state.inode.clone();
+        drop(state);
+        Ok((inner, inode))
+    }
+}
+
+impl Inode for Ext2Node
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        let (_inner, inode) = self.lock()?;
+        Ok(Metadata {
+            inode: self.number as u64,
+            file_type: inode.file_type(),
+            size: inode.size,
+            mode: inode.mode & 0o7777,
+            links: inode.links as u32,
+            accessed: inode.accessed as u64,
+            modified: inode.modified as u64,
+            created: inode.changed as u64
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File>
+    {
+        if self.state.lock().inode.mode & inode::MODE_TYPE_MASK == MODE_REGULAR { Some(self) } else { None }
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory>
+    {
+        if self.state.lock().inode.is_directory() { Some(self) } else { None }
+    }
+
+    fn read_link(&self) -> Result<String, FsError>
+    {
+        let (mut inner, inode) = self.lock()?;
+        if inode.file_type() != FileType::Symlink
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if inode.is_fast_symlink()
+        {
+            return Ok(String::from_utf8_lossy(&inode.inline_data()[..inode.size as usize]).into_owned());
+        }
+        let mut target = vec![0u8; core::cmp::min(inode.size, self.fs.block_size()) as usize];
+        let length = self.fs.read_data(&mut inner, self.number, &inode, 0, &mut target)?;
+        Ok(String::from_utf8_lossy(&target[..length]).into_owned())
+    }
+
+    fn sync(&self) -> Result<(), FsError>
+    {
+        self.fs.sync()
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl File for Ext2Node
+{
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let (mut inner, inode) = self.lock()?;
+        self.fs.read_data(&mut inner, self.number, &inode, offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        let (mut inner, mut inode) = self.lock()?;
+        self.fs.check_writable()?;
+        if buffer.is_empty()
+        {
+            return Ok(0);
+        }
+        if offset.checked_add(buffer.len() as u64).map_or(true, |end| end > self.fs.max_file_size())
+        {
+            return Err(FsError::NoSpace);
+        }
+
+        self.fs.write_data(&mut inner, self.number, &mut inode, offset, buffer)?;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError>
+    {
+        let (mut inner, mut inode) = self.lock()?;
+        self.fs.check_writable()?;
+        if size > self.fs.max_file_size()
+        {
+            return Err(FsError::NoSpace);
+        }
+        self.fs.resize(&mut inner, self.number, &mut inode, size)
+    }
+}
+
+impl Directory for Ext2Node
+{
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        let (mut inner, _) = self.lock()?;
+        let (_, record) = self.fs.find_record(&mut inner, self.number, name)?.ok_or(FsError::NotFound)?;
+        Ok(self.fs.node(&mut inner, record.inode)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>
+    {
+        let (mut inner, _) = self.lock()?;
+        let records = self.fs.read_records(&mut inner, self.number)?;
+
+        let mut entries = Vec::new();
+        for (_, record) in records.into_iter().filter(|(_, record)| record.inode != 0 && !record.is_dot())
+        {
+            // Without the file type feature, the type is in the inode
+            let file_type = match record.file_type
+            {
+                2 => FileType::Directory,
+                7 => FileType::Symlink,
+                3 => FileType::CharDevice,
+                4 => FileType::BlockDevice,
+                0 => self.fs.load(&mut inner, record.inode)?.file_type(),
+                _ => FileType::Regular
+            };
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(&record.name).into_owned(),
+                inode: record.inode as u64,
+                file_type
+            });
+        }
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError>
+    {
+        validate_name(name)?;
+        let (mut inner, _) = self.lock()?;
+        self.fs.check_writable()?;
+
+        let mode = match file_type
+        {
+            FileType::Regular => MODE_REGULAR | 0o644,
+            FileType::Directory => MODE_DIRECTORY | 0o755,
+            _ => return Err(FsError::NotSupported)
+        };
+        let number = self.fs.create_inode(&mut inner, self.number, name, mode, &[])?;
+        Ok(self.fs.node(&mut inner, number)?)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        validate_name(name)?;
+        if target.len() >= self.fs.block_size() as usize
+        {
+            return Err(FsError::NameTooLong);
+        }
+        let (mut inner, _) = self.lock()?;
+        self.fs.check_writable()?;
+
+        let number = self.fs.create_inode(&mut inner, self.number, name, MODE_SYMLINK | 0o777, target.as_bytes())?;
+        Ok(self.fs.node(&mut inner, number)?)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError>
+    {
+        let (mut inner, _) = self.lock()?;
+        self.fs.check_writable()?;
+
+        let (block, record) = self.fs.find_record(&mut inner, self.number, name)?.ok_or(FsError::NotFound)?;
+        let is_directory = self.fs.load(&mut inner, record.inode)?.is_directory();
+        if is_directory && !self.fs.is_empty_directory(&mut inner, record.inode)?
+        {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.fs.remove_record(&mut inner, block, record.offset)?;
+        if is_directory
+        {
+            self.fs.adjust_links(&mut inner, self.number, -1)?;
+        }
+        self.fs.unlink(&mut inner, record.inode)
+    }
+
+    fn rename(&self, name: &str, target: &dyn Inode, new_name: &str) -> Result<(), FsError>
+    {
+        validate_name(new_name)?;
+        let target = target.as_any().downcast_ref::<Ext2Node>()
+            .filter(|target| Arc::ptr_eq(&target.fs, &self.fs))
+            .ok_or(FsError::CrossDevice)?;
+
+        let (mut inner, _) = self.lock()?;
+        self.fs.check_writable()?;
+        let target_inode = self.fs.load(&mut inner, target.number)?;
+        if target.state.lock().deleted
+        {
+            return Err(FsError::NotFound);
+        }
+        if !target_inode.is_directory()
+        {
+            return Err(FsError::NotADirectory);
+        }
+
+        let (_, record) = self.fs.find_record(&mut inner, self.number, name)?.ok_or(FsError::NotFound)?;
+        let source = self.fs.load(&mut inner, record.inode)?;
+
+        // An existing file is replaced, a directory never is
+        if let Some((block, existing)) = self.fs.find_record(&mut inner, target.number, new_name)?
+        {
+            if existing.inode == record.inode
+            {
+                return if self.number == target.number && name == new_name
+                {
+                    Ok(())
+                }
+                else
+                {
+                    // Both names are links to the same inode, the old one goes away
+                    let (block, record) = self.fs.find_record(&mut inner, self.number, name)?.unwrap();
+                    self.fs.remove_record(&mut inner, block, record.offset)?;
+                    self.fs.unlink(&mut inner, record.inode)
+                };
+            }
+            if self.fs.load(&mut inner, existing.inode)?.is_directory()
+            {
+                return Err(FsError::AlreadyExists);
+            }
+            if source.is_directory()
+            {
+                return Err(FsError::NotADirectory);
+            }
+            self.fs.remove_record(&mut inner, block, existing.offset)?;
+            self.fs.unlink(&mut inner, existing.inode)?;
+        }
+
+        self.fs.add_record(&mut inner, target.number, new_name, record.inode, directory::file_type_of(source.mode))?;
+        // Adding the new record may have moved the old one
+        let (block, old) = self.fs.read_records(&mut inner, self.number)?.into_iter()
+            .find(|(_, old)| old.inode == record.inode && old.name == name.as_bytes())
+            .unwrap();
+        self.fs.remove_record(&mut inner, block, old.offset)?;
+
+        // A moved directory's `..` points to its new parent, which gets the link of the old one
+        if source.is_directory() && self.number != target.number
+        {
+            let (block, dot_dot) = self.fs.find_record(&mut inner, record.inode, "..")?
+                .ok_or_else(|| FsError::Corrupted(format!("ext2 inode {}: directory without ..", record.inode)))?;
+            inner.volume.write(self.fs.geometry.block_offset(block) + dot_dot.offset as u64, &target.number.to_le_bytes())?;
+            self.fs.adjust_links(&mut inner, self.number, -1)?;
+            self.fs.adjust_links(&mut inner, target.number, 1)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use alloc::format;
+use alloc::string::String;
+
+use crate::fs::FsError;
+
+/// Offset of the superblock from the start of the volume, whatever the block size
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+pub const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+/// Filesystem states
+pub const STATE_CLEAN: u16 = 1;
+pub const STATE_ERRORS: u16 = 2;
+
+/// Revision 0 has fixed 128 byte inodes and no feature flags
+const GOOD_OLD_REVISION: u32 = 0;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+
+/// Incompatible features: directory entries hold the file type
+pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+/// Read-only compatible features: backup superblocks in some groups only, and files over 2 GiB
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+#[derive(Debug, Clone)]
+pub struct Superblock
+{
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub state: u16,
+    pub revision: u32,
+    /// First inode available to files, the ones below are reserved
+    pub first_inode: u32,
+    pub inode_size: u32,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub volume_name: String
+}
+
+impl Superblock
+{
+    pub fn parse(data: &[u8; SUPERBLOCK_SIZE]) -> Result<Self, FsError>
+    {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let invalid = |reason: &str| FsError::Corrupted(format!("ext2 superblock: {}", reason));
+
+        if u16_at(56) != MAGIC
+        {
+            return Err(FsError::UnknownFileSystem);
+        }
+
+        let log_block_size = u32_at(24);
+        if log_block_size > 6
+        {
+            return Err(invalid("invalid block size"));
+        }
+        let revision = u32_at(76);
+        let (first_inode, inode_size, feature_compat, feature_incompat, feature_ro_compat) = if revision == GOOD_OLD_REVISION
+        {
+            (GOOD_OLD_FIRST_INODE, GOOD_OLD_INODE_SIZE, 0, 0, 0)
+        }
+        else
+        {
+            (u32_at(84), u16_at(88) as u32, u32_at(92), u32_at(96), u32_at(100))
+        };
+
+        let superblock = Superblock {
+            inodes_count: u32_at(0),
+            blocks_count: u32_at(4),
+            free_blocks_count: u32_at(12),
+            free_inodes_count: u32_at(16),
+            first_data_block: u32_at(20),
+            block_size: 1024 << log_block_size,
+            blocks_per_group: u32_at(32),
+            inodes_per_group: u32_at(40),
+            state: u16_at(58),
+            revision,
+            first_inode,
+            inode_size,
+            feature_compat,
+            feature_incompat,
+            feature_ro_compat,
+            volume_name: String::from(String::from_utf8_lossy(&data[120..136]).trim_end_matches('\0'))
+        };
+        superblock.check()?;
+        Ok(superblock)
+    }
+
+    /// Checks the geometry of the filesystem is consistent
+    fn check(&self) -> Result<(), FsError>
+    {
+        let invalid = |reason: String| Err(FsError::Corrupted(format!("ext2 superblock: {}", reason)));
+
+        if self.feature_incompat & !INCOMPAT_SUPPORTED != 0
+        {
+            return invalid(format!("unsupported incompatible features {:#x}", self.feature_incompat & !INCOMPAT_SUPPORTED));
+        }
+        if self.first_data_block != if self.block_size == 1024 { 1 } else { 0 }
+        {
+            return invalid(format!("first data block {} with blocks of {} bytes", self.first_data_block, self.block_size));
+        }
+        if self.blocks_per_group == 0 || self.blocks_per_group > self.block_size * 8
+            || self.inodes_per_group == 0 || self.inodes_per_group > self.block_size * 8
+        {
+            return invalid(format!("{} blocks and {} inodes per group", self.blocks_per_group, self.inodes_per_group));
+        }
+        if self.inode_size < GOOD_OLD_INODE_SIZE || !self.inode_size.is_power_of_two() || self.inode_size > self.block_size
+        {
+            return invalid(format!("invalid inode size {}", self.inode_size));
+        }
+        if self.blocks_count <= self.first_data_block || self.first_inode <= super::ROOT_INODE
+        {
+            return invalid(String::from("invalid layout"));
+        }
+        if self.inodes_per_group.checked_mul(self.group_count()) != Some(self.inodes_count)
+        {
+            return invalid(format!("{} inodes in {} groups of {}", self.inodes_count, self.group_count(), self.inodes_per_group));
+        }
+        if self.free_blocks_count > self.blocks_count || self.free_inodes_count > self.inodes_count
+        {
+            return invalid(String::from("more free blocks or inodes than the filesystem holds"));
+        }
+        Ok(())
+    }
+
+    /// Features of the filesystem unknown to the driver which prevent writing to it
+    pub fn unsupported_read_only_features(&self) -> u32
+    {
+        self.feature_ro_compat & !RO_COMPAT_SUPPORTED
+    }
+
+    #[inline]
+    pub fn group_count(&self) -> u32
+    {
+        (self.blocks_count - self.first_data_block - 1) / self.blocks_per_group + 1
+    }
+
+    /// Byte offset of the block `block`
+    #[inline]
+    pub fn block_offset(&self, block: u32) -> u64
+    {
+        block as u64 * self.block_size as u64
+    }
+
+    /// Byte offset of the group descriptor table, in the block following the superblock
+    pub fn group_table_offset(&self) -> u64
+    {
+        self.block_offset(self.first_data_block + 1)
+    }
+
+    /// Number of blocks of the group `group`, the last one may be shorter
+    pub fn blocks_in_group(&self, group: u32) -> u32
+    {
+        core::cmp::min(self.blocks_per_group, self.blocks_count - self.first_data_block - group * self.blocks_per_group)
+    }
+
+    /// Offset of the free counts in the superblock, and their encoding
+    pub const COUNTS_OFFSET: u64 = 12;
+
+    pub fn encode_counts(&self) -> [u8; 8]
+    {
+        let mut data = [0u8; 8];
+        data[0..4].copy_from_slice(&self.free_blocks_count.to_le_bytes());
+        data[4..8].copy_from_slice(&self.free_inodes_count.to_le_bytes());
+        data
+    }
+}
+
+pub const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct GroupDescriptor
+{
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16
+}
+
+impl GroupDescriptor
+{
+    pub fn parse(data: &[u8]) -> Self
+    {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+
+        GroupDescriptor {
+            block_bitmap: u32_at(0),
+            inode_bitmap: u32_at(4),
+            inode_table: u32_at(8),
+            free_blocks_count: u16_at(12),
+            free_inodes_count: u16_at(14),
+            used_dirs_count: u16_at(16)
+        }
+    }
+
+    /// Offset of the counts in a descriptor, and their encoding. The block numbers never change.
+    pub const COUNTS_OFFSET: u64 = 12;
+
+    pub fn encode_counts(&self) -> [u8; 6]
+    {
+        let mut data = [0u8; 6];
+        data[0..2].copy_from_slice(&self.free_blocks_count.to_le_bytes());
+        data[2..4].copy_from_slice(&self.free_inodes_count.to_le_bytes());
+        data[4..6].copy_from_slice(&self.used_dirs_count.to_le_bytes());
+        data
+    }
+
+    /// Checks the bitmaps and the inode table of the group `group` are in the group
+    pub fn check(&self, superblock: &Superblock, group: u32) -> Result<(), FsError>
+    {
+        let first = superblock.first_data_block + group * superblock.blocks_per_group;
+        let end = first + superblock.blocks_in_group(group);
+        let table_size = superblock.inodes_per_group as u64 * superblock.inode_size as u64;
+        let table_blocks = (table_size + superblock.block_size as u64 - 1) / superblock.block_size as u64;
+
+        let in_group = |block: u32, count: u64| block >= first && block as u64 + count <= end as u64;
+        if !in_group(self.block_bitmap, 1) || !in_group(self.inode_bitmap, 1) || !in_group(self.inode_table, table_blocks)
+        {
+            return Err(FsError::Corrupted(format!("ext2 group {}: metadata outside of the group", group)));
+        }
+        if self.free_blocks_count as u32 > superblock.blocks_in_group(group) || self.free_inodes_count as u32 > superblock.inodes_per_group
+        {
+            return Err(FsError::Corrupted(format!("ext2 group {}: invalid free counts", group)));
+        }
+        Ok(())
+    }
+}
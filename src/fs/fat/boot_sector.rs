@@ -0,0 +1,208 @@
+use alloc::format;
+use alloc::string::String;
+
+use crate::fs::FsError;
+
+/// Below this many clusters a volume is FAT12, and below `FAT16_MAX_CLUSTERS` FAT16, whatever its label says
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FatType
+{
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+impl FatType
+{
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32"
+        }
+    }
+}
+
+/// The geometry of a FAT volume, from the BIOS parameter block of its boot sector
+#[derive(Debug, Clone)]
+pub struct BootSector
+{
+    pub fat_type: FatType,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_count: u32,
+    /// Entries of the fixed root directory of FAT12 and FAT16 volumes
+    pub root_entry_count: u32,
+    pub total_sectors: u32,
+    pub fat_size: u32,
+    /// First cluster of the root directory of FAT32 volumes
+    pub root_cluster: u32,
+    /// Sector of the FSInfo structure of FAT32 volumes
+    pub fs_info_sector: Option<u32>,
+    /// FAT32 only: the FATs are not mirrored, only `active_fat` is used
+    pub mirroring_disabled: bool,
+    pub active_fat: u32,
+    pub cluster_count: u32,
+    pub volume_id: u32,
+    pub volume_label: String
+}
+
+impl BootSector
+{
+    pub fn parse(data: &[u8; 512]) -> Result<Self, FsError>
+    {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]) as u32;
+        let u32_at = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let invalid = |reason: &str| FsError::Corrupted(format!("FAT boot sector: {}", reason));
+
+        if data[510] != 0x55 || data[511] != 0xAA || !(data[0] == 0xEB || data[0] == 0xE9)
+        {
+            return Err(invalid("no boot signature"));
+        }
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = data[13] as u32;
+        let reserved_sectors = u16_at(14);
+        let fat_count = data[16] as u32;
+        let root_entry_count = u16_at(17);
+        let total_sectors = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+        let fat_size = if u16_at(22) != 0 { u16_at(22) } else { u32_at(36) };
+
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        {
+            return Err(invalid("invalid sector size"));
+        }
+        if sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two()
+        {
+            return Err(invalid("invalid cluster size"));
+        }
+        if reserved_sectors == 0 || fat_count == 0 || fat_size == 0
+        {
+            return Err(invalid("invalid layout"));
+        }
+
+        let root_dir_sectors = (root_entry_count * 32 + bytes_per_sector - 1) / bytes_per_sector;
+        let data_start = fat_count.checked_mul(fat_size)
+            .and_then(|fat_sectors| fat_sectors.checked_add(reserved_sectors + root_dir_sectors))
+            .ok_or_else(|| invalid("FATs larger than the volume"))?;
+        if total_sectors <= data_start
+        {
+            return Err(invalid("no data region"));
+        }
+
+        let cluster_count = (total_sectors - data_start) / sectors_per_cluster;
+        let fat_type = if cluster_count < FAT12_MAX_CLUSTERS
+        {
+            FatType::Fat12
+        }
+        else if cluster_count < FAT16_MAX_CLUSTERS
+        {
+            FatType::Fat16
+        }
+        else
+        {
+            FatType::Fat32
+        };
+
+        // The extended boot record is at 36 on FAT12/16 and at 64 on FAT32
+        let (extended, root_cluster, fs_info_sector, extended_flags) = match fat_type
+        {
+            FatType::Fat32 => {
+                if root_entry_count != 0
+                {
+                    return Err(invalid("FAT32 with a fixed root directory"));
+                }
+                let root_cluster = u32_at(44);
+                if root_cluster < 2 || root_cluster - 2 >= cluster_count
+                {
+                    return Err(invalid("invalid root cluster"));
+                }
+                let fs_info = u16_at(48);
+                (64, root_cluster, if fs_info != 0 && fs_info != 0xFFFF { Some(fs_info) } else { None }, u16_at(40))
+            }
+            _ => {
+                if root_entry_count == 0
+                {
+                    return Err(invalid("no root directory"));
+                }
+                (36, 0, None, 0)
+            }
+        };
+
+        // Extended boot signature, the volume id and label are only valid with it
+        let (volume_id, volume_label) = if data[extended + 2] == 0x29
+        {
+            (u32_at(extended + 3), String::from(String::from_utf8_lossy(&data[extended + 7..extended + 18]).trim_end()))
+        }
+        else
+        {
+            (0, String::new())
+        };
+
+        Ok(BootSector {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            root_entry_count,
+            total_sectors,
+            fat_size,
+            root_cluster,
+            fs_info_sector,
+            mirroring_disabled: extended_flags & (1 << 7) != 0,
+            active_fat: extended_flags & 0xF,
+            cluster_count,
+            volume_id,
+            volume_label
+        })
+    }
+
+    #[inline]
+    pub fn cluster_size(&self) -> u64
+    {
+        (self.bytes_per_sector * self.sectors_per_cluster) as u64
+    }
+
+    /// Byte offset of the FAT number `index`
+    pub fn fat_offset(&self, index: u32) -> u64
+    {
+        (self.reserved_sectors as u64 + index as u64 * self.fat_size as u64) * self.bytes_per_sector as u64
+    }
+
+    /// Byte offset of the fixed root directory of FAT12 and FAT16 volumes
+    pub fn root_dir_offset(&self) -> u64
+    {
+        self.fat_offset(self.fat_count)
+    }
+
+    pub fn root_dir_size(&self) -> u64
+    {
+        self.root_entry_count as u64 * 32
+    }
+
+    /// Byte offset of the data cluster `cluster`, numbered from 2. Clusters outside of the data region, like
+    /// the 0 of an empty file, come from a corrupted entry.
+    pub fn cluster_offset(&self, cluster: u32) -> Result<u64, FsError>
+    {
+        if !self.is_valid_cluster(cluster)
+        {
+            return Err(FsError::Corrupted(format!("FAT: invalid cluster {}", cluster)));
+        }
+        let root_dir_sectors = (self.root_dir_size() + self.bytes_per_sector as u64 - 1) / self.bytes_per_sector as u64;
+        Ok(self.root_dir_offset() + root_dir_sectors * self.bytes_per_sector as u64 + (cluster - 2) as u64 * self.cluster_size())
+    }
+
+    /// Whether `cluster` is a data cluster of the volume
+    #[inline]
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool
+    {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+}
@@ -0,0 +1,459 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::path::MAX_NAME_LENGTH;
+use crate::fs::FsError;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+pub const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+pub const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+pub const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// Attributes of the entries holding a long file name
+pub const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+/// First byte of a deleted entry
+pub const DELETED_ENTRY: u8 = 0xE5;
+/// First byte of the entry ending the directory
+pub const END_OF_DIRECTORY: u8 = 0x00;
+/// Stands for a first byte of 0xE5 in a short name, which would mark the entry as deleted
+const KANJI_E5: u8 = 0x05;
+
+/// Case flags of the reserved byte, set by Windows NT for 8.3 names in lowercase
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXTENSION: u8 = 0x10;
+
+const LONG_NAME_LAST: u8 = 0x40;
+const LONG_NAME_CHARACTERS: usize = 13;
+/// Offsets of the UTF-16 characters within a long name entry
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARACTERS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Date of the FAT epoch, 1980-01-01, used for new entries as there is no wall clock
+pub const FAT_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+/// A short directory entry
+#[derive(Debug, Clone)]
+pub struct RawEntry
+{
+    pub name: [u8; 11],
+    pub attributes: u8,
+    pub case_flags: u8,
+    pub created_time: u16,
+    pub created_date: u16,
+    pub accessed_date: u16,
+    pub first_cluster: u32,
+    pub modified_time: u16,
+    pub modified_date: u16,
+    pub size: u32
+}
+
+impl RawEntry
+{
+    pub fn new(name: [u8; 11], case_flags: u8, attributes: u8, first_cluster: u32) -> Self
+    {
+        RawEntry {
+            name,
+            attributes,
+            case_flags,
+            created_time: 0,
+            created_date: FAT_EPOCH_DATE,
+            accessed_date: FAT_EPOCH_DATE,
+            first_cluster,
+            modified_time: 0,
+            modified_date: FAT_EPOCH_DATE,
+            size: 0
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Self
+    {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&data[0..11]);
+        RawEntry {
+            name,
+            attributes: data[11],
+            case_flags: data[12],
+            created_time: u16_at(14),
+            created_date: u16_at(16),
+            accessed_date: u16_at(18),
+            first_cluster: (u16_at(20) as u32) << 16 | u16_at(26) as u32,
+            modified_time: u16_at(22),
+            modified_date: u16_at(24),
+            size: u32::from_le_bytes([data[28], data[29], data[30], data[31]])
+        }
+    }
+
+    pub fn encode(&self) -> [u8; ENTRY_SIZE]
+    {
+        let mut data = [0u8; ENTRY_SIZE];
+        data[0..11].copy_from_slice(&self.name);
+        data[11] = self.attributes;
+        data[12] = self.case_flags;
+        data[14..16].copy_from_slice(&self.created_time.to_le_bytes());
+        data[16..18].copy_from_slice(&self.created_date.to_le_bytes());
+        data[18..20].copy_from_slice(&self.accessed_date.to_le_bytes());
+        data[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        data[22..24].copy_from_slice(&self.modified_time.to_le_bytes());
+        data[24..26].copy_from_slice(&self.modified_date.to_le_bytes());
+        data[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        data[28..32].copy_from_slice(&self.size.to_le_bytes());
+        data
+    }
+
+    #[inline]
+    pub fn is_directory(&self) -> bool
+    {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    /// The 8.3 name as displayed, in lowercase where the case flags say so
+    pub fn short_name(&self) -> String
+    {
+        let mut name = self.name;
+        if name[0] == KANJI_E5
+        {
+            name[0] = DELETED_ENTRY;
+        }
+
+        let part = |bytes: &[u8], lowercase: bool| -> String {
+            bytes.iter()
+                .rev().skip_while(|&&c| c == b' ').collect::<Vec<_>>().into_iter().rev()
+                .map(|&c| if lowercase { c.to_ascii_lowercase() as char } else { c as char })
+                .collect()
+        };
+
+        let mut short_name = part(&name[0..8], self.case_flags & NT_LOWERCASE_BASE != 0);
+        let extension = part(&name[8..11], self.case_flags & NT_LOWERCASE_EXTENSION != 0);
+        if !extension.is_empty()
+        {
+            short_name.push('.');
+            short_name.push_str(&extension);
+        }
+        short_name
+    }
+}
+
+/// An entry of a directory, with the range of slots holding its long name and short entry
+#[derive(Debug, Clone)]
+pub struct Entry
+{
+    pub name: String,
+    pub raw: RawEntry,
+    /// Index of the first slot of the entry, the first long name slot if it has some
+    pub first_slot: usize,
+    /// Index of the slot of the short entry
+    pub slot: usize
+}
+
+impl Entry
+{
+    pub fn matches(&self, name: &str) -> bool
+    {
+        // FAT names are case insensitive, and a file may also be reached by its short name
+        equal_ignore_case(&self.name, name) || equal_ignore_case(&self.raw.short_name(), name)
+    }
+}
+
+fn equal_ignore_case(a: &str, b: &str) -> bool
+{
+    a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Parses the content of a directory, skipping `.`, `..`, the volume label and deleted entries. Long names
+/// which do not belong to their short entry are ignored.
+pub fn parse(data: &[u8]) -> Vec<Entry>
+{
+    let mut entries = Vec::new();
+    // Long name pieces collected so far: first slot, checksum, next expected sequence number and characters
+    let mut long_name: Option<(usize, u8, u8, Vec<u16>)> = None;
+
+    for (slot, entry) in data.chunks_exact(ENTRY_SIZE).enumerate()
+    {
+        match entry[0]
+        {
+            END_OF_DIRECTORY => break,
+            DELETED_ENTRY => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        if entry[11] & 0x3F == ATTRIBUTE_LONG_NAME
+        {
+            let sequence = entry[0] & 0x1F;
+            let checksum = entry[13];
+            let mut characters: Vec<u16> = LONG_NAME_OFFSETS.iter()
+                .map(|&offset| u16::from_le_bytes([entry[offset], entry[offset + 1]]))
+                .collect();
+
+            long_name = if entry[0] & LONG_NAME_LAST != 0 && sequence != 0
+            {
+                Some((slot, checksum, sequence - 1, characters))
+            }
+            else
+            {
+                match long_name.take()
+                {
+                    // The pieces are stored last first, each one goes before the ones already collected
+                    Some((first, expected_checksum, expected, mut collected)) if sequence == expected && sequence != 0 && checksum == expected_checksum => {
+                        characters.append(&mut collected);
+                        Some((first, checksum, sequence - 1, characters))
+                    }
+                    _ => None
+                }
+            };
+            continue;
+        }
+
+        let raw = RawEntry::parse(entry);
+        let pieces = long_name.take();
+        if raw.attributes & ATTRIBUTE_VOLUME_ID != 0 || raw.name[0] == b'.'
+        {
+            continue;
+        }
+
+        let (name, first_slot) = match pieces
+        {
+            Some((first, checksum, 0, characters)) if checksum == checksum_of(&raw.name) => {
+                let length = characters.iter().position(|&c| c == 0x0000 || c == 0xFFFF).unwrap_or(characters.len());
+                (String::from_utf16_lossy(&characters[..length]), first)
+            }
+            _ => (raw.short_name(), slot)
+        };
+
+        entries.push(Entry {
+            name,
+            raw,
+            first_slot,
+            slot
+        });
+    }
+
+    entries
+}
+
+/// Index of the first of `count` consecutive unused slots, the end of directory marker and the slots
+/// following it being unused
+pub fn find_free_slots(data: &[u8], count: usize) -> Option<usize>
+{
+    let mut run = 0;
+    for (slot, entry) in data.chunks_exact(ENTRY_SIZE).enumerate()
+    {
+        if entry[0] == END_OF_DIRECTORY
+        {
+            let available = data.len() / ENTRY_SIZE - (slot - run);
+            return if available >= count { Some(slot - run) } else { None };
+        }
+
+        if entry[0] == DELETED_ENTRY
+        {
+            run += 1;
+            if run == count
+            {
+                return Some(slot + 1 - count);
+            }
+        }
+        else
+        {
+            run = 0;
+        }
+    }
+    None
+}
+
+/// Whether the directory content only holds `.`, `..` and deleted entries
+pub fn is_empty(data: &[u8]) -> bool
+{
+    data.chunks_exact(ENTRY_SIZE)
+        .take_while(|entry| entry[0] != END_OF_DIRECTORY)
+        .all(|entry| entry[0] == DELETED_ENTRY || entry[0] == b'.')
+}
+
+/// Checksum of a short name, stored in its long name entries
+pub fn checksum_of(name: &[u8; 11]) -> u8
+{
+    name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+/// Checks that `name` can be the name of a file, FAT names do not end with a dot or a space
+pub fn validate_name(name: &str) -> Result<(), FsError>
+{
+    if name.is_empty() || name == "." || name == ".."
+    {
+        return Err(FsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > MAX_NAME_LENGTH
+    {
+        return Err(FsError::NameTooLong);
+    }
+    if name.ends_with('.') || name.ends_with(' ')
+        || name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c))
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+fn is_short_name_character(c: u8) -> bool
+{
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// The 8.3 name and case flags storing `name` without a long name, if it has one
+pub fn short_name_of(name: &str) -> Option<([u8; 11], u8)>
+{
+    let (base, extension) = match name.split_once('.')
+    {
+        Some((base, extension)) => (base, extension),
+        None => (name, "")
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || extension.contains('.')
+    {
+        return None;
+    }
+
+    // Each part must be in a single case to be stored with the case flags
+    let case_flags = |part: &str, flag: u8| -> Option<u8> {
+        let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+        match (lower, upper)
+        {
+            (true, true) => None,
+            (true, false) => Some(flag),
+            _ => Some(0)
+        }
+    };
+    let flags = case_flags(base, NT_LOWERCASE_BASE)? | case_flags(extension, NT_LOWERCASE_EXTENSION)?;
+
+    let mut short_name = [b' '; 11];
+    for (i, c) in base.bytes().enumerate()
+    {
+        short_name[i] = c.to_ascii_uppercase();
+    }
+    for (i, c) in extension.bytes().enumerate()
+    {
+        short_name[8 + i] = c.to_ascii_uppercase();
+    }
+    if !short_name.iter().all(|&c| c == b' ' || is_short_name_character(c))
+    {
+        return None;
+    }
+
+    Some((short_name, flags))
+}
+
+/// Generates the 8.3 alias of a long name, with a numeric tail not taken by the names `exists` accepts
+pub fn generate_short_name(name: &str, exists: impl Fn(&[u8; 11]) -> bool) -> Result<[u8; 11], FsError>
+{
+    let convert = |part: &str, length: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = if c.is_ascii() { c.to_ascii_uppercase() as u8 } else { b'_' };
+                if is_short_name_character(c) { c } else { b'_' }
+            })
+            .take(length)
+            .collect()
+    };
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rsplit_once('.')
+    {
+        Some((base, extension)) => (convert(base, 8), convert(extension, 3)),
+        None => (convert(trimmed, 8), Vec::new())
+    };
+
+    let mut short_name = [b' '; 11];
+    short_name[8..8 + extension.len()].copy_from_slice(&extension);
+
+    for tail in 1..1_000_000u32
+    {
+        let mut digits = [0u8; 7];
+        let mut length = 0;
+        let mut n = tail;
+        while n != 0
+        {
+            digits[length] = b'0' + (n % 10) as u8;
+            n /= 10;
+            length += 1;
+        }
+
+        // The base is shortened to leave room for the tail
+        let kept = core::cmp::min(base.len(), 8 - 1 - length);
+        short_name[0..8].fill(b' ');
+        short_name[0..kept].copy_from_slice(&base[..kept]);
+        if kept == 0
+        {
+            short_name[0] = b'_';
+        }
+        let start = core::cmp::max(kept, 1);
+        short_name[start] = b'~';
+        for i in 0..length
+        {
+            short_name[start + 1 + i] = digits[length - 1 - i];
+        }
+
+        if !exists(&short_name)
+        {
+            return Ok(short_name);
+        }
+    }
+
+    Err(FsError::AlreadyExists)
+}
+
+/// The long name entries of `name` for the short name with the checksum `checksum`, in their on-disk order
+pub fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]>
+{
+    let characters: Vec<u16> = name.encode_utf16().collect();
+    let count = (characters.len() + LONG_NAME_CHARACTERS - 1) / LONG_NAME_CHARACTERS;
+
+    (0..count).rev().map(|index| {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0] = (index + 1) as u8 | if index == count - 1 { LONG_NAME_LAST } else { 0 };
+        entry[11] = ATTRIBUTE_LONG_NAME;
+        entry[13] = checksum;
+
+        // The name is terminated by a null character if it does not fill the last entry, then padded with 0xFFFF
+        for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate()
+        {
+            let position = index * LONG_NAME_CHARACTERS + i;
+            let character = match position.cmp(&characters.len())
+            {
+                core::cmp::Ordering::Less => characters[position],
+                core::cmp::Ordering::Equal => 0x0000,
+                core::cmp::Ordering::Greater => 0xFFFF
+            };
+            entry[offset..offset + 2].copy_from_slice(&character.to_le_bytes());
+        }
+        entry
+    }).collect()
+}
+
+/// Converts a FAT date and time to seconds since the Unix epoch
+pub fn to_unix_time(date: u16, time: u16) -> u64
+{
+    if date == 0
+    {
+        return 0;
+    }
+
+    let year = 1980 + (date >> 9) as u64;
+    let month = core::cmp::max((date >> 5) & 0xF, 1) as u64;
+    let day = core::cmp::max(date & 0x1F, 1) as u64;
+
+    // Days since the epoch of the civil date, counting years from March so that leap days come last
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = (time >> 11) as u64 * 3600 + ((time >> 5) & 0x3F) as u64 * 60 + (time & 0x1F) as u64 * 2;
+    days * 86400 + seconds
+}
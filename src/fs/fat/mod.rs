@@ -0,0 +1,750 @@
+pub mod boot_sector;
+mod directory;
+mod table;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::Ordering;
+use log::info;
+use spin::{Mutex, MutexGuard};
+
+use crate::block::BlockDevice;
+use crate::fs::fat::boot_sector::{BootSector, FatType};
+use crate::fs::fat::directory::{Entry, RawEntry, ATTRIBUTE_ARCHIVE, ATTRIBUTE_DIRECTORY, ATTRIBUTE_READ_ONLY, DELETED_ENTRY, ENTRY_SIZE};
+use crate::fs::fat::table::FatTable;
+use crate::fs::volume::Volume;
+use crate::fs::{register_filesystem_type, DirEntry, Directory, File, FileSystem, FileSystemType, FileType, FsError, FsStats, Inode, Metadata};
+
+/// Inode number of the root directory, which has no directory entry. Other inodes are numbered with the
+/// byte offset of their short entry on the device.
+const ROOT_INODE: u64 = 1;
+/// A directory holds at most 65536 entries
+const MAX_DIRECTORY_SLOTS: usize = 65536;
+
+pub fn init()
+{
+    register_filesystem_type(FileSystemType {
+        name: "fat",
+        mount
+    });
+}
+
+/// Mounts the FAT12, FAT16 or FAT32 volume stored on `device`
+pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError>
+{
+    let volume = Volume::new(device);
+    let mut sector = [0u8; 512];
+    volume.read(0, &mut sector)?;
+
+    let boot = BootSector::parse(&sector)?;
+    if boot.total_sectors as u64 * boot.bytes_per_sector as u64 > volume.size()
+    {
+        return Err(FsError::Corrupted(alloc::format!("FAT: volume of {} sectors larger than its device", boot.total_sectors)));
+    }
+    let table = FatTable::new(&volume, &boot)?;
+
+    info!("[FAT] Mounting {} volume \"{}\" ({:#x}), {} clusters of {} bytes", boot.fat_type.name(), boot.volume_label,
+        boot.volume_id, boot.cluster_count, boot.cluster_size());
+
+    Ok(Arc::new_cyclic(|this| FatFileSystem {
+        boot,
+        inner: Mutex::new(FatInner {
+            volume,
+            table,
+            nodes: BTreeMap::new()
+        }),
+        this: this.clone()
+    }))
+}
+
+/// A mounted FAT volume. Every operation holds the filesystem lock, node states are only locked with it held.
+pub struct FatFileSystem
+{
+    boot: BootSector,
+    inner: Mutex<FatInner>,
+    this: Weak<FatFileSystem>
+}
+
+struct FatInner
+{
+    volume: Volume,
+    table: FatTable,
+    /// Nodes in use by inode number, so that a file opened twice shares its size and clusters
+    nodes: BTreeMap<u64, Weak<FatNode>>
+}
+
+/// The content of a directory, with the device offset of each of its slots
+struct DirectoryContent
+{
+    data: Vec<u8>,
+    offsets: Vec<u64>
+}
+
+impl DirectoryContent
+{
+    fn entries(&self) -> Vec<Entry>
+    {
+        directory::parse(&self.data)
+    }
+
+    fn find(&self, name: &str) -> Option<Entry>
+    {
+        self.entries().into_iter().find(|entry| entry.matches(name))
+    }
+}
+
+impl FatFileSystem
+{
+    fn this(&self) -> Arc<FatFileSystem>
+    {
+        self.this.upgrade().unwrap()
+    }
+
+    /// The node of the entry at `offset`, created from `raw` if it is not in use
+    fn node(&self, inner: &mut FatInner, offset: u64, raw: RawEntry) -> Arc<FatNode>
+    {
+        if let Some(node) = inner.nodes.get(&offset).and_then(Weak::upgrade)
+        {
+            return node;
+        }
+
+        let node = Arc::new(FatNode {
+            fs: self.this(),
+            state: Mutex::new(NodeState {
+                entry: Some(offset),
+                raw,
+                deleted: false,
+                cursor: None
+            })
+        });
+        inner.nodes.retain(|_, node| node.strong_count() != 0);
+        inner.nodes.insert(offset, Arc::downgrade(&node));
+        node
+    }
+
+    fn root_node(&self, inner: &mut FatInner) -> Arc<FatNode>
+    {
+        if let Some(node) = inner.nodes.get(&ROOT_INODE).and_then(Weak::upgrade)
+        {
+            return node;
+        }
+
+        let first_cluster = if self.boot.fat_type == FatType::Fat32 { self.boot.root_cluster } else { 0 };
+        let node = Arc::new(FatNode {
+            fs: self.this(),
+            state: Mutex::new(NodeState {
+                entry: None,
+                raw: RawEntry::new([b' '; 11], 0, ATTRIBUTE_DIRECTORY, first_cluster),
+                deleted: false,
+                cursor: None
+            })
+        });
+        inner.nodes.insert(ROOT_INODE, Arc::downgrade(&node));
+        node
+    }
+
+    /// Reads the directory starting at `first_cluster`, 0 being the fixed root directory of FAT12 and FAT16
+    fn read_directory(&self, inner: &mut FatInner, first_cluster: u32) -> Result<DirectoryContent, FsError>
+    {
+        let regions: Vec<(u64, u64)> = if first_cluster == 0
+        {
+            vec![(self.boot.root_dir_offset(), self.boot.root_dir_size())]
+        }
+        else
+        {
+            let FatInner { volume, table, .. } = inner;
+            table.chain(volume, first_cluster)?.into_iter()
+                .map(|cluster| Ok((self.boot.cluster_offset(cluster)?, self.boot.cluster_size())))
+                .collect::<Result<_, FsError>>()?
+        };
+
+        let mut content = DirectoryContent {
+            data: Vec::new(),
+            offsets: Vec::new()
+        };
+        for (offset, length) in regions
+        {
+            let start = content.data.len();
+            content.data.resize(start + length as usize, 0);
+            inner.volume.read(offset, &mut content.data[start..])?;
+            content.offsets.extend((0..length).step_by(ENTRY_SIZE).map(|slot| offset + slot));
+        }
+        Ok(content)
+    }
+
+    /// Allocates a cluster filled with zeroes, appended to the chain ending at `previous` if given
+    fn allocate_cluster(&self, inner: &mut FatInner, previous: Option<u32>) -> Result<u32, FsError>
+    {
+        let FatInner { volume, table, .. } = inner;
+        let cluster = table.allocate(volume, previous)?;
+        volume.zero(self.boot.cluster_offset(cluster)?, self.boot.cluster_size())?;
+        Ok(cluster)
+    }
+
+    /// Writes the short entry `raw` and the long name entries of `name` to the directory starting at
+    /// `first_cluster`, growing it if needed. Returns the offset of the short entry.
+    fn add_entry(&self, inner: &mut FatInner, first_cluster: u32, name: &str, mut raw: RawEntry) -> Result<u64, FsError>
+    {
+        let mut content = self.read_directory(inner, first_cluster)?;
+
+        let long_name = match directory::short_name_of(name)
+        {
+            Some((short_name, case_flags)) => {
+                raw.name = short_name;
+                raw.case_flags = case_flags;
+                Vec::new()
+            }
+            None => {
+                let shorts: Vec<[u8; 11]> = content.entries().into_iter().map(|entry| entry.raw.name).collect();
+                raw.name = directory::generate_short_name(name, |candidate| shorts.contains(candidate))?;
+                raw.case_flags = 0;
+                directory::long_name_entries(name, directory::checksum_of(&raw.name))
+            }
+        };
+        if raw.name[0] == DELETED_ENTRY
+        {
+            raw.name[0] = 0x05;
+        }
+
+        let count = long_name.len() + 1;
+        let slot = loop
+        {
+            if let Some(slot) = directory::find_free_slots(&content.data, count)
+            {
+                break slot;
+            }
+            if first_cluster == 0 || content.offsets.len() + count > MAX_DIRECTORY_SLOTS
+            {
+                return Err(FsError::NoSpace);
+            }
+
+            let last = {
+                let FatInner { volume, table, .. } = &mut *inner;
+                *table.chain(volume, first_cluster)?.last().unwrap()
+            };
+            self.allocate_cluster(inner, Some(last))?;
+            content = self.read_directory(inner, first_cluster)?;
+        };
+
+        for (i, entry) in long_name.iter().enumerate()
+        {
+            inner.volume.write(content.offsets[slot + i], entry)?;
+        }
+        let offset = content.offsets[slot + long_name.len()];
+        inner.volume.write(offset, &raw.encode())?;
+        Ok(offset)
+    }
+
+    /// Marks the slots of `entry` as deleted
+    fn delete_entry(&self, inner: &mut FatInner, content: &DirectoryContent, entry: &Entry) -> Result<(), FsError>
+    {
+        for slot in entry.first_slot..=entry.slot
+        {
+            inner.volume.write(content.offsets[slot], &[DELETED_ENTRY])?;
+        }
+        Ok(())
+    }
+
+    /// Deletes `entry` and frees its clusters. The node using it, if any, is marked deleted.
+    fn remove_entry(&self, inner: &mut FatInner, content: &DirectoryContent, entry: &Entry) -> Result<(), FsError>
+    {
+        if entry.raw.is_directory()
+        {
+            let children = self.read_directory(inner, entry.raw.first_cluster)?;
+            if !directory::is_empty(&children.data)
+            {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+
+        self.delete_entry(inner, content, entry)?;
+        if entry.raw.first_cluster != 0
+        {
+            let FatInner { volume, table, .. } = &mut *inner;
+            table.free_chain(volume, entry.raw.first_cluster)?;
+        }
+
+        if let Some(node) = inner.nodes.remove(&content.offsets[entry.slot]).and_then(|node| node.upgrade())
+        {
+            let mut state = node.state.lock();
+            state.deleted = true;
+            state.entry = None;
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for FatFileSystem
+{
+    fn name(&self) -> &str
+    {
+        self.boot.fat_type.name()
+    }
+
+    fn root(&self) -> Arc<dyn Inode>
+    {
+        let mut inner = self.inner.lock();
+        self.root_node(&mut inner)
+    }
+
+    fn sync(&self) -> Result<(), FsError>
+    {
+        let mut inner = self.inner.lock();
+        let FatInner { volume, table, .. } = &mut *inner;
+        table.sync(volume)?;
+        volume.sync()
+    }
+
+    fn stats(&self) -> Result<FsStats, FsError>
+    {
+        let mut inner = self.inner.lock();
+        let FatInner { volume, table, .. } = &mut *inner;
+        Ok(FsStats {
+            block_size: self.boot.cluster_size() as usize,
+            total_blocks: self.boot.cluster_count as u64,
+            free_blocks: table.free_clusters(volume)? as u64,
+            total_inodes: 0,
+            free_inodes: 0
+        })
+    }
+}
+
+struct NodeState
+{
+    /// Offset of the short entry on the device, `None` for the root directory and deleted nodes
+    entry: Option<u64>,
+    raw: RawEntry,
+    deleted: bool,
+    /// Index in the chain and number of the last cluster reached, to avoid walking the chain from its start
+    cursor: Option<(u64, u32)>
+}
+
+/// A file or directory of a FAT volume
+pub struct FatNode
+{
+    fs: Arc<FatFileSystem>,
+    state: Mutex<NodeState>
+}
+
+impl FatNode
+{
+    /// Locks the filesystem and the node, failing if the node was deleted
+    fn lock(&self) -> Result<(MutexGuard<'_, FatInner>, MutexGuard<'_, NodeState>), FsError>
+    {
+        let inner = self.fs.inner.lock();
+        let state = self.state.lock();
+        if state.deleted
+        {
+            return Err(FsError::NotFound);
+        }
+        Ok((inner, state))
+    }
+
+    fn is_root(state: &NodeState) -> bool
+    {
+        state.entry.is_none()
+    }
+
+    /// The cluster number `index` of the node's chain. With `allocate`, the chain is extended to reach it.
+    fn cluster(&self, inner: &mut FatInner, state: &mut NodeState, index: u64, allocate: bool) -> Result<u32, FsError>
+    {
+        if state.raw.first_cluster == 0
+        {
+            if !allocate
+            {
+                return Err(FsError::Corrupted(alloc::format!("FAT: no cluster for a file of {} bytes", state.raw.size)));
+            }
+            state.raw.first_cluster = self.fs.allocate_cluster(inner, None)?;
+            state.cursor = None;
+        }
+
+        let (mut position, mut cluster) = match state.cursor
+        {
+            Some((position, cluster)) if position <= index => (position, cluster),
+            _ => (0, state.raw.first_cluster)
+        };
+        while position < index
+        {
+            let next = {
+                let FatInner { volume, table, .. } = &mut *inner;
+                table.next(volume, cluster)?
+            };
+            cluster = match next
+            {
+                Some(next) => next,
+                None if allocate => self.fs.allocate_cluster(inner, Some(cluster))?,
+                None => return Err(FsError::Corrupted(alloc::format!("FAT: chain shorter than a file of {} bytes", state.raw.size)))
+            };
+            position += 1;
+        }
+
+        state.cursor = Some((index, cluster));
+        Ok(cluster)
+    }
+
+    /// Calls `io` with the device offset of each piece of the range of the node at `offset`, and the matching
+    /// range of the buffer
+    fn transfer(&self, inner: &mut FatInner, state: &mut NodeState, offset: u64, length: usize,
+                mut io: impl FnMut(&Volume, u64, core::ops::Range<usize>) -> Result<(), FsError>, allocate: bool) -> Result<(), FsError>
+    {
+        let cluster_size = self.fs.boot.cluster_size();
+        let mut done = 0;
+        while done < length
+        {
+            let position = offset + done as u64;
+            let cluster = self.cluster(inner, state, position / cluster_size, allocate)?;
+            let start = position % cluster_size;
+            let chunk = core::cmp::min((cluster_size - start) as usize, length - done);
+
+            io(&inner.volume, self.fs.boot.cluster_offset(cluster)? + start, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn write_entry(&self, inner: &mut FatInner, state: &NodeState) -> Result<(), FsError>
+    {
+        match state.entry
+        {
+            Some(offset) => inner.volume.write(offset, &state.raw.encode()),
+            None => Ok(())
+        }
+    }
+
+    fn check_writable(&self, inner: &FatInner, state: &NodeState) -> Result<(), FsError>
+    {
+        // The read-only attribute of directories is not enforced, as on other systems
+        if inner.volume.is_read_only() || (state.raw.attributes & ATTRIBUTE_READ_ONLY != 0 && !state.raw.is_directory())
+        {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+}
+
+impl Inode for FatNode
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        let (_inner, state) = self.lock()?;
+        let raw = &state.raw;
+        let file_type = if raw.is_directory() { FileType::Directory } else { FileType::Regular };
+
+        let mut metadata = Metadata::new(state.entry.unwrap_or(ROOT_INODE), file_type, if raw.is_directory() { 0 } else { raw.size as u64 });
+        if raw.attributes & ATTRIBUTE_READ_ONLY != 0
+        {
+            metadata.mode &= !0o222;
+        }
+        if !Self::is_root(&state)
+        {
+            metadata.accessed = directory::to_unix_time(raw.accessed_date, 0);
+            metadata.modified = directory::to_unix_time(raw.modified_date, raw.modified_time);
+            metadata.created = directory::to_unix_time(raw.created_date, raw.created_time);
+        }
+        Ok(metadata)
+    }
+
+    fn as_file(&self) -> Option<&dyn File>
+    {
+        if self.state.lock().raw.is_directory() { None } else { Some(self) }
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory>
+    {
+        if self.state.lock().raw.is_directory() { Some(self) } else { None }
+    }
+
+    fn sync(&self) -> Result<(), FsError>
+    {
+        self.fs.sync()
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl File for FatNode
+{
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let (mut inner, mut state) = self.lock()?;
+        let size = state.raw.size as u64;
+        if offset >= size
+        {
+            return Ok(0);
+        }
+
+        let length = core::cmp::min(buffer.len() as u64, size - offset) as usize;
+        self.transfer(&mut inner, &mut state, offset, length, |volume, position, range| {
+            volume.read(position, &mut buffer[range])
+        }, false)?;
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        let (mut inner, mut state) = self.lock()?;
+        self.check_writable(&inner, &state)?;
+        if buffer.is_empty()
+        {
+            return Ok(0);
+        }
+        // The size of a file is stored on 32 bits
+        let end = offset.checked_add(buffer.len() as u64).filter(|&end| end <= u32::MAX as u64).ok_or(FsError::NoSpace)?;
+
+        // Writing past the end leaves a hole which reads as zeroes
+        let size = state.raw.size as u64;
+        if offset > size
+        {
+            self.transfer(&mut inner, &mut state, size, (offset - size) as usize, |volume, position, range| {
+                volume.zero(position, range.len() as u64)
+            }, true)?;
+        }
+        let result = self.transfer(&mut inner, &mut state, offset, buffer.len(), |volume, position, range| {
+            volume.write(position, &buffer[range])
+        }, true);
+
+        // Clusters allocated before running out of space are kept, as part of the file
+        if result.is_ok()
+        {
+            state.raw.size = core::cmp::max(state.raw.size, end as u32);
+        }
+        state.raw.attributes |= ATTRIBUTE_ARCHIVE;
+        self.write_entry(&mut inner, &state)?;
+        result.map(|_| buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError>
+    {
+        let (mut inner, mut state) = self.lock()?;
+        self.check_writable(&inner, &state)?;
+        if size > u32::MAX as u64
+        {
+            return Err(FsError::NoSpace);
+        }
+
+        let current = state.raw.size as u64;
+        match size.cmp(&current)
+        {
+            Ordering::Greater => {
+                self.transfer(&mut inner, &mut state, current, (size - current) as usize, |volume, position, range| {
+                    volume.zero(position, range.len() as u64)
+                }, true)?;
+            }
+            Ordering::Less => {
+                let clusters = (size + self.fs.boot.cluster_size() - 1) / self.fs.boot.cluster_size();
+                if clusters == 0
+                {
+                    let FatInner { volume, table, .. } = &mut *inner;
+                    table.free_chain(volume, state.raw.first_cluster)?;
+                    state.raw.first_cluster = 0;
+                }
+                else
+                {
+                    let last = self.cluster(&mut inner, &mut state, clusters - 1, false)?;
+                    let FatInner { volume, table, .. } = &mut *inner;
+                    table.truncate_chain(volume, last)?;
+                }
+                state.cursor = None;
+            }
+            Ordering::Equal => {}
+        }
+
+        state.raw.size = size as u32;
+        state.raw.attributes |= ATTRIBUTE_ARCHIVE;
+        self.write_entry(&mut inner, &state)
+    }
+}
+
+impl Directory for FatNode
+{
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        let (mut inner, state) = self.lock()?;
+        let first_cluster = state.raw.first_cluster;
+        drop(state);
+
+        let content = self.fs.read_directory(&mut inner, first_cluster)?;
+        let entry = content.find(name).ok_or(FsError::NotFound)?;
+        Ok(self.fs.node(&mut inner, content.offsets[entry.slot], entry.raw))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>
+    {
+        let (mut inner, state) = self.lock()?;
+        let first_cluster = state.raw.first_cluster;
+        drop(state);
+
+        let content = self.fs.read_directory(&mut inner, first_cluster)?;
+        Ok(content.entries().into_iter().map(|entry| DirEntry {
+            inode: content.offsets[entry.slot],
+            file_type: if entry.raw.is_directory() { FileType::Directory } else { FileType::Regular },
+            name: entry.name
+        }).collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError>
+    {
+        directory::validate_name(name)?;
+        let (mut inner, state) = self.lock()?;
+        self.check_writable(&inner, &state)?;
+        let first_cluster = state.raw.first_cluster;
+        drop(state);
+
+        let raw = match file_type
+        {
+            FileType::Regular => RawEntry::new([b' '; 11], 0, ATTRIBUTE_ARCHIVE, 0),
+            FileType::Directory => RawEntry::new([b' '; 11], 0, ATTRIBUTE_DIRECTORY, 0),
+            _ => return Err(FsError::NotSupported)
+        };
+
+        let content = self.fs.read_directory(&mut inner, first_cluster)?;
+        if content.find(name).is_some()
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // A directory starts with `.` and `..`, the latter pointing to cluster 0 for the root directory
+        let mut raw = raw;
+        if file_type == FileType::Directory
+        {
+            let cluster = self.fs.allocate_cluster(&mut inner, None)?;
+            let parent = if self.state.lock().entry.is_none() { 0 } else { first_cluster };
+            let offset = self.fs.boot.cluster_offset(cluster)?;
+            inner.volume.write(offset, &RawEntry::new(*b".          ", 0, ATTRIBUTE_DIRECTORY, cluster).encode())?;
+            inner.volume.write(offset + ENTRY_SIZE as u64, &RawEntry::new(*b"..         ", 0, ATTRIBUTE_DIRECTORY, parent).encode())?;
+            raw.first_cluster = cluster;
+        }
+
+        let offset = match self.fs.add_entry(&mut inner, first_cluster, name, raw.clone())
+        {
+            Ok(offset) => offset,
+            Err(e) => {
+                if raw.first_cluster != 0
+                {
+                    let FatInner { volume, table, .. } = &mut *inner;
+                    table.free_chain(volume, raw.first_cluster)?;
+                }
+                return Err(e);
+            }
+        };
+
+        let raw = RawEntry::parse(&{
+            let mut data = [0u8; ENTRY_SIZE];
+            inner.volume.read(offset, &mut data)?;
+            data
+        });
+        Ok(self.fs.node(&mut inner, offset, raw))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError>
+    {
+        let (mut inner, state) = self.lock()?;
+        self.check_writable(&inner, &state)?;
+        let first_cluster = state.raw.first_cluster;
+        drop(state);
+
+        let content = self.fs.read_directory(&mut inner, first_cluster)?;
+        let entry = content.find(name).ok_or(FsError::NotFound)?;
+        self.fs.remove_entry(&mut inner, &content, &entry)
+    }
+
+    fn rename(&self, name: &str, target: &dyn Inode, new_name: &str) -> Result<(), FsError>
+    {
+        directory::validate_name(new_name)?;
+        let target = target.as_any().downcast_ref::<FatNode>()
+            .filter(|target| Arc::ptr_eq(&target.fs, &self.fs))
+            .ok_or(FsError::CrossDevice)?;
+
+        let (mut inner, state) = self.lock()?;
+        self.check_writable(&inner, &state)?;
+        let source_cluster = state.raw.first_cluster;
+        drop(state);
+
+        let (target_cluster, target_is_root) = {
+            let target_state = target.state.lock();
+            if target_state.deleted
+            {
+                return Err(FsError::NotFound);
+            }
+            if !target_state.raw.is_directory()
+            {
+                return Err(FsError::NotADirectory);
+            }
+            (target_state.raw.first_cluster, target_state.entry.is_none())
+        };
+
+        let content = self.fs.read_directory(&mut inner, source_cluster)?;
+        let entry = content.find(name).ok_or(FsError::NotFound)?;
+        let old_offset = content.offsets[entry.slot];
+        // A moved directory's `..` points to its new parent, its cluster is checked before anything is changed
+        let dot_dot_offset = if entry.raw.is_directory() && source_cluster != target_cluster
+        {
+            Some(self.fs.boot.cluster_offset(entry.raw.first_cluster)? + ENTRY_SIZE as u64)
+        }
+        else
+        {
+            None
+        };
+
+        // An existing file is replaced, unless it is the renamed entry itself, e.g. when changing the case
+        let target_content = self.fs.read_directory(&mut inner, target_cluster)?;
+        if let Some(existing) = target_content.find(new_name)
+        {
+            if target_content.offsets[existing.slot] != old_offset
+            {
+                if existing.raw.is_directory()
+                {
+                    return Err(FsError::AlreadyExists);
+                }
+                self.fs.remove_entry(&mut inner, &target_content, &existing)?;
+            }
+        }
+
+        // The old entry goes first, so that a case change can reuse the short name
+        let content = self.fs.read_directory(&mut inner, source_cluster)?;
+        let entry = content.find(name).ok_or(FsError::NotFound)?;
+        self.fs.delete_entry(&mut inner, &content, &entry)?;
+        let new_offset = match self.fs.add_entry(&mut inner, target_cluster, new_name, entry.raw.clone())
+        {
+            Ok(offset) => offset,
+            Err(e) => {
+                // Restores the old entry
+                for slot in entry.first_slot..=entry.slot
+                {
+                    inner.volume.write(content.offsets[slot], &content.data[slot * ENTRY_SIZE..(slot + 1) * ENTRY_SIZE])?;
+                }
+                return Err(e);
+            }
+        };
+
+        if let Some(offset) = dot_dot_offset
+        {
+            let parent = if target_is_root { 0 } else { target_cluster };
+            let mut data = [0u8; ENTRY_SIZE];
+            inner.volume.read(offset, &mut data)?;
+            let mut dot_dot = RawEntry::parse(&data);
+            dot_dot.first_cluster = parent;
+            inner.volume.write(offset, &dot_dot.encode())?;
+        }
+
+        let mut data = [0u8; ENTRY_SIZE];
+        inner.volume.read(new_offset, &mut data)?;
+        let raw = RawEntry::parse(&data);
+        if let Some(node) = inner.nodes.remove(&old_offset).and_then(|node| node.upgrade())
+        {
+            let mut state = node.state.lock();
+            state.entry = Some(new_offset);
+            state.raw.name = raw.name;
+            state.raw.case_flags = raw.case_flags;
+            drop(state);
+            inner.nodes.insert(new_offset, Arc::downgrade(&node));
+        }
+        Ok(())
+    }
+}
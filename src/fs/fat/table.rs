@@ -0,0 +1,341 @@
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fs::fat::boot_sector::{BootSector, FatType};
+use crate::fs::volume::Volume;
+use crate::fs::FsError;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FS_INFO_STRUCT_OFFSET: u64 = 484;
+const FS_INFO_FREE_COUNT_OFFSET: u64 = 488;
+const FS_INFO_NEXT_FREE_OFFSET: u64 = 492;
+const FS_INFO_TRAIL_OFFSET: u64 = 508;
+/// Value of the FSInfo fields when unknown
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+pub const FREE_CLUSTER: u32 = 0;
+
+/// The file allocation table, with the free cluster count and allocation hint kept in FSInfo on FAT32
+///
+/// Reads go through a one sector window of the FAT in use, writes go to every copy of the FAT.
+pub struct FatTable
+{
+    boot: BootSector,
+    /// Offset in the FAT and content of the sector held by the window
+    window: Option<(u64, Vec<u8>)>,
+    free_clusters: Option<u32>,
+    next_free: u32,
+    fs_info_dirty: bool
+}
+
+impl FatTable
+{
+    pub fn new(volume: &Volume, boot: &BootSector) -> Result<Self, FsError>
+    {
+        let mut table = FatTable {
+            boot: boot.clone(),
+            window: None,
+            free_clusters: None,
+            next_free: 2,
+            fs_info_dirty: false
+        };
+
+        if let Some(offset) = table.fs_info_offset()
+        {
+            let valid = volume.read_u32(offset)? == FS_INFO_LEAD_SIGNATURE
+                && volume.read_u32(offset + FS_INFO_STRUCT_OFFSET)? == FS_INFO_STRUCT_SIGNATURE
+                && volume.read_u32(offset + FS_INFO_TRAIL_OFFSET)? == FS_INFO_TRAIL_SIGNATURE;
+            if valid
+            {
+                // The fields are hints which may be stale, they are ignored when out of range
+                let free_count = volume.read_u32(offset + FS_INFO_FREE_COUNT_OFFSET)?;
+                let next_free = volume.read_u32(offset + FS_INFO_NEXT_FREE_OFFSET)?;
+                if free_count != FS_INFO_UNKNOWN && free_count <= boot.cluster_count
+                {
+                    table.free_clusters = Some(free_count);
+                }
+                if boot.is_valid_cluster(next_free)
+                {
+                    table.next_free = next_free;
+                }
+            }
+        }
+
+        Ok(table)
+    }
+
+    fn fs_info_offset(&self) -> Option<u64>
+    {
+        self.boot.fs_info_sector.map(|sector| sector as u64 * self.boot.bytes_per_sector as u64)
+    }
+
+    /// Value marking the last cluster of a chain
+    pub fn end_of_chain(&self) -> u32
+    {
+        match self.boot.fat_type
+        {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF
+        }
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool
+    {
+        match self.boot.fat_type
+        {
+            FatType::Fat12 => value >= 0xFF8,
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= 0x0FFF_FFF8
+        }
+    }
+
+    /// Byte offset of the entry of `cluster` within a FAT
+    fn entry_offset(&self, cluster: u32) -> u64
+    {
+        match self.boot.fat_type
+        {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4
+        }
+    }
+
+    fn read_fat(&self) -> u32
+    {
+        if self.boot.mirroring_disabled { self.boot.active_fat } else { 0 }
+    }
+
+    fn byte(&mut self, volume: &Volume, offset: u64) -> Result<u8, FsError>
+    {
+        let sector_size = self.boot.bytes_per_sector as u64;
+        let start = offset - offset % sector_size;
+        match &self.window
+        {
+            Some((window, data)) if *window == start => Ok(data[(offset - start) as usize]),
+            _ => {
+                let mut data = vec![0u8; sector_size as usize];
+                volume.read(self.boot.fat_offset(self.read_fat()) + start, &mut data)?;
+                let byte = data[(offset - start) as usize];
+                self.window = Some((start, data));
+                Ok(byte)
+            }
+        }
+    }
+
+    fn bytes(&mut self, volume: &Volume, offset: u64, length: usize) -> Result<u32, FsError>
+    {
+        let mut value = 0;
+        for i in 0..length
+        {
+            value |= (self.byte(volume, offset + i as u64)? as u32) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    /// Writes `length` bytes of `value` at `offset` in every FAT, and in the window
+    fn write_bytes(&mut self, volume: &Volume, offset: u64, length: usize, value: u32) -> Result<(), FsError>
+    {
+        let bytes = value.to_le_bytes();
+        let fats = if self.boot.mirroring_disabled { self.boot.active_fat..self.boot.active_fat + 1 } else { 0..self.boot.fat_count };
+        for fat in fats
+        {
+            volume.write(self.boot.fat_offset(fat) + offset, &bytes[..length])?;
+        }
+
+        if let Some((start, data)) = &mut self.window
+        {
+            for i in 0..length as u64
+            {
+                if offset + i >= *start && offset + i < *start + data.len() as u64
+                {
+                    data[(offset + i - *start) as usize] = bytes[i as usize];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&mut self, volume: &Volume, cluster: u32) -> Result<u32, FsError>
+    {
+        let offset = self.entry_offset(cluster);
+        Ok(match self.boot.fat_type
+        {
+            FatType::Fat12 => {
+                let value = self.bytes(volume, offset, 2)?;
+                if cluster & 1 != 0 { value >> 4 } else { value & 0xFFF }
+            }
+            FatType::Fat16 => self.bytes(volume, offset, 2)?,
+            FatType::Fat32 => self.bytes(volume, offset, 4)? & 0x0FFF_FFFF
+        })
+    }
+
+    pub fn set(&mut self, volume: &Volume, cluster: u32, value: u32) -> Result<(), FsError>
+    {
+        let offset = self.entry_offset(cluster);
+        match self.boot.fat_type
+        {
+            FatType::Fat12 => {
+                // Two entries share the middle byte of each group of three
+                let current = self.bytes(volume, offset, 2)?;
+                let value = if cluster & 1 != 0
+                {
+                    (current & 0x000F) | (value & 0xFFF) << 4
+                }
+                else
+                {
+                    (current & 0xF000) | (value & 0xFFF)
+                };
+                self.write_bytes(volume, offset, 2, value)
+            }
+            FatType::Fat16 => self.write_bytes(volume, offset, 2, value & 0xFFFF),
+            FatType::Fat32 => {
+                // The high 4 bits are reserved and must be preserved
+                let current = self.bytes(volume, offset, 4)?;
+                self.write_bytes(volume, offset, 4, (current & 0xF000_0000) | (value & 0x0FFF_FFFF))
+            }
+        }
+    }
+
+    /// The cluster following `cluster` in its chain, `None` at the end of the chain
+    pub fn next(&mut self, volume: &Volume, cluster: u32) -> Result<Option<u32>, FsError>
+    {
+        let value = self.get(volume, cluster)?;
+        if self.is_end_of_chain(value)
+        {
+            Ok(None)
+        }
+        else if self.boot.is_valid_cluster(value)
+        {
+            Ok(Some(value))
+        }
+        else
+        {
+            Err(FsError::Corrupted(format!("FAT: cluster {} is followed by {:#x}", cluster, value)))
+        }
+    }
+
+    /// The clusters of the chain starting at `first`
+    pub fn chain(&mut self, volume: &Volume, first: u32) -> Result<Vec<u32>, FsError>
+    {
+        let mut clusters = Vec::new();
+        let mut cluster = Some(first);
+        while let Some(current) = cluster
+        {
+            // A chain longer than the volume loops
+            if !self.boot.is_valid_cluster(current) || clusters.len() > self.boot.cluster_count as usize
+            {
+                return Err(FsError::Corrupted(format!("FAT: invalid chain starting at cluster {}", first)));
+            }
+            clusters.push(current);
+            cluster = self.next(volume, current)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Allocates a free cluster as the end of a chain, appended to `previous` if given
+    pub fn allocate(&mut self, volume: &Volume, previous: Option<u32>) -> Result<u32, FsError>
+    {
+        if self.free_clusters == Some(0)
+        {
+            return Err(FsError::NoSpace);
+        }
+
+        let count = self.boot.cluster_count;
+        let start = self.next_free.saturating_sub(2) % count;
+        for i in 0..count
+        {
+            let cluster = 2 + (start + i) % count;
+            if self.get(volume, cluster)? != FREE_CLUSTER
+            {
+                continue;
+            }
+
+            let end_of_chain = self.end_of_chain();
+            self.set(volume, cluster, end_of_chain)?;
+            if let Some(previous) = previous
+            {
+                self.set(volume, previous, cluster)?;
+            }
+
+            self.next_free = if cluster + 1 < count + 2 { cluster + 1 } else { 2 };
+            self.free_clusters = self.free_clusters.map(|free| free.saturating_sub(1));
+            self.fs_info_dirty = true;
+            return Ok(cluster);
+        }
+
+        self.free_clusters = Some(0);
+        Err(FsError::NoSpace)
+    }
+
+    /// Frees the chain starting at `first`
+    pub fn free_chain(&mut self, volume: &Volume, first: u32) -> Result<(), FsError>
+    {
+        let clusters = self.chain(volume, first)?;
+        for &cluster in clusters.iter()
+        {
+            self.set(volume, cluster, FREE_CLUSTER)?;
+        }
+
+        self.free_clusters = self.free_clusters.map(|free| free + clusters.len() as u32);
+        self.fs_info_dirty = true;
+        Ok(())
+    }
+
+    /// Ends the chain at `last` and frees the clusters following it
+    pub fn truncate_chain(&mut self, volume: &Volume, last: u32) -> Result<(), FsError>
+    {
+        let next = self.next(volume, last)?;
+        let end_of_chain = self.end_of_chain();
+        self.set(volume, last, end_of_chain)?;
+        match next
+        {
+            Some(next) => self.free_chain(volume, next),
+            None => Ok(())
+        }
+    }
+
+    /// Number of free clusters, counted on first use if FSInfo does not hold it
+    pub fn free_clusters(&mut self, volume: &Volume) -> Result<u32, FsError>
+    {
+        if let Some(free) = self.free_clusters
+        {
+            return Ok(free);
+        }
+
+        let mut free = 0;
+        for cluster in 2..self.boot.cluster_count + 2
+        {
+            if self.get(volume, cluster)? == FREE_CLUSTER
+            {
+                free += 1;
+            }
+        }
+
+        self.free_clusters = Some(free);
+        self.fs_info_dirty = true;
+        Ok(free)
+    }
+
+    /// Writes the free cluster count and the allocation hint to FSInfo
+    pub fn sync(&mut self, volume: &Volume) -> Result<(), FsError>
+    {
+        let offset = match self.fs_info_offset()
+        {
+            Some(offset) if self.fs_info_dirty => offset,
+            _ => return Ok(())
+        };
+        if volume.read_u32(offset)? != FS_INFO_LEAD_SIGNATURE
+        {
+            return Ok(());
+        }
+
+        volume.write(offset + FS_INFO_FREE_COUNT_OFFSET, &self.free_clusters.unwrap_or(FS_INFO_UNKNOWN).to_le_bytes())?;
+        volume.write(offset + FS_INFO_NEXT_FREE_OFFSET, &self.next_free.to_le_bytes())?;
+        self.fs_info_dirty = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use spin::Mutex;
+
+use crate::fs::{FsError, Inode, Mount};
+
+/// How a file is opened, combined with `|`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags
+{
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Creates the file if it does not exist
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// With `CREATE`, fails if the file exists
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 3);
+    /// Empties the file when opening it for writing
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+    /// Every write goes to the end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 5);
+    /// Fails unless the path is a directory
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 6);
+
+    #[inline]
+    pub const fn contains(self, flags: OpenFlags) -> bool
+    {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for OpenFlags
+{
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: Self) -> Self::Output
+    {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SeekFrom
+{
+    Start(u64),
+    End(i64)
+}
+
+/// An open file or directory, with the offset of the next read or write
+pub struct FileHandle
+{
+    /// Keeps the mount the file belongs to from being unmounted while the handle exists
+    _mount: Arc<Mount>,
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    offset: Mutex<u64>
+}
+
+impl FileHandle
+{
+    pub(super) fn new(mount: Arc<Mount>, inode: Arc<dyn Inode>, flags: OpenFlags) -> Self
+    {
+        FileHandle {
+            _mount: mount,
+            inode,
+            flags,
+            offset: Mutex::new(0)
+        }
+    }
+
+    /// Reads at the current offset and advances it past the bytes read
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        if !self.flags.contains(OpenFlags::READ)
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let file = self.inode.as_file().ok_or(FsError::IsADirectory)?;
+
+        let mut offset = self.offset.lock();
+        let read = file.read_at(*offset, buffer)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    /// Reads from the current offset to the end of the file
+    pub fn read_to_end(&self) -> Result<Vec<u8>, FsError>
+    {
+        let mut data = Vec::new();
+        let mut chunk = [0u8; 512];
+        loop
+        {
+            let read = self.read(&mut chunk)?;
+            if read == 0
+            {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Writes at the current offset, or at the end of the file if opened with `APPEND`, and advances the offset
+    /// past the bytes written
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        if !self.flags.contains(OpenFlags::WRITE)
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let file = self.inode.as_file().ok_or(FsError::IsADirectory)?;
+
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND)
+        {
+            *offset = self.inode.metadata()?.size;
+        }
+        let written = file.write_at(*offset, buffer)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    /// Moves the offset, which may go past the end of the file. Returns the new offset.
+    pub fn seek(&self, position: SeekFrom) -> Result<u64, FsError>
+    {
+        let mut offset = self.offset.lock();
+        let new_offset = match position
+        {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.inode.metadata()?.size.checked_add_signed(delta)
+        };
+
+        *offset = new_offset.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+}
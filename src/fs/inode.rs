@@ -0,0 +1,226 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use crate::block::BlockError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FsError
+{
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    /// The path is empty or holds an invalid component
+    InvalidPath,
+    NameTooLong,
+    ReadOnly,
+    NoSpace,
+    /// The filesystem does not implement the operation
+    NotSupported,
+    /// The file or filesystem is in use, e.g. unmounting with open files or removing a mount point
+    Busy,
+    /// Renaming across filesystems
+    CrossDevice,
+    InvalidArgument,
+    /// Too many symbolic links were followed while resolving a path
+    SymlinkLoop,
+    /// The on-disk structures are inconsistent, with a description of the problem
+    Corrupted(String),
+    /// No filesystem type recognized the device
+    UnknownFileSystem,
+    Io(BlockError)
+}
+
+impl From<BlockError> for FsError
+{
+    fn from(error: BlockError) -> Self
+    {
+        match error
+        {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            error => FsError::Io(error)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FileType
+{
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata
+{
+    /// Number of the inode, unique within its filesystem
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Permission bits, as in the low 12 bits of a Unix mode
+    pub mode: u16,
+    pub links: u32,
+    /// Timestamps in seconds since the Unix epoch, 0 when unknown
+    pub accessed: u64,
+    pub modified: u64,
+    pub created: u64
+}
+
+impl Metadata
+{
+    /// Metadata of a synthesized file or directory, without timestamps
+    pub fn new(inode: u64, file_type: FileType, size: u64) -> Self
+    {
+        Metadata {
+            inode,
+            file_type,
+            size,
+            mode: if file_type == FileType::Directory { 0o755 } else { 0o644 },
+            links: 1,
+            accessed: 0,
+            modified: 0,
+            created: 0
+        }
+    }
+
+    #[inline]
+    pub fn is_directory(&self) -> bool
+    {
+        self.file_type == FileType::Directory
+    }
+}
+
+/// An entry of a directory listing, `.` and `..` are never listed
+#[derive(Debug, Clone)]
+pub struct DirEntry
+{
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FsStats
+{
+    pub block_size: usize,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64
+}
+
+/// A file, directory or device of a filesystem. The operations it supports are reached through `as_file`
+/// and `as_directory`.
+pub trait Inode: Send + Sync
+{
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    fn as_file(&self) -> Option<&dyn File>
+    {
+        None
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory>
+    {
+        None
+    }
+
+    /// The target of a symbolic link
+    fn read_link(&self) -> Result<String, FsError>
+    {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Writes the cached changes of the inode to its device
+    fn sync(&self) -> Result<(), FsError>
+    {
+        Ok(())
+    }
+
+    /// For filesystems to recognize their own inodes, e.g. the target directory of a rename
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Data operations of an inode, positioned by the caller
+pub trait File: Send + Sync
+{
+    /// Reads at most `buffer.len()` bytes at `offset`, returns the number of bytes read, 0 at the end of the file
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes `buffer` at `offset`, extending the file if needed. Returns the number of bytes written.
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError>
+    {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+
+    /// Device specific request, with a meaning defined by the device, e.g. the `devfs` requests
+    fn ioctl(&self, _request: u32, _argument: u64) -> Result<u64, FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+}
+
+/// Operations of a directory inode. Names are single path components, `.` and `..` are resolved by the VFS
+/// and never reach the filesystem.
+pub trait Directory: Send + Sync
+{
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>;
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Creates an empty file or directory called `name`
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+
+    /// Creates a symbolic link called `name` pointing to `target`
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+
+    /// Removes the entry `name`, which must be an empty directory if it is one
+    fn remove(&self, _name: &str) -> Result<(), FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+
+    /// Moves the entry `name` to `new_name` in `target`, a directory of the same filesystem, replacing the
+    /// file `new_name` if it exists
+    fn rename(&self, _name: &str, _target: &dyn Inode, _new_name: &str) -> Result<(), FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+}
+
+/// A mounted filesystem
+pub trait FileSystem: Send + Sync
+{
+    /// Name of the filesystem type, e.g. `fat32`
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes every cached change to the device
+    fn sync(&self) -> Result<(), FsError>
+    {
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<FsStats, FsError>
+    {
+        Err(FsError::NotSupported)
+    }
+}
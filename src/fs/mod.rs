@@ -0,0 +1,404 @@
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+mod file;
+mod inode;
+mod mount;
+pub mod path;
+pub mod procfs;
+pub mod rootfs;
+pub mod tmpfs;
+mod volume;
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::block::{cache, BlockDevice, BLOCK_DEVICES};
+use crate::fs::devfs::DevFs;
+use crate::fs::procfs::ProcFs;
+use crate::fs::rootfs::RootFs;
+use crate::fs::tmpfs::TmpFs;
+
+pub use crate::fs::file::{FileHandle, OpenFlags, SeekFrom};
+pub use crate::fs::inode::{DirEntry, Directory, File, FileSystem, FileType, FsError, FsStats, Inode, Metadata};
+pub use crate::fs::mount::{Mount, MountTable, MOUNTS};
+
+/// Directories created in the root filesystem at boot, to mount the other filesystems on
+const ROOT_DIRECTORIES: [&str; 6] = ["/boot", "/dev", "/mnt", "/proc", "/sys", "/tmp"];
+/// Size limit of the tmpfs mounted on `/tmp`
+const TMP_SIZE_LIMIT: u64 = 16 * 1024 * 1024;
+/// Symbolic links followed at most while resolving a path
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// Mounts the filesystem stored on a device, or fails if the device does not hold one
+pub type MountFn = fn(Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError>;
+
+/// A filesystem driver able to mount block devices
+#[derive(Debug, Copy, Clone)]
+pub struct FileSystemType
+{
+    pub name: &'static str,
+    pub mount: MountFn
+}
+
+static FILESYSTEM_TYPES: Mutex<Vec<FileSystemType>> = Mutex::new(Vec::new());
+
+lazy_static!
+{
+    static ref CURRENT_DIR: Mutex<String> = Mutex::new(String::from("/"));
+}
+
+/// Mounts the root filesystem, creates the usual mount points, mounts `/dev`, `/proc` and `/tmp` and registers the
+/// filesystem types
+pub fn init()
+{
+    fat::init();
+    ext2::init();
+    devfs::init();
+
+    mount("/", Arc::new(RootFs::new())).expect("Failed to mount the root filesystem");
+    for directory in ROOT_DIRECTORIES
+    {
+        if let Err(e) = create_dir(directory)
+        {
+            warn!("[VFS] Failed to create {}: {:?}", directory, e);
+        }
+    }
+    if let Err(e) = mount("/dev", Arc::new(DevFs::new()))
+    {
+        warn!("[VFS] Failed to mount /dev: {:?}", e);
+    }
+    if let Err(e) = mount("/proc", Arc::new(ProcFs::new()))
+    {
+        warn!("[VFS] Failed to mount /proc: {:?}", e);
+    }
+    if let Err(e) = mount("/tmp", Arc::new(TmpFs::new(TMP_SIZE_LIMIT)))
+    {
+        warn!("[VFS] Failed to mount /tmp: {:?}", e);
+    }
+}
+
+pub fn register_filesystem_type(filesystem_type: FileSystemType)
+{
+    info!("[VFS] Registered filesystem type {}", filesystem_type.name);
+    FILESYSTEM_TYPES.lock().push(filesystem_type);
+}
+
+pub fn current_dir() -> String
+{
+    CURRENT_DIR.lock().clone()
+}
+
+/// Changes the directory relative paths are resolved against
+pub fn set_current_dir(path: &str) -> Result<(), FsError>
+{
+    let cwd = current_dir();
+    let components = path::normalize(&cwd, path)?;
+    let (_, inode) = lookup_components(&components, true)?;
+    if !inode.metadata()?.is_directory()
+    {
+        return Err(FsError::NotADirectory);
+    }
+
+    *CURRENT_DIR.lock() = path::join(&components);
+    Ok(())
+}
+
+/// Resolves the absolute path made of `components` to its inode, and the mount it belongs to. Symbolic links
+/// are followed, the last component's only with `follow_last`.
+fn lookup_components<S: AsRef<str>>(components: &[S], follow_last: bool) -> Result<(Arc<Mount>, Arc<dyn Inode>), FsError>
+{
+    resolve(components, follow_last, 0)
+}
+
+fn resolve<S: AsRef<str>>(components: &[S], follow_last: bool, depth: usize) -> Result<(Arc<Mount>, Arc<dyn Inode>), FsError>
+{
+    let mount = MOUNTS.read().find(components).ok_or(FsError::NotFound)?;
+
+    let mut inode = mount.filesystem().root();
+    for (index, name) in components.iter().enumerate().skip(mount.depth())
+    {
+        let next = inode.as_directory().ok_or(FsError::NotADirectory)?.lookup(name.as_ref())?;
+        let last = index + 1 == components.len();
+        if (!last || follow_last) && next.metadata()?.file_type == FileType::Symlink
+        {
+            if depth == MAX_SYMLINK_DEPTH
+            {
+                return Err(FsError::SymlinkLoop);
+            }
+
+            // The target is relative to the directory holding the link, and followed by the rest of the path
+            let mut target = next.read_link()?;
+            for rest in components[index + 1..].iter()
+            {
+                target.push(path::SEPARATOR);
+                target.push_str(rest.as_ref());
+            }
+            let directory = path::join(&components[..index]);
+            let resolved = path::normalize(&directory, &target)?;
+            return resolve(&resolved, follow_last, depth + 1);
+        }
+        inode = next;
+    }
+
+    Ok((mount, inode))
+}
+
+/// Resolves `path` to its inode and the mount it belongs to
+pub fn lookup(path: &str) -> Result<(Arc<Mount>, Arc<dyn Inode>), FsError>
+{
+    let cwd = current_dir();
+    lookup_components(&path::normalize(&cwd, path)?, true)
+}
+
+/// A directory, the mount it belongs to and the name of an entry in it
+type ParentEntry<'a> = (Arc<Mount>, Arc<dyn Inode>, &'a str);
+
+/// Resolves the parent directory of the last component of `components`. The root has no parent.
+fn lookup_parent<'a>(components: &[&'a str]) -> Result<ParentEntry<'a>, FsError>
+{
+    let (name, parent) = components.split_last().ok_or(FsError::InvalidPath)?;
+    let (mount, inode) = lookup_components(parent, true)?;
+    if inode.as_directory().is_none()
+    {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((mount, inode, name))
+}
+
+/// Opens the file or directory at `path`
+pub fn open(path: &str, flags: OpenFlags) -> Result<FileHandle, FsError>
+{
+    let cwd = current_dir();
+    let components = path::normalize(&cwd, path)?;
+
+    let (mount, inode) = if flags.contains(OpenFlags::CREATE)
+    {
+        let (mount, parent, name) = lookup_parent(&components)?;
+        let directory = parent.as_directory().unwrap();
+        match directory.lookup(name)
+        {
+            Ok(_) if flags.contains(OpenFlags::EXCLUSIVE) => return Err(FsError::AlreadyExists),
+            Ok(inode) if inode.metadata()?.file_type == FileType::Symlink => lookup_components(&components, true)?,
+            Ok(inode) => (mount, inode),
+            Err(FsError::NotFound) => {
+                let inode = directory.create(name, FileType::Regular)?;
+                (mount, inode)
+            }
+            Err(e) => return Err(e)
+        }
+    }
+    else
+    {
+        lookup_components(&components, true)?
+    };
+
+    let metadata = inode.metadata()?;
+    if metadata.is_directory() && flags.contains(OpenFlags::WRITE)
+    {
+        return Err(FsError::IsADirectory);
+    }
+    if !metadata.is_directory() && flags.contains(OpenFlags::DIRECTORY)
+    {
+        return Err(FsError::NotADirectory);
+    }
+    if flags.contains(OpenFlags::WRITE | OpenFlags::TRUNCATE) && metadata.size != 0
+    {
+        inode.as_file().ok_or(FsError::NotSupported)?.truncate(0)?;
+    }
+
+    Ok(FileHandle::new(mount, inode, flags))
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, FsError>
+{
+    lookup(path)?.1.metadata()
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError>
+{
+    lookup(path)?.1.as_directory().ok_or(FsError::NotADirectory)?.read_dir()
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError>
+{
+    let cwd = current_dir();
+    let components = path::normalize(&cwd, path)?;
+    if components.is_empty()
+    {
+        return Err(FsError::AlreadyExists);
+    }
+
+    let (_, parent, name) = lookup_parent(&components)?;
+    parent.as_directory().unwrap().create(name, FileType::Directory)?;
+    Ok(())
+}
+
+/// Creates a symbolic link at `path` pointing to `target`, which is not resolved
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError>
+{
+    let cwd = current_dir();
+    let components = path::normalize(&cwd, path)?;
+    if target.is_empty()
+    {
+        return Err(FsError::InvalidPath);
+    }
+
+    let (_, parent, name) = lookup_parent(&components)?;
+    parent.as_directory().unwrap().symlink(name, target)?;
+    Ok(())
+}
+
+/// The target of the symbolic link at `path`
+pub fn read_link(path: &str) -> Result<String, FsError>
+{
+    let cwd = current_dir();
+    lookup_components(&path::normalize(&cwd, path)?, false)?.1.read_link()
+}
+
+/// Removes the file or empty directory at `path`, which must not be a mount point
+pub fn remove(path: &str) -> Result<(), FsError>
+{
+    let cwd = current_dir();
+    let components = path::normalize(&cwd, path)?;
+    if components.is_empty() || MOUNTS.read().is_mount_point(&components)
+    {
+        return Err(FsError::Busy);
+    }
+
+    let (_, parent, name) = lookup_parent(&components)?;
+    parent.as_directory().unwrap().remove(name)
+}
+
+/// Moves the file or directory at `from` to `to`, both on the same filesystem
+pub fn rename(from: &str, to: &str) -> Result<(), FsError>
+{
+    let cwd = current_dir();
+    let from = path::normalize(&cwd, from)?;
+    let to = path::normalize(&cwd, to)?;
+    if from.is_empty() || to.is_empty()
+    {
+        return Err(FsError::Busy);
+    }
+    {
+        let mounts = MOUNTS.read();
+        if mounts.is_mount_point(&from) || mounts.is_mount_point(&to)
+        {
+            return Err(FsError::Busy);
+        }
+    }
+    // A directory cannot be moved below itself
+    if path::starts_with(&to, &from)
+    {
+        return if to.len() == from.len() { Ok(()) } else { Err(FsError::InvalidArgument) };
+    }
+
+    let (from_mount, from_parent, from_name) = lookup_parent(&from)?;
+    let (to_mount, to_parent, to_name) = lookup_parent(&to)?;
+    if !Arc::ptr_eq(&from_mount, &to_mount)
+    {
+        return Err(FsError::CrossDevice);
+    }
+
+    from_parent.as_directory().unwrap().rename(from_name, to_parent.as_ref(), to_name)
+}
+
+/// Mounts `filesystem` on the directory at `path`. The first mount must be the root filesystem at `/`.
+pub fn mount(path: &str, filesystem: Arc<dyn FileSystem>) -> Result<(), FsError>
+{
+    mount_from(path, filesystem, None)
+}
+
+fn mount_from(path: &str, filesystem: Arc<dyn FileSystem>, source: Option<&str>) -> Result<(), FsError>
+{
+    let cwd = current_dir();
+    let components = path::normalize(&cwd, path)?;
+
+    // Any directory is a valid mount point, except when mounting the root filesystem
+    let root = MOUNTS.read().find(&components).is_none();
+    if !root && !lookup_components(&components, true)?.1.metadata()?.is_directory()
+    {
+        return Err(FsError::NotADirectory);
+    }
+    if root && !components.is_empty()
+    {
+        return Err(FsError::NotFound);
+    }
+
+    let name = filesystem.name().to_string();
+    MOUNTS.write().add(&components, filesystem, source)?;
+    info!("[VFS] Mounted {} on {}{}", name, path::join(&components), source.map_or(String::new(), |source| alloc::format!(" from {}", source)));
+    Ok(())
+}
+
+/// Mounts the filesystem on the block device `device` at `path`, with the filesystem type `filesystem_type`
+/// or the first registered type recognizing the device
+pub fn mount_device(path: &str, device: &str, filesystem_type: Option<&str>) -> Result<(), FsError>
+{
+    let block_device = BLOCK_DEVICES.lock().get(device).ok_or(FsError::NotFound)?;
+    let types: Vec<FileSystemType> = FILESYSTEM_TYPES.lock().iter()
+        .filter(|candidate| filesystem_type.map_or(true, |name| candidate.name == name))
+        .copied()
+        .collect();
+
+    let mut error = FsError::UnknownFileSystem;
+    for candidate in types
+    {
+        match (candidate.mount)(block_device.clone())
+        {
+            Ok(filesystem) => return mount_from(path, filesystem, Some(device)),
+            // Without a type, a device not holding the filesystem is expected to fail
+            Err(e) if filesystem_type.is_some() => error = e,
+            Err(_) => {}
+        }
+    }
+
+    Err(error)
+}
+
+/// Syncs and unmounts the filesystem mounted at `path`. Fails while files are open on it, and for the
+/// root filesystem.
+pub fn unmount(path: &str) -> Result<(), FsError>
+{
+    let cwd = current_dir();
+    let components = path::normalize(&cwd, path)?;
+    if components.is_empty()
+    {
+        return Err(FsError::Busy);
+    }
+
+    let mount = MOUNTS.write().remove(&components)?;
+    if let Err(e) = mount.filesystem().sync()
+    {
+        warn!("[VFS] Failed to sync {} before unmounting it: {:?}", mount.path(), e);
+    }
+    info!("[VFS] Unmounted {}", mount.path());
+    Ok(())
+}
+
+/// Writes the cached changes of every mounted filesystem, then every dirty block of the buffer cache, to their
+/// devices
+pub fn sync() -> Result<(), FsError>
+{
+    let mounts: Vec<Arc<Mount>> = MOUNTS.read().iter().cloned().collect();
+    let mut result = Ok(());
+    for mount in mounts
+    {
+        if let Err(e) = mount.filesystem().sync()
+        {
+            warn!("[VFS] Failed to sync {}: {:?}", mount.path(), e);
+            result = Err(e);
+        }
+    }
+    // Devices written without a filesystem, like the crash dump partition
+    if let Err(e) = cache::sync()
+    {
+        warn!("[VFS] Failed to sync the buffer cache: {:?}", e);
+        result = Err(e.into());
+    }
+    result
+}
@@ -0,0 +1,116 @@
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::RwLock;
+
+use crate::fs::{path, FileSystem, FsError};
+
+/// A filesystem mounted on a directory
+pub struct Mount
+{
+    components: Vec<String>,
+    filesystem: Arc<dyn FileSystem>,
+    /// Name of the block device the filesystem was mounted from
+    source: Option<String>
+}
+
+impl Mount
+{
+    pub fn path(&self) -> String
+    {
+        path::join(&self.components)
+    }
+
+    #[inline]
+    pub fn filesystem(&self) -> &Arc<dyn FileSystem>
+    {
+        &self.filesystem
+    }
+
+    #[inline]
+    pub fn source(&self) -> Option<&str>
+    {
+        self.source.as_deref()
+    }
+
+    /// Number of path components of the mount point, 0 for the root
+    #[inline]
+    pub fn depth(&self) -> usize
+    {
+        self.components.len()
+    }
+}
+
+/// The mounted filesystems. A path belongs to the deepest mount it is below.
+pub struct MountTable
+{
+    mounts: Vec<Arc<Mount>>
+}
+
+impl MountTable
+{
+    pub const fn new() -> Self
+    {
+        MountTable {
+            mounts: Vec::new()
+        }
+    }
+
+    /// The deepest mount containing the path made of `components`
+    pub fn find<S: AsRef<str>>(&self, components: &[S]) -> Option<Arc<Mount>>
+    {
+        self.mounts.iter()
+            .filter(|mount| path::starts_with(components, &mount.components))
+            .max_by_key(|mount| mount.components.len())
+            .cloned()
+    }
+
+    /// Whether a filesystem is mounted exactly at the path made of `components`
+    pub fn is_mount_point<S: AsRef<str>>(&self, components: &[S]) -> bool
+    {
+        self.mounts.iter().any(|mount| path::starts_with(components, &mount.components) && mount.components.len() == components.len())
+    }
+
+    pub(super) fn add<S: AsRef<str>>(&mut self, components: &[S], filesystem: Arc<dyn FileSystem>, source: Option<&str>) -> Result<(), FsError>
+    {
+        if self.is_mount_point(components)
+        {
+            return Err(FsError::Busy);
+        }
+
+        self.mounts.push(Arc::new(Mount {
+            components: components.iter().map(|component| component.as_ref().to_string()).collect(),
+            filesystem,
+            source: source.map(String::from)
+        }));
+        Ok(())
+    }
+
+    /// Removes the mount at the path made of `components`, unless files are open on it or other
+    /// filesystems are mounted below it
+    pub(super) fn remove<S: AsRef<str>>(&mut self, components: &[S]) -> Result<Arc<Mount>, FsError>
+    {
+        let index = self.mounts.iter()
+            .position(|mount| path::starts_with(components, &mount.components) && mount.components.len() == components.len())
+            .ok_or(FsError::InvalidArgument)?;
+
+        let nested = self.mounts.iter().any(|mount| mount.components.len() > components.len() && path::starts_with(&mount.components, components));
+        if nested || Arc::strong_count(&self.mounts[index]) > 1
+        {
+            return Err(FsError::Busy);
+        }
+
+        Ok(self.mounts.remove(index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Mount>>
+    {
+        self.mounts.iter()
+    }
+}
+
+lazy_static!
+{
+    pub static ref MOUNTS: RwLock<MountTable> = RwLock::new(MountTable::new());
+}
@@ -0,0 +1,59 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::FsError;
+
+pub const SEPARATOR: char = '/';
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Resolves `path` against the absolute directory `cwd` into the components of an absolute path, applying
+/// `.` and `..` lexically. `..` at the root stays at the root.
+pub fn normalize<'a>(cwd: &'a str, path: &'a str) -> Result<Vec<&'a str>, FsError>
+{
+    if path.is_empty()
+    {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    let relative = if path.starts_with(SEPARATOR) { None } else { Some(cwd) };
+
+    for component in relative.into_iter().chain(core::iter::once(path)).flat_map(|part| part.split(SEPARATOR))
+    {
+        match component
+        {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name if name.len() > MAX_NAME_LENGTH => return Err(FsError::NameTooLong),
+            name if name.contains('\0') => return Err(FsError::InvalidPath),
+            name => components.push(name)
+        }
+    }
+
+    Ok(components)
+}
+
+/// The absolute path made of `components`
+pub fn join<S: AsRef<str>>(components: &[S]) -> String
+{
+    if components.is_empty()
+    {
+        return String::from("/");
+    }
+
+    let mut path = String::new();
+    for component in components
+    {
+        path.push(SEPARATOR);
+        path.push_str(component.as_ref());
+    }
+    path
+}
+
+/// Whether the path made of `components` is `prefix` or below it
+pub fn starts_with<S: AsRef<str>, T: AsRef<str>>(components: &[S], prefix: &[T]) -> bool
+{
+    components.len() >= prefix.len() && components.iter().zip(prefix).all(|(a, b)| a.as_ref() == b.as_ref())
+}
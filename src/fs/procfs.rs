@@ -0,0 +1,357 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::{self, Write};
+use ::aml::{AmlHandle, AmlName, AmlValue, LevelType, NamespaceLevel};
+
+use crate::acpi::{ACPI, AML_CONTEXT};
+use crate::block::cache;
+use crate::fs::{DirEntry, Directory, File, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::logger::LOG_RING;
+use crate::pci::{Bar, PCI_HANDLER};
+use crate::pmm::PMM;
+use crate::{allocator, cmdline, crash, interrupts};
+
+/// Writes the content of a file, generated when the file is looked up
+type Generator = fn(&mut String) -> fmt::Result;
+
+struct Entry
+{
+    name: &'static str,
+    inode: u64,
+    node: Node
+}
+
+enum Node
+{
+    File(Generator),
+    Directory(&'static [Entry])
+}
+
+const ROOT_INODE: u64 = 1;
+
+static ROOT: &[Entry] = &[
+    Entry { name: "acpi", inode: 2, node: Node::Directory(&[
+        Entry { name: "namespace", inode: 3, node: Node::File(aml_namespace) },
+        Entry { name: "tables", inode: 4, node: Node::File(acpi_tables) }
+    ]) },
+    Entry { name: "cache", inode: 11, node: Node::File(cache_stats) },
+    Entry { name: "cmdline", inode: 10, node: Node::File(command_line) },
+    Entry { name: "crash", inode: 9, node: Node::File(previous_crash) },
+    Entry { name: "interrupts", inode: 5, node: Node::File(interrupt_counts) },
+    Entry { name: "kmsg", inode: 6, node: Node::File(kernel_log) },
+    Entry { name: "meminfo", inode: 7, node: Node::File(memory_info) },
+    Entry { name: "pci", inode: 8, node: Node::File(pci_devices) }
+];
+
+/// A read-only filesystem exposing the state of the kernel as text files, usually mounted on `/proc`.
+///
+/// The content of a file is generated when it is looked up, so that an open file reads a consistent
+/// snapshot.
+pub struct ProcFs
+{
+    root: Arc<ProcDirectory>
+}
+
+impl ProcFs
+{
+    pub fn new() -> Self
+    {
+        ProcFs {
+            root: Arc::new(ProcDirectory {
+                inode: ROOT_INODE,
+                entries: ROOT
+            })
+        }
+    }
+}
+
+impl FileSystem for ProcFs
+{
+    fn name(&self) -> &str
+    {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode>
+    {
+        self.root.clone()
+    }
+}
+
+struct ProcDirectory
+{
+    inode: u64,
+    entries: &'static [Entry]
+}
+
+impl Inode for ProcDirectory
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        Ok(Metadata {
+            mode: 0o555,
+            ..Metadata::new(self.inode, FileType::Directory, 0)
+        })
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory>
+    {
+        Some(self)
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl Directory for ProcDirectory
+{
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        let entry = self.entries.iter().find(|entry| entry.name == name).ok_or(FsError::NotFound)?;
+        match entry.node
+        {
+            Node::File(generate) => {
+                let mut content = String::new();
+                generate(&mut content).map_err(|_| FsError::InvalidArgument)?;
+                Ok(Arc::new(ProcFile {
+                    inode: entry.inode,
+                    content
+                }))
+            }
+            Node::Directory(entries) => Ok(Arc::new(ProcDirectory {
+                inode: entry.inode,
+                entries
+            }))
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>
+    {
+        Ok(self.entries.iter().map(|entry| DirEntry {
+            name: String::from(entry.name),
+            inode: entry.inode,
+            file_type: match entry.node
+            {
+                Node::File(_) => FileType::Regular,
+                Node::Directory(_) => FileType::Directory
+            }
+        }).collect())
+    }
+}
+
+struct ProcFile
+{
+    inode: u64,
+    content: String
+}
+
+impl Inode for ProcFile
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        Ok(Metadata {
+            mode: 0o444,
+            ..Metadata::new(self.inode, FileType::Regular, self.content.len() as u64)
+        })
+    }
+
+    fn as_file(&self) -> Option<&dyn File>
+    {
+        Some(self)
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl File for ProcFile
+{
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let content = self.content.as_bytes();
+        let start = core::cmp::min(offset, content.len() as u64) as usize;
+        let length = core::cmp::min(buffer.len(), content.len() - start);
+        buffer[..length].copy_from_slice(&content[start..start + length]);
+        Ok(length)
+    }
+}
+
+/// `/proc/acpi/tables`: signature, physical address and length of each table
+fn acpi_tables(output: &mut String) -> fmt::Result
+{
+    let acpi = ACPI.lock();
+    let tables = &acpi.acpi_tables;
+    for (signature, sdt) in tables.sdts.iter()
+    {
+        writeln!(output, "{} {:#010x} {:>6}{}", signature, sdt.physical_address, sdt.length, if sdt.validated { "" } else { " unvalidated" })?;
+    }
+    if let Some(dsdt) = &tables.dsdt
+    {
+        writeln!(output, "DSDT {:#010x} {:>6}", dsdt.address, dsdt.length)?;
+    }
+    for ssdt in tables.ssdts.iter()
+    {
+        writeln!(output, "SSDT {:#010x} {:>6}", ssdt.address, ssdt.length)?;
+    }
+    Ok(())
+}
+
+/// A scope of the AML namespace, its type and the handles of its objects by name
+type NamespaceScope = (AmlName, LevelType, Vec<(String, AmlHandle)>);
+
+/// `/proc/acpi/namespace`: each scope of the AML namespace with its objects
+fn aml_namespace(output: &mut String) -> fmt::Result
+{
+    let mut context = AML_CONTEXT.lock();
+
+    // The namespace cannot be read while it is traversed, the objects are looked up afterwards
+    let mut levels: Vec<NamespaceScope> = Vec::new();
+    let traversal = context.namespace.traverse(|name: &AmlName, level: &NamespaceLevel| {
+        levels.push((name.clone(), level.typ, level.values.iter().map(|(seg, handle)| (seg.as_str().to_string(), *handle)).collect()));
+        Ok(true)
+    });
+    if let Err(e) = traversal
+    {
+        return writeln!(output, "Failed to traverse the namespace: {:?}", e);
+    }
+
+    for (name, typ, values) in levels
+    {
+        writeln!(output, "{} ({:?})", name.as_string(), typ)?;
+        for (seg, handle) in values
+        {
+            match context.namespace.get(handle)
+            {
+                Ok(value) => writeln!(output, "    {} = {}", seg, describe_aml_value(value))?,
+                Err(e) => writeln!(output, "    {} = <{:?}>", seg, e)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Short description of an AML object, methods and buffers being too large to print
+fn describe_aml_value(value: &AmlValue) -> String
+{
+    match value
+    {
+        AmlValue::Boolean(value) => format!("{}", value),
+        AmlValue::Integer(value) => format!("{:#x}", value),
+        AmlValue::String(value) => format!("\"{}\"", value),
+        AmlValue::Buffer(data) => format!("Buffer[{}]", data.lock().len()),
+        AmlValue::Package(elements) => format!("Package[{}]", elements.len()),
+        AmlValue::Device => String::from("Device"),
+        AmlValue::Method { .. } => String::from("Method"),
+        _ => String::from("Object")
+    }
+}
+
+/// `/proc/cache`: usage and hit rate of the buffer cache
+fn cache_stats(output: &mut String) -> fmt::Result
+{
+    let stats = cache::stats();
+    writeln!(output, "Capacity:   {:>10} blocks", stats.capacity_blocks)?;
+    writeln!(output, "Cached:     {:>10} blocks", stats.cached_blocks)?;
+    writeln!(output, "Dirty:      {:>10} blocks", stats.dirty_blocks)?;
+    writeln!(output, "Hits:       {:>10}", stats.hits)?;
+    writeln!(output, "Misses:     {:>10}", stats.misses)?;
+    writeln!(output, "Readahead:  {:>10}", stats.readahead)?;
+    writeln!(output, "Writebacks: {:>10}", stats.writebacks)?;
+    writeln!(output, "Evictions:  {:>10}", stats.evictions)
+}
+
+/// `/proc/cmdline`: the command line, then the value of each parameter
+fn command_line(output: &mut String) -> fmt::Result
+{
+    writeln!(output, "{}", cmdline::command_line())?;
+    for parameter in cmdline::parameters()
+    {
+        writeln!(output, "{} = {}  # {}", parameter.name(), parameter.value(), parameter.description())?;
+    }
+    Ok(())
+}
+
+/// `/proc/crash`: the crash dump saved by the previous boot, empty if there was none
+fn previous_crash(output: &mut String) -> fmt::Result
+{
+    if let Some(dump) = crash::previous_dump()
+    {
+        output.push_str(&dump);
+    }
+    Ok(())
+}
+
+/// `/proc/interrupts`: interrupts received by each IRQ vector in use
+fn interrupt_counts(output: &mut String) -> fmt::Result
+{
+    for stats in interrupts::irq_stats()
+    {
+        writeln!(output, "{:>3}: {:>10}{}", stats.vector, stats.count, if stats.registered { "" } else { " (no handler)" })?;
+    }
+    writeln!(output, "SPU: {:>10}", interrupts::spurious_count())
+}
+
+/// `/proc/kmsg`: the log lines kept in memory
+fn kernel_log(output: &mut String) -> fmt::Result
+{
+    for record in LOG_RING.lock().records()
+    {
+        writeln!(output, "{}", record)?;
+    }
+    Ok(())
+}
+
+/// `/proc/meminfo`: physical memory handed out by the PMM and usage of the kernel heap
+fn memory_info(output: &mut String) -> fmt::Result
+{
+    let (total, allocated) = {
+        let pmm = PMM.lock();
+        (pmm.total_memory(), pmm.allocated_frames() as u64 * 4096)
+    };
+    let heap = allocator::stats();
+
+    writeln!(output, "PhysicalTotal: {:>10} KiB", total / 1024)?;
+    writeln!(output, "PhysicalUsed:  {:>10} KiB", allocated / 1024)?;
+    writeln!(output, "PhysicalFree:  {:>10} KiB", total.saturating_sub(allocated) / 1024)?;
+    writeln!(output, "HeapTotal:     {:>10} KiB", heap.size / 1024)?;
+    writeln!(output, "HeapUsed:      {:>10} KiB", heap.used / 1024)?;
+    writeln!(output, "HeapFree:      {:>10} KiB", heap.free / 1024)
+}
+
+/// `/proc/pci`: address, IDs, class and BARs of each PCI function
+fn pci_devices(output: &mut String) -> fmt::Result
+{
+    let mut handler = PCI_HANDLER.lock();
+    let handler = match handler.as_mut()
+    {
+        Some(handler) => handler,
+        None => return writeln!(output, "PCI is not available")
+    };
+
+    for device in handler.enumerate_devices()
+    {
+        let address = device.get_address();
+        let (vendor_id, device_id) = device.get_ids();
+        let (class, subclass, interface) = device.get_class();
+        writeln!(output, "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} {} {}", address, vendor_id, device_id, class, subclass,
+            interface, device.get_vendor_name(), device.get_device_name())?;
+
+        for (slot, bar) in handler.bars(address)
+        {
+            match *bar
+            {
+                Bar::Memory { base, size, prefetchable } => writeln!(output, "    BAR{}: memory at {:#x}, {:#x} bytes{}", slot,
+                    base.as_u64(), size, if prefetchable { ", prefetchable" } else { "" })?,
+                Bar::Io { port } => writeln!(output, "    BAR{}: I/O at {:#x}", slot, port)?
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::fs::{DirEntry, Directory, FileSystem, FileType, FsError, Inode, Metadata};
+
+/// The filesystem mounted at `/` at boot. It only holds directories, which serve as mount points for the
+/// filesystems holding actual files.
+pub struct RootFs
+{
+    root: Arc<RootDirectory>
+}
+
+impl RootFs
+{
+    pub fn new() -> Self
+    {
+        RootFs {
+            root: Arc::new(RootDirectory::new())
+        }
+    }
+}
+
+impl FileSystem for RootFs
+{
+    fn name(&self) -> &str
+    {
+        "rootfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode>
+    {
+        self.root.clone()
+    }
+}
+
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+struct RootDirectory
+{
+    inode: u64,
+    entries: Mutex<BTreeMap<String, Arc<RootDirectory>>>
+}
+
+impl RootDirectory
+{
+    fn new() -> Self
+    {
+        RootDirectory {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            entries: Mutex::new(BTreeMap::new())
+        }
+    }
+}
+
+impl Inode for RootDirectory
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        let entries = self.entries.lock();
+        Ok(Metadata {
+            links: 2 + entries.len() as u32,
+            ..Metadata::new(self.inode, FileType::Directory, 0)
+        })
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory>
+    {
+        Some(self)
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl Directory for RootDirectory
+{
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        match self.entries.lock().get(name)
+        {
+            Some(directory) => Ok(directory.clone()),
+            None => Err(FsError::NotFound)
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>
+    {
+        Ok(self.entries.lock().iter().map(|(name, directory)| DirEntry {
+            name: name.clone(),
+            inode: directory.inode,
+            file_type: FileType::Directory
+        }).collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError>
+    {
+        if file_type != FileType::Directory
+        {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name)
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let directory = Arc::new(RootDirectory::new());
+        entries.insert(String::from(name), directory.clone());
+        Ok(directory)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError>
+    {
+        let mut entries = self.entries.lock();
+        match entries.get(name)
+        {
+            None => Err(FsError::NotFound),
+            Some(directory) if !directory.entries.lock().is_empty() => Err(FsError::DirectoryNotEmpty),
+            Some(_) => {
+                entries.remove(name);
+                Ok(())
+            }
+        }
+    }
+
+    fn rename(&self, name: &str, target: &dyn Inode, new_name: &str) -> Result<(), FsError>
+    {
+        let target = target.as_any().downcast_ref::<RootDirectory>().ok_or(FsError::CrossDevice)?;
+        if core::ptr::eq(self, target)
+        {
+            let mut entries = self.entries.lock();
+            if entries.contains_key(new_name)
+            {
+                return Err(FsError::AlreadyExists);
+            }
+            let directory = entries.remove(name).ok_or(FsError::NotFound)?;
+            entries.insert(String::from(new_name), directory);
+            return Ok(());
+        }
+
+        // Locked one after the other, so that two renames in opposite directions cannot deadlock
+        let directory = self.entries.lock().get(name).cloned().ok_or(FsError::NotFound)?;
+        {
+            let mut target_entries = target.entries.lock();
+            if target_entries.contains_key(new_name)
+            {
+                return Err(FsError::AlreadyExists);
+            }
+            target_entries.insert(String::from(new_name), directory);
+        }
+        self.entries.lock().remove(name);
+        Ok(())
+    }
+}
@@ -0,0 +1,471 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::fs::path::MAX_NAME_LENGTH;
+use crate::fs::{DirEntry, Directory, File, FileSystem, FileType, FsError, FsStats, Inode, Metadata};
+use crate::vmm::VMM;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Pages released by tmpfs files, kept for reuse as the PMM cannot take them back
+static FREE_PAGES: Mutex<Vec<VirtAddr>> = Mutex::new(Vec::new());
+
+/// A filesystem keeping its files in memory, in pages from the PMM. It holds at most `size_limit` bytes of
+/// file content.
+pub struct TmpFs
+{
+    root: Arc<TmpNode>,
+    shared: Arc<Shared>
+}
+
+/// The state shared by the nodes of a tmpfs
+struct Shared
+{
+    page_limit: usize,
+    used_pages: AtomicUsize,
+    next_inode: AtomicU64
+}
+
+impl Shared
+{
+    fn new_node(self: &Arc<Self>, content: Content) -> Arc<TmpNode>
+    {
+        Arc::new(TmpNode {
+            inode: self.next_inode.fetch_add(1, Ordering::Relaxed),
+            shared: self.clone(),
+            content
+        })
+    }
+
+    /// Takes a zeroed page, counted against the size limit
+    fn allocate_page(&self) -> Result<VirtAddr, FsError>
+    {
+        if self.used_pages.fetch_add(1, Ordering::Relaxed) >= self.page_limit
+        {
+            self.used_pages.fetch_sub(1, Ordering::Relaxed);
+            return Err(FsError::NoSpace);
+        }
+
+        let page = FREE_PAGES.lock().pop();
+        let page = match page
+        {
+            Some(page) => page,
+            None => match VMM.lock().allocate_pages(1)
+            {
+                Ok(page) => page,
+                Err(_) => {
+                    self.used_pages.fetch_sub(1, Ordering::Relaxed);
+                    return Err(FsError::NoSpace);
+                }
+            }
+        };
+
+        unsafe { core::ptr::write_bytes(page.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+        Ok(page)
+    }
+
+    fn free_page(&self, page: VirtAddr)
+    {
+        FREE_PAGES.lock().push(page);
+        self.used_pages.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl TmpFs
+{
+    pub fn new(size_limit: u64) -> Self
+    {
+        let shared = Arc::new(Shared {
+            page_limit: ((size_limit + PAGE_SIZE - 1) / PAGE_SIZE) as usize,
+            used_pages: AtomicUsize::new(0),
+            next_inode: AtomicU64::new(1)
+        });
+
+        TmpFs {
+            root: shared.new_node(Content::Directory(Mutex::new(BTreeMap::new()))),
+            shared
+        }
+    }
+}
+
+impl FileSystem for TmpFs
+{
+    fn name(&self) -> &str
+    {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode>
+    {
+        self.root.clone()
+    }
+
+    fn stats(&self) -> Result<FsStats, FsError>
+    {
+        let used = self.shared.used_pages.load(Ordering::Relaxed);
+        Ok(FsStats {
+            block_size: PAGE_SIZE as usize,
+            total_blocks: self.shared.page_limit as u64,
+            free_blocks: self.shared.page_limit.saturating_sub(used) as u64,
+            total_inodes: 0,
+            free_inodes: 0
+        })
+    }
+}
+
+/// Content of a file by page index, pages not written to yet are holes reading as zeroes
+struct FileData
+{
+    pages: BTreeMap<u64, VirtAddr>,
+    size: u64
+}
+
+enum Content
+{
+    File(Mutex<FileData>),
+    Directory(Mutex<BTreeMap<String, Arc<TmpNode>>>),
+    Symlink(String)
+}
+
+/// A file, directory or symbolic link of a tmpfs. Its pages are released once it is unlinked and unused.
+struct TmpNode
+{
+    inode: u64,
+    shared: Arc<Shared>,
+    content: Content
+}
+
+impl TmpNode
+{
+    fn entries(&self) -> &Mutex<BTreeMap<String, Arc<TmpNode>>>
+    {
+        match &self.content
+        {
+            Content::Directory(entries) => entries,
+            _ => unreachable!("tmpfs: directory operation on a file")
+        }
+    }
+
+    fn data(&self) -> &Mutex<FileData>
+    {
+        match &self.content
+        {
+            Content::File(data) => data,
+            _ => unreachable!("tmpfs: file operation on a directory")
+        }
+    }
+
+    fn file_type(&self) -> FileType
+    {
+        match self.content
+        {
+            Content::File(_) => FileType::Regular,
+            Content::Directory(_) => FileType::Directory,
+            Content::Symlink(_) => FileType::Symlink
+        }
+    }
+
+    fn is_directory(&self) -> bool
+    {
+        matches!(self.content, Content::Directory(_))
+    }
+
+    /// Frees the pages past `size` and zeroes the end of the last page kept
+    fn shrink(&self, data: &mut FileData, size: u64)
+    {
+        let kept = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        for page in data.pages.split_off(&kept).into_values()
+        {
+            self.shared.free_page(page);
+        }
+
+        let tail = (size % PAGE_SIZE) as usize;
+        if tail != 0
+        {
+            if let Some(page) = data.pages.get(&(kept - 1))
+            {
+                unsafe { core::ptr::write_bytes((*page + tail as u64).as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize - tail) };
+            }
+        }
+    }
+}
+
+impl Drop for TmpNode
+{
+    fn drop(&mut self)
+    {
+        if let Content::File(data) = &self.content
+        {
+            let mut data = data.lock();
+            self.shrink(&mut data, 0);
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), FsError>
+{
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0')
+    {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > MAX_NAME_LENGTH
+    {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+impl Inode for TmpNode
+{
+    fn metadata(&self) -> Result<Metadata, FsError>
+    {
+        let (size, links) = match &self.content
+        {
+            Content::File(data) => (data.lock().size, 1),
+            Content::Directory(entries) => {
+                let entries = entries.lock();
+                (0, 2 + entries.values().filter(|node| node.is_directory()).count() as u32)
+            }
+            Content::Symlink(target) => (target.len() as u64, 1)
+        };
+
+        let mut metadata = Metadata::new(self.inode, self.file_type(), size);
+        metadata.links = links;
+        if let Content::Symlink(_) = self.content
+        {
+            metadata.mode = 0o777;
+        }
+        Ok(metadata)
+    }
+
+    fn as_file(&self) -> Option<&dyn File>
+    {
+        match self.content
+        {
+            Content::File(_) => Some(self),
+            _ => None
+        }
+    }
+
+    fn as_directory(&self) -> Option<&dyn Directory>
+    {
+        match self.content
+        {
+            Content::Directory(_) => Some(self),
+            _ => None
+        }
+    }
+
+    fn read_link(&self) -> Result<String, FsError>
+    {
+        match &self.content
+        {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument)
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+impl File for TmpNode
+{
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>
+    {
+        let data = self.data().lock();
+        if offset >= data.size
+        {
+            return Ok(0);
+        }
+
+        let length = core::cmp::min(buffer.len() as u64, data.size - offset) as usize;
+        let mut done = 0;
+        while done < length
+        {
+            let position = offset + done as u64;
+            let start = (position % PAGE_SIZE) as usize;
+            let chunk = core::cmp::min(PAGE_SIZE as usize - start, length - done);
+
+            match data.pages.get(&(position / PAGE_SIZE)).copied()
+            {
+                Some(page) => unsafe {
+                    core::ptr::copy_nonoverlapping((page + start as u64).as_ptr::<u8>(), buffer[done..].as_mut_ptr(), chunk);
+                },
+                None => buffer[done..done + chunk].fill(0)
+            }
+            done += chunk;
+        }
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError>
+    {
+        let mut data = self.data().lock();
+        offset.checked_add(buffer.len() as u64).ok_or(FsError::InvalidArgument)?;
+
+        let mut done = 0;
+        let mut error = None;
+        while done < buffer.len()
+        {
+            let position = offset + done as u64;
+            let start = (position % PAGE_SIZE) as usize;
+            let chunk = core::cmp::min(PAGE_SIZE as usize - start, buffer.len() - done);
+
+            let index = position / PAGE_SIZE;
+            let page = match data.pages.get(&index).copied()
+            {
+                Some(page) => page,
+                None => match self.shared.allocate_page()
+                {
+                    Ok(page) => {
+                        data.pages.insert(index, page);
+                        page
+                    }
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                }
+            };
+            unsafe { core::ptr::copy_nonoverlapping(buffer[done..].as_ptr(), (page + start as u64).as_mut_ptr::<u8>(), chunk) };
+            done += chunk;
+        }
+
+        // What was written before running out of space stays
+        data.size = core::cmp::max(data.size, offset + done as u64);
+        match error
+        {
+            Some(e) if done == 0 => Err(e),
+            _ => Ok(done)
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError>
+    {
+        let mut data = self.data().lock();
+        if size < data.size
+        {
+            self.shrink(&mut data, size);
+        }
+        data.size = size;
+        Ok(())
+    }
+}
+
+impl Directory for TmpNode
+{
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        match self.entries().lock().get(name)
+        {
+            Some(node) => Ok(node.clone()),
+            None => Err(FsError::NotFound)
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>
+    {
+        Ok(self.entries().lock().iter().map(|(name, node)| DirEntry {
+            name: name.clone(),
+            inode: node.inode,
+            file_type: node.file_type()
+        }).collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError>
+    {
+        validate_name(name)?;
+        let content = match file_type
+        {
+            FileType::Regular => Content::File(Mutex::new(FileData {
+                pages: BTreeMap::new(),
+                size: 0
+            })),
+            FileType::Directory => Content::Directory(Mutex::new(BTreeMap::new())),
+            _ => return Err(FsError::NotSupported)
+        };
+
+        let mut entries = self.entries().lock();
+        if entries.contains_key(name)
+        {
+            return Err(FsError::AlreadyExists);
+        }
+        let node = self.shared.new_node(content);
+        entries.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError>
+    {
+        validate_name(name)?;
+        let mut entries = self.entries().lock();
+        if entries.contains_key(name)
+        {
+            return Err(FsError::AlreadyExists);
+        }
+        let node = self.shared.new_node(Content::Symlink(String::from(target)));
+        entries.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError>
+    {
+        // The node is dropped after the directory is unlocked, its pages are freed unless it is in use
+        let removed = {
+            let mut entries = self.entries().lock();
+            match entries.get(name)
+            {
+                None => return Err(FsError::NotFound),
+                Some(node) if node.is_directory() && !node.entries().lock().is_empty() => return Err(FsError::DirectoryNotEmpty),
+                Some(_) => entries.remove(name)
+            }
+        };
+        drop(removed);
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, target: &dyn Inode, new_name: &str) -> Result<(), FsError>
+    {
+        validate_name(new_name)?;
+        let target = target.as_any().downcast_ref::<TmpNode>()
+            .filter(|target| Arc::ptr_eq(&target.shared, &self.shared))
+            .ok_or(FsError::CrossDevice)?;
+        if !target.is_directory()
+        {
+            return Err(FsError::NotADirectory);
+        }
+
+        let node = self.entries().lock().get(name).cloned().ok_or(FsError::NotFound)?;
+
+        // An existing file is replaced, a directory never is
+        let replaced = {
+            let mut target_entries = target.entries().lock();
+            match target_entries.get(new_name)
+            {
+                Some(existing) if Arc::ptr_eq(existing, &node) => return Ok(()),
+                Some(existing) if existing.is_directory() => return Err(FsError::AlreadyExists),
+                Some(_) if node.is_directory() => return Err(FsError::NotADirectory),
+                _ => target_entries.insert(String::from(new_name), node.clone())
+            }
+        };
+
+        // Locked one after the other, so that two renames in opposite directions cannot deadlock
+        let mut entries = self.entries().lock();
+        if entries.get(name).map_or(false, |current| Arc::ptr_eq(current, &node))
+        {
+            entries.remove(name);
+        }
+        drop(entries);
+        drop(replaced);
+        Ok(())
+    }
+}
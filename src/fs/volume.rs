@@ -0,0 +1,111 @@
+use alloc::sync::Arc;
+use alloc::vec;
+
+use crate::block::cache::CachedDevice;
+use crate::block::BlockDevice;
+use crate::fs::FsError;
+
+/// A block device addressed in bytes, through the buffer cache. Partial sectors are read, modified and
+/// written back.
+pub struct Volume
+{
+    device: CachedDevice
+}
+
+impl Volume
+{
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self
+    {
+        Volume {
+            device: CachedDevice::new(device)
+        }
+    }
+
+    /// Size of the device in bytes
+    pub fn size(&self) -> u64
+    {
+        self.device.sector_count() * self.device.sector_size() as u64
+    }
+
+    pub fn is_read_only(&self) -> bool
+    {
+        self.device.is_read_only()
+    }
+
+    pub fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError>
+    {
+        let sector_size = self.device.sector_size() as u64;
+        if offset % sector_size == 0 && buffer.len() as u64 % sector_size == 0
+        {
+            return Ok(self.device.read_sectors(offset / sector_size, buffer)?);
+        }
+
+        let mut sector = vec![0u8; sector_size as usize];
+        let mut done = 0;
+        while done < buffer.len()
+        {
+            let position = offset + done as u64;
+            let start = (position % sector_size) as usize;
+            let length = core::cmp::min(buffer.len() - done, sector_size as usize - start);
+
+            self.device.read_sectors(position / sector_size, &mut sector)?;
+            buffer[done..done + length].copy_from_slice(&sector[start..start + length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, offset: u64, buffer: &[u8]) -> Result<(), FsError>
+    {
+        let sector_size = self.device.sector_size() as u64;
+        if offset % sector_size == 0 && buffer.len() as u64 % sector_size == 0
+        {
+            return Ok(self.device.write_sectors(offset / sector_size, buffer)?);
+        }
+
+        let mut sector = vec![0u8; sector_size as usize];
+        let mut done = 0;
+        while done < buffer.len()
+        {
+            let position = offset + done as u64;
+            let start = (position % sector_size) as usize;
+            let length = core::cmp::min(buffer.len() - done, sector_size as usize - start);
+
+            if length != sector_size as usize
+            {
+                self.device.read_sectors(position / sector_size, &mut sector)?;
+            }
+            sector[start..start + length].copy_from_slice(&buffer[done..done + length]);
+            self.device.write_sectors(position / sector_size, &sector)?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Fills `length` bytes at `offset` with zeroes
+    pub fn zero(&self, offset: u64, length: u64) -> Result<(), FsError>
+    {
+        let zeroes = [0u8; 4096];
+        let mut done = 0;
+        while done < length
+        {
+            let chunk = core::cmp::min(length - done, zeroes.len() as u64) as usize;
+            self.write(offset + done, &zeroes[..chunk])?;
+            done += chunk as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes the cached sectors back and flushes the device
+    pub fn sync(&self) -> Result<(), FsError>
+    {
+        Ok(self.device.flush()?)
+    }
+
+    pub fn read_u32(&self, offset: u64) -> Result<u32, FsError>
+    {
+        let mut bytes = [0u8; 4];
+        self.read(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
@@ -0,0 +1,156 @@
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::pmm::PMM;
+use crate::vmm::VMM;
+
+const PAGE_SIZE: u64 = 0x1000;
+/// Opcode of `int3`, written over the first byte of the instruction a breakpoint is set on
+const INT3: u8 = 0xCC;
+const MAX_BREAKPOINTS: usize = 32;
+
+#[derive(Debug)]
+pub enum MemoryError
+{
+    /// The interrupted code holds the page tables or the frame allocator, waiting for them would never end
+    Busy,
+    /// The address is not canonical or not mapped
+    NotMapped(u64),
+    /// No writable mapping of the page could be made
+    MappingFailed,
+    /// Every breakpoint is in use
+    NoFreeBreakpoint
+}
+
+fn virtual_address(address: u64) -> Result<VirtAddr, MemoryError>
+{
+    VirtAddr::try_new(address).map_err(|_| MemoryError::NotMapped(address))
+}
+
+/// Calls `f` with each of the `length` bytes at `address`, checking their pages are mapped with
+/// `Vmm::translate_addr` before reading them
+pub fn read(address: u64, length: u64, mut f: impl FnMut(u8)) -> Result<(), MemoryError>
+{
+    let vmm = VMM.try_lock().ok_or(MemoryError::Busy)?;
+    for offset in 0..length
+    {
+        let virt_addr = virtual_address(address.wrapping_add(offset))?;
+        if offset == 0 || virt_addr.is_aligned(PAGE_SIZE)
+        {
+            vmm.translate_addr(virt_addr).ok_or(MemoryError::NotMapped(virt_addr.as_u64()))?;
+        }
+        f(unsafe { virt_addr.as_ptr::<u8>().read_volatile() });
+    }
+    Ok(())
+}
+
+/// Writes `data` at `address` through a writable mapping of the physical pages behind it, so that read-only
+/// code can be patched
+pub fn write(address: u64, data: impl Iterator<Item = u8>) -> Result<(), MemoryError>
+{
+    let mut vmm = VMM.try_lock().ok_or(MemoryError::Busy)?;
+    // Mapping may allocate page tables
+    if PMM.is_locked()
+    {
+        return Err(MemoryError::Busy);
+    }
+
+    let mut data = data.peekable();
+    let mut address = address;
+    while data.peek().is_some()
+    {
+        let virt_addr = virtual_address(address)?;
+        let phys_addr = vmm.translate_addr(virt_addr).ok_or(MemoryError::NotMapped(address))?;
+        let length = PAGE_SIZE - address % PAGE_SIZE;
+        let mapping = vmm.map_region(phys_addr, length, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+            .map_err(|_| MemoryError::MappingFailed)?;
+
+        let pointer = mapping.as_mut_ptr::<u8>();
+        let mut written = 0;
+        while written < length
+        {
+            match data.next()
+            {
+                Some(byte) => unsafe { pointer.add(written as usize).write_volatile(byte) },
+                None => break
+            }
+            written += 1;
+        }
+
+        // The temporary mapping is lost if it cannot be removed, the write itself succeeded
+        let _ = vmm.unmap_region(mapping, length);
+        address = address.wrapping_add(written);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Breakpoint
+{
+    address: u64,
+    /// The byte replaced by `int3`
+    original: u8
+}
+
+/// The software breakpoints set by the debugger
+pub struct Breakpoints
+{
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS]
+}
+
+impl Breakpoints
+{
+    pub const fn new() -> Self
+    {
+        Breakpoints {
+            breakpoints: [None; MAX_BREAKPOINTS]
+        }
+    }
+
+    pub fn insert(&mut self, address: u64) -> Result<(), MemoryError>
+    {
+        if self.breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == address)
+        {
+            return Ok(());
+        }
+        let slot = self.breakpoints.iter_mut().find(|slot| slot.is_none()).ok_or(MemoryError::NoFreeBreakpoint)?;
+
+        let mut original = 0;
+        read(address, 1, |byte| original = byte)?;
+        write(address, core::iter::once(INT3))?;
+        *slot = Some(Breakpoint { address, original });
+        Ok(())
+    }
+
+    /// Puts the original byte back, removing a breakpoint which is not set succeeds
+    pub fn remove(&mut self, address: u64) -> Result<(), MemoryError>
+    {
+        for slot in self.breakpoints.iter_mut()
+        {
+            if let Some(breakpoint) = *slot
+            {
+                if breakpoint.address == address
+                {
+                    write(address, core::iter::once(breakpoint.original))?;
+                    *slot = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every breakpoint, keeping the ones which could not be removed
+    pub fn remove_all(&mut self)
+    {
+        for slot in self.breakpoints.iter_mut()
+        {
+            if let Some(breakpoint) = *slot
+            {
+                if write(breakpoint.address, core::iter::once(breakpoint.original)).is_ok()
+                {
+                    *slot = None;
+                }
+            }
+        }
+    }
+}
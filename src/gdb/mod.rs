@@ -0,0 +1,432 @@
+mod memory;
+mod packet;
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{info, warn};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+
+use crate::cmdline::Parameter;
+use crate::fs::devfs;
+use crate::interrupts::{self, TrapFrame};
+use crate::serial::COM2;
+use memory::{Breakpoints, MemoryError};
+use packet::{Reply, PACKET_SIZE};
+
+/// Legacy IRQ of the second serial port
+const COM2_IRQ: u8 = 3;
+/// Offset of the line status register, and its bit telling a byte was received
+const LINE_STATUS: u16 = 5;
+const DATA_READY: u8 = 1 << 0;
+/// Bit of RFLAGS raising a debug exception after the next instruction
+const TRAP_FLAG: u64 = 1 << 8;
+/// Signals reported to the debugger when the kernel stops
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+/// Errors replied to the debugger
+const EFAULT: u8 = 14;
+const EBUSY: u8 = 16;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+/// Registers of the `g` packet, in the order of the amd64 target of GDB
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+
+pub static GDB_ENABLED: Parameter<bool> = Parameter::new("gdb", false, "whether to run the GDB stub on the second serial port, on or off");
+pub static GDB_WAIT: Parameter<bool> = Parameter::new("gdb.wait", false, "whether to stop at boot until the debugger attaches, on or off");
+
+/// The state of the stub, kept while the kernel runs. It lives outside of the heap, which the stopped code may
+/// be using.
+struct Stub
+{
+    port: SerialPort,
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+    breakpoints: Breakpoints,
+    /// Whether the debugger is attached and waits for a stop reply when the kernel stops
+    attached: bool
+}
+
+static STUB: Mutex<Option<Stub>> = Mutex::new(None);
+
+/// Set by the serial port interrupt when the debugger asks to stop the kernel
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// What to do once a packet is handled
+enum Action
+{
+    Reply,
+    /// Resumes the kernel, for one instruction when stepping
+    Resume { step: bool },
+    /// Removes the breakpoints and resumes the kernel until the debugger attaches again
+    Detach
+}
+
+/// The value and size in bytes of the register `number`
+fn register(frame: &TrapFrame, number: usize) -> Option<(u64, usize)>
+{
+    let value = match number
+    {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        RIP => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        20 => DS::get_reg().0 as u64,
+        21 => ES::get_reg().0 as u64,
+        22 => FS::get_reg().0 as u64,
+        23 => GS::get_reg().0 as u64,
+        _ => return None
+    };
+    Some((value, if number <= RIP { 8 } else { 4 }))
+}
+
+/// The register `number` of the interrupted code, if it can be changed. The segment registers cannot.
+fn register_mut(frame: &mut TrapFrame, number: usize) -> Option<&mut u64>
+{
+    match number
+    {
+        0 => Some(&mut frame.rax),
+        1 => Some(&mut frame.rbx),
+        2 => Some(&mut frame.rcx),
+        3 => Some(&mut frame.rdx),
+        4 => Some(&mut frame.rsi),
+        5 => Some(&mut frame.rdi),
+        6 => Some(&mut frame.rbp),
+        7 => Some(&mut frame.rsp),
+        8 => Some(&mut frame.r8),
+        9 => Some(&mut frame.r9),
+        10 => Some(&mut frame.r10),
+        11 => Some(&mut frame.r11),
+        12 => Some(&mut frame.r12),
+        13 => Some(&mut frame.r13),
+        14 => Some(&mut frame.r14),
+        15 => Some(&mut frame.r15),
+        RIP => Some(&mut frame.rip),
+        17 => Some(&mut frame.rflags),
+        _ => None
+    }
+}
+
+/// Sets the register `number` to the value encoded in `hex`, returns whether it is valid
+fn set_register(frame: &mut TrapFrame, number: usize, hex: &[u8]) -> bool
+{
+    let (_, size) = match register(frame, number)
+    {
+        Some(register) => register,
+        None => return false
+    };
+    let value = match packet::parse_le(hex)
+    {
+        Some(value) if hex.len() == size * 2 => value,
+        _ => return false
+    };
+    if let Some(register) = register_mut(frame, number)
+    {
+        *register = value;
+    }
+    true
+}
+
+fn memory_error(reply: &mut Reply, error: MemoryError)
+{
+    reply.error(match error
+    {
+        MemoryError::Busy => EBUSY,
+        MemoryError::NoFreeBreakpoint => ENOSPC,
+        MemoryError::NotMapped(_) | MemoryError::MappingFailed => EFAULT
+    });
+}
+
+/// Splits `arguments` at the first `separator`
+fn split(arguments: &[u8], separator: u8) -> Option<(&[u8], &[u8])>
+{
+    let index = arguments.iter().position(|byte| *byte == separator)?;
+    Some((&arguments[..index], &arguments[index + 1..]))
+}
+
+/// Parses `address,length`
+fn parse_range(arguments: &[u8]) -> Option<(u64, u64)>
+{
+    let (address, length) = split(arguments, b',')?;
+    Some((packet::parse_hex(address)?, packet::parse_hex(length)?))
+}
+
+/// Handles a general query, the unsupported ones get an empty reply
+fn query(query: &[u8], reply: &mut Reply)
+{
+    if query.starts_with(b"Supported")
+    {
+        reply.push_str("PacketSize=");
+        reply.push_hex(PACKET_SIZE as u64);
+    }
+    else if query == b"Attached"
+    {
+        // Detaching leaves the kernel running rather than killing it
+        reply.push_str("1");
+    }
+    else if query == b"C"
+    {
+        reply.push_str("QC1");
+    }
+    else if query == b"fThreadInfo"
+    {
+        reply.push_str("m1");
+    }
+    else if query == b"sThreadInfo"
+    {
+        reply.push_str("l");
+    }
+}
+
+/// Handles the packet `command` from the debugger, writing the reply to `reply`
+fn handle(command: &[u8], frame: &mut TrapFrame, breakpoints: &mut Breakpoints, reply: &mut Reply, signal: u8) -> Action
+{
+    let (kind, arguments) = match command.split_first()
+    {
+        Some((kind, arguments)) => (*kind, arguments),
+        None => return Action::Reply
+    };
+
+    match kind
+    {
+        b'?' => {
+            reply.push_str("S");
+            reply.push_byte(signal);
+        }
+        b'g' => {
+            for number in 0..REGISTER_COUNT
+            {
+                if let Some((value, size)) = register(frame, number)
+                {
+                    reply.push_le(value, size);
+                }
+            }
+        }
+        b'G' => {
+            let mut rest = arguments;
+            for number in 0..REGISTER_COUNT
+            {
+                let size = register(frame, number).map_or(0, |(_, size)| size * 2);
+                if rest.len() < size
+                {
+                    break;
+                }
+                let (value, next) = rest.split_at(size);
+                if !set_register(frame, number, value)
+                {
+                    reply.error(EINVAL);
+                    return Action::Reply;
+                }
+                rest = next;
+            }
+            reply.push_str("OK");
+        }
+        b'p' => match packet::parse_hex(arguments).and_then(|number| register(frame, number as usize))
+        {
+            Some((value, size)) => reply.push_le(value, size),
+            None => reply.error(EINVAL)
+        },
+        b'P' => {
+            let valid = split(arguments, b'=')
+                .and_then(|(number, value)| Some(set_register(frame, packet::parse_hex(number)? as usize, value)))
+                .unwrap_or(false);
+            if valid { reply.push_str("OK") } else { reply.error(EINVAL) }
+        }
+        b'm' => match parse_range(arguments)
+        {
+            // Each byte takes two digits of the reply
+            Some((address, length)) if length <= PACKET_SIZE as u64 / 2 => {
+                if let Err(e) = memory::read(address, length, |byte| reply.push_byte(byte))
+                {
+                    memory_error(reply, e);
+                }
+            }
+            _ => reply.error(EINVAL)
+        },
+        b'M' => {
+            let write = split(arguments, b':').and_then(|(range, data)| {
+                let (address, length) = parse_range(range)?;
+                let data = packet::parse_bytes(data).filter(|data| data.len() as u64 == length)?;
+                Some(memory::write(address, data))
+            });
+            match write
+            {
+                Some(Ok(())) => reply.push_str("OK"),
+                Some(Err(e)) => memory_error(reply, e),
+                None => reply.error(EINVAL)
+            }
+        }
+        b'c' | b's' => {
+            if !arguments.is_empty()
+            {
+                match packet::parse_hex(arguments)
+                {
+                    Some(address) => frame.rip = address,
+                    None => {
+                        reply.error(EINVAL);
+                        return Action::Reply;
+                    }
+                }
+            }
+            return Action::Resume { step: kind == b's' };
+        }
+        // Only software breakpoints, `Z0,address,kind`, are supported
+        b'Z' | b'z' if arguments.starts_with(b"0,") => {
+            let address = split(&arguments[2..], b',').and_then(|(address, _)| packet::parse_hex(address));
+            let result = match address
+            {
+                Some(address) if kind == b'Z' => breakpoints.insert(address),
+                Some(address) => breakpoints.remove(address),
+                None => {
+                    reply.error(EINVAL);
+                    return Action::Reply;
+                }
+            };
+            match result
+            {
+                Ok(()) => reply.push_str("OK"),
+                Err(e) => memory_error(reply, e)
+            }
+        }
+        b'D' => {
+            reply.push_str("OK");
+            return Action::Detach;
+        }
+        // The kernel cannot be killed, it is left running as on a detach
+        b'k' => return Action::Detach,
+        b'q' => query(arguments, reply),
+        // There is a single thread
+        b'H' | b'T' => reply.push_str("OK"),
+        _ => {}
+    }
+    Action::Reply
+}
+
+impl Stub
+{
+    /// Talks with the debugger until it resumes the kernel
+    fn run(&mut self, frame: &mut TrapFrame, signal: u8)
+    {
+        if self.attached
+        {
+            self.reply.clear();
+            self.reply.push_str("S");
+            self.reply.push_byte(signal);
+            packet::send(&mut self.port, self.reply.as_bytes());
+        }
+
+        loop
+        {
+            let length = packet::receive(&mut self.port, &mut self.packet);
+            self.attached = true;
+            self.reply.clear();
+            match handle(&self.packet[..length], frame, &mut self.breakpoints, &mut self.reply, signal)
+            {
+                Action::Reply => packet::send(&mut self.port, self.reply.as_bytes()),
+                Action::Resume { step } => {
+                    if step
+                    {
+                        frame.rflags |= TRAP_FLAG;
+                    }
+                    else
+                    {
+                        frame.rflags &= !TRAP_FLAG;
+                    }
+                    return;
+                }
+                Action::Detach => {
+                    if !self.reply.is_empty()
+                    {
+                        packet::send(&mut self.port, self.reply.as_bytes());
+                    }
+                    self.breakpoints.remove_all();
+                    self.attached = false;
+                    frame.rflags &= !TRAP_FLAG;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Stops the kernel in the debugger, from the breakpoint and debug exceptions. Returns false if the stub is
+/// not running, or is already talking with the debugger.
+pub fn trap(frame: &mut TrapFrame) -> bool
+{
+    let mut stub = match STUB.try_lock()
+    {
+        Some(stub) => stub,
+        None => return false
+    };
+    let stub = match stub.as_mut()
+    {
+        Some(stub) => stub,
+        None => return false
+    };
+
+    let signal = if INTERRUPTED.swap(false, Ordering::Relaxed) { SIGINT } else { SIGTRAP };
+    stub.run(frame, signal);
+    true
+}
+
+/// Starts the GDB stub on the second serial port if enabled on the command line, and waits for the debugger
+/// there if asked to
+pub fn init()
+{
+    if !GDB_ENABLED.get()
+    {
+        return;
+    }
+
+    // The stub owns the port from now on
+    devfs::unregister_char_device("ttyS1");
+    let mut port = unsafe { SerialPort::new(COM2) };
+    port.init();
+    *STUB.lock() = Some(Stub {
+        port,
+        packet: [0; PACKET_SIZE],
+        reply: Reply::new(),
+        breakpoints: Breakpoints::new(),
+        attached: false
+    });
+
+    // The debugger sends a byte to stop the kernel, left in the port for the stub to read
+    let registered = interrupts::register_legacy_handler(COM2_IRQ, Arc::new(|| {
+        let status = unsafe { Port::<u8>::new(COM2 + LINE_STATUS).read() };
+        if status & DATA_READY != 0
+        {
+            INTERRUPTED.store(true, Ordering::Relaxed);
+            x86_64::instructions::interrupts::int3();
+        }
+    }));
+    if !registered
+    {
+        warn!("[GDB] Failed to register the serial port interrupt handler, the debugger cannot stop the kernel");
+    }
+
+    info!("[GDB] Listening on the second serial port");
+    if GDB_WAIT.get()
+    {
+        info!("[GDB] Waiting for the debugger to attach");
+        x86_64::instructions::interrupts::int3();
+    }
+}
@@ -0,0 +1,215 @@
+use uart_16550::SerialPort;
+
+/// Largest packet received or sent, advertised to the debugger which then never sends a longer one
+pub const PACKET_SIZE: usize = 4096;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(byte: u8) -> Option<u8>
+{
+    match byte
+    {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None
+    }
+}
+
+/// Parses a big-endian hexadecimal number, like addresses and lengths
+pub fn parse_hex(hex: &[u8]) -> Option<u64>
+{
+    if hex.is_empty() || hex.len() > 16
+    {
+        return None;
+    }
+    hex.iter().try_fold(0, |value, byte| Some((value << 4) | hex_digit(*byte)? as u64))
+}
+
+/// Decodes a pair of hexadecimal digits
+fn parse_byte(pair: &[u8]) -> Option<u8>
+{
+    match pair
+    {
+        [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+        _ => None
+    }
+}
+
+/// Decodes bytes encoded as pairs of hexadecimal digits, `None` if a digit is invalid
+pub fn parse_bytes(hex: &[u8]) -> Option<impl ExactSizeIterator<Item = u8> + '_>
+{
+    if hex.len() % 2 != 0 || !hex.iter().all(|byte| hex_digit(*byte).is_some())
+    {
+        return None;
+    }
+    Some(hex.chunks(2).map(|pair| parse_byte(pair).unwrap_or(0)))
+}
+
+/// Parses a value of `hex.len() / 2` bytes in the byte order of the target, like registers
+pub fn parse_le(hex: &[u8]) -> Option<u64>
+{
+    if hex.len() > 16
+    {
+        return None;
+    }
+    parse_bytes(hex)?.enumerate().try_fold(0, |value, (index, byte)| Some(value | ((byte as u64) << (index * 8))))
+}
+
+/// A packet being written, longer contents are cut
+pub struct Reply
+{
+    data: [u8; PACKET_SIZE],
+    length: usize
+}
+
+impl Reply
+{
+    pub const fn new() -> Self
+    {
+        Reply {
+            data: [0; PACKET_SIZE],
+            length: 0
+        }
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.length = 0;
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.length == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8]
+    {
+        &self.data[..self.length]
+    }
+
+    fn push(&mut self, byte: u8)
+    {
+        if self.length < PACKET_SIZE
+        {
+            self.data[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    pub fn push_str(&mut self, s: &str)
+    {
+        for byte in s.bytes()
+        {
+            self.push(byte);
+        }
+    }
+
+    /// Replaces the reply by the error `code`, an errno value
+    pub fn error(&mut self, code: u8)
+    {
+        self.clear();
+        self.push_str("E");
+        self.push_byte(code);
+    }
+
+    /// Writes `byte` as two hexadecimal digits
+    pub fn push_byte(&mut self, byte: u8)
+    {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xF) as usize]);
+    }
+
+    /// Writes `value` as a big-endian hexadecimal number, like lengths
+    pub fn push_hex(&mut self, value: u64)
+    {
+        let mut leading = true;
+        for index in (0..16).rev()
+        {
+            let digit = (value >> (index * 4)) as usize & 0xF;
+            leading &= digit == 0 && index != 0;
+            if !leading
+            {
+                self.push(HEX_DIGITS[digit]);
+            }
+        }
+    }
+
+    /// Writes the `size` low bytes of `value` in the byte order of the target
+    pub fn push_le(&mut self, value: u64, size: usize)
+    {
+        for byte in &value.to_le_bytes()[..size]
+        {
+            self.push_byte(*byte);
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8
+{
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Waits for a packet `$data#checksum` from the debugger and copies its data to `buffer`, returns its length.
+/// Packets are acknowledged with `+`, or `-` to have them sent again when corrupted or too long. Other bytes,
+/// like the interrupt request or the acknowledgement of a previous reply, are skipped.
+pub fn receive(port: &mut SerialPort, buffer: &mut [u8; PACKET_SIZE]) -> usize
+{
+    loop
+    {
+        while port.receive() != b'$' {}
+
+        let mut length = 0;
+        let mut overflow = false;
+        loop
+        {
+            let byte = port.receive();
+            if byte == b'#'
+            {
+                break;
+            }
+            if length == PACKET_SIZE
+            {
+                overflow = true;
+                continue;
+            }
+            buffer[length] = byte;
+            length += 1;
+        }
+
+        let expected = parse_byte(&[port.receive(), port.receive()]);
+        if !overflow && expected == Some(checksum(&buffer[..length]))
+        {
+            port.send(b'+');
+            return length;
+        }
+        port.send(b'-');
+    }
+}
+
+/// Sends `data` as a packet, again until the debugger acknowledges it
+pub fn send(port: &mut SerialPort, data: &[u8])
+{
+    loop
+    {
+        port.send(b'$');
+        for byte in data
+        {
+            port.send(*byte);
+        }
+        port.send(b'#');
+        let checksum = checksum(data);
+        port.send(HEX_DIGITS[(checksum >> 4) as usize]);
+        port.send(HEX_DIGITS[(checksum & 0xF) as usize]);
+
+        loop
+        {
+            match port.receive()
+            {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use lazy_static::lazy_static;
+use log::info;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+use crate::vmm::VMM;
+
+/// Entries of the interrupt stack table used by the exceptions which cannot trust the current stack
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const IST_STACK_COUNT: u64 = 3;
+
+/// Size of each IST stack. An unmapped guard page lies below each of them.
+const IST_STACK_PAGES: u64 = 5;
+/// The IST stacks are mapped below the heap, out of the range searched by `Vmm::allocate_pages`, so that
+/// their guard pages are never handed out
+const IST_STACKS_START: u64 = 0x_4444_0000_0000;
+
+struct Selectors
+{
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector
+}
+
+/// Maps the IST stack `index` above its guard page and returns the top of the stack
+fn map_ist_stack(index: u64) -> VirtAddr
+{
+    let guard = VirtAddr::new(IST_STACKS_START + index * (IST_STACK_PAGES + 1) * 0x1000);
+    let bottom = guard + 0x1000u64;
+    let mut vmm = VMM.lock();
+    for i in 0..IST_STACK_PAGES
+    {
+        let page = Page::containing_address(bottom + i * 0x1000);
+        unsafe { vmm.map(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE) }
+            .expect("[GDT] Failed to map an IST stack")
+            .flush();
+    }
+    bottom + IST_STACK_PAGES * 0x1000
+}
+
+/// The IST stack whose guard page holds `address`, to tell an overflow of that stack from other faults
+pub fn ist_guard_page(address: VirtAddr) -> Option<u16>
+{
+    let offset = address.as_u64().checked_sub(IST_STACKS_START)?;
+    let index = offset / ((IST_STACK_PAGES + 1) * 0x1000);
+    let in_guard = offset % ((IST_STACK_PAGES + 1) * 0x1000) < 0x1000;
+    if index < IST_STACK_COUNT && in_guard { Some(index as u16) } else { None }
+}
+
+lazy_static!
+{
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        for index in [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX]
+        {
+            tss.interrupt_stack_table[index as usize] = map_ist_stack(index as u64);
+        }
+        tss
+    };
+
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        (gdt, Selectors { code, data, tss })
+    };
+}
+
+/// Replaces the GDT of the bootloader by ours and loads the TSS, must run before the IDT is loaded
+pub fn init()
+{
+    GDT.0.load();
+    unsafe {
+        CS::set_reg(GDT.1.code);
+        SS::set_reg(GDT.1.data);
+        DS::set_reg(GDT.1.data);
+        ES::set_reg(GDT.1.data);
+        load_tss(GDT.1.tss);
+    }
+    info!("[GDT] Loaded the GDT and the TSS, {} IST stacks of {} KiB", IST_STACK_COUNT, IST_STACK_PAGES * 4);
+}
@@ -1,147 +1,469 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::Cr2;
+use x86_64::VirtAddr;
 use crate::serial_println;
+use crate::{apic, crash, extable, gdb, gdt, page_fault, pic};
+use crate::extable::Fault;
+use crate::pic::PIC_VECTOR_BASE;
 use lazy_static::lazy_static;
+use spin::Mutex;
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame)
+/// First vector handed out to MSI capable devices, the vectors below are used by the legacy PICs.
+pub const DYNAMIC_VECTOR_BASE: u8 = PIC_VECTOR_BASE + 16;
+const IRQ_VECTOR_COUNT: usize = 32;
+/// A page fault this far below the stack pointer of a double fault is taken for a stack overflow
+const STACK_OVERFLOW_DISTANCE: u64 = 0x1000;
+/// Machine check registers: the capabilities holding the number of banks, and the status of the first bank,
+/// the next ones being 4 registers apart
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MC0_STATUS: u32 = 0x401;
+const MC_STATUS_VALID: u64 = 1 << 63;
+
+pub type InterruptHandler = Arc<dyn Fn() + Send + Sync>;
+
+const NO_HANDLER: Option<InterruptHandler> = None;
+
+static IRQ_HANDLERS: Mutex<[Option<InterruptHandler>; IRQ_VECTOR_COUNT]> = Mutex::new([NO_HANDLER; IRQ_VECTOR_COUNT]);
+
+/// Interrupts received on each IRQ vector, and spurious interrupts from the PICs and the local APIC
+static IRQ_COUNTS: [AtomicU64; IRQ_VECTOR_COUNT] = [const { AtomicU64::new(0) }; IRQ_VECTOR_COUNT];
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Copy, Clone)]
+pub struct IrqStats
+{
+    pub vector: u8,
+    pub count: u64,
+    /// Whether a handler is installed on the vector
+    pub registered: bool
+}
+
+/// The registers of the code interrupted by an exception, saved by an entry of `trap_entry!` below the frame
+/// pushed by the processor. They are restored from it, changes made by the handler apply to the resumed code.
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame
+{
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64
+}
+
+/// Defines `$entry`, the entry point of an exception without error code which saves the general purpose
+/// registers in a `TrapFrame` and passes it to `$handler`, an `extern "C"` function.
+///
+/// The processor aligns the stack before pushing its 5 words, so it is aligned again after 15 more.
+macro_rules! trap_entry {
+    ($entry:ident, $handler:ident) => {
+        global_asm!(
+            ".pushsection .text",
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            "push rax", "push rbx", "push rcx", "push rdx", "push rsi", "push rdi", "push rbp", "push r8",
+            "push r9", "push r10", "push r11", "push r12", "push r13", "push r14", "push r15",
+            "mov rdi, rsp",
+            "cld",
+            concat!("call ", stringify!($handler)),
+            "pop r15", "pop r14", "pop r13", "pop r12", "pop r11", "pop r10", "pop r9", "pop r8",
+            "pop rbp", "pop rdi", "pop rsi", "pop rdx", "pop rcx", "pop rbx", "pop rax",
+            "iretq",
+            ".popsection"
+        );
+
+        extern "C"
+        {
+            fn $entry();
+        }
+    };
+}
+
+trap_entry!(breakpoint_entry, breakpoint_handler);
+trap_entry!(debug_entry, debug_handler);
+
+/// Stops in the debugger when it is enabled
+#[no_mangle]
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame)
 {
-    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    if gdb::trap(frame)
+    {
+        return;
+    }
+    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
 }
 
+/// Runs on its own IST stack, so that a fault which cannot be pushed on the kernel stack can be reported
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
     serial_println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
     serial_println!("EXCEPTION: [{}]", _error_code);
-    loop {}
+
+    // A double fault usually follows a page fault which could not be delivered, whose address is still in CR2
+    let fault_address = Cr2::read();
+    let stack_pointer = stack_frame.stack_pointer;
+    if let Some(index) = gdt::ist_guard_page(fault_address)
+    {
+        serial_println!("EXCEPTION: IST stack {} overflowed at {:#x}", index, fault_address.as_u64());
+    }
+    else if fault_address <= stack_pointer && stack_pointer - fault_address < STACK_OVERFLOW_DISTANCE
+    {
+        serial_println!("EXCEPTION: kernel stack overflow: page fault at {:#x} with the stack pointer at {:#x}",
+            fault_address.as_u64(), stack_pointer.as_u64());
+    }
+    crash::exception(8, "double fault", Some(_error_code), &stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode)
+/// Resumes the faulting code when the region of the address resolves the fault, or at the fixup of the
+/// faulting instruction if it has one. Reports the fault otherwise.
+extern "x86-interrupt" fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
 {
-    serial_println!("EXCEPTION: PAGE FAULT\n{:#?}", stack_frame);
-    serial_println!("EXCEPTION: [{:?}]", _error_code);
-    loop {}
+    let address = Cr2::read();
+    if page_fault::resolve(address, error_code) || extable::fixup(&mut stack_frame, Fault::PageFault { address, error_code })
+    {
+        return;
+    }
+
+    page_fault::report(address, error_code, &stack_frame);
+    serial_println!("{:#?}", stack_frame);
+    crash::exception(14, "page fault", Some(error_code.bits()), &stack_frame);
 }
 
-extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame)
+/// Stops in the debugger after a single step, only the debugger sets the trap flag
+#[no_mangle]
+extern "C" fn debug_handler(frame: &mut TrapFrame)
 {
-    serial_println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
-    loop {}
+    if gdb::trap(frame)
+    {
+        return;
+    }
+    serial_println!("EXCEPTION: DEBUG\n{:#?}", frame);
+    crash::trap(1, "debug", frame);
 }
 
+/// Halts the processor when it is stopped by another one, see `halt_all_cpus`
 extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame)
 {
+    if HALTING.load(Ordering::SeqCst)
+    {
+        halt_all_cpus();
+    }
     serial_println!("EXCEPTION: NON MASKABLE INTERRUPT\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(2, "non maskable interrupt", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(4, "overflow", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(5, "bound range exceeded", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(6, "invalid opcode", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(7, "device not available", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, _error_code: u64)
 {
     serial_println!("EXCEPTION: INVALID TSS\n{:#?}", stack_frame);
     serial_println!("EXCEPTION: [{}]", _error_code);
-    loop {}
+    crash::exception(10, "invalid TSS", Some(_error_code), &stack_frame);
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, _error_code: u64)
 {
     serial_println!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}", stack_frame);
     serial_println!("EXCEPTION: [{}]", _error_code);
-    loop {}
+    crash::exception(11, "segment not present", Some(_error_code), &stack_frame);
 }
 
 extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64)
 {
     serial_println!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}", stack_frame);
     serial_println!("EXCEPTION: [{}]", _error_code);
-    loop {}
+    crash::exception(12, "stack segment fault", Some(_error_code), &stack_frame);
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64)
+/// Resumes at the fixup of the faulting instruction if it has one, reports the fault otherwise
+extern "x86-interrupt" fn general_protection_fault_handler(mut stack_frame: InterruptStackFrame, error_code: u64)
 {
+    if extable::fixup(&mut stack_frame, Fault::GeneralProtection { error_code })
+    {
+        return;
+    }
+
     serial_println!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
-    serial_println!("EXCEPTION: [{}]", _error_code);
-    loop {}
+    serial_println!("EXCEPTION: [{}]", error_code);
+    crash::exception(13, "general protection fault", Some(error_code), &stack_frame);
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: X87 FLOATING POINT\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(16, "x87 floating point", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFrame, _error_code: u64)
 {
     serial_println!("EXCEPTION: ALIGNMENT CHECK\n{:#?}", stack_frame);
     serial_println!("EXCEPTION: [{}]", _error_code);
-    loop {}
+    crash::exception(17, "alignment check", Some(_error_code), &stack_frame);
 }
 
 extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> !
 {
     serial_println!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
-    loop {}
+    print_machine_check_banks();
+    crash::exception(18, "machine check", None, &stack_frame);
+}
+
+/// Prints the machine check banks holding an error. The registers do not exist on processors without the
+/// machine check architecture, reading them faults.
+fn print_machine_check_banks()
+{
+    let banks = match unsafe { extable::read_msr(IA32_MCG_CAP) }
+    {
+        Ok(capabilities) => (capabilities & 0xFF) as u32,
+        Err(e) => {
+            serial_println!("EXCEPTION: no machine check architecture: {:?}", e);
+            return;
+        }
+    };
+    for bank in 0..banks
+    {
+        match unsafe { extable::read_msr(IA32_MC0_STATUS + 4 * bank) }
+        {
+            Ok(status) if status & MC_STATUS_VALID != 0 => {
+                serial_println!("EXCEPTION: bank {} status {:#018x}", bank, status);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                serial_println!("EXCEPTION: failed to read bank {}: {:?}", bank, e);
+            }
+        }
+    }
 }
 
 extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(19, "SIMD floating point", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: VIRTUALIZATION\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(20, "virtualization", None, &stack_frame);
 }
 
 extern "x86-interrupt" fn security_exception_handler(stack_frame: InterruptStackFrame, _error_code: u64)
 {
     serial_println!("EXCEPTION: SECURITY EXCEPTION\n{:#?}", stack_frame);
     serial_println!("EXCEPTION: [{}]", _error_code);
-    loop {}
+    crash::exception(30, "security exception", Some(_error_code), &stack_frame);
 }
 
 extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: InterruptStackFrame)
 {
     serial_println!("EXCEPTION: DIVISION BY ZERO\n{:#?}", stack_frame);
-    loop {}
+    crash::exception(0, "division by zero", None, &stack_frame);
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame)
+{
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+fn dispatch_irq(vector: u8)
+{
+    let index = (vector - PIC_VECTOR_BASE) as usize;
+
+    if vector < DYNAMIC_VECTOR_BASE && pic::is_spurious(index as u8)
+    {
+        // A spurious IRQ from the slave still went through the master, which expects an EOI
+        if index == 15
+        {
+            pic::end_of_interrupt(2);
+        }
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    IRQ_COUNTS[index].fetch_add(1, Ordering::Relaxed);
+
+    let handler = IRQ_HANDLERS.lock()[index].clone();
+    if let Some(handler) = handler
+    {
+        handler();
+    }
+
+    if vector < DYNAMIC_VECTOR_BASE
+    {
+        pic::end_of_interrupt(index as u8);
+    }
+    else
+    {
+        apic::end_of_interrupt();
+    }
+}
+
+macro_rules! irq_handlers {
+    ($($vector:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame)
+            {
+                dispatch_irq($vector);
+            }
+        )*
+
+        fn set_irq_handlers(idt: &mut InterruptDescriptorTable)
+        {
+            $(idt[$vector].set_handler_fn($name);)*
+        }
+    };
+}
+
+irq_handlers!(
+    32 => irq_32, 33 => irq_33, 34 => irq_34, 35 => irq_35, 36 => irq_36, 37 => irq_37, 38 => irq_38, 39 => irq_39,
+    40 => irq_40, 41 => irq_41, 42 => irq_42, 43 => irq_43, 44 => irq_44, 45 => irq_45, 46 => irq_46, 47 => irq_47,
+    48 => irq_48, 49 => irq_49, 50 => irq_50, 51 => irq_51, 52 => irq_52, 53 => irq_53, 54 => irq_54, 55 => irq_55,
+    56 => irq_56, 57 => irq_57, 58 => irq_58, 59 => irq_59, 60 => irq_60, 61 => irq_61, 62 => irq_62, 63 => irq_63,
+);
+
+/// Installs `handler` for the legacy PIC line `irq` and unmasks it. Handlers of shared lines are chained.
+pub fn register_legacy_handler(irq: u8, handler: InterruptHandler) -> bool
+{
+    if irq >= 16
+    {
+        return false;
+    }
+
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = &mut handlers[irq as usize];
+        *slot = match slot.take()
+        {
+            Some(previous) => Some(Arc::new(move || {
+                previous();
+                handler();
+            })),
+            None => Some(handler)
+        };
+    });
+
+    pic::unmask(irq);
+    true
+}
+
+/// Installs `handler` on the first free MSI vector and returns that vector.
+pub fn register_dynamic_handler(handler: InterruptHandler) -> Option<u8>
+{
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let start = (DYNAMIC_VECTOR_BASE - PIC_VECTOR_BASE) as usize;
+        let index = (start..IRQ_VECTOR_COUNT).find(|i| handlers[*i].is_none())?;
+        handlers[index] = Some(handler);
+        Some(PIC_VECTOR_BASE + index as u8)
+    })
+}
+
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Disables interrupts and stops the other processors with an NMI, whose handler halts them. Returns false if
+/// the processors are already being stopped, by this processor or another one.
+pub fn stop_other_cpus() -> bool
+{
+    x86_64::instructions::interrupts::disable();
+    let first = !HALTING.swap(true, Ordering::SeqCst);
+    if first && apic::is_initialized()
+    {
+        apic::send_nmi_to_others();
+    }
+    first
+}
+
+/// Stops every processor after an unrecoverable error
+pub fn halt_all_cpus() -> !
+{
+    stop_other_cpus();
+    loop
+    {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Statistics of the IRQ vectors which have a handler or received interrupts
+pub fn irq_stats() -> Vec<IrqStats>
+{
+    let registered: Vec<bool> = without_interrupts(|| IRQ_HANDLERS.lock().iter().map(|handler| handler.is_some()).collect());
+    registered.into_iter().enumerate()
+        .map(|(index, registered)| IrqStats {
+            vector: PIC_VECTOR_BASE + index as u8,
+            count: IRQ_COUNTS[index].load(Ordering::Relaxed),
+            registered
+        })
+        .filter(|stats| stats.registered || stats.count != 0)
+        .collect()
+}
+
+pub fn spurious_count() -> u64
+{
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
 }
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        unsafe {
+            idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_entry as usize as u64));
+        }
         idt.divide_error.set_handler_fn(divide_by_zero_handler);
-        idt.debug.set_handler_fn(debug_handler);
-        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        unsafe {
+            idt.debug.set_handler_addr(VirtAddr::new(debug_entry as usize as u64));
+        }
+        unsafe {
+            idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler).set_stack_index(gdt::NMI_IST_INDEX);
+        }
         idt.overflow.set_handler_fn(overflow_handler);
         idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         idt.device_not_available.set_handler_fn(device_not_available_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
         idt.invalid_tss.set_handler_fn(invalid_tss_handler);
         idt.segment_not_present.set_handler_fn(segment_not_present_handler);
         idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
@@ -149,14 +471,19 @@ lazy_static! {
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
         idt.alignment_check.set_handler_fn(alignment_check_handler);
-        idt.machine_check.set_handler_fn(machine_check_handler);
+        unsafe {
+            idt.machine_check.set_handler_fn(machine_check_handler).set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
         idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
         idt.virtualization.set_handler_fn(virtualization_handler);
         idt.security_exception.set_handler_fn(security_exception_handler);
+        set_irq_handlers(&mut idt);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
+/// Loads the IDT, the GDT must be loaded first for the IST stacks to exist
 pub fn init_idt()
 {
     IDT.load();
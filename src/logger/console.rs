@@ -0,0 +1,170 @@
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use bootloader::boot_info::{FrameBuffer, PixelFormat};
+use log::Level;
+use spin::Mutex;
+use crate::fs::devfs::FramebufferDevice;
+use crate::logger::font::{FIRST_CHARACTER, GLYPHS, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::logger::{LogRecord, LogSink};
+
+const BACKGROUND: (u8, u8, u8) = (0, 0, 0);
+
+fn level_color(level: Level) -> (u8, u8, u8)
+{
+    match level
+    {
+        Level::Error => (0xFF, 0x55, 0x55),
+        Level::Warn => (0xFF, 0xFF, 0x55),
+        Level::Info => (0xC0, 0xC0, 0xC0),
+        Level::Debug => (0x80, 0x80, 0x80),
+        Level::Trace => (0x60, 0x60, 0x60)
+    }
+}
+
+/// Draws text on a framebuffer, starting a line at each line feed or when a line is full and scrolling up
+/// once the screen is full
+struct Cursor<'a>
+{
+    framebuffer: &'a mut FrameBuffer,
+    column: &'a mut usize,
+    row: &'a mut usize,
+    color: [u8; 4]
+}
+
+impl Cursor<'_>
+{
+    fn columns(&self) -> usize
+    {
+        self.framebuffer.info().horizontal_resolution / GLYPH_WIDTH
+    }
+
+    fn rows(&self) -> usize
+    {
+        self.framebuffer.info().vertical_resolution / GLYPH_HEIGHT
+    }
+
+    fn new_line(&mut self)
+    {
+        *self.column = 0;
+        if *self.row + 1 < self.rows()
+        {
+            *self.row += 1;
+            return;
+        }
+
+        let info = self.framebuffer.info();
+        let line_size = info.stride * info.bytes_per_pixel * GLYPH_HEIGHT;
+        let used_size = line_size * self.rows();
+        let pixels = self.framebuffer.buffer_mut();
+        pixels.copy_within(line_size..used_size, 0);
+        pixels[used_size - line_size..used_size].fill(0);
+    }
+
+    fn draw(&mut self, character: u8)
+    {
+        let glyph = match character
+        {
+            FIRST_CHARACTER..=b'~' => &GLYPHS[(character - FIRST_CHARACTER) as usize],
+            _ => &GLYPHS[(b'?' - FIRST_CHARACTER) as usize]
+        };
+        let info = self.framebuffer.info();
+        let background = encode(info.pixel_format, BACKGROUND);
+        let x = *self.column * GLYPH_WIDTH;
+        let y = *self.row * GLYPH_HEIGHT;
+        let color = self.color;
+        let pixels = self.framebuffer.buffer_mut();
+
+        for (row, bits) in glyph.iter().enumerate()
+        {
+            for column in 0..GLYPH_WIDTH
+            {
+                let offset = ((y + row) * info.stride + x + column) * info.bytes_per_pixel;
+                let pixel = if bits & (0x80 >> column) != 0 { &color } else { &background };
+                pixels[offset..offset + info.bytes_per_pixel].copy_from_slice(&pixel[..info.bytes_per_pixel]);
+            }
+        }
+    }
+}
+
+impl Write for Cursor<'_>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for character in s.bytes()
+        {
+            if character == b'\n'
+            {
+                self.new_line();
+                continue;
+            }
+            if *self.column == self.columns()
+            {
+                self.new_line();
+            }
+            self.draw(character);
+            *self.column += 1;
+        }
+        Ok(())
+    }
+}
+
+/// The bytes of a pixel of `color`
+fn encode(format: PixelFormat, (red, green, blue): (u8, u8, u8)) -> [u8; 4]
+{
+    match format
+    {
+        PixelFormat::RGB => [red, green, blue, 0],
+        PixelFormat::U8 => [((red as u16 + green as u16 + blue as u16) / 3) as u8, 0, 0, 0],
+        _ => [blue, green, red, 0]
+    }
+}
+
+/// A text console on the framebuffer, showing the log records colored by level
+pub struct FramebufferConsole
+{
+    device: Arc<FramebufferDevice>,
+    /// Column and row of the next character
+    position: Mutex<(usize, usize)>
+}
+
+impl FramebufferConsole
+{
+    /// Clears the framebuffer to start the console on it
+    pub fn new(device: Arc<FramebufferDevice>) -> Self
+    {
+        device.try_with_framebuffer(|framebuffer| framebuffer.buffer_mut().fill(0));
+        FramebufferConsole {
+            device,
+            position: Mutex::new((0, 0))
+        }
+    }
+}
+
+impl LogSink for FramebufferConsole
+{
+    fn name(&self) -> &str
+    {
+        "console"
+    }
+
+    fn write(&self, record: &LogRecord)
+    {
+        let mut position = self.position.lock();
+        let (column, row) = &mut *position;
+        // The record is not shown if the framebuffer is in use
+        self.device.try_with_framebuffer(|framebuffer| {
+            let color = encode(framebuffer.info().pixel_format, level_color(record.level));
+            let mut cursor = Cursor {
+                framebuffer,
+                column,
+                row,
+                color
+            };
+            if *cursor.column != 0
+            {
+                cursor.new_line();
+            }
+            let _ = write!(cursor, "{}", record);
+        });
+    }
+}
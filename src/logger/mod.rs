@@ -0,0 +1,382 @@
+mod console;
+mod font;
+mod pending;
+mod ring;
+mod sink;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use log::{warn, LevelFilter, Log, Metadata, Record};
+use spin::Once;
+use crate::cmdline::Parameter;
+use crate::deferred::{self, Work};
+use crate::sync::{IrqMutex, IrqMutexGuard};
+use crate::{apic, serial, time};
+
+pub use crate::logger::console::FramebufferConsole;
+pub use crate::logger::ring::{LogRecord, LogRing};
+pub use crate::logger::sink::{FileSink, LogSink, SerialSink};
+use crate::logger::ring::{truncate, MAX_MESSAGE_LENGTH, MAX_RECORD_SIZE};
+
+/// Level of the targets without a filter of their own
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+/// Filters set at boot, the AML interpreter traces every opcode it runs
+const DEFAULT_FILTERS: [(&str, LevelFilter); 2] = [("aml", LevelFilter::Warn), ("acpi", LevelFilter::Warn)];
+
+pub static LOG_LEVELS: Parameter<&str> = Parameter::new("log", "", "comma-separated levels, the default one alone and the others as target=level, e.g. debug,aml=trace");
+
+/// Sinks registered at most
+const MAX_SINKS: usize = 8;
+
+/// The records logged since boot, dmesg-style. Records logged while it is locked are kept aside until the
+/// next holder takes them.
+pub static LOG_RING: IrqMutex<LogRing> = IrqMutex::new(LogRing::new());
+
+/// Levels of the targets, a filter applies to its target and the modules below it
+struct Filters
+{
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>
+}
+
+impl Filters
+{
+    fn level(&self, target: &str) -> LevelFilter
+    {
+        self.targets.iter()
+            .filter(|(prefix, _)| target == prefix || target.strip_prefix(prefix.as_str()).map_or(false, |rest| rest.starts_with("::")))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+static FILTERS: IrqMutex<Filters> = IrqMutex::new(Filters {
+    default: DEFAULT_LEVEL,
+    targets: Vec::new()
+});
+
+struct SinkSlot
+{
+    sink: Option<&'static dyn LogSink>,
+    level: LevelFilter,
+    /// Sequence number of the next record to write
+    next_sequence: u64,
+    /// Set while the records are written, so that a record logged meanwhile, e.g. by an interrupt handler,
+    /// is left to the writer instead of being written out of order
+    busy: bool
+}
+
+const NO_SINK: SinkSlot = SinkSlot {
+    sink: None,
+    level: LevelFilter::Off,
+    next_sequence: 0,
+    busy: false
+};
+
+/// The serial port gets the records from the first one, the other sinks are added once the kernel can
+/// allocate them
+static SINKS: IrqMutex<[SinkSlot; MAX_SINKS]> = IrqMutex::new([
+    SinkSlot {
+        sink: Some(&SerialSink),
+        level: LevelFilter::Trace,
+        next_sequence: 0,
+        busy: false
+    },
+    NO_SINK, NO_SINK, NO_SINK, NO_SINK, NO_SINK, NO_SINK, NO_SINK
+]);
+
+/// Writes the records to the sinks which are not immediate
+static DEFERRED_SINKS_WORK: Once<Arc<Work>> = Once::new();
+
+/// A message formatted on the stack, truncated to `MAX_MESSAGE_LENGTH` bytes
+struct MessageBuffer
+{
+    data: [u8; MAX_MESSAGE_LENGTH],
+    length: usize
+}
+
+impl MessageBuffer
+{
+    fn new() -> Self
+    {
+        MessageBuffer {
+            data: [0; MAX_MESSAGE_LENGTH],
+            length: 0
+        }
+    }
+
+    fn as_str(&self) -> &str
+    {
+        // Only whole strings are written
+        core::str::from_utf8(&self.data[..self.length]).unwrap_or("")
+    }
+}
+
+impl Write for MessageBuffer
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        let s = truncate(s, MAX_MESSAGE_LENGTH - self.length);
+        self.data[self.length..self.length + s.len()].copy_from_slice(s.as_bytes());
+        self.length += s.len();
+        Ok(())
+    }
+}
+
+/// Locks the ring, unless it is held by the code this processor interrupted or by a processor stopped after
+/// a panic
+fn lock_ring() -> Option<IrqMutexGuard<'static, LogRing>>
+{
+    match LOG_RING.try_lock()
+    {
+        Some(ring) => Some(ring),
+        None if LOG_RING.is_locked_by_current_cpu() || serial::in_panic_mode() => None,
+        None => Some(LOG_RING.lock())
+    }
+}
+
+/// Writes the records the sink at `index` has not written yet, if it is immediate or `immediate` is false.
+///
+/// Gives up if the sinks or the ring are held by the code this processor interrupted, the records are then
+/// written by the next flush.
+fn flush_sink(index: usize, immediate: bool)
+{
+    let mut buffer = [0u8; MAX_RECORD_SIZE];
+    loop
+    {
+        let (sink, level, mut next_sequence) = {
+            let mut sinks = match SINKS.try_lock()
+            {
+                Some(sinks) => sinks,
+                None if SINKS.is_locked_by_current_cpu() => return,
+                None => SINKS.lock()
+            };
+            let slot = &mut sinks[index];
+            match slot.sink
+            {
+                Some(sink) if !slot.busy && (sink.is_immediate() || !immediate) => {
+                    slot.busy = true;
+                    (sink, slot.level, slot.next_sequence)
+                }
+                _ => return
+            }
+        };
+
+        let mut done = true;
+        loop
+        {
+            let ring = match lock_ring()
+            {
+                Some(ring) => ring,
+                None => {
+                    done = false;
+                    break;
+                }
+            };
+            // Records dropped from the ring before the sink wrote them are lost
+            next_sequence = next_sequence.max(ring.first_sequence());
+            let record = match ring.copy(next_sequence, &mut buffer)
+            {
+                Some(record) => record,
+                None => break
+            };
+            drop(ring);
+
+            next_sequence += 1;
+            if record.level <= level
+            {
+                sink.write(&record);
+            }
+        }
+
+        {
+            // The slot is busy, the code this processor interrupted cannot be holding the sinks
+            let mut sinks = SINKS.lock();
+            sinks[index].next_sequence = next_sequence;
+            sinks[index].busy = false;
+        }
+        // A record logged while the sink was busy is written now
+        if !done || lock_ring().map_or(true, |ring| ring.next_sequence() == next_sequence)
+        {
+            return;
+        }
+    }
+}
+
+fn flush_sinks(immediate: bool)
+{
+    for index in 0..MAX_SINKS
+    {
+        flush_sink(index, immediate);
+    }
+}
+
+/// Adds `sink`, which gets the records of at most `level`, starting with the records kept in the ring.
+/// Returns false if there are already `MAX_SINKS` sinks.
+pub fn add_sink(sink: &'static dyn LogSink, level: LevelFilter) -> bool
+{
+    let first_sequence = LOG_RING.lock().first_sequence();
+    let added = {
+        let mut sinks = SINKS.lock();
+        match sinks.iter_mut().find(|slot| slot.sink.is_none())
+        {
+            Some(slot) => {
+                *slot = SinkSlot {
+                    sink: Some(sink),
+                    level,
+                    next_sequence: first_sequence,
+                    busy: false
+                };
+                true
+            }
+            None => false
+        }
+    };
+
+    if added
+    {
+        flush_sinks(true);
+        schedule_deferred_sinks();
+    }
+    added
+}
+
+/// Sets the level of the records written to the sink called `name`, returns false if there is none
+pub fn set_sink_level(name: &str, level: LevelFilter) -> bool
+{
+    let mut sinks = SINKS.lock();
+    match sinks.iter_mut().find(|slot| slot.sink.map_or(false, |sink| sink.name() == name))
+    {
+        Some(slot) => {
+            slot.level = level;
+            true
+        }
+        None => false
+    }
+}
+
+/// Sets the level of `target` and the modules below it, or of all the targets without a filter if `target`
+/// is `None`
+pub fn set_level(target: Option<&str>, level: LevelFilter)
+{
+    let mut filters = FILTERS.lock();
+    match target
+    {
+        None => filters.default = level,
+        Some(target) => match filters.targets.iter_mut().find(|(prefix, _)| prefix == target)
+        {
+            Some(filter) => filter.1 = level,
+            None => filters.targets.push((String::from(target), level))
+        }
+    }
+}
+
+/// The default level and the level of each target with a filter
+pub fn levels() -> (LevelFilter, Vec<(String, LevelFilter)>)
+{
+    let filters = FILTERS.lock();
+    (filters.default, filters.targets.clone())
+}
+
+/// Sets the default filters and writes the records to the sinks which are not immediate from the kernel
+/// main loop. Must be called once the heap is available.
+pub fn init()
+{
+    for (target, level) in DEFAULT_FILTERS
+    {
+        set_level(Some(target), level);
+    }
+    DEFERRED_SINKS_WORK.call_once(|| deferred::register(|| {
+        // Records logged while the ring was locked are kept aside until now if nothing was logged since
+        pending::drain(&mut LOG_RING.lock());
+        flush_sinks(false);
+    }));
+}
+
+/// Applies the levels given on the command line over the current ones
+pub fn configure()
+{
+    for level in LOG_LEVELS.get().split(',').map(str::trim).filter(|level| !level.is_empty())
+    {
+        let (target, value) = match level.split_once('=')
+        {
+            Some((target, value)) => (Some(target), value),
+            None => (None, level)
+        };
+        match value.parse()
+        {
+            Ok(value) => set_level(target, value),
+            Err(_) => warn!("[LOG] Invalid level {} on the command line", level)
+        }
+    }
+}
+
+fn schedule_deferred_sinks()
+{
+    if let Some(work) = DEFERRED_SINKS_WORK.get()
+    {
+        work.schedule();
+    }
+}
+
+/// The logger of the kernel: keeps the records in `LOG_RING` and writes them to the sinks
+pub struct KernelLogger;
+
+impl Log for KernelLogger
+{
+    fn enabled(&self, metadata: &Metadata) -> bool
+    {
+        match FILTERS.try_lock()
+        {
+            Some(filters) => metadata.level() <= filters.level(metadata.target()),
+            // Changing the filters was interrupted, the default level applies meanwhile
+            None if FILTERS.is_locked_by_current_cpu() || serial::in_panic_mode() => metadata.level() <= DEFAULT_LEVEL,
+            None => metadata.level() <= FILTERS.lock().level(metadata.target())
+        }
+    }
+
+    fn log(&self, record: &Record)
+    {
+        if !self.enabled(record.metadata())
+        {
+            return;
+        }
+
+        let mut message = MessageBuffer::new();
+        let _ = write!(message, "{}", record.args());
+        let timestamp_ms = time::uptime_ms();
+        match lock_ring()
+        {
+            Some(mut ring) => {
+                pending::drain(&mut ring);
+                ring.push(timestamp_ms, record.level(), record.target(), message.as_str());
+            }
+            // An exception or NMI interrupted the holder of the ring
+            None => pending::push(apic::cpu_id(), timestamp_ms, record.level(), record.target(), message.as_str())
+        }
+
+        if serial::in_panic_mode()
+        {
+            // The sinks may be held by the stopped processors, the serial port is taken anyway
+            SerialSink.write(&LogRecord {
+                sequence: 0,
+                timestamp_ms,
+                level: record.level(),
+                target: record.target(),
+                message: message.as_str()
+            });
+            return;
+        }
+        flush_sinks(true);
+        schedule_deferred_sinks();
+    }
+
+    fn flush(&self)
+    {
+        flush_sinks(true);
+    }
+}
+
+pub static KERNEL_LOGGER: KernelLogger = KernelLogger;
@@ -0,0 +1,166 @@
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use log::Level;
+use crate::logger::MessageBuffer;
+use crate::logger::ring::{truncate, LogRing, MAX_TARGET_LENGTH};
+use crate::time;
+
+/// Processors with a buffer of their own, the others share the buffers by their ID modulo this
+const MAX_CPUS: usize = 8;
+/// Records each buffer holds before the next ones are lost
+const PENDING_RECORDS: usize = 16;
+/// Messages are truncated to keep the buffers small
+const MAX_PENDING_MESSAGE_LENGTH: usize = 256;
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+struct PendingRecord
+{
+    timestamp_ms: u64,
+    level: Level,
+    target: [u8; MAX_TARGET_LENGTH],
+    target_length: usize,
+    message: [u8; MAX_PENDING_MESSAGE_LENGTH],
+    message_length: usize
+}
+
+struct Slot
+{
+    state: AtomicU8,
+    record: UnsafeCell<PendingRecord>
+}
+
+impl Slot
+{
+    const fn new() -> Self
+    {
+        Slot {
+            state: AtomicU8::new(EMPTY),
+            record: UnsafeCell::new(PendingRecord {
+                timestamp_ms: 0,
+                level: Level::Trace,
+                target: [0; MAX_TARGET_LENGTH],
+                target_length: 0,
+                message: [0; MAX_PENDING_MESSAGE_LENGTH],
+                message_length: 0
+            })
+        }
+    }
+}
+
+/// Records logged while the ring was locked, e.g. by an exception or NMI handler which interrupted the code
+/// holding it, until they are moved into the ring.
+///
+/// Any number of nested handlers of the processor can add records, only the holder of the ring takes them.
+struct PendingBuffer
+{
+    slots: [Slot; PENDING_RECORDS],
+    /// Number of the next slot to fill and of the next slot to take, the slot is the number modulo
+    /// `PENDING_RECORDS`
+    write: AtomicUsize,
+    read: AtomicUsize,
+    lost: AtomicUsize
+}
+
+// The record of a slot is only accessed by the writer which reserved it until it is ready, and then by the
+// holder of the ring
+unsafe impl Sync for PendingBuffer {}
+
+static PENDING: [PendingBuffer; MAX_CPUS] = [const { PendingBuffer::new() }; MAX_CPUS];
+
+impl PendingBuffer
+{
+    const fn new() -> Self
+    {
+        PendingBuffer {
+            slots: [const { Slot::new() }; PENDING_RECORDS],
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            lost: AtomicUsize::new(0)
+        }
+    }
+
+    fn push(&self, timestamp_ms: u64, level: Level, target: &str, message: &str)
+    {
+        let mut write = self.write.load(Ordering::Relaxed);
+        loop
+        {
+            if write - self.read.load(Ordering::Acquire) >= PENDING_RECORDS
+            {
+                self.lost.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            match self.write.compare_exchange_weak(write, write + 1, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => write = current
+            }
+        }
+
+        let slot = &self.slots[write % PENDING_RECORDS];
+        slot.state.store(WRITING, Ordering::Relaxed);
+        let record = unsafe { &mut *slot.record.get() };
+        let target = truncate(target, MAX_TARGET_LENGTH);
+        let message = truncate(message, MAX_PENDING_MESSAGE_LENGTH);
+        record.timestamp_ms = timestamp_ms;
+        record.level = level;
+        record.target[..target.len()].copy_from_slice(target.as_bytes());
+        record.target_length = target.len();
+        record.message[..message.len()].copy_from_slice(message.as_bytes());
+        record.message_length = message.len();
+        slot.state.store(READY, Ordering::Release);
+    }
+
+    /// Moves the records into `ring`, up to the first one still being written
+    fn drain(&self, ring: &mut LogRing)
+    {
+        loop
+        {
+            let read = self.read.load(Ordering::Relaxed);
+            if read == self.write.load(Ordering::Acquire)
+            {
+                break;
+            }
+            let slot = &self.slots[read % PENDING_RECORDS];
+            if slot.state.load(Ordering::Acquire) != READY
+            {
+                break;
+            }
+
+            let record = unsafe { &*slot.record.get() };
+            // Only whole strings are written
+            let target = core::str::from_utf8(&record.target[..record.target_length]).unwrap_or("?");
+            let message = core::str::from_utf8(&record.message[..record.message_length]).unwrap_or("?");
+            ring.push(record.timestamp_ms, record.level, target, message);
+
+            slot.state.store(EMPTY, Ordering::Relaxed);
+            self.read.store(read + 1, Ordering::Release);
+        }
+
+        let lost = self.lost.swap(0, Ordering::Relaxed);
+        if lost != 0
+        {
+            let mut message = MessageBuffer::new();
+            let _ = write!(message, "[LOG] {} records logged while the log was locked were lost", lost);
+            ring.push(time::uptime_ms(), Level::Warn, module_path!(), message.as_str());
+        }
+    }
+}
+
+/// Keeps a record logged while the ring is locked in the buffer of the running processor, without waiting
+pub fn push(cpu: u8, timestamp_ms: u64, level: Level, target: &str, message: &str)
+{
+    PENDING[cpu as usize % MAX_CPUS].push(timestamp_ms, level, target, message);
+}
+
+/// Moves the records of all the processors into `ring`
+pub fn drain(ring: &mut LogRing)
+{
+    for buffer in PENDING.iter()
+    {
+        buffer.drain(ring);
+    }
+}
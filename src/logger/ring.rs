@@ -0,0 +1,248 @@
+use core::fmt::{self, Display, Formatter};
+use log::Level;
+
+/// Bytes of records kept in memory, the oldest records are dropped to make room
+const LOG_RING_SIZE: usize = 64 * 1024;
+/// Each record starts with its length (u16, 0 marking the end of the used part before the ring wraps), its
+/// level (u8), the length of its target (u8), its sequence number (u64) and its timestamp (u64)
+const HEADER_SIZE: usize = 20;
+pub const MAX_TARGET_LENGTH: usize = 64;
+pub const MAX_MESSAGE_LENGTH: usize = 1024;
+pub const MAX_RECORD_SIZE: usize = HEADER_SIZE + MAX_TARGET_LENGTH + MAX_MESSAGE_LENGTH;
+
+/// A record of the log ring
+#[derive(Debug, Copy, Clone)]
+pub struct LogRecord<'a>
+{
+    /// Number of the record since boot, the first record is 0
+    pub sequence: u64,
+    pub timestamp_ms: u64,
+    pub level: Level,
+    /// Module which logged the record, e.g. `rust_kernel::pci`
+    pub target: &'a str,
+    pub message: &'a str
+}
+
+impl Display for LogRecord<'_>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "[{:5}.{:03}] [{}] {}: {}", self.timestamp_ms / 1000, self.timestamp_ms % 1000, self.level, self.target, self.message)
+    }
+}
+
+fn encode_level(level: Level) -> u8
+{
+    level as u8
+}
+
+fn decode_level(level: u8) -> Level
+{
+    match level
+    {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace
+    }
+}
+
+/// The longest prefix of `s` of at most `length` bytes ending on a character boundary
+pub fn truncate(s: &str, length: usize) -> &str
+{
+    if s.len() <= length
+    {
+        return s;
+    }
+    let mut end = length;
+    while !s.is_char_boundary(end)
+    {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// The records logged since boot, as many as fit in `LOG_RING_SIZE` bytes.
+///
+/// Records are stored one after the other from the oldest, a record never wraps around the end of the ring.
+pub struct LogRing
+{
+    data: [u8; LOG_RING_SIZE],
+    /// Offset of the oldest record and of the end of the newest one
+    head: usize,
+    tail: usize,
+    count: usize,
+    first_sequence: u64,
+    next_sequence: u64
+}
+
+impl LogRing
+{
+    pub const fn new() -> Self
+    {
+        LogRing {
+            data: [0; LOG_RING_SIZE],
+            head: 0,
+            tail: 0,
+            count: 0,
+            first_sequence: 0,
+            next_sequence: 0
+        }
+    }
+
+    /// Sequence number of the oldest record kept
+    pub fn first_sequence(&self) -> u64
+    {
+        self.first_sequence
+    }
+
+    /// Sequence number the next record will get
+    pub fn next_sequence(&self) -> u64
+    {
+        self.next_sequence
+    }
+
+    fn read_u16(&self, offset: usize) -> u16
+    {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    fn read_u64(&self, offset: usize) -> u64
+    {
+        u64::from_le_bytes(self.data[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// The offset of the record written at `offset` or after it, once the ring has wrapped
+    fn position(&self, offset: usize) -> usize
+    {
+        if LOG_RING_SIZE - offset < HEADER_SIZE || self.read_u16(offset) == 0
+        {
+            0
+        }
+        else
+        {
+            offset
+        }
+    }
+
+    fn drop_oldest(&mut self)
+    {
+        let offset = self.position(self.head);
+        let length = self.read_u16(offset) as usize;
+        self.count -= 1;
+        self.first_sequence += 1;
+        if self.count == 0
+        {
+            self.head = 0;
+            self.tail = 0;
+        }
+        else
+        {
+            self.head = self.position(offset + length);
+        }
+    }
+
+    /// Drops the oldest records until `size` contiguous bytes are free, returns their offset
+    fn reserve(&mut self, size: usize) -> usize
+    {
+        loop
+        {
+            if self.count == 0 || self.tail > self.head
+            {
+                if LOG_RING_SIZE - self.tail >= size
+                {
+                    return self.tail;
+                }
+                if self.head >= size
+                {
+                    if LOG_RING_SIZE - self.tail >= 2
+                    {
+                        self.data[self.tail..self.tail + 2].fill(0);
+                    }
+                    self.tail = 0;
+                    return 0;
+                }
+            }
+            else if self.head - self.tail >= size
+            {
+                return self.tail;
+            }
+            self.drop_oldest();
+        }
+    }
+
+    /// Adds a record, truncating its target and message if they are too long. Returns its sequence number.
+    pub fn push(&mut self, timestamp_ms: u64, level: Level, target: &str, message: &str) -> u64
+    {
+        let target = truncate(target, MAX_TARGET_LENGTH);
+        let message = truncate(message, MAX_MESSAGE_LENGTH);
+        let size = HEADER_SIZE + target.len() + message.len();
+        let offset = self.reserve(size);
+        let sequence = self.next_sequence;
+
+        let record = &mut self.data[offset..offset + size];
+        record[0..2].copy_from_slice(&(size as u16).to_le_bytes());
+        record[2] = encode_level(level);
+        record[3] = target.len() as u8;
+        record[4..12].copy_from_slice(&sequence.to_le_bytes());
+        record[12..20].copy_from_slice(&timestamp_ms.to_le_bytes());
+        record[HEADER_SIZE..HEADER_SIZE + target.len()].copy_from_slice(target.as_bytes());
+        record[HEADER_SIZE + target.len()..].copy_from_slice(message.as_bytes());
+
+        self.tail = offset + size;
+        self.count += 1;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    /// The record at `offset` and the offset of the next one
+    fn record_at(&self, offset: usize) -> (LogRecord<'_>, usize)
+    {
+        let offset = self.position(offset);
+        let size = self.read_u16(offset) as usize;
+        let target_length = self.data[offset + 3] as usize;
+        let target = &self.data[offset + HEADER_SIZE..offset + HEADER_SIZE + target_length];
+        let message = &self.data[offset + HEADER_SIZE + target_length..offset + size];
+        let record = LogRecord {
+            sequence: self.read_u64(offset + 4),
+            timestamp_ms: self.read_u64(offset + 12),
+            level: decode_level(self.data[offset + 2]),
+            // Only whole strings are written
+            target: core::str::from_utf8(target).unwrap_or("?"),
+            message: core::str::from_utf8(message).unwrap_or("?")
+        };
+        (record, offset + size)
+    }
+
+    /// The records kept, from the oldest
+    pub fn records(&self) -> impl Iterator<Item = LogRecord<'_>>
+    {
+        let mut offset = self.head;
+        (0..self.count).map(move |_| {
+            let (record, next) = self.record_at(offset);
+            offset = next;
+            record
+        })
+    }
+
+    /// Copies the record `sequence` into `buffer`, so that it can be used once the ring is unlocked
+    pub fn copy<'a>(&self, sequence: u64, buffer: &'a mut [u8; MAX_RECORD_SIZE]) -> Option<LogRecord<'a>>
+    {
+        if sequence < self.first_sequence
+        {
+            return None;
+        }
+        let record = self.records().nth((sequence - self.first_sequence) as usize)?;
+        let (target, message) = buffer.split_at_mut(record.target.len());
+        target.copy_from_slice(record.target.as_bytes());
+        message[..record.message.len()].copy_from_slice(record.message.as_bytes());
+        Some(LogRecord {
+            sequence: record.sequence,
+            timestamp_ms: record.timestamp_ms,
+            level: record.level,
+            target: core::str::from_utf8(target).unwrap_or("?"),
+            message: core::str::from_utf8(&message[..record.message.len()]).unwrap_or("?")
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use alloc::format;
+use alloc::string::String;
+use spin::Mutex;
+use crate::fs::{self, FileHandle, OpenFlags};
+use crate::logger::LogRecord;
+use crate::serial_println;
+
+/// A destination of the log records.
+///
+/// Each sink gets the records in order, including the records logged before it was added that are still
+/// in the ring.
+pub trait LogSink: Send + Sync
+{
+    fn name(&self) -> &str;
+
+    fn write(&self, record: &LogRecord);
+
+    /// Whether records are written as they are logged. The records of the other sinks are written later by
+    /// the kernel main loop, as sinks which lock or allocate, e.g. to write a file, cannot be written from an
+    /// interrupt handler.
+    fn is_immediate(&self) -> bool
+    {
+        true
+    }
+}
+
+/// Writes the records to the first serial port
+pub struct SerialSink;
+
+impl LogSink for SerialSink
+{
+    fn name(&self) -> &str
+    {
+        "serial"
+    }
+
+    fn write(&self, record: &LogRecord)
+    {
+        serial_println!("{}", record);
+    }
+}
+
+/// Appends the records to a file, created if needed when the first record is written
+pub struct FileSink
+{
+    path: String,
+    file: Mutex<Option<FileHandle>>
+}
+
+impl FileSink
+{
+    pub fn new(path: &str) -> Self
+    {
+        FileSink {
+            path: String::from(path),
+            file: Mutex::new(None)
+        }
+    }
+}
+
+impl LogSink for FileSink
+{
+    fn name(&self) -> &str
+    {
+        "file"
+    }
+
+    fn write(&self, record: &LogRecord)
+    {
+        let mut file = self.file.lock();
+        if file.is_none()
+        {
+            // Failures are not logged, they would be written to this sink again
+            *file = fs::open(&self.path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::APPEND).ok();
+        }
+        if let Some(file) = file.as_ref()
+        {
+            let _ = file.write(format!("{}\n", record).as_bytes());
+        }
+    }
+
+    fn is_immediate(&self) -> bool
+    {
+        false
+    }
+}
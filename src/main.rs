@@ -3,39 +3,87 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 #![feature(new_uninit)]
+#![feature(inline_const)]
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::ops::Deref;
 use core::ptr::NonNull;
-use log::{error, info};
+use log::{error, info, warn};
+use x86_64::instructions::port::Port;
 use crate::acpi::{ACPI, AML_CONTEXT};
 use crate::interrupts::init_idt;
+use crate::cmdline::Parameter;
 use crate::vmm::VMM;
-use crate::logger::SERIAL_LOGGER;
+use crate::logger::KERNEL_LOGGER;
 use crate::pci::PCI_HANDLER;
+use crate::drivers::Driver;
 
 mod serial;
+mod backtrace;
+mod gdt;
 mod interrupts;
+mod extable;
+mod crash;
+mod page_fault;
 mod vmm;
 mod pmm;
 mod allocator;
 mod logger;
 mod pci;
 mod acpi;
+mod block;
+mod fs;
 mod device;
 mod drivers;
+mod pic;
+mod apic;
+mod sync;
+mod time;
+mod deferred;
+mod crc32;
+mod cmdline;
+mod power;
+mod shell;
+mod gdb;
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> !
 {
+    serial::enter_panic_mode();
     error!("{}", _info);
-    loop {}
+    crash::panic(_info);
 }
 
 bootloader::entry_point!(kernel_main);
 
+/// File the log records are written to once the filesystems are mounted
+const KERNEL_LOG_PATH: &str = "/tmp/kernel.log";
+/// Where the root device is mounted, next to the in-memory root filesystem
+const ROOT_MOUNT_POINT: &str = "/mnt";
+/// I/O port of the QEMU `isa-debug-exit` device, which makes QEMU exit with `(value << 1) | 1`
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+pub static ROOT_DEVICE: Parameter<&str> = Parameter::new("root", "", "block device to mount on /mnt, e.g. ram0p1");
+pub static TEST_MODE: Parameter<bool> = Parameter::new("test", false, "exit QEMU once the kernel is initialized or crashed, on or off");
+
+#[derive(Debug, Copy, Clone)]
+#[repr(u32)]
+pub enum QemuExitCode
+{
+    Success = 0x10,
+    Failure = 0x11
+}
+
+/// Exits QEMU if it has an `isa-debug-exit` device on `QEMU_EXIT_PORT`, returns otherwise
+pub fn exit_qemu(code: QemuExitCode)
+{
+    unsafe { Port::<u32>::new(QEMU_EXIT_PORT).write(code as u32) };
+}
+
 #[derive(Clone)]
 struct BootInfoRef
 {
@@ -66,20 +114,49 @@ fn kernel_main(boot_info : &'static mut bootloader::BootInfo) -> !
         framebuffer.buffer_mut().fill(0x90);
     }
 
-    log::set_logger(&SERIAL_LOGGER).map(|()| log::set_max_level(log::LevelFilter::Trace)).expect("Failed to set logger");
+    log::set_logger(&KERNEL_LOGGER).map(|()| log::set_max_level(log::LevelFilter::Trace)).expect("Failed to set logger");
 
+    gdt::init();
     init_idt();
 
     x86_64::instructions::interrupts::int3();
 
     allocator::init().expect("Heap initialization failed");
+    cmdline::init();
+    logger::init();
+    fs::init();
+    if let Some(framebuffer) = boot_info.framebuffer.as_mut()
+    {
+        let framebuffer = Arc::new(fs::devfs::FramebufferDevice::new(framebuffer));
+        if let Err(e) = fs::devfs::register_char_device("fb0", framebuffer.clone())
+        {
+            error!("Failed to register the framebuffer: {:?}", e);
+        }
+        logger::add_sink(Box::leak(Box::new(logger::FramebufferConsole::new(framebuffer))), log::LevelFilter::Info);
+    }
+    logger::add_sink(Box::leak(Box::new(logger::FileSink::new(KERNEL_LOG_PATH))), log::LevelFilter::Trace);
+
+    pic::init();
+    gdb::init();
+    time::init();
+    block::cache::init();
+    block::ramdisk::load_initrd();
+    cmdline::load_boot_file();
+    logger::configure();
+    block::cache::configure();
+    if let Err(e) = apic::init()
+    {
+        error!("Failed to initialize the local APIC, MSIs are unavailable: {:?}", e);
+    }
+    x86_64::instructions::interrupts::enable();
+
     // Initialize AML context and PCI handler before locking ACPI
     PCI_HANDLER.lock();
     AML_CONTEXT.lock();
 
     let devices = ACPI.lock().enumerate_devices();
 
-    let drivers = devices.iter().map(|device|
+    let drivers: Vec<Driver> = devices.iter().filter_map(|device|
         {
             match device.find_driver()
             {
@@ -92,17 +169,39 @@ fn kernel_main(boot_info : &'static mut bootloader::BootInfo) -> !
                     None
                 }
             }
-        });
+        }).collect();
+
+    for driver in drivers.iter()
+    {
+        info!("Driver found : {:?}", driver);
+    }
 
-    for driver in drivers
+    // The boot partition may be on a disk found by the drivers
+    if !cmdline::load_boot_file()
     {
-        if let Some(driver) = driver
+        info!("[CMDLINE] No command line file found");
+    }
+    logger::configure();
+    block::cache::configure();
+    let root = ROOT_DEVICE.get();
+    if !root.is_empty()
+    {
+        if let Err(e) = fs::mount_device(ROOT_MOUNT_POINT, root, None)
         {
-            info!("Driver found : {:?}", driver);
+            warn!("[VFS] Failed to mount the root device {}: {:?}", root, e);
         }
     }
 
     info!("Kernel initialized");
+    if TEST_MODE.get()
+    {
+        exit_qemu(QemuExitCode::Success);
+    }
+    shell::init();
 
-    loop {}
+    loop
+    {
+        deferred::run_pending();
+        x86_64::instructions::hlt();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,149 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::allocator::{MAX_HEAP_SIZE, HEAP_START};
+use crate::vmm::VMM;
+use crate::{gdt, serial_println};
+
+/// Resolves a fault in a region, e.g. by mapping the page. Returns whether the faulting access can be retried.
+pub type FaultResolver = Arc<dyn Fn(VirtAddr, PageFaultErrorCode) -> bool + Send + Sync>;
+
+/// A range of addresses named in fault reports, and able to resolve the faults on it
+#[derive(Clone)]
+pub struct FaultRegion
+{
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    resolver: Option<FaultResolver>
+}
+
+impl FaultRegion
+{
+    fn contains(&self, address: VirtAddr) -> bool
+    {
+        self.start <= address && address < self.end
+    }
+}
+
+static REGIONS: Mutex<Vec<FaultRegion>> = Mutex::new(Vec::new());
+
+/// Registers the region of `size` bytes at `start`. Faults in it are handed to `resolver`, or reported with
+/// the name of the region without one.
+pub fn register_region(name: &'static str, start: VirtAddr, size: u64, resolver: Option<FaultResolver>)
+{
+    let region = FaultRegion {
+        name,
+        start,
+        end: start + size,
+        resolver
+    };
+    without_interrupts(|| REGIONS.lock().push(region));
+}
+
+/// The registered region holding `address`
+fn find_region(address: VirtAddr) -> Option<FaultRegion>
+{
+    REGIONS.try_lock()?.iter().find(|region| region.contains(address)).cloned()
+}
+
+/// Lets the region of the faulting address resolve the fault, returns whether execution can resume
+pub fn resolve(address: VirtAddr, error_code: PageFaultErrorCode) -> bool
+{
+    match find_region(address).and_then(|region| region.resolver)
+    {
+        Some(resolver) => resolver(address, error_code),
+        None => false
+    }
+}
+
+/// The part of the address space holding `address`
+fn describe(address: VirtAddr, error_code: PageFaultErrorCode) -> &'static str
+{
+    let heap = VirtAddr::new(HEAP_START as u64)..VirtAddr::new((HEAP_START + MAX_HEAP_SIZE) as u64);
+    if address.as_u64() < 0x1000
+    {
+        "null page"
+    }
+    else if gdt::ist_guard_page(address).is_some()
+    {
+        "guard page of an IST stack"
+    }
+    else if let Some(region) = find_region(address)
+    {
+        region.name
+    }
+    else if heap.contains(&address)
+    {
+        "kernel heap"
+    }
+    else if error_code.contains(PageFaultErrorCode::USER_MODE)
+    {
+        "user space"
+    }
+    else if address >= heap.end
+    {
+        "dynamic mappings (MMIO, DMA and allocated pages)"
+    }
+    else
+    {
+        "kernel image, stack or bootloader mappings"
+    }
+}
+
+/// Prints the faulting address, its region, the decoded error code and the page table walk of the address
+pub fn report(address: VirtAddr, error_code: PageFaultErrorCode, stack_frame: &InterruptStackFrame)
+{
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+    {
+        "instruction fetch"
+    }
+    else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+    {
+        "write"
+    }
+    else
+    {
+        "read"
+    };
+    let cause = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE)
+    {
+        "reserved bit set in a page table"
+    }
+    else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        "protection violation"
+    }
+    else
+    {
+        "page not present"
+    };
+
+    serial_println!("EXCEPTION: PAGE FAULT at {:#x} ({})", address.as_u64(), describe(address, error_code));
+    serial_println!("  {} from {} mode at {:#x}: {} [{:?}]", access, if error_code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" },
+        stack_frame.instruction_pointer.as_u64(), cause, error_code);
+
+    let vmm = match VMM.try_lock()
+    {
+        Some(vmm) => vmm,
+        None => {
+            serial_println!("  page tables locked, no walk");
+            return;
+        }
+    };
+    for entry in vmm.walk(address).iter().flatten()
+    {
+        if entry.flags.contains(PageTableFlags::PRESENT)
+        {
+            serial_println!("  L{}[{:3}] -> {:#x} {:?}", entry.level, entry.index, entry.address.as_u64(), entry.flags);
+        }
+        else
+        {
+            serial_println!("  L{}[{:3}] not present", entry.level, entry.index);
+        }
+    }
+}
@@ -2,7 +2,7 @@ use alloc::format;
 use core::fmt::{Display, Formatter};
 use x86_64::PhysAddr;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum Bar
 {
     Memory {
@@ -0,0 +1,77 @@
+use bit_field::BitField;
+use crate::pci::pci_address::PciAddress;
+use crate::pci::PciHandler;
+
+pub const CAPABILITY_ID_MSI: u8 = 0x05;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Capability
+{
+    address: PciAddress,
+    id: u8,
+    offset: u16
+}
+
+impl Capability
+{
+    pub fn new(address: PciAddress, id: u8, offset: u16) -> Self
+    {
+        Capability {
+            address,
+            id,
+            offset
+        }
+    }
+
+    pub fn as_msi(&self) -> Option<MsiCapability>
+    {
+        match self.id
+        {
+            CAPABILITY_ID_MSI => Some(MsiCapability(*self)),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MsiCapability(Capability);
+
+impl MsiCapability
+{
+    fn message_control(&self, pci_handler: &PciHandler) -> u32
+    {
+        unsafe { pci_handler.read(self.0.address, self.0.offset) }
+    }
+
+    pub fn is_64bit(&self, pci_handler: &PciHandler) -> bool
+    {
+        self.message_control(pci_handler).get_bit(16 + 7)
+    }
+
+    /// Programs the capability to deliver a single message `data` written at `address` and enables it.
+    pub fn enable(&self, pci_handler: &PciHandler, address: u64, data: u16)
+    {
+        let is_64bit = self.is_64bit(pci_handler);
+
+        unsafe {
+            pci_handler.write(self.0.address, self.0.offset + 0x04, address as u32);
+            if is_64bit
+            {
+                pci_handler.write(self.0.address, self.0.offset + 0x08, (address >> 32) as u32);
+                pci_handler.write(self.0.address, self.0.offset + 0x0C, data as u32);
+            }
+            else
+            {
+                pci_handler.write(self.0.address, self.0.offset + 0x08, data as u32);
+            }
+        }
+
+        /*
+         * Only one message is requested (Multiple Message Enable = 0), then MSI is enabled.
+         */
+        let mut control = self.message_control(pci_handler);
+        control.set_bits(16 + 4..16 + 7, 0);
+        control.set_bit(16, true);
+        unsafe { pci_handler.write(self.0.address, self.0.offset, control) };
+    }
+}
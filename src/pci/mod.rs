@@ -6,10 +6,13 @@ mod pci_header;
 mod status_register;
 mod bar;
 mod device_type;
+mod capability;
 
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use acpi::{AcpiError, AcpiHandler, AcpiTables, PciConfigRegions};
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::{Page, PageTableFlags};
 use x86_64::{PhysAddr, VirtAddr};
 use spin::Mutex;
@@ -17,18 +20,22 @@ use log::{error, info};
 
 use crate::VMM;
 use crate::acpi::ACPI;
+use crate::cmdline::Parameter;
 
 pub use crate::pci::pci_address::PciAddress;
 pub use crate::pci::pci_driver::PciDriver;
 pub use crate::pci::pci_device::PciDevice;
-pub use crate::pci::pci_header::{PciHeader, StandardHeader, BistError};
+pub use crate::pci::pci_header::{PciHeader, StandardHeader, BistError, HeaderType};
 pub use crate::pci::bar::Bar;
+pub use crate::pci::capability::{Capability, MsiCapability};
 
 #[derive(Clone)]
 pub struct PciHandler
 {
     pci_config_regions: PciConfigRegions,
-    page: VirtAddr
+    page: VirtAddr,
+    /// The BARs of each standard function, sized once when it is first enumerated
+    bars: BTreeMap<PciAddress, Vec<(u8, Bar)>>
 }
 
 impl PciHandler
@@ -43,7 +50,8 @@ impl PciHandler
                     PhysAddr::new(0),
                     2,
                     PageTableFlags::PRESENT | PageTableFlags::WRITABLE
-                ).expect("[PCI] Failed to find free pages")
+                ).expect("[PCI] Failed to find free pages"),
+                bars: BTreeMap::new()
             }),
             Err(err) => Err(err)
         }
@@ -100,11 +108,42 @@ impl PciHandler
         {
             if let Some(device) = PciDevice::new(address, self)
             {
+                if !self.bars.contains_key(&address)
+                {
+                    let bars = self.size_bars(address);
+                    self.bars.insert(address, bars);
+                }
                 devices.push(device);
             }
         }
     }
 
+    /// Sizes the BARs of the standard function at `address`. Sizing moves a BAR for a moment, so memory and
+    /// I/O decoding are disabled meanwhile and the function cannot claim accesses meant for another device.
+    fn size_bars(&self, address: PciAddress) -> Vec<(u8, Bar)>
+    {
+        let header = PciHeader::new(address);
+        if !matches!(header.header_type(self), Ok(HeaderType::Standard))
+        {
+            return Vec::new();
+        }
+
+        // The handler of the function must not touch it while it does not decode
+        without_interrupts(|| {
+            let command = header.command(self);
+            header.set_command(self, command & !0b11);
+            let bars = StandardHeader::new(address).bars(self);
+            header.set_command(self, command);
+            bars
+        })
+    }
+
+    /// The BARs of the function at `address` with their slot, as sized when it was enumerated
+    pub fn bars(&self, address: PciAddress) -> &[(u8, Bar)]
+    {
+        self.bars.get(&address).map_or(&[], |bars| bars.as_slice())
+    }
+
     pub unsafe fn read(&self, address: PciAddress, offset: u16) -> u32
     {
         let physical_address = self.pci_config_regions.physical_address(address.segment(), address.bus(), address.device(), address.function())
@@ -137,10 +176,16 @@ impl PciHandler
     }
 }
 
+pub static PCI_ENABLED: Parameter<bool> = Parameter::new("pci", true, "whether to enumerate the PCI devices, on or off");
+
 lazy_static!
 {
     pub static ref PCI_HANDLER: Mutex<Option<PciHandler>> = Mutex::new(match PciHandler::new(&ACPI.lock().acpi_tables)
         {
+            Ok(_) if !PCI_ENABLED.get() => {
+                info!("[PCI] Disabled on the command line");
+                None
+            }
             Ok(handler) => Some(handler),
             Err(err) => {
                 error!("[PCI] Failed to initialize PCI handler: {:?}", err);
@@ -1,7 +1,7 @@
 use core::fmt::Display;
 use bit_field::BitField;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PciAddress(u32);
 
 impl PciAddress
@@ -49,7 +49,7 @@ impl PciDevice
         }
     }
 
-    fn get_vendor_name(&self) -> &str
+    pub fn get_vendor_name(&self) -> &str
     {
         match self.vendor_id
         {
@@ -60,7 +60,7 @@ impl PciDevice
         }
     }
 
-    fn get_device_name(&self) -> &str
+    pub fn get_device_name(&self) -> &str
     {
         match (self.vendor_id, self.device_id)
         {
@@ -93,6 +93,7 @@ impl PciDevice
     {
         match (DeviceType::from((self.class_code, self.subclass_code)), self.prog_interface)
         {
+            (DeviceType::SataController, 0x1) if drivers::is_skipped("ahci") => None,
             (DeviceType::SataController, 0x1) => match drivers::SataControllerAhci::init(self.clone())
             {
                 Ok(driver) => Some(Driver::PciDriver(Box::new(driver))),
@@ -116,6 +117,18 @@ impl PciDevice
     {
         self.address
     }
+
+    #[inline]
+    pub fn get_ids(&self) -> (VendorId, DeviceId)
+    {
+        (self.vendor_id, self.device_id)
+    }
+
+    #[inline]
+    pub fn get_class(&self) -> (ClassCode, SubClass, ProgramInterface)
+    {
+        (self.class_code, self.subclass_code, self.prog_interface)
+    }
 }
 
 impl Display for PciDevice
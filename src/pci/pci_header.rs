@@ -1,5 +1,7 @@
+use alloc::vec::Vec;
 use bit_field::BitField;
 use x86_64::PhysAddr;
+use crate::pci::capability::Capability;
 use crate::pci::bar::Bar;
 use crate::pci::pci_address::PciAddress;
 use crate::pci::PciHandler;
@@ -82,6 +84,67 @@ impl PciHeader
         StatusRegister::new(data as u16)
     }
 
+    pub fn command(&self, pci_handler: &PciHandler) -> u16
+    {
+        unsafe { pci_handler.read(self.0, 0x04) }.get_bits(0..16) as u16
+    }
+
+    pub fn set_command(&self, pci_handler: &PciHandler, command: u16)
+    {
+        /*
+         * The status register shares the dword, its bits are cleared by writing ones so they are written as zeroes.
+         */
+        unsafe { pci_handler.write(self.0, 0x04, command as u32) };
+    }
+
+    /// Allows the function to decode memory accesses and to issue DMA on the bus.
+    pub fn enable_bus_mastering(&self, pci_handler: &PciHandler)
+    {
+        let mut command = self.command(pci_handler);
+        command.set_bit(1, true).set_bit(2, true);
+        self.set_command(pci_handler, command);
+    }
+
+    pub fn set_interrupt_disable(&self, pci_handler: &PciHandler, disabled: bool)
+    {
+        let mut command = self.command(pci_handler);
+        command.set_bit(10, disabled);
+        self.set_command(pci_handler, command);
+    }
+
+    pub fn interrupt_line(&self, pci_handler: &PciHandler) -> u8
+    {
+        unsafe { pci_handler.read(self.0, 0x3C) }.get_bits(0..8) as u8
+    }
+
+    pub fn interrupt_pin(&self, pci_handler: &PciHandler) -> u8
+    {
+        unsafe { pci_handler.read(self.0, 0x3C) }.get_bits(8..16) as u8
+    }
+
+    pub fn capabilities(&self, pci_handler: &PciHandler) -> Vec<Capability>
+    {
+        let mut capabilities = Vec::new();
+        if !self.status(pci_handler).has_capability_list()
+        {
+            return capabilities;
+        }
+
+        let mut offset = unsafe { pci_handler.read(self.0, 0x34) }.get_bits(0..8) as u16 & !0x3;
+
+        /*
+         * The list is bounded to protect against malformed or looping lists.
+         */
+        while offset != 0 && capabilities.len() < 48
+        {
+            let register = unsafe { pci_handler.read(self.0, offset) };
+            capabilities.push(Capability::new(self.0, register.get_bits(0..8) as u8, offset));
+            offset = register.get_bits(8..16) as u16 & !0x3;
+        }
+
+        capabilities
+    }
+
     pub fn header_type(&self, pci_handler: &PciHandler) -> Result<HeaderType, ()>
     {
         HeaderType::try_from(unsafe { pci_handler.read(self.0, 0x0C) }.get_bits(16..23) as u8)
@@ -126,6 +189,28 @@ impl StandardHeader
         StandardHeader(address)
     }
 
+    /// The implemented BARs with their slot. The second slot of a 64 bit BAR is skipped, as are the BARs of
+    /// reserved type.
+    pub fn bars(&self, pci_handler: &PciHandler) -> Vec<(u8, Bar)>
+    {
+        let mut bars = Vec::new();
+        let mut slot = 0;
+        while slot < 6
+        {
+            let bar = unsafe { pci_handler.read(self.0, 0x10 + (slot as u16) * 4) };
+            let memory_type = if bar.get_bit(0) { None } else { Some(bar.get_bits(1..3)) };
+            if memory_type != Some(0b11)
+            {
+                if let Some(bar) = self.bar(pci_handler, slot)
+                {
+                    bars.push((slot, bar));
+                }
+            }
+            slot += if memory_type == Some(0b10) { 2 } else { 1 };
+        }
+        bars
+    }
+
     pub fn bar(&self, pci_handler: &PciHandler, slot: u8) -> Option<Bar>
     {
         if slot >= 6 {
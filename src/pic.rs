@@ -0,0 +1,105 @@
+use x86_64::instructions::port::Port;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0B;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// Vector of the first legacy IRQ once the PICs have been remapped.
+pub const PIC_VECTOR_BASE: u8 = 32;
+
+unsafe fn io_wait()
+{
+    Port::<u8>::new(0x80).write(0);
+}
+
+/// Remaps both 8259 PICs above the CPU exceptions and masks every line.
+/// Lines are unmasked one by one as drivers register legacy IRQ handlers.
+pub fn init()
+{
+    unsafe {
+        let mut pic1_command = Port::<u8>::new(PIC1_COMMAND);
+        let mut pic1_data = Port::<u8>::new(PIC1_DATA);
+        let mut pic2_command = Port::<u8>::new(PIC2_COMMAND);
+        let mut pic2_data = Port::<u8>::new(PIC2_DATA);
+
+        pic1_command.write(ICW1_INIT);
+        io_wait();
+        pic2_command.write(ICW1_INIT);
+        io_wait();
+
+        pic1_data.write(PIC_VECTOR_BASE);
+        io_wait();
+        pic2_data.write(PIC_VECTOR_BASE + 8);
+        io_wait();
+
+        // The slave PIC is cascaded on IRQ 2 of the master
+        pic1_data.write(4);
+        io_wait();
+        pic2_data.write(2);
+        io_wait();
+
+        pic1_data.write(ICW4_8086);
+        io_wait();
+        pic2_data.write(ICW4_8086);
+        io_wait();
+
+        pic1_data.write(0xFF);
+        pic2_data.write(0xFF);
+    }
+}
+
+pub fn unmask(irq: u8)
+{
+    unsafe {
+        if irq >= 8
+        {
+            let mut pic2_data = Port::<u8>::new(PIC2_DATA);
+            let mask = pic2_data.read();
+            pic2_data.write(mask & !(1 << (irq - 8)));
+
+            // Lines of the slave PIC only reach the CPU through the cascade line
+            let mut pic1_data = Port::<u8>::new(PIC1_DATA);
+            let mask = pic1_data.read();
+            pic1_data.write(mask & !(1 << 2));
+        }
+        else
+        {
+            let mut pic1_data = Port::<u8>::new(PIC1_DATA);
+            let mask = pic1_data.read();
+            pic1_data.write(mask & !(1 << irq));
+        }
+    }
+}
+
+/// Returns true if `irq` was raised without being latched in the in-service register, which happens
+/// for IRQ 7 and 15 when a line is deasserted before the CPU acknowledges it.
+pub fn is_spurious(irq: u8) -> bool
+{
+    if irq != 7 && irq != 15
+    {
+        return false;
+    }
+
+    unsafe {
+        let mut command = Port::<u8>::new(if irq == 7 { PIC1_COMMAND } else { PIC2_COMMAND });
+        command.write(OCW3_READ_ISR);
+        command.read() & (1 << 7) == 0
+    }
+}
+
+pub fn end_of_interrupt(irq: u8)
+{
+    unsafe {
+        if irq >= 8
+        {
+            Port::<u8>::new(PIC2_COMMAND).write(END_OF_INTERRUPT);
+        }
+        Port::<u8>::new(PIC1_COMMAND).write(END_OF_INTERRUPT);
+    }
+}
@@ -28,6 +28,18 @@ impl BootInfoFrameAllocator {
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Bytes of usable physical memory reported by the bootloader
+    pub fn total_memory(&self) -> u64
+    {
+        self.memory_regions.iter().filter(|r| r.kind == MemoryRegionKind::Usable).map(|r| r.end - r.start).sum()
+    }
+
+    /// Number of frames handed out so far, frames are never freed
+    pub fn allocated_frames(&self) -> usize
+    {
+        self.next
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -0,0 +1,142 @@
+use ::aml::{AmlName, AmlValue};
+use acpi::fadt::Fadt;
+use acpi::platform::address::{AddressSpace, GenericAddress};
+use acpi::sdt::Signature;
+use log::{info, warn};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::acpi::{ACPI, AML_CONTEXT};
+use crate::{fs, time};
+
+/// Reset control register of the chipset, and the value asking it for a full reset
+const RESET_CONTROL_PORT: u16 = 0xCF9;
+const RESET_CONTROL_RESET: u8 = 0x06;
+/// Status and command ports of the 8042 keyboard controller, which can pulse the reset line of the CPU
+const KEYBOARD_STATUS_PORT: u16 = 0x64;
+const KEYBOARD_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_PULSE_RESET: u8 = 0xFE;
+/// Bit of PM1 control starting the transition to the sleep type written next to it
+const SLEEP_ENABLE: u16 = 1 << 13;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+/// Time given to the firmware to power off before giving up
+const SHUTDOWN_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug)]
+pub enum PowerError
+{
+    /// The FADT is missing or does not describe the register
+    NoRegister,
+    /// The register is not in I/O space
+    UnsupportedAddressSpace(AddressSpace),
+    /// The AML namespace has no usable `\_S5` object
+    NoSleepState,
+    /// The machine was still running after the transition was started
+    Timeout
+}
+
+/// Runs `f` on the FADT, which describes the power management registers
+fn with_fadt<R>(f: impl FnOnce(&Fadt) -> R) -> Result<R, PowerError>
+{
+    let acpi = ACPI.lock();
+    match unsafe { acpi.acpi_tables.get_sdt::<Fadt>(Signature::FADT) }
+    {
+        Ok(Some(fadt)) => Ok(f(&fadt)),
+        _ => Err(PowerError::NoRegister)
+    }
+}
+
+/// Address of the register read from the FADT by `register`, which must be an I/O port
+fn io_port(register: impl FnOnce(&Fadt) -> Result<GenericAddress, acpi::AcpiError>) -> Result<u16, PowerError>
+{
+    let address = with_fadt(register)?.map_err(|_| PowerError::NoRegister)?;
+    match address.address_space
+    {
+        AddressSpace::SystemIo => Ok(address.address as u16),
+        space => Err(PowerError::UnsupportedAddressSpace(space))
+    }
+}
+
+/// Writes the ACPI reset value to the reset register
+fn acpi_reset() -> Result<(), PowerError>
+{
+    let port = io_port(|fadt| fadt.reset_register())?;
+    let value = with_fadt(|fadt| fadt.reset_value)?;
+    unsafe { Port::<u8>::new(port).write(value) };
+    Ok(())
+}
+
+/// Syncs the filesystems and resets the machine, trying the ACPI reset register, the chipset, the keyboard
+/// controller and finally a triple fault
+pub fn reboot() -> !
+{
+    info!("[POWER] Rebooting");
+    if let Err(e) = fs::sync()
+    {
+        warn!("[POWER] Failed to sync the filesystems: {:?}", e);
+    }
+
+    if let Err(e) = acpi_reset()
+    {
+        warn!("[POWER] ACPI reset unavailable: {:?}", e);
+    }
+    interrupts::disable();
+    unsafe {
+        Port::<u8>::new(RESET_CONTROL_PORT).write(RESET_CONTROL_RESET);
+
+        let mut status = Port::<u8>::new(KEYBOARD_STATUS_PORT);
+        while status.read() & KEYBOARD_INPUT_FULL != 0 {}
+        status.write(KEYBOARD_PULSE_RESET);
+
+        // Without an IDT, the next exception is a triple fault, which resets the CPU
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero()
+        });
+    }
+    x86_64::instructions::interrupts::int3();
+    loop
+    {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// The `SLP_TYPa` value of the soft-off state, from the `\_S5` package
+fn soft_off_sleep_type() -> Result<u16, PowerError>
+{
+    let name = AmlName::from_str("\\_S5").map_err(|_| PowerError::NoSleepState)?;
+    let context = AML_CONTEXT.lock();
+    match context.namespace.get_by_path(&name)
+    {
+        Ok(AmlValue::Package(values)) => match values.first()
+        {
+            Some(AmlValue::Integer(sleep_type)) => Ok(*sleep_type as u16),
+            _ => Err(PowerError::NoSleepState)
+        },
+        _ => Err(PowerError::NoSleepState)
+    }
+}
+
+/// Syncs the filesystems and powers the machine off through the ACPI soft-off state. Only returns on
+/// failure.
+pub fn shutdown() -> PowerError
+{
+    info!("[POWER] Shutting down");
+    if let Err(e) = fs::sync()
+    {
+        warn!("[POWER] Failed to sync the filesystems: {:?}", e);
+    }
+
+    let (sleep_type, port) = match soft_off_sleep_type().and_then(|sleep_type| Ok((sleep_type, io_port(|fadt| fadt.pm1a_control_block())?)))
+    {
+        Ok(registers) => registers,
+        Err(e) => return e
+    };
+    unsafe { Port::<u16>::new(port).write((sleep_type << SLEEP_TYPE_SHIFT) | SLEEP_ENABLE) };
+
+    time::sleep_ms(SHUTDOWN_TIMEOUT_MS);
+    PowerError::Timeout
+}
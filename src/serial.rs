@@ -1,20 +1,75 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+use crate::sync::IrqMutex;
+
+/// I/O ports of the first and second serial ports
+const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+/// Offset of the line status register, and its bit telling a byte was received
+const LINE_STATUS: u16 = 5;
+const DATA_READY: u8 = 1 << 0;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::new(serial_port)
     };
 }
 
+/// Set once the kernel panicked or hit a fatal exception, the port then belongs to whoever prints
+static PANIC_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Makes the next prints take the serial port even if it is locked, as its holder will never release it
+/// once the other processors are stopped
+pub fn enter_panic_mode()
+{
+    PANIC_MODE.store(true, Ordering::SeqCst);
+}
+
+pub fn in_panic_mode() -> bool
+{
+    PANIC_MODE.load(Ordering::SeqCst)
+}
+
+/// Returns the byte received on the first serial port, if any, without waiting for one
+pub fn try_receive() -> Option<u8>
+{
+    try_receive_on(&mut SERIAL1.lock(), COM1)
+}
+
+/// Returns the byte received on `port`, whose I/O port is `base`, if any, without waiting for one
+pub fn try_receive_on(port: &mut SerialPort, base: u16) -> Option<u8>
+{
+    let status = unsafe { Port::<u8>::new(base + LINE_STATUS).read() };
+    if status & DATA_READY != 0 { Some(port.receive()) } else { None }
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments)
 {
     use core::fmt::Write;
-    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed!");
+    let mut serial = match SERIAL1.try_lock()
+    {
+        Some(serial) => serial,
+        // After a panic or a fatal exception, the holder never runs again: the interrupted line is cut
+        None if in_panic_mode() => {
+            unsafe { SERIAL1.force_unlock() };
+            SERIAL1.lock()
+        }
+        // Printing from an exception or NMI which interrupted a print on this processor: waiting would never
+        // end, the line is written through another handle in the middle of the interrupted one
+        None if SERIAL1.is_locked_by_current_cpu() => {
+            let mut port = unsafe { SerialPort::new(COM1) };
+            let _ = port.write_fmt(args);
+            return;
+        }
+        None => SERIAL1.lock()
+    };
+    // The port never fails to send a byte
+    let _ = serial.write_fmt(args);
 }
 
 #[macro_export]
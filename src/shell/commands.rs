@@ -0,0 +1,562 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use ::aml::value::Args;
+use ::aml::{AmlName, AmlValue, NamespaceLevel};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+
+use crate::acpi::{AcpiDevice, ACPI, AML_CONTEXT};
+use crate::block::ramdisk;
+use crate::fs::{self, FileType, OpenFlags, SeekFrom, MOUNTS};
+use crate::logger::{self, LOG_RING};
+use crate::pci::{PciAddress, PCI_HANDLER};
+use crate::{power, serial_print, serial_println, VMM};
+
+/// Bytes `peek` dumps by default and at most
+const DEFAULT_PEEK_LENGTH: u64 = 64;
+const MAX_PEEK_LENGTH: u64 = 4096;
+/// Records `dmesg` prints by default
+const DEFAULT_DMESG_RECORDS: usize = 50;
+/// Size of the PCI configuration space of a function
+const PCI_CONFIG_SIZE: u16 = 4096;
+
+type CommandResult = Result<(), String>;
+
+struct Command
+{
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    run: fn(&[&str]) -> CommandResult
+}
+
+static COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "help", description: "list the commands", run: help },
+    Command { name: "lspci", usage: "lspci", description: "list the PCI functions and their BARs", run: lspci },
+    Command { name: "lsacpi", usage: "lsacpi", description: "list the ACPI tables and the devices of the AML namespace", run: lsacpi },
+    Command { name: "mkram", usage: "mkram <size>", description: "create a zeroed RAM disk of size bytes", run: mkram },
+    Command { name: "meminfo", usage: "meminfo", description: "show the physical memory and heap usage", run: meminfo },
+    Command { name: "cd", usage: "cd [path]", description: "change the current directory, / by default", run: cd },
+    Command { name: "pwd", usage: "pwd", description: "print the current directory", run: pwd },
+    Command { name: "ls", usage: "ls [path]", description: "list a directory, the current one by default", run: ls },
+    Command { name: "rm", usage: "rm <path>", description: "remove a file or an empty directory", run: rm },
+    Command { name: "mv", usage: "mv <from> <to>", description: "move a file or directory within its filesystem", run: mv },
+    Command { name: "ln", usage: "ln <target> <path>", description: "create a symbolic link to target", run: ln },
+    Command { name: "readlink", usage: "readlink <path>", description: "print the target of a symbolic link", run: readlink },
+    Command { name: "mount", usage: "mount [device path [type]]", description: "list the mounts, or mount a block device", run: mount },
+    Command { name: "umount", usage: "umount <path>", description: "unmount the filesystem mounted on path", run: umount },
+    Command { name: "hexdump", usage: "hexdump <path> [offset] [length]", description: "dump part of a file, a negative offset counts from the end",
+        run: hexdump },
+    Command { name: "dmesg", usage: "dmesg [count]", description: "show the last log records", run: dmesg },
+    Command { name: "log", usage: "log [level [target]] | log sink <name> <level>",
+        description: "show the levels, set the level of a target or of a sink like serial, console or file",
+        run: log_level },
+    Command { name: "peek", usage: "peek <phys address> [length]", description: "dump physical memory", run: peek },
+    Command { name: "poke", usage: "poke <phys address> <value> [1|2|4|8]", description: "write physical memory, 4 bytes by default", run: poke },
+    Command { name: "pcicfg", usage: "pcicfg read <bus:device.function> <offset> | pcicfg write <bus:device.function> <offset> <value>",
+        description: "read or write a dword of a PCI configuration space", run: pcicfg },
+    Command { name: "aml", usage: "aml eval <path> [integer arguments]", description: "evaluate an AML object or method, e.g. \\_SB.PCI0._CRS", run: aml },
+    Command { name: "reboot", usage: "reboot", description: "sync the filesystems and reset the machine", run: reboot },
+    Command { name: "shutdown", usage: "shutdown", description: "sync the filesystems and power off", run: shutdown }
+];
+
+/// Runs the command typed on `line`
+pub fn run(line: &str)
+{
+    let arguments: Vec<&str> = line.split_whitespace().collect();
+    let name = match arguments.first()
+    {
+        Some(name) => *name,
+        None => return
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name)
+    {
+        Some(command) => if let Err(e) = (command.run)(&arguments[1..])
+        {
+            serial_println!("{}: {}", name, e);
+        },
+        None => {
+            serial_println!("Unknown command {}, type help for the commands", name);
+        }
+    }
+}
+
+fn usage(command: &str) -> String
+{
+    let usage = COMMANDS.iter().find(|candidate| candidate.name == command).map_or("", |candidate| candidate.usage);
+    format!("usage: {}", usage)
+}
+
+/// Parses a number, in hexadecimal with a `0x` prefix
+fn parse_number(s: &str) -> Result<u64, String>
+{
+    let number = match s.strip_prefix("0x")
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse()
+    };
+    number.map_err(|_| format!("invalid number {}", s))
+}
+
+fn help(_arguments: &[&str]) -> CommandResult
+{
+    for command in COMMANDS
+    {
+        serial_println!("  {:<40} {}", command.usage, command.description);
+    }
+    Ok(())
+}
+
+/// Prints a file of `/proc`
+fn cat(path: &str) -> CommandResult
+{
+    let content = fs::open(path, OpenFlags::READ)
+        .and_then(|file| file.read_to_end())
+        .map_err(|e| format!("failed to read {}: {:?}", path, e))?;
+    for line in String::from_utf8_lossy(&content).lines()
+    {
+        serial_println!("{}", line);
+    }
+    Ok(())
+}
+
+fn lspci(_arguments: &[&str]) -> CommandResult
+{
+    cat("/proc/pci")
+}
+
+fn meminfo(_arguments: &[&str]) -> CommandResult
+{
+    cat("/proc/meminfo")
+}
+
+fn lsacpi(_arguments: &[&str]) -> CommandResult
+{
+    for (signature, sdt) in ACPI.lock().acpi_tables.sdts.iter()
+    {
+        serial_println!("{} {:#010x} {:>6}", signature, sdt.physical_address, sdt.length);
+    }
+
+    // The namespace cannot be read while it is traversed, the IDs are looked up afterwards
+    let mut context = AML_CONTEXT.lock();
+    let mut devices = Vec::new();
+    context.namespace.traverse(|name: &AmlName, level: &NamespaceLevel| {
+        if let Some((_, handle)) = level.values.iter().find(|(seg, _)| seg.as_str() == "_HID")
+        {
+            devices.push((name.as_string(), *handle));
+        }
+        Ok(true)
+    }).map_err(|e| format!("failed to traverse the namespace: {:?}", e))?;
+
+    for (name, handle) in devices
+    {
+        match context.namespace.get(handle)
+        {
+            Ok(AmlValue::Integer(id)) => {
+                serial_println!("{:<24} {}", name, AcpiDevice::decompress_eisa_id(*id as u32));
+            }
+            Ok(AmlValue::String(id)) => {
+                serial_println!("{:<24} {}", name, id);
+            }
+            // The ID is computed by a method, evaluating it could touch the hardware
+            Ok(_) => {
+                serial_println!("{:<24} ?", name);
+            }
+            Err(e) => {
+                serial_println!("{:<24} <{:?}>", name, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn mkram(arguments: &[&str]) -> CommandResult
+{
+    let size = parse_number(arguments.first().ok_or_else(|| usage("mkram"))?)?;
+    if size == 0 || size > isize::MAX as u64
+    {
+        return Err(format!("invalid size {}", size));
+    }
+
+    let name = ramdisk::create(size as usize).map_err(|e| format!("failed to allocate the RAM disk: {:?}", e))?;
+    serial_println!("Created {}", name);
+    Ok(())
+}
+
+fn cd(arguments: &[&str]) -> CommandResult
+{
+    let path = arguments.first().copied().unwrap_or("/");
+    fs::set_current_dir(path).map_err(|e| format!("failed to enter {}: {:?}", path, e))
+}
+
+fn pwd(_arguments: &[&str]) -> CommandResult
+{
+    serial_println!("{}", fs::current_dir());
+    Ok(())
+}
+
+fn ls(arguments: &[&str]) -> CommandResult
+{
+    let path = arguments.first().copied().unwrap_or(".");
+    let mut entries = fs::read_dir(path).map_err(|e| format!("failed to list {}: {:?}", path, e))?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries
+    {
+        let kind = match entry.file_type
+        {
+            FileType::Regular => '-',
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b'
+        };
+        serial_println!("{} {:>8} {}", kind, entry.inode, entry.name);
+    }
+    Ok(())
+}
+
+fn rm(arguments: &[&str]) -> CommandResult
+{
+    let path = arguments.first().ok_or_else(|| usage("rm"))?;
+    fs::remove(path).map_err(|e| format!("failed to remove {}: {:?}", path, e))
+}
+
+fn mv(arguments: &[&str]) -> CommandResult
+{
+    match arguments
+    {
+        [from, to] => fs::rename(from, to).map_err(|e| format!("failed to move {} to {}: {:?}", from, to, e)),
+        _ => Err(usage("mv"))
+    }
+}
+
+fn ln(arguments: &[&str]) -> CommandResult
+{
+    match arguments
+    {
+        [target, path] => fs::symlink(target, path).map_err(|e| format!("failed to create {}: {:?}", path, e)),
+        _ => Err(usage("ln"))
+    }
+}
+
+fn readlink(arguments: &[&str]) -> CommandResult
+{
+    let path = arguments.first().ok_or_else(|| usage("readlink"))?;
+    let target = fs::read_link(path).map_err(|e| format!("failed to read {}: {:?}", path, e))?;
+    serial_println!("{}", target);
+    Ok(())
+}
+
+fn mount(arguments: &[&str]) -> CommandResult
+{
+    match arguments
+    {
+        [] => {
+            for mount in MOUNTS.read().iter()
+            {
+                let filesystem = mount.filesystem().name();
+                serial_println!("{} on {} type {}", mount.source().unwrap_or(filesystem), mount.path(), filesystem);
+            }
+            Ok(())
+        }
+        [device, path] => fs::mount_device(path, device, None).map_err(|e| format!("failed to mount {}: {:?}", device, e)),
+        [device, path, filesystem_type] => {
+            fs::mount_device(path, device, Some(filesystem_type)).map_err(|e| format!("failed to mount {}: {:?}", device, e))
+        }
+        _ => Err(usage("mount"))
+    }
+}
+
+fn umount(arguments: &[&str]) -> CommandResult
+{
+    let path = arguments.first().ok_or_else(|| usage("umount"))?;
+    fs::unmount(path).map_err(|e| format!("failed to unmount {}: {:?}", path, e))
+}
+
+fn dmesg(arguments: &[&str]) -> CommandResult
+{
+    let count = match arguments.first()
+    {
+        Some(count) => parse_number(count)? as usize,
+        None => DEFAULT_DMESG_RECORDS
+    };
+
+    // Formatted first, printing them would log while the ring is locked
+    let records: Vec<String> = {
+        let ring = LOG_RING.lock();
+        let total = ring.records().count();
+        ring.records().skip(total.saturating_sub(count)).map(|record| record.to_string()).collect()
+    };
+    for record in records
+    {
+        serial_println!("{}", record);
+    }
+    Ok(())
+}
+
+/// Maps `length` bytes of physical memory at `address` uncached, runs `f` on them and unmaps them
+fn with_physical<R>(address: u64, length: u64, flags: PageTableFlags, f: impl FnOnce(*mut u8) -> R) -> Result<R, String>
+{
+    let virt_addr = VMM.lock().map_region(PhysAddr::new(address), length, PageTableFlags::PRESENT | PageTableFlags::NO_CACHE | flags)
+        .map_err(|e| format!("failed to map {:#x}: {:?}", address, e))?;
+    let result = f(virt_addr.as_mut_ptr());
+    if let Err(e) = VMM.lock().unmap_region(virt_addr, length)
+    {
+        serial_println!("Failed to unmap {:#x}: {:?}", address, e);
+    }
+    Ok(result)
+}
+
+fn parse_level(s: &str) -> Result<log::LevelFilter, String>
+{
+    s.parse().map_err(|_| format!("invalid level {}", s))
+}
+
+fn log_level(arguments: &[&str]) -> CommandResult
+{
+    match arguments
+    {
+        [] => {
+            let (default, targets) = logger::levels();
+            serial_println!("default: {}", default);
+            for (target, level) in targets
+            {
+                serial_println!("{}: {}", target, level);
+            }
+        }
+        ["sink", name, level] => {
+            if !logger::set_sink_level(name, parse_level(level)?)
+            {
+                return Err(format!("no sink called {}", name));
+            }
+        }
+        [level] => logger::set_level(None, parse_level(level)?),
+        [level, target] => logger::set_level(Some(*target), parse_level(level)?),
+        _ => return Err(usage("log"))
+    }
+    Ok(())
+}
+
+fn peek(arguments: &[&str]) -> CommandResult
+{
+    let address = parse_number(arguments.first().ok_or_else(|| usage("peek"))?)?;
+    let length = match arguments.get(1)
+    {
+        Some(length) => parse_number(length)?.clamp(1, MAX_PEEK_LENGTH),
+        None => DEFAULT_PEEK_LENGTH
+    };
+
+    let mut data = alloc::vec![0u8; length as usize];
+    with_physical(address, length, PageTableFlags::empty(), |pointer| {
+        for (offset, byte) in data.iter_mut().enumerate()
+        {
+            *byte = unsafe { pointer.add(offset).read_volatile() };
+        }
+    })?;
+
+    print_dump(address, &data);
+    Ok(())
+}
+
+fn hexdump(arguments: &[&str]) -> CommandResult
+{
+    let path = arguments.first().ok_or_else(|| usage("hexdump"))?;
+    let position = match arguments.get(1)
+    {
+        Some(offset) => match offset.strip_prefix('-')
+        {
+            Some(from_end) => SeekFrom::End(-(parse_number(from_end)?.min(i64::MAX as u64) as i64)),
+            None => SeekFrom::Start(parse_number(offset)?)
+        },
+        None => SeekFrom::Start(0)
+    };
+    let length = match arguments.get(2)
+    {
+        Some(length) => parse_number(length)?.clamp(1, MAX_PEEK_LENGTH),
+        None => DEFAULT_PEEK_LENGTH
+    };
+
+    let file = fs::open(path, OpenFlags::READ).map_err(|e| format!("failed to open {}: {:?}", path, e))?;
+    let offset = file.seek(position).map_err(|e| format!("failed to seek in {}: {:?}", path, e))?;
+    let mut data = alloc::vec![0u8; length as usize];
+    let read = file.read(&mut data).map_err(|e| format!("failed to read {}: {:?}", path, e))?;
+    print_dump(offset, &data[..read]);
+    Ok(())
+}
+
+/// Prints `data` as lines of 16 bytes in hexadecimal and ASCII, labelled from `address`
+fn print_dump(address: u64, data: &[u8])
+{
+    for (index, line) in data.chunks(16).enumerate()
+    {
+        serial_print!("{:#014x}:", address + index as u64 * 16);
+        for byte in line
+        {
+            serial_print!(" {:02x}", byte);
+        }
+        serial_print!("{:width$}  ", "", width = (16 - line.len()) * 3);
+        for byte in line
+        {
+            serial_print!("{}", if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' });
+        }
+        serial_println!();
+    }
+}
+
+fn poke(arguments: &[&str]) -> CommandResult
+{
+    if arguments.len() < 2
+    {
+        return Err(usage("poke"));
+    }
+    let address = parse_number(arguments[0])?;
+    let value = parse_number(arguments[1])?;
+    let width = match arguments.get(2)
+    {
+        Some(width) => parse_number(width)?,
+        None => 4
+    };
+    if !matches!(width, 1 | 2 | 4 | 8) || address % width != 0
+    {
+        return Err(String::from("the width must be 1, 2, 4 or 8 bytes and the address aligned on it"));
+    }
+
+    with_physical(address, width, PageTableFlags::WRITABLE, |pointer| unsafe {
+        match width
+        {
+            1 => (pointer as *mut u8).write_volatile(value as u8),
+            2 => (pointer as *mut u16).write_volatile(value as u16),
+            4 => (pointer as *mut u32).write_volatile(value as u32),
+            _ => (pointer as *mut u64).write_volatile(value)
+        }
+    })
+}
+
+/// Parses a PCI address, `bus:device.function` or `segment:bus:device.function` in hexadecimal like `lspci`
+/// prints them
+fn parse_pci_address(s: &str) -> Result<PciAddress, String>
+{
+    let invalid = || format!("invalid PCI address {}, expected [segment:]bus:device.function", s);
+    let parts: Vec<&str> = s.split(':').collect();
+    let (segment, bus, rest) = match parts.as_slice()
+    {
+        [bus, rest] => ("0", *bus, *rest),
+        [segment, bus, rest] => (*segment, *bus, *rest),
+        _ => return Err(invalid())
+    };
+    let (device, function) = rest.split_once('.').ok_or_else(invalid)?;
+
+    let segment = u16::from_str_radix(segment, 16).map_err(|_| invalid())?;
+    let bus = u8::from_str_radix(bus, 16).map_err(|_| invalid())?;
+    let device = u8::from_str_radix(device, 16).map_err(|_| invalid())?;
+    let function = u8::from_str_radix(function, 16).map_err(|_| invalid())?;
+    if device >= 32 || function >= 8
+    {
+        return Err(invalid());
+    }
+    Ok(PciAddress::new(segment, bus, device, function))
+}
+
+fn pcicfg(arguments: &[&str]) -> CommandResult
+{
+    let (write, count) = match arguments.first()
+    {
+        Some(&"read") => (false, 3),
+        Some(&"write") => (true, 4),
+        _ => return Err(usage("pcicfg"))
+    };
+    if arguments.len() != count
+    {
+        return Err(usage("pcicfg"));
+    }
+    let address = parse_pci_address(arguments[1])?;
+    let offset = parse_number(arguments[2])?;
+    if offset % 4 != 0 || offset >= PCI_CONFIG_SIZE as u64
+    {
+        return Err(String::from("the offset must be a multiple of 4 below 0x1000"));
+    }
+
+    let handler = PCI_HANDLER.lock();
+    let handler = handler.as_ref().ok_or_else(|| String::from("PCI is not available"))?;
+    if !handler.function_exists(address)
+    {
+        return Err(format!("no configuration space for {}", address));
+    }
+    if write
+    {
+        let value = parse_number(arguments[3])?;
+        unsafe { handler.write(address, offset as u16, value as u32) };
+    }
+    let value = unsafe { handler.read(address, offset as u16) };
+    serial_println!("{} [{:#05x}] = {:#010x}", address, offset, value);
+    Ok(())
+}
+
+/// Prints an AML value, the elements of a package indented below it
+fn print_aml_value(value: &AmlValue, indent: usize)
+{
+    match value
+    {
+        AmlValue::Boolean(value) => {
+            serial_println!("{:indent$}{}", "", value, indent = indent);
+        }
+        AmlValue::Integer(value) => {
+            serial_println!("{:indent$}{:#x}", "", value, indent = indent);
+        }
+        AmlValue::String(value) => {
+            serial_println!("{:indent$}\"{}\"", "", value, indent = indent);
+        }
+        AmlValue::Buffer(data) => {
+            let data = data.lock();
+            serial_println!("{:indent$}Buffer[{}]", "", data.len(), indent = indent);
+            for line in data.chunks(16)
+            {
+                serial_print!("{:indent$}", "", indent = indent + 4);
+                for byte in line
+                {
+                    serial_print!("{:02x} ", byte);
+                }
+                serial_println!();
+            }
+        }
+        AmlValue::Package(elements) => {
+            serial_println!("{:indent$}Package[{}]", "", elements.len(), indent = indent);
+            for element in elements
+            {
+                print_aml_value(element, indent + 4);
+            }
+        }
+        other => {
+            serial_println!("{:indent$}{:?}", "", other, indent = indent);
+        }
+    }
+}
+
+fn aml(arguments: &[&str]) -> CommandResult
+{
+    if arguments.len() < 2 || arguments[0] != "eval"
+    {
+        return Err(usage("aml"));
+    }
+    let path = AmlName::from_str(arguments[1]).map_err(|e| format!("invalid path {}: {:?}", arguments[1], e))?;
+    let values = arguments[2..].iter()
+        .map(|argument| parse_number(argument).map(AmlValue::Integer))
+        .collect::<Result<Vec<AmlValue>, String>>()?;
+    let args = Args::from_list(values).map_err(|e| format!("{:?}", e))?;
+
+    let value = AML_CONTEXT.lock().invoke_method(&path, args).map_err(|e| format!("failed to evaluate {}: {:?}", arguments[1], e))?;
+    print_aml_value(&value, 0);
+    Ok(())
+}
+
+fn reboot(_arguments: &[&str]) -> CommandResult
+{
+    power::reboot();
+}
+
+fn shutdown(_arguments: &[&str]) -> CommandResult
+{
+    Err(format!("failed to power off: {:?}", power::shutdown()))
+}
@@ -0,0 +1,300 @@
+mod commands;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::cmdline::Parameter;
+use crate::deferred::{self, Work};
+use crate::sync::IrqMutex;
+use crate::{interrupts, serial, serial_print};
+
+/// Legacy IRQ of the first serial port
+const COM1_IRQ: u8 = 4;
+const PROMPT: &str = "kernel> ";
+/// Bytes received and not handled yet, the next ones are dropped
+const INPUT_QUEUE_SIZE: usize = 256;
+/// Lines kept in the history
+const MAX_HISTORY: usize = 32;
+const MAX_LINE_LENGTH: usize = 256;
+
+pub static SHELL_ENABLED: Parameter<bool> = Parameter::new("shell", true, "whether to run the debug shell on the first serial port, on or off");
+
+/// The bytes received by the interrupt handler, for the main loop
+struct InputQueue
+{
+    data: [u8; INPUT_QUEUE_SIZE],
+    head: usize,
+    length: usize
+}
+
+impl InputQueue
+{
+    fn push(&mut self, byte: u8)
+    {
+        if self.length < INPUT_QUEUE_SIZE
+        {
+            self.data[(self.head + self.length) % INPUT_QUEUE_SIZE] = byte;
+            self.length += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8>
+    {
+        if self.length == 0
+        {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % INPUT_QUEUE_SIZE;
+        self.length -= 1;
+        Some(byte)
+    }
+}
+
+static INPUT: IrqMutex<InputQueue> = IrqMutex::new(InputQueue {
+    data: [0; INPUT_QUEUE_SIZE],
+    head: 0,
+    length: 0
+});
+
+static INPUT_WORK: Once<Arc<Work>> = Once::new();
+
+/// State of the escape sequence being received, e.g. `ESC [ A` for the up arrow
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Escape
+{
+    None,
+    Escape,
+    /// A control sequence, with its numeric parameter so far
+    Csi(u8)
+}
+
+/// The line being typed, edited in place with the arrows, Home, End, Delete, Backspace, ^A, ^E, ^U and ^C
+struct LineEditor
+{
+    line: Vec<u8>,
+    cursor: usize,
+    escape: Escape,
+    history: Vec<String>,
+    /// Line of the history shown, `history.len()` for the line being typed
+    history_index: usize,
+    /// The line being typed, kept while the history is browsed
+    draft: Vec<u8>,
+    /// Whether the previous byte was a carriage return, which terminals may follow with a line feed
+    after_return: bool
+}
+
+impl LineEditor
+{
+    const fn new() -> Self
+    {
+        LineEditor {
+            line: Vec::new(),
+            cursor: 0,
+            escape: Escape::None,
+            history: Vec::new(),
+            history_index: 0,
+            draft: Vec::new(),
+            after_return: false
+        }
+    }
+
+    /// Redraws the line and puts the terminal cursor back on the editing cursor
+    fn redraw(&self)
+    {
+        serial_print!("\r{}{}\x1b[K", PROMPT, core::str::from_utf8(&self.line).unwrap_or(""));
+        if self.cursor < self.line.len()
+        {
+            serial_print!("\x1b[{}D", self.line.len() - self.cursor);
+        }
+    }
+
+    fn show_history(&mut self, index: usize)
+    {
+        if self.history_index == self.history.len()
+        {
+            self.draft = self.line.clone();
+        }
+        self.history_index = index;
+        self.line = match self.history.get(index)
+        {
+            Some(line) => line.as_bytes().to_vec(),
+            None => self.draft.clone()
+        };
+        self.cursor = self.line.len();
+        self.redraw();
+    }
+
+    /// Handles the final byte of a control sequence
+    fn control_sequence(&mut self, parameter: u8, byte: u8)
+    {
+        match (byte, parameter)
+        {
+            (b'A', _) if self.history_index > 0 => self.show_history(self.history_index - 1),
+            (b'B', _) if self.history_index < self.history.len() => self.show_history(self.history_index + 1),
+            (b'C', _) if self.cursor < self.line.len() => {
+                self.cursor += 1;
+                serial_print!("\x1b[C");
+            }
+            (b'D', _) if self.cursor > 0 => {
+                self.cursor -= 1;
+                serial_print!("\x1b[D");
+            }
+            (b'H', _) | (b'~', 1) => {
+                self.cursor = 0;
+                self.redraw();
+            }
+            (b'F', _) | (b'~', 4) => {
+                self.cursor = self.line.len();
+                self.redraw();
+            }
+            (b'~', 3) if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw();
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles a received byte, returns the line once Enter is pressed
+    fn input(&mut self, byte: u8) -> Option<String>
+    {
+        match self.escape
+        {
+            Escape::Escape => {
+                self.escape = if byte == b'[' { Escape::Csi(0) } else { Escape::None };
+                return None;
+            }
+            Escape::Csi(parameter) => {
+                if byte.is_ascii_digit()
+                {
+                    self.escape = Escape::Csi(parameter.saturating_mul(10).saturating_add(byte - b'0'));
+                }
+                else
+                {
+                    self.escape = Escape::None;
+                    self.control_sequence(parameter, byte);
+                }
+                return None;
+            }
+            Escape::None => {}
+        }
+
+        let after_return = core::mem::replace(&mut self.after_return, byte == b'\r');
+        match byte
+        {
+            b'\n' if after_return => {}
+            0x1B => self.escape = Escape::Escape,
+            b'\r' | b'\n' => {
+                serial_print!("\r\n");
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+                self.cursor = 0;
+                if !line.trim().is_empty() && self.history.last() != Some(&line)
+                {
+                    if self.history.len() == MAX_HISTORY
+                    {
+                        self.history.remove(0);
+                    }
+                    self.history.push(line.clone());
+                }
+                self.history_index = self.history.len();
+                return Some(line);
+            }
+            // Backspace and Delete
+            0x08 | 0x7F if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                self.redraw();
+            }
+            // ^A and ^E
+            0x01 => {
+                self.cursor = 0;
+                self.redraw();
+            }
+            0x05 => {
+                self.cursor = self.line.len();
+                self.redraw();
+            }
+            // ^U erases the line, ^C drops it
+            0x15 => {
+                self.line.clear();
+                self.cursor = 0;
+                self.redraw();
+            }
+            0x03 => {
+                serial_print!("^C\r\n");
+                self.line.clear();
+                self.cursor = 0;
+                self.history_index = self.history.len();
+                self.redraw();
+            }
+            0x20..=0x7E if self.line.len() < MAX_LINE_LENGTH => {
+                self.line.insert(self.cursor, byte);
+                self.cursor += 1;
+                if self.cursor == self.line.len()
+                {
+                    serial_print!("{}", byte as char);
+                }
+                else
+                {
+                    self.redraw();
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+static EDITOR: Mutex<LineEditor> = Mutex::new(LineEditor::new());
+
+/// Handles the bytes received, from the kernel main loop
+fn handle_input()
+{
+    loop
+    {
+        // The queue is not kept locked while a command runs
+        let byte = match INPUT.lock().pop()
+        {
+            Some(byte) => byte,
+            None => return
+        };
+        let line = EDITOR.lock().input(byte);
+        if let Some(line) = line
+        {
+            commands::run(&line);
+            serial_print!("{}", PROMPT);
+        }
+    }
+}
+
+/// Starts the shell on the first serial port, unless disabled on the command line
+pub fn init()
+{
+    if !SHELL_ENABLED.get()
+    {
+        return;
+    }
+
+    let work = INPUT_WORK.call_once(|| deferred::register(handle_input)).clone();
+    let registered = interrupts::register_legacy_handler(COM1_IRQ, Arc::new(move || {
+        let mut input = INPUT.lock();
+        while let Some(byte) = serial::try_receive()
+        {
+            input.push(byte);
+        }
+        work.schedule();
+    }));
+    if !registered
+    {
+        warn!("[SHELL] Failed to register the serial port interrupt handler");
+        return;
+    }
+
+    info!("[SHELL] Listening on the first serial port, type help for the commands");
+    serial_print!("{}", PROMPT);
+}
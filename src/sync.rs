@@ -0,0 +1,182 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::instructions::interrupts;
+use crate::{apic, time};
+
+/// One-shot event signalled from an interrupt handler.
+///
+/// Waiters halt the CPU until the next interrupt instead of spinning, and re-check the event each time
+/// they are woken up.
+#[derive(Debug)]
+pub struct Completion
+{
+    done: AtomicBool,
+}
+
+impl Completion
+{
+    pub const fn new() -> Self
+    {
+        Completion {
+            done: AtomicBool::new(false)
+        }
+    }
+
+    #[inline]
+    pub fn reset(&self)
+    {
+        self.done.store(false, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn complete(&self)
+    {
+        self.done.store(true, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn is_completed(&self) -> bool
+    {
+        self.done.load(Ordering::Acquire)
+    }
+
+    /// Blocks until the event is signalled or `timeout_ms` milliseconds have passed, returns whether the event
+    /// was signalled. Interrupts must be enabled, since the event is signalled from an interrupt handler.
+    pub fn wait_timeout(&self, timeout_ms: u64) -> bool
+    {
+        debug_assert!(interrupts::are_enabled(), "Waiting on a completion with interrupts disabled");
+
+        let deadline = time::uptime_ms() + timeout_ms;
+        loop
+        {
+            // Checking the flag and halting must be atomic with respect to interrupts, otherwise the
+            // wake-up could be delivered between the two and lost.
+            interrupts::disable();
+            if self.is_completed()
+            {
+                interrupts::enable();
+                return true;
+            }
+            if time::uptime_ms() >= deadline
+            {
+                interrupts::enable();
+                return false;
+            }
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// A spin lock which disables interrupts while it is held, so that an interrupt handler taking it cannot wait
+/// for the code it interrupted.
+///
+/// Exceptions and NMIs are not masked: code which can run in them must use `try_lock`, and can tell with
+/// `is_locked_by_current_cpu` whether the lock is held by the code it interrupted.
+pub struct IrqMutex<T>
+{
+    inner: spin::Mutex<T>,
+    /// ID of the processor holding the lock plus one, 0 when it is free
+    owner: AtomicU32
+}
+
+impl<T> IrqMutex<T>
+{
+    pub const fn new(value: T) -> Self
+    {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            owner: AtomicU32::new(0)
+        }
+    }
+
+    fn guard<'a>(&'a self, guard: spin::MutexGuard<'a, T>, interrupts_enabled: bool) -> IrqMutexGuard<'a, T>
+    {
+        self.owner.store(apic::cpu_id() as u32 + 1, Ordering::Relaxed);
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            owner: &self.owner,
+            interrupts_enabled
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T>
+    {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        self.guard(self.inner.lock(), interrupts_enabled)
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>>
+    {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock()
+        {
+            Some(guard) => Some(self.guard(guard, interrupts_enabled)),
+            None => {
+                if interrupts_enabled
+                {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether the lock is held by the code this processor interrupted, which taking it would wait for
+    /// forever
+    pub fn is_locked_by_current_cpu(&self) -> bool
+    {
+        self.inner.is_locked() && self.owner.load(Ordering::Relaxed) == apic::cpu_id() as u32 + 1
+    }
+
+    /// Releases the lock held by someone else, e.g. by the code interrupted by a fatal exception.
+    ///
+    /// # Safety
+    /// The holder must never use its guard again, or the two users of the data will race.
+    pub unsafe fn force_unlock(&self)
+    {
+        self.owner.store(0, Ordering::Relaxed);
+        self.inner.force_unlock();
+    }
+}
+
+pub struct IrqMutexGuard<'a, T>
+{
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    owner: &'a AtomicU32,
+    interrupts_enabled: bool
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T>
+{
+    fn drop(&mut self)
+    {
+        self.owner.store(0, Ordering::Relaxed);
+        // The lock is released before interrupts are enabled again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled
+        {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::deferred::Work;
+use crate::interrupts::register_legacy_handler;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_IRQ: u8 = 0;
+
+/// Frequency of the system tick, one tick is one millisecond
+pub const TICKS_PER_SECOND: u64 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+struct PeriodicWork
+{
+    interval: u64,
+    next: u64,
+    work: Arc<Work>
+}
+
+/// Only locked with interrupts disabled, so that the tick handler never finds it locked
+static PERIODIC_WORK: Mutex<Vec<PeriodicWork>> = Mutex::new(Vec::new());
+
+/// Programs the PIT to interrupt `TICKS_PER_SECOND` times per second and counts the ticks.
+/// The periodic interrupt also wakes up CPUs halted in `Completion::wait`.
+pub fn init()
+{
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+
+    unsafe {
+        // Channel 0, low byte then high byte, mode 2 (rate generator)
+        Port::<u8>::new(PIT_COMMAND).write(0b0011_0100);
+        let mut channel_0 = Port::<u8>::new(PIT_CHANNEL_0);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+
+    register_legacy_handler(PIT_IRQ, Arc::new(|| {
+        let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        for periodic in PERIODIC_WORK.lock().iter_mut()
+        {
+            if ticks >= periodic.next
+            {
+                periodic.next = ticks + periodic.interval;
+                periodic.work.schedule();
+            }
+        }
+    }));
+}
+
+/// Schedules `work` every `interval_ms` milliseconds, it then runs from the kernel main loop
+pub fn schedule_periodic(interval_ms: u64, work: Arc<Work>)
+{
+    let interval = core::cmp::max(interval_ms * TICKS_PER_SECOND / 1000, 1);
+    interrupts::without_interrupts(|| {
+        PERIODIC_WORK.lock().push(PeriodicWork {
+            interval,
+            next: ticks() + interval,
+            work
+        });
+    });
+}
+
+#[inline]
+pub fn ticks() -> u64
+{
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds elapsed since `init`
+#[inline]
+pub fn uptime_ms() -> u64
+{
+    ticks() * 1000 / TICKS_PER_SECOND
+}
+
+/// Halts the CPU for at least `milliseconds`. Interrupts must be enabled for the tick to advance.
+pub fn sleep_ms(milliseconds: u64)
+{
+    debug_assert!(interrupts::are_enabled(), "Sleeping with interrupts disabled");
+
+    let deadline = uptime_ms() + milliseconds;
+    while uptime_ms() < deadline
+    {
+        x86_64::instructions::hlt();
+    }
+}
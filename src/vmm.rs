@@ -7,12 +7,25 @@ use lazy_static::lazy_static;
 use log::info;
 use spin::Mutex;
 use x86_64::structures::paging::page::PageRangeInclusive;
-use crate::allocator::{HEAP_SIZE, HEAP_START};
+use crate::allocator::{MAX_HEAP_SIZE, HEAP_START};
 use crate::BOOT_INFO;
 
 pub struct Vmm
 {
-    mapper: RecursivePageTable<'static>
+    mapper: RecursivePageTable<'static>,
+    /// Entry of the level 4 table pointing to itself, through which the tables are reachable
+    recursive_index: u16
+}
+
+/// An entry of the page tables met while translating an address
+#[derive(Debug, Copy, Clone)]
+pub struct WalkEntry
+{
+    /// Level of the table holding the entry, from 4 down to 1
+    pub level: u8,
+    pub index: u16,
+    pub address: PhysAddr,
+    pub flags: PageTableFlags
 }
 
 #[derive(Debug)]
@@ -55,9 +68,11 @@ impl Vmm
 
     pub unsafe fn new() -> Vmm
     {
-        let level_4_table = Self::active_level_4_table(BOOT_INFO.recursive_index.into_option().unwrap());
+        let recursive_index = BOOT_INFO.recursive_index.into_option().unwrap();
+        let level_4_table = Self::active_level_4_table(recursive_index);
         Vmm {
-            mapper: RecursivePageTable::new(level_4_table).expect("Failed to create recursive page table")
+            mapper: RecursivePageTable::new(level_4_table).expect("Failed to create recursive page table"),
+            recursive_index
         }
     }
 
@@ -91,6 +106,42 @@ impl Vmm
         self.mapper.translate_addr(addr)
     }
 
+    /// The entries translating `addr`, from the level 4 table down to the first one which is not present or
+    /// maps a huge page. The tables are read through the recursive mapping without allocating, so that fault
+    /// handlers can use it.
+    pub fn walk(&self, addr: VirtAddr) -> [Option<WalkEntry>; 4]
+    {
+        let recursive = self.recursive_index as u64;
+        let indexes = [u16::from(addr.p4_index()), u16::from(addr.p3_index()), u16::from(addr.p2_index()), u16::from(addr.p1_index())];
+        let mut entries = [None; 4];
+
+        for depth in 0..4
+        {
+            // The table of each level is reached by going through the recursive entry once more per level left
+            let mut table_addr = 0;
+            for i in 0..4
+            {
+                let index = if i < 4 - depth { recursive } else { indexes[i - (4 - depth)] as u64 };
+                table_addr |= index << (39 - 9 * i);
+            }
+            let table = unsafe { &*(VirtAddr::new_truncate(table_addr).as_ptr::<PageTable>()) };
+            let entry = &table[indexes[depth] as usize];
+
+            entries[depth] = Some(WalkEntry {
+                level: 4 - depth as u8,
+                index: indexes[depth],
+                address: entry.addr(),
+                flags: entry.flags()
+            });
+            if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+            {
+                break;
+            }
+        }
+
+        entries
+    }
+
     fn range_inclusive(start: VirtAddr, end: VirtAddr) -> PageRangeInclusive<Size4KiB>
     {
         let page_start = Page::containing_address(start);
@@ -106,7 +157,7 @@ impl Vmm
         let page_count = (end_phys_addr_aligned - phys_addr_aligned) / 0x1000;
 
         if let Some(virt_addr) = self.find_free_pages(
-            Page::containing_address(VirtAddr::new((HEAP_START + HEAP_SIZE) as u64)),
+            Page::containing_address(VirtAddr::new((HEAP_START + MAX_HEAP_SIZE) as u64)),
             Page::containing_address(VirtAddr::new(u64::MAX)),
             page_count as usize
         )
@@ -134,6 +185,34 @@ impl Vmm
         }
     }
 
+    /// Allocates a physical frame and maps it uncached, for memory shared with a device through DMA.
+    pub fn allocate_dma_page(&mut self) -> Result<(PhysAddr, VirtAddr), MappingError>
+    {
+        let frame: PhysFrame<Size4KiB> = PMM.lock().allocate_frame().ok_or(MappingError::MapToError(MapToError::FrameAllocationFailed))?;
+        let virt_addr = self.map_region(frame.start_address(), 0x1000, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE)?;
+
+        unsafe { core::ptr::write_bytes(virt_addr.as_mut_ptr::<u8>(), 0, 0x1000) };
+
+        Ok((frame.start_address(), virt_addr))
+    }
+
+    /// Allocates `count` physical frames and maps them at contiguous virtual addresses, with caching enabled.
+    pub fn allocate_pages(&mut self, count: usize) -> Result<VirtAddr, MappingError>
+    {
+        let virt_addr = self.find_free_pages(
+            Page::containing_address(VirtAddr::new((HEAP_START + MAX_HEAP_SIZE) as u64)),
+            Page::containing_address(VirtAddr::new(u64::MAX)),
+            count
+        ).ok_or(MappingError::NoFreePages)?;
+
+        for i in 0..count as u64
+        {
+            unsafe { self.map(Page::containing_address(virt_addr + i * 0x1000), PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?.flush() };
+        }
+
+        Ok(virt_addr)
+    }
+
     pub fn unmap_region(&mut self, virt_addr: VirtAddr, size: u64) -> Result<(), UnmapError>
     {
         let virt_addr_aligned = virt_addr.align_down(0x1000 as u64);
@@ -0,0 +1,123 @@
+"""Writes the symbol table of the kernel into its `.ksymtab` section, for the kernel to symbolize backtraces.
+
+The section is reserved at a fixed size by `src/backtrace/symbols.rs`, so filling it moves nothing else in the
+image. Run it on the kernel ELF after each build, before creating the boot image:
+
+    python tools/embed_symbols.py target/x86_64-os/debug/rust-kernel
+
+Table layout, little endian:
+    magic "KSYM", u32 count, u32 offset of the names from the start of the table
+    count entries sorted by address: u64 address, u32 size, u32 offset of the name in the names
+    names, each ending with a NUL byte
+"""
+
+import re
+import struct
+import sys
+
+SECTION_NAME = b".ksymtab"
+MAGIC = b"KSYM"
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+# Legacy Rust mangling escapes
+ESCAPES = {"$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">", "$LP$": "(", "$RP$": ")", "$C$": ",",
+           "$u7e$": "~", "$u20$": " ", "$u27$": "'", "$u5b$": "[", "$u5d$": "]", "$u7b$": "{", "$u7d$": "}",
+           "$u3b$": ";", "$u2b$": "+", "$u22$": "\""}
+
+
+def demangle(name):
+    """Demangles a legacy Rust symbol such as `_ZN4core9panicking5panic17h0123456789abcdefE`, other names are
+    returned as they are"""
+    if not (name.startswith("_ZN") and name.endswith("E")):
+        return name
+    components = []
+    rest = name[3:-1]
+    while rest:
+        match = re.match(r"(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        components.append(rest[start:start + length])
+        rest = rest[start + length:]
+    if components and re.fullmatch(r"h[0-9a-f]{16}", components[-1]):
+        components.pop()
+
+    def unescape(component):
+        if component.startswith("_$"):
+            component = component[1:]
+        for escape, char in ESCAPES.items():
+            component = component.replace(escape, char)
+        return component.replace("..", "::")
+
+    return "::".join(unescape(component) for component in components)
+
+
+def read_sections(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 2 or elf[5] != 1:
+        sys.exit("not a little endian ELF64 file")
+    section_offset, = struct.unpack_from("<Q", elf, 0x28)
+    entry_size, count, names_index = struct.unpack_from("<HHH", elf, 0x3A)
+
+    sections = []
+    for i in range(count):
+        name, kind, _, address, offset, size, link, _, _, _ = struct.unpack_from("<IIQQQQIIQQ", elf, section_offset + i * entry_size)
+        sections.append({"name": name, "type": kind, "address": address, "offset": offset, "size": size, "link": link})
+
+    names = sections[names_index]
+    for section in sections:
+        start = names["offset"] + section["name"]
+        section["name"] = elf[start:elf.index(b"\0", start)]
+    return sections
+
+
+def read_functions(elf, sections):
+    symtab = next((section for section in sections if section["type"] == SHT_SYMTAB), None)
+    if symtab is None:
+        sys.exit("the kernel has no symbol table, it must not be stripped")
+    strtab = sections[symtab["link"]]
+
+    functions = {}
+    for offset in range(symtab["offset"], symtab["offset"] + symtab["size"], 24):
+        name, info, _, _, address, size = struct.unpack_from("<IBBHQQ", elf, offset)
+        if info & 0xF != STT_FUNC or address == 0:
+            continue
+        start = strtab["offset"] + name
+        functions[address] = (size, demangle(elf[start:elf.index(b"\0", start)].decode("utf-8", "replace")))
+    return sorted(functions.items())
+
+
+def encode(functions):
+    header_size = 12
+    entries = bytearray()
+    names = bytearray()
+    for address, (size, name) in functions:
+        entries += struct.pack("<QII", address, min(size, 0xFFFFFFFF), len(names))
+        names += name.encode("utf-8") + b"\0"
+    return MAGIC + struct.pack("<II", len(functions), header_size + len(entries)) + entries + names
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: embed_symbols.py <kernel ELF>")
+
+    with open(sys.argv[1], "r+b") as file:
+        elf = bytearray(file.read())
+        sections = read_sections(elf)
+        section = next((section for section in sections if section["name"] == SECTION_NAME), None)
+        if section is None:
+            sys.exit("the kernel has no {} section".format(SECTION_NAME.decode()))
+
+        functions = read_functions(elf, sections)
+        table = encode(functions)
+        if len(table) > section["size"]:
+            sys.exit("the symbol table needs {} bytes, {} are reserved".format(len(table), section["size"]))
+
+        file.seek(section["offset"])
+        file.write(table + bytes(section["size"] - len(table)))
+        print("Embedded {} symbols ({} of {} bytes)".format(len(functions), len(table), section["size"]))
+
+
+if __name__ == "__main__":
+    main()